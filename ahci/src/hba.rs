@@ -8,6 +8,7 @@
 use sunrise_libuser::io::{Io, Mmio};
 use sunrise_libuser::syscalls::{sleep_thread, query_physical_address};
 use sunrise_libuser::mem::{map_mmio, virt_to_phys};
+use sunrise_libuser::threads::yield_now;
 use sunrise_libuser::error::{Error, AhciError};
 use sunrise_libuser::zero_box::*;
 use core::fmt::{self, Debug, Formatter};
@@ -677,7 +678,7 @@ impl Px {
     // body:   I should look this up.
     fn wait_command_completion(&self, slot: usize) -> Result<(), Error> {
         while self.command_running(slot) {
-            sleep_thread(0).unwrap();
+            yield_now();
         }
         if self.is.read().is_err() {
             Err(AhciError::IoError.into())
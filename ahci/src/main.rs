@@ -101,6 +101,9 @@ static DISKS: Mutex<Vec<Arc<Mutex<Disk>>>> = Mutex::new(Vec::new());
 ///     - Push the created [Disk]s in [DISKS].
 /// 3. Start the event loop.
 fn main() {
+    // lm writes its log files through us, don't log through it.
+    sunrise_libuser::log_impl::disable_log_manager();
+
     debug!("AHCI driver starting up");
     let ahci_controllers = pci::get_ahci_controllers();
     debug!("AHCI controllers : {:#x?}", ahci_controllers);
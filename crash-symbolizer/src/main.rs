@@ -0,0 +1,414 @@
+//! Crash symbolizer
+//!
+//! When the kernel panics, it prints a crash record on the serial port: a machine-readable block
+//! holding the panic message, the registers, and the backtrace of the kernel stack of every thread.
+//! See `write_crash_record` in the kernel's panic module for the format.
+//!
+//! This tool finds the crash records in a serial log, and prints them with every address
+//! resolved to a function of the kernel ELF. It warns if the build id of the record doesn't
+//! match the ELF, as the symbols would then be meaningless.
+//!
+//! Usage: <kernel_elf> [serial_log]
+//!
+//! The serial log is read from stdin if not given.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use rustc_demangle::demangle;
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+/// Line opening a crash record. Must match the kernel's `CRASH_RECORD_BEGIN`.
+const CRASH_RECORD_BEGIN: &str = "===== BEGIN SUNRISE CRASH RECORD =====";
+
+/// Line closing a crash record. Must match the kernel's `CRASH_RECORD_END`.
+const CRASH_RECORD_END: &str = "===== END SUNRISE CRASH RECORD =====";
+
+/// Version of the crash record format we understand.
+const CRASH_RECORD_VERSION: &str = "1";
+
+/// A function of the kernel.
+#[derive(Debug)]
+struct Symbol {
+    /// Address of the function.
+    address: u64,
+    /// Size of the function, in bytes.
+    size: u64,
+    /// Demangled name of the function.
+    name: String,
+}
+
+/// The functions and build id of the kernel ELF.
+#[derive(Debug)]
+struct Kernel {
+    /// The build id, from the `.note.gnu.build-id` section. Empty if there is none.
+    build_id: Vec<u8>,
+    /// The functions, sorted by address.
+    symbols: Vec<Symbol>,
+}
+
+impl Kernel {
+    /// Parses the build id and the symbol table of a kernel ELF.
+    fn parse(data: &[u8]) -> Result<Kernel, String> {
+        let elf = ElfFile::new(data).map_err(|err| format!("Invalid ELF: {}", err))?;
+
+        let build_id = elf.find_section_by_name(".note.gnu.build-id")
+            .map(|section| parse_build_id_note(section.raw_data(&elf)))
+            .unwrap_or_default();
+
+        let symtab = elf.find_section_by_name(".symtab")
+            .ok_or_else(|| String::from("The kernel ELF has no symbol table"))?
+            .get_data(&elf)
+            .map_err(|err| format!("Invalid symbol table: {}", err))?;
+        let entries = match symtab {
+            SectionData::SymbolTable32(entries) => entries,
+            _ => return Err(String::from("Expected a 32-bit symbol table"))
+        };
+
+        let mut symbols: Vec<Symbol> = entries.iter()
+            .filter(|entry| entry.size() != 0)
+            .filter(|entry| match entry.get_type() { Ok(Type::Func) => true, _ => false })
+            .filter_map(|entry| Some(Symbol {
+                address: entry.value(),
+                size: entry.size(),
+                name: format!("{:#}", demangle(entry.get_name(&elf).ok()?)),
+            }))
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+
+        Ok(Kernel { build_id, symbols })
+    }
+
+    /// Finds the function containing `address`.
+    fn lookup(&self, address: u64) -> Option<&Symbol> {
+        let index = match self.symbols.binary_search_by_key(&address, |symbol| symbol.address) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        if address < symbol.address + symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    /// Formats `address` as `function+offset`.
+    ///
+    /// `lookup_address` is the address used to find the function. It differs from `address` for
+    /// return addresses: those point after the call, which may be past the end of the caller.
+    fn symbolize(&self, address: u64, lookup_address: u64) -> String {
+        match self.lookup(lookup_address) {
+            Some(symbol) => format!("{}+{:#x}", symbol.name, address - symbol.address),
+            None => String::from("??"),
+        }
+    }
+}
+
+/// Gets the desc of an ELF note: namesz, descsz, type, then the name and the desc, both padded
+/// to 4 bytes.
+fn parse_build_id_note(note: &[u8]) -> Vec<u8> {
+    let word = |offset: usize| note.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+    let (namesz, descsz) = match (word(0), word(4)) {
+        (Some(namesz), Some(descsz)) => (namesz, descsz),
+        _ => return Vec::new()
+    };
+    let desc_start = 12 + ((namesz + 3) & !3);
+    note.get(desc_start..desc_start + descsz).map(<[u8]>::to_vec).unwrap_or_default()
+}
+
+/// Reverts the escaping of backslashes and line breaks done by the kernel.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Parses a hexadecimal address, with or without the `0x` prefix.
+fn parse_address(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// A crash record found in a serial log.
+#[derive(Debug, PartialEq)]
+struct CrashRecord<'a> {
+    /// The lines between the delimiters of the record.
+    lines: Vec<&'a str>,
+    /// Whether the record was closed. A record is cut short if the kernel faulted again while
+    /// printing it, or was reset.
+    complete: bool,
+}
+
+/// Finds the crash records in a serial log.
+///
+/// The delimiters may be prefixed by whatever the serial port carried before them on the same
+/// line.
+fn find_records(log: &str) -> Vec<CrashRecord<'_>> {
+    let mut records: Vec<CrashRecord<'_>> = Vec::new();
+    let mut in_record = false;
+    for line in log.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.ends_with(CRASH_RECORD_BEGIN) {
+            records.push(CrashRecord { lines: Vec::new(), complete: false });
+            in_record = true;
+        } else if !in_record {
+            continue;
+        } else if line.ends_with(CRASH_RECORD_END) {
+            records.last_mut().unwrap().complete = true;
+            in_record = false;
+        } else {
+            records.last_mut().unwrap().lines.push(line);
+        }
+    }
+    records
+}
+
+/// Prints a crash record, given as the lines between its delimiters, with every address
+/// symbolized.
+fn print_record<W: Write>(out: &mut W, kernel: &Kernel, lines: &[&str]) -> io::Result<()> {
+    // Index of the next frame of the current thread.
+    let mut frame_index = 0;
+
+    for line in lines {
+        let (key, value) = match line.find(' ') {
+            Some(pos) => (&line[..pos], &line[pos + 1..]),
+            None => (*line, ""),
+        };
+        match key {
+            "version" => {
+                if value != CRASH_RECORD_VERSION {
+                    writeln!(out, "WARNING: unknown crash record version {}, expected {}", value, CRASH_RECORD_VERSION)?;
+                }
+            },
+            "build-id" => {
+                let kernel_build_id: String = kernel.build_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+                if value.is_empty() || kernel_build_id.is_empty() {
+                    writeln!(out, "Build id: unknown, cannot check the kernel ELF matches")?;
+                } else if value != kernel_build_id {
+                    writeln!(out, "WARNING: build id {} doesn't match the kernel ELF ({}), symbols are likely wrong",
+                             value, kernel_build_id)?;
+                } else {
+                    writeln!(out, "Build id: {}", value)?;
+                }
+            },
+            "reason" => writeln!(out, "Reason: {}", value)?,
+            "message" => writeln!(out, "Message: {}", unescape(value))?,
+            "registers" => {
+                writeln!(out, "Registers:")?;
+                for register in value.split_whitespace() {
+                    let mut parts = register.splitn(2, '=');
+                    let (name, address) = match (parts.next(), parts.next().and_then(parse_address)) {
+                        (Some(name), Some(address)) => (name, address),
+                        _ => continue
+                    };
+                    if name == "eip" {
+                        writeln!(out, "    {:>6}={:#010x} {}", name, address, kernel.symbolize(address, address))?;
+                    } else {
+                        writeln!(out, "    {:>6}={:#010x}", name, address)?;
+                    }
+                }
+            },
+            "thread" => {
+                // <pid> <tid> <state> <current|saved> <name>
+                let mut parts = value.splitn(5, ' ');
+                let pid = parts.next().unwrap_or("?");
+                let tid = parts.next().unwrap_or("?");
+                let state = parts.next().unwrap_or("?");
+                let origin = parts.next().unwrap_or("?");
+                let name = unescape(parts.next().unwrap_or(""));
+                writeln!(out)?;
+                writeln!(out, "Thread {} (pid {}, tid {}) - {}{}", name, pid, tid, state,
+                         if origin == "current" { ", panicked here" } else { "" })?;
+                frame_index = 0;
+            },
+            "frame" => {
+                match parse_address(value) {
+                    Some(address) => {
+                        // All frames but the first one are return addresses.
+                        let lookup_address = if frame_index == 0 { address } else { address.saturating_sub(1) };
+                        writeln!(out, "    #{:<2} {:#010x} {}", frame_index, address, kernel.symbolize(address, lookup_address))?;
+                    },
+                    None => writeln!(out, "    #{:<2} invalid address {}", frame_index, value)?,
+                }
+                frame_index += 1;
+            },
+            _ if key.starts_with('#') => writeln!(out, "{}", line)?,
+            _ => writeln!(out, "?? {}", line)?,
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        println!("usage: <kernel_elf> [serial_log]");
+        process::exit(1);
+    }
+
+    let elf_data = fs::read(&args[1]).expect("Cannot read the kernel ELF");
+    let kernel = match Kernel::parse(&elf_data) {
+        Ok(kernel) => kernel,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let log = match args.get(2) {
+        Some(path) => fs::read(path).expect("Cannot read the serial log"),
+        None => {
+            let mut log = Vec::new();
+            io::stdin().read_to_end(&mut log).expect("Cannot read stdin");
+            log
+        }
+    };
+    // The serial port may carry garbage, don't choke on it.
+    let log = String::from_utf8_lossy(&log);
+
+    let records = find_records(&log);
+    if records.is_empty() {
+        eprintln!("No crash record found");
+        process::exit(1);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (index, record) in records.iter().enumerate() {
+        println!("===== Crash record #{} =====", index + 1);
+        if !record.complete {
+            println!("WARNING: this crash record is truncated");
+        }
+        print_record(&mut out, &kernel, &record.lines).expect("Cannot write to stdout");
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A kernel with a hole between its two functions.
+    fn kernel() -> Kernel {
+        Kernel {
+            build_id: vec![0xde, 0xad, 0xbe, 0xef],
+            symbols: vec![
+                Symbol { address: 0x1000, size: 0x100, name: String::from("sunrise_kernel::main") },
+                Symbol { address: 0x1200, size: 0x40, name: String::from("sunrise_kernel::panic::kernel_panic") },
+            ],
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let kernel = kernel();
+        assert!(kernel.lookup(0xfff).is_none());
+        assert_eq!(kernel.lookup(0x1000).unwrap().name, "sunrise_kernel::main");
+        assert_eq!(kernel.lookup(0x10ff).unwrap().name, "sunrise_kernel::main");
+        assert!(kernel.lookup(0x1100).is_none());
+        assert_eq!(kernel.lookup(0x1210).unwrap().name, "sunrise_kernel::panic::kernel_panic");
+        assert!(kernel.lookup(0x1240).is_none());
+    }
+
+    #[test]
+    fn symbolize() {
+        let kernel = kernel();
+        assert_eq!(kernel.symbolize(0x1010, 0x1010), "sunrise_kernel::main+0x10");
+        // A return address right after a call ending its function.
+        assert_eq!(kernel.symbolize(0x1100, 0x10ff), "sunrise_kernel::main+0x100");
+        assert_eq!(kernel.symbolize(0x1100, 0x1100), "??");
+    }
+
+    #[test]
+    fn build_id_note() {
+        let mut note = Vec::new();
+        note.extend_from_slice(&4u32.to_le_bytes());
+        note.extend_from_slice(&4u32.to_le_bytes());
+        note.extend_from_slice(&3u32.to_le_bytes());
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_build_id_note(&note), vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse_build_id_note(&note[..14]), Vec::<u8>::new());
+        assert_eq!(parse_build_id_note(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn unescape_strings() {
+        assert_eq!(unescape("plain"), "plain");
+        assert_eq!(unescape("line\\nbreak\\r"), "line\nbreak\r");
+        assert_eq!(unescape("back\\\\slash"), "back\\slash");
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("0x1000"), Some(0x1000));
+        assert_eq!(parse_address("c0ffee"), Some(0xc0ffee));
+        assert_eq!(parse_address("0xnope"), None);
+    }
+
+    #[test]
+    fn records() {
+        let log = format!("booting\r\n[ 1.0] {}\r\nversion 1\n{}\nnoise\n{}\nreason KernelAssert\n",
+                          CRASH_RECORD_BEGIN, CRASH_RECORD_END, CRASH_RECORD_BEGIN);
+        let records = find_records(&log);
+        assert_eq!(records, vec![
+            CrashRecord { lines: vec!["version 1"], complete: true },
+            CrashRecord { lines: vec!["reason KernelAssert"], complete: false },
+        ]);
+        assert!(find_records("no crash here\n").is_empty());
+    }
+
+    #[test]
+    fn print() {
+        let lines = [
+            "version 1",
+            "build-id deadbeef",
+            "message assertion\\nfailed",
+            "registers eip=0x1010 esp=0x2000",
+            "thread 1 2 Running current init\\\\thread",
+            "frame 0x1010",
+            "frame 0x1100",
+            "frame 0x5000",
+            "frame bogus",
+            "# this thread has no saved context",
+        ];
+        let mut out = Vec::new();
+        print_record(&mut out, &kernel(), &lines).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = "\
+Build id: deadbeef
+Message: assertion
+failed
+Registers:
+       eip=0x00001010 sunrise_kernel::main+0x10
+       esp=0x00002000
+
+Thread init\\thread (pid 1, tid 2) - Running, panicked here
+    #0  0x00001010 sunrise_kernel::main+0x10
+    #1  0x00001100 sunrise_kernel::main+0x100
+    #2  0x00005000 ??
+    #3  invalid address bogus
+# this thread has no saved context
+";
+        assert_eq!(out, expected);
+    }
+}
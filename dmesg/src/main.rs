@@ -0,0 +1,136 @@
+//! Kernel log viewer
+//!
+//! Prints the kernel log ring buffer in a terminal window, then keeps
+//! printing new records as the kernel logs them. The records are read through
+//! the `read_kernel_log` syscall.
+//!
+//! Takes an optional level as argument (`error`, `warn`, `info`, `debug` or
+//! `trace`): only the records at this level or above are shown. Defaults to
+//! showing everything.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+
+use core::fmt::Write;
+use sunrise_libuser::argv;
+use sunrise_libuser::terminal::{Terminal, WindowSize};
+use sunrise_libuser::syscalls::{self, KernelLogRecord};
+
+/// Number of records read per syscall.
+const BATCH_SIZE: usize = 16;
+
+/// Time between two polls of the kernel log, in nanoseconds.
+const POLL_PERIOD_NS: usize = 500_000_000;
+
+/// Names of the log levels, indexed by level - 1.
+const LEVEL_NAMES: [&str; 5] = ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
+
+/// Gets the first argument we were launched with, if any.
+fn first_arg() -> Option<&'static [u8]> {
+    if argv::argc() < 2 {
+        return None;
+    }
+
+    unsafe {
+        let ptr = *argv::argv().offset(1);
+        let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+        Some(core::slice::from_raw_parts(ptr, len))
+    }
+}
+
+/// Parses a level name into the numerical level used in [KernelLogRecord].
+fn parse_level(name: &[u8]) -> Option<u8> {
+    LEVEL_NAMES.iter()
+        .position(|level| level.as_bytes().eq_ignore_ascii_case(name))
+        .map(|idx| idx as u8 + 1)
+}
+
+/// Prints a record on a single line.
+fn print_record(terminal: &mut Terminal, record: &KernelLogRecord) {
+    let level = LEVEL_NAMES.get((record.level as usize).wrapping_sub(1)).unwrap_or(&"?");
+    let _ = writeln!(terminal, "[{:>5}.{:06}] [{}] - {} - {}",
+                     record.timestamp / 1_000_000_000, (record.timestamp / 1000) % 1_000_000,
+                     level, record.target(), record.message());
+}
+
+fn main() {
+    let mut terminal = Terminal::new(WindowSize::FontLines(-1, false)).unwrap();
+
+    let max_level = match first_arg() {
+        None => 5,
+        Some(arg) => parse_level(arg).unwrap_or_else(|| {
+            let _ = writeln!(terminal, "usage: dmesg [error|warn|info|debug|trace]");
+            5
+        })
+    };
+
+    let mut records = [KernelLogRecord::default(); BATCH_SIZE];
+    let mut next_sequence = 0;
+    loop {
+        let count = syscalls::read_kernel_log(&mut records, next_sequence).unwrap();
+        if count == 0 {
+            let _ = terminal.draw();
+            syscalls::sleep_thread(POLL_PERIOD_NS).unwrap();
+            continue;
+        }
+
+        for record in &records[..count] {
+            // The kernel skips the records that were overwritten before we
+            // could read them.
+            if record.sequence > next_sequence {
+                let _ = writeln!(terminal, "-- {} records lost --", record.sequence - next_sequence);
+            }
+            if record.level <= max_level {
+                print_record(&mut terminal, record);
+            }
+            next_sequence = record.sequence + 1;
+        }
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"dmesg\0\0\0\0\0\0\0",
+    title_id: 0x0200000000001090,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
+
+        sunrise_libuser::syscalls::nr::ReadKernelLog,
+    ]
+});
@@ -48,6 +48,9 @@ use detail::driver::fat::FATDriver;
 pub type LibUserResult<T> = Result<T, Error>;
 
 fn main() {
+    // lm writes its log files through us, don't log through it.
+    sunrise_libuser::log_impl::disable_log_manager();
+
     {
         let mut driver_manager = DRIVER_MANAGER.lock();
         driver_manager.register_driver(Box::new(FATDriver) as Box<dyn FileSystemDriver>);
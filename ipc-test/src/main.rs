@@ -0,0 +1,424 @@
+//! IPC test title
+//!
+//! Exercises the way the kernel passes buffers over IPC. The client sends
+//! requests carrying X, A, B and W buffers, and checks the results.
+//!
+//! When launched without arguments, the tests are first run against a client
+//! thread of this very process. Then, it registers the `ipctest` service,
+//! launches itself with the `client` argument, and serves the requests of this
+//! new client.
+//!
+//! The command to run is passed in the raw data of the requests, to keep the
+//! server free of any dispatch machinery.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+#[macro_use]
+extern crate alloc;
+
+use sunrise_libuser::argv;
+use sunrise_libuser::ipc::Message;
+use sunrise_libuser::types::{ClientSession, ServerSession};
+use sunrise_libuser::error::{Error, KernelError};
+use sunrise_libuser::sm::IUserInterfaceProxy;
+use sunrise_libuser::ldr::ILoaderInterfaceProxy;
+use sunrise_libuser::syscalls;
+use sunrise_libuser::threads::{self, Thread};
+use alloc::boxed::Box;
+use log::info;
+
+/// Sums the bytes of the X buffers of the request.
+const CMD_SUM_POINTERS: u32 = 0;
+/// Replies with an X buffer containing [REPLY_DATA].
+const CMD_REPLY_POINTER: u32 = 1;
+/// Sums the bytes of the A buffer of the request.
+const CMD_SUM_IN_BUFFER: u32 = 2;
+/// Fills the B buffer of the request with [FILL_BYTE].
+const CMD_FILL_OUT_BUFFER: u32 = 3;
+/// Increments every byte of the W buffer of the request.
+const CMD_INCREMENT_INOUT_BUFFER: u32 = 4;
+/// Attempts to reply with an A buffer, which the kernel should refuse.
+const CMD_REPLY_IN_BUFFER: u32 = 5;
+/// Stops the server.
+const CMD_STOP: u32 = 6;
+
+/// Size of each of the two C buffers of the server.
+const POINTER_BUF_SIZE: usize = 0x40;
+
+/// Data sent back by [CMD_REPLY_POINTER].
+static REPLY_DATA: [u8; 14] = *b"Hello, client!";
+
+/// Byte written by [CMD_FILL_OUT_BUFFER].
+const FILL_BYTE: u8 = 0xA5;
+
+/// Name of the service registered by the server.
+fn service_name() -> u64 {
+    u64::from_le_bytes(*b"ipctest\0")
+}
+
+/// Computes the wrapping sum of the given bytes.
+fn sum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, &v| sum.wrapping_add(u32::from(v)))
+}
+
+/// Checks whether we were launched as the client, that is, with `client` as
+/// first argument.
+fn is_client() -> bool {
+    if argv::argc() < 2 {
+        return false;
+    }
+
+    let arg = unsafe {
+        let ptr = *argv::argv().offset(1);
+        let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+        core::slice::from_raw_parts(ptr, len)
+    };
+
+    arg == b"client"
+}
+
+/// Serves the requests of the client until it sends [CMD_STOP].
+fn serve(session: &ServerSession) {
+    let mut pointer_bufs = [[0u8; POINTER_BUF_SIZE]; 2];
+
+    loop {
+        let mut buf = [0u8; 0x100];
+
+        {
+            // Two C buffers, so X buffers get copied based on their counter.
+            let (first, second) = pointer_bufs.split_at_mut(1);
+            let mut req = Message::<(), [_; 2], [_; 0], [_; 0]>::new_request(None, 0);
+            req.push_in_pointer(&mut first[0][..], false);
+            req.push_in_pointer(&mut second[0][..], false);
+            req.pack(&mut buf[..]);
+        }
+
+        if let Err(err) = session.receive(&mut buf[..], None) {
+            // The kernel answers the client with the error of requests it
+            // couldn't pass to us.
+            info!("Failed to receive a request: {:?}", err);
+            continue;
+        }
+
+        let mut req = Message::<u32, [_; 2], [_; 0], [_; 0]>::unpack(&buf[..]);
+        let cmd = req.raw();
+
+        let mut reply_buf = [0u8; 0x100];
+        match cmd {
+            CMD_SUM_POINTERS => {
+                let mut total = 0u32;
+                while let Ok(data) = unsafe { req.pop_in_pointer::<[u8]>() } {
+                    total = total.wrapping_add(sum(data));
+                }
+                let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(total);
+                msg.pack(&mut reply_buf[..]);
+            },
+            CMD_REPLY_POINTER => {
+                let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(0);
+                msg.push_out_pointer(&REPLY_DATA[..]);
+                msg.pack(&mut reply_buf[..]);
+            },
+            CMD_SUM_IN_BUFFER => {
+                let total = unsafe { req.pop_in_buffer::<[u8]>() }.map(sum).unwrap();
+                let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(total);
+                msg.pack(&mut reply_buf[..]);
+            },
+            CMD_FILL_OUT_BUFFER => {
+                let data = unsafe { req.pop_out_buffer::<[u8]>() }.unwrap();
+                for v in data.iter_mut() {
+                    *v = FILL_BYTE;
+                }
+                let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(0);
+                msg.pack(&mut reply_buf[..]);
+            },
+            CMD_INCREMENT_INOUT_BUFFER => {
+                let data = unsafe { req.pop_inout_buffer::<[u8]>() }.unwrap();
+                for v in data.iter_mut() {
+                    *v = v.wrapping_add(1);
+                }
+                let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(0);
+                msg.pack(&mut reply_buf[..]);
+            },
+            CMD_REPLY_IN_BUFFER => {
+                let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(0);
+                msg.push_out_buffer(&REPLY_DATA[..]);
+                msg.pack(&mut reply_buf[..]);
+            },
+            _ => {
+                let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+                msg.push_raw(0);
+                msg.pack(&mut reply_buf[..]);
+            }
+        }
+
+        if let Err(err) = session.reply(&mut reply_buf[..]) {
+            info!("Failed to reply to command {}: {:?}", cmd, err);
+        }
+
+        if cmd == CMD_STOP {
+            return;
+        }
+    }
+}
+
+/// Sends the request packed in `buf`, returning the raw data of the reply.
+fn send(session: &ClientSession, buf: &mut [u8]) -> Result<u32, Error> {
+    session.send_sync_request_with_user_buffer(buf)?;
+    let res = Message::<u32, [_; 1], [_; 0], [_; 0]>::unpack(buf);
+    res.error()?;
+    Ok(res.raw())
+}
+
+/// Panics if `res` isn't the `expected` kernel error.
+fn expect_kernel_error(res: Result<u32, Error>, expected: KernelError) {
+    match res {
+        Err(Error::Kernel(err, _)) if err == expected => (),
+        res => panic!("Expected {:?}, got {:?}", expected, res)
+    }
+}
+
+/// Sends a single X buffer.
+fn test_pointer(session: &ClientSession) {
+    let data = b"Hello, server!";
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_SUM_POINTERS);
+    msg.push_out_pointer(&data[..]);
+    msg.pack(&mut buf[..]);
+    assert_eq!(send(session, &mut buf[..]).unwrap(), sum(data));
+}
+
+/// Sends two X buffers, which should end up in the two C buffers of the server.
+/// The second one exactly fills its C buffer.
+fn test_numbered_pointers(session: &ClientSession) {
+    let first = [1u8, 2, 3];
+    let second = [4u8; POINTER_BUF_SIZE];
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 2], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_SUM_POINTERS);
+    msg.push_out_pointer(&first[..]);
+    msg.push_out_pointer(&second[..]);
+    msg.pack(&mut buf[..]);
+    assert_eq!(send(session, &mut buf[..]).unwrap(), sum(&first) + sum(&second));
+}
+
+/// Sends an X buffer bigger than the C buffer of the server.
+fn test_pointer_too_large(session: &ClientSession) {
+    let data = [0u8; POINTER_BUF_SIZE + 1];
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_SUM_POINTERS);
+    msg.push_out_pointer(&data[..]);
+    msg.pack(&mut buf[..]);
+    expect_kernel_error(send(session, &mut buf[..]), KernelError::OutOfResource);
+}
+
+/// Receives an X buffer from the server in our C buffer.
+fn test_reply_pointer(session: &ClientSession) {
+    let mut data = [0u8; 0x40];
+    let mut buf = [0u8; 0x100];
+    {
+        let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+        msg.push_raw(CMD_REPLY_POINTER);
+        msg.push_in_pointer(&mut data[..], false);
+        msg.pack(&mut buf[..]);
+    }
+    send(session, &mut buf[..]).unwrap();
+    assert_eq!(&data[..REPLY_DATA.len()], &REPLY_DATA[..]);
+}
+
+/// Sends an A buffer spanning several pages, starting and ending in the middle
+/// of a page.
+fn test_in_buffer(session: &ClientSession) {
+    let data: alloc::vec::Vec<u8> = (0..0x3000).map(|v| v as u8).collect();
+    let data = &data[0x10..0x2ff0];
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_SUM_IN_BUFFER);
+    msg.push_out_buffer(data);
+    msg.pack(&mut buf[..]);
+    assert_eq!(send(session, &mut buf[..]).unwrap(), sum(data));
+}
+
+/// Sends a B buffer for the server to fill.
+fn test_out_buffer(session: &ClientSession) {
+    let mut data = vec![0u8; 0x3000];
+    let mut buf = [0u8; 0x100];
+    {
+        let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+        msg.push_raw(CMD_FILL_OUT_BUFFER);
+        msg.push_in_buffer(&mut data[0x10..0x2ff0]);
+        msg.pack(&mut buf[..]);
+    }
+    send(session, &mut buf[..]).unwrap();
+    assert!(data[..0x10].iter().all(|&v| v == 0));
+    assert!(data[0x10..0x2ff0].iter().all(|&v| v == FILL_BYTE));
+    assert!(data[0x2ff0..].iter().all(|&v| v == 0));
+}
+
+/// Sends a W buffer, which the server should be able to both read and write.
+fn test_inout_buffer(session: &ClientSession) {
+    let mut data: alloc::vec::Vec<u8> = (0..0x3000).map(|v| v as u8).collect();
+    let mut buf = [0u8; 0x100];
+    {
+        let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+        msg.push_raw(CMD_INCREMENT_INOUT_BUFFER);
+        msg.push_inout_buffer(&mut data[0x10..0x2ff0]);
+        msg.pack(&mut buf[..]);
+    }
+    send(session, &mut buf[..]).unwrap();
+    for (i, &v) in data.iter().enumerate() {
+        if i < 0x10 || i >= 0x2ff0 {
+            assert_eq!(v, i as u8);
+        } else {
+            assert_eq!(v, (i as u8).wrapping_add(1));
+        }
+    }
+}
+
+/// Sends a B buffer pointing to read-only memory.
+fn test_out_buffer_read_only(session: &ClientSession) {
+    // The kernel must refuse to map it, so the server never gets to write to
+    // it.
+    let data = unsafe {
+        core::slice::from_raw_parts_mut(REPLY_DATA.as_ptr() as *mut u8, REPLY_DATA.len())
+    };
+    let mut buf = [0u8; 0x100];
+    {
+        let mut msg = Message::<u32, [_; 1], [_; 0], [_; 0]>::new_request(None, 0);
+        msg.push_raw(CMD_FILL_OUT_BUFFER);
+        msg.push_in_buffer(data);
+        msg.pack(&mut buf[..]);
+    }
+    expect_kernel_error(send(session, &mut buf[..]), KernelError::InvalidMemState);
+}
+
+/// Has the server reply with an A buffer.
+fn test_reply_in_buffer(session: &ClientSession) {
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_REPLY_IN_BUFFER);
+    msg.pack(&mut buf[..]);
+    expect_kernel_error(send(session, &mut buf[..]), KernelError::InvalidCombination);
+}
+
+/// Runs all the tests against the server, and stops it.
+fn run_tests(session: &ClientSession) {
+    test_pointer(session);
+    info!("test_pointer: OK");
+    test_numbered_pointers(session);
+    info!("test_numbered_pointers: OK");
+    test_pointer_too_large(session);
+    info!("test_pointer_too_large: OK");
+    test_reply_pointer(session);
+    info!("test_reply_pointer: OK");
+    test_in_buffer(session);
+    info!("test_in_buffer: OK");
+    test_out_buffer(session);
+    info!("test_out_buffer: OK");
+    test_inout_buffer(session);
+    info!("test_inout_buffer: OK");
+    test_out_buffer_read_only(session);
+    info!("test_out_buffer_read_only: OK");
+    test_reply_in_buffer(session);
+    info!("test_reply_in_buffer: OK");
+
+    let mut buf = [0u8; 0x100];
+    let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_request(None, 0);
+    msg.push_raw(CMD_STOP);
+    msg.pack(&mut buf[..]);
+    send(session, &mut buf[..]).unwrap();
+
+    info!("All IPC tests passed");
+}
+
+/// Entry point of the client thread, running the tests against a server in the
+/// same process. Takes ownership of the boxed [ClientSession] passed as
+/// argument.
+fn client_thread(session: usize) {
+    let session = unsafe { Box::from_raw(session as *mut ClientSession) };
+    run_tests(&session);
+}
+
+fn main() {
+    if is_client() {
+        let session = IUserInterfaceProxy::raw_new().unwrap().get_service(service_name()).unwrap();
+        run_tests(&session);
+        return;
+    }
+
+    // Talk to ourselves first.
+    let (server, client) = syscalls::create_session(false, 0).unwrap();
+    let thread = Thread::create(client_thread, Box::into_raw(Box::new(client)) as usize, threads::DEFAULT_STACK_SIZE, "client")
+        .expect("Failed to create the client thread");
+    thread.start().expect("Failed to start the client thread");
+    serve(&server);
+    thread.join().unwrap();
+
+    // Then to another process.
+    let port = IUserInterfaceProxy::new().unwrap().register_service(service_name(), false, 0).unwrap();
+    let loader = ILoaderInterfaceProxy::raw_new().unwrap();
+    let pid = loader.launch_title(b"ipc-test", b"ipc-test client").unwrap();
+
+    syscalls::wait_synchronization(&[port.0.as_ref()], None).unwrap();
+    let session = port.accept().unwrap();
+    serve(&session);
+
+    let status = loader.wait(pid).unwrap();
+    info!("Client exited with status {}", status);
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"ipc-test\0\0\0\0",
+    title_id: 0x0200000000001070,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+        sunrise_libuser::syscalls::nr::CreateThread,
+        sunrise_libuser::syscalls::nr::StartThread,
+        sunrise_libuser::syscalls::nr::ExitThread,
+        sunrise_libuser::syscalls::nr::SetThreadName,
+    ]
+});
@@ -354,6 +354,11 @@ impl Hpet {
         unsafe { (*self.inner).identifier.read().legacy_rt_capability() }
     }
 
+    /// Return true if the main counter of the device is 64 bits wide.
+    pub fn has_64bit_counter(&self) -> bool {
+        unsafe { (*self.inner).identifier.read().counter_size_capability() }
+    }
+
     /// Return the period of the HPET device.
     pub fn get_period(&self) -> u32 {
         self.period
@@ -451,6 +456,52 @@ assert_eq_size!(HpetRegister, [u8; 0x100]);
 /// The instance of the HPET device we are using.
 static mut HPET_INSTANCE: Option<Hpet> = None;
 
+/// The HPET timer programmed by the kernel timer queue.
+static mut HPET_MAIN_TIMER: Option<HpetTimer> = None;
+
+/// Gets the value of the main counter.
+///
+/// On i386, the 64 bits counter is read in two halves. If the low half wraps
+/// around in-between, we get a torn value, so we read it until two consecutive
+/// reads are consistent.
+fn read_main_counter(hpet: &Hpet) -> u64 {
+    loop {
+        let first = hpet.get_main_counter_value();
+        let second = hpet.get_main_counter_value();
+        if second.wrapping_sub(first) < 1 << 31 {
+            return second;
+        }
+    }
+}
+
+/// Gets the time elapsed since the HPET was enabled, in nanoseconds.
+///
+/// # Panics
+///
+/// Panics if the HPET was not initialized.
+pub fn now_ns() -> u64 {
+    let hpet = unsafe { HPET_INSTANCE.as_ref() }.expect("HPET is not initialized");
+    let femtoseconds = u128::from(read_main_counter(hpet)) * u128::from(hpet.get_period());
+    (femtoseconds / 1_000_000) as u64
+}
+
+/// Programs the main timer to trigger an interrupt once the HPET time reaches
+/// `deadline_ns`, as returned by [now_ns].
+///
+/// The interrupt is only triggered when the main counter goes through the
+/// comparator value: the caller should check the deadline has not already passed.
+///
+/// # Panics
+///
+/// Panics if the HPET was not initialized.
+pub fn set_deadline(deadline_ns: u64) {
+    let hpet = unsafe { HPET_INSTANCE.as_ref() }.expect("HPET is not initialized");
+    let timer = unsafe { HPET_MAIN_TIMER.as_ref() }.expect("HPET is not initialized");
+    let ticks = u128::from(deadline_ns) * 1_000_000 / u128::from(hpet.get_period());
+    let ticks = if ticks > u128::from(u64::max_value()) { u64::max_value() } else { ticks as u64 };
+    timer.set_comparator_value(ticks);
+}
+
 /// Try to initialize the HPET in legacy mode.
 pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
     let physical_mem = PhysicalMemRegion::on_fixed_mmio(
@@ -461,7 +512,7 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
     let virtual_address = paging::kernel_memory::get_kernel_memory().map_phys_region(
         physical_mem,
         MappingAccessRights::READABLE | MappingAccessRights::WRITABLE,
-    );
+    ).expect("Cannot map the HPET registers");
     let hpet_mmio = virtual_address.addr() as *mut HpetRegister;
     let hpet_instance = Hpet::new(hpet_mmio);
 
@@ -473,44 +524,40 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
 
     // We don't need the HPET has it's useless for us.
     if !hpet_instance.has_legacy_mapping() {
-        paging::kernel_memory::get_kernel_memory().unmap(virtual_address, PAGE_SIZE);
+        paging::kernel_memory::get_kernel_memory().unmap_whole_mapping(virtual_address, PAGE_SIZE);
+        return false;
+    }
+
+    // A 32 bits counter would wrap around in a few minutes, breaking the kernel time.
+    if !hpet_instance.has_64bit_counter() {
+        paging::kernel_memory::get_kernel_memory().unmap_whole_mapping(virtual_address, PAGE_SIZE);
         return false;
     }
 
     let main_timer_opt = hpet_instance.get_timer(0);
 
     if main_timer_opt.is_none() {
-        paging::kernel_memory::get_kernel_memory().unmap(virtual_address, PAGE_SIZE);
+        paging::kernel_memory::get_kernel_memory().unmap_whole_mapping(virtual_address, PAGE_SIZE);
         return false;
     }
 
     let main_timer = main_timer_opt.unwrap();
 
-    // The timer must support periodic interrupt otherwise we cannot use it!
-    if !main_timer.support_periodic_interrupt() {
-        paging::kernel_memory::get_kernel_memory().unmap(virtual_address, PAGE_SIZE);
+    // The timer comparator must be as wide as the main counter to be used in one-shot mode.
+    if !main_timer.support_64bit() {
+        paging::kernel_memory::get_kernel_memory().unmap_whole_mapping(virtual_address, PAGE_SIZE);
         return false;
     }
 
-    // Set the tick rate in femtoseconds
-    // Kernel needs an update frequency of 1 milliseconds.
-    // TODO: Switch to a lower update frequency in HPET
-    // BODY: We will maybe prefer to have a better resolution for kernel time.
-    // BODY: For that to be possible, we need to take care of the sleep_thread logic in userland first (sleep_thread(0) shouldn't be used).
-
-    let irq_period_ns = 1 * 1_000_000;
-    let irq_period_fs = irq_period_ns * 1_000_000;
     info!("HPET frequency: {} Hz", hpet_instance.get_frequency());
-    info!("HPET IRQ period: {} fs", irq_period_fs);
-
-    let irq_period_tick = irq_period_fs / u64::from(hpet_instance.get_period());
+    info!("HPET period: {} fs", hpet_instance.get_period());
 
     // IO-APIC expects edge triggering by default.
     main_timer.set_edge_trigger();
-    main_timer.set_periodic_mode();
+    // The kernel timer queue programs the next deadline every time a timer expires.
+    main_timer.set_one_shot_mode();
+    main_timer.set_comparator_value(u64::max_value());
     main_timer.enable_interrupt();
-    main_timer.set_accumulator_value(irq_period_tick);
-    main_timer.set_comparator_value(irq_period_tick);
     // TODO: Use IRQ2 for HPET.
     // BODY: Idealy, HPET should be using IRQ2 (which seems to be generally
     // BODY: wired properly). Unfortunately, qemu has an unfortunate bug where
@@ -528,8 +575,11 @@ pub unsafe fn init(hpet: &acpi::Hpet) -> bool {
     // Clear the interrupt state
     hpet_instance.enable();
 
-    timer::set_kernel_timer_info(16, hpet_instance.get_frequency(), irq_period_ns);
-
+    let frequency = hpet_instance.get_frequency();
     HPET_INSTANCE = Some(hpet_instance);
+    HPET_MAIN_TIMER = Some(main_timer);
+
+    timer::set_kernel_timer_info(16, frequency, timer::TimerSource::Hpet);
+
     true
 }
@@ -238,7 +238,8 @@ impl IoApic {
 
         let mmio = PhysicalMemRegion::on_fixed_mmio(address.floor(), 0x1000).unwrap();
 
-        let vaddr = get_kernel_memory().map_phys_region(mmio, MappingAccessRights::k_rw());
+        let vaddr = get_kernel_memory().map_phys_region(mmio, MappingAccessRights::k_rw())
+            .expect("Cannot map the IO-APIC registers");
 
         let vaddr_start = vaddr + (address - address.floor());
 
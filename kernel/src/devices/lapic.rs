@@ -572,7 +572,8 @@ impl LocalApic {
     pub unsafe fn new(address: PhysicalAddress) -> Self {
         assert!(address.addr() % PAGE_SIZE == 0, "Unaligned local APIC address");
 
-        let lapic = get_kernel_memory().map_phys_region(PhysicalMemRegion::on_fixed_mmio(address, 0x1000).unwrap(), MappingAccessRights::k_rw());
+        let lapic = get_kernel_memory().map_phys_region(PhysicalMemRegion::on_fixed_mmio(address, 0x1000).unwrap(), MappingAccessRights::k_rw())
+            .expect("Cannot map the local APIC registers");
 
         let lapic = LocalApic {
             internal: (lapic.addr() as *const UnsafeCell<LocalApicInternal>).as_ref().unwrap(),
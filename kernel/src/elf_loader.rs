@@ -44,6 +44,7 @@ pub struct MappedGrubModule<'a> {
 /// # Error:
 ///
 /// * VirtualMemoryExhaustion: cannot find virtual memory where to map it.
+/// * OutOfMemory: a page table could not be allocated.
 pub fn map_grub_module(module: &ModuleTag) -> Result<MappedGrubModule<'_>, KernelError> {
     let start_address_aligned = PhysicalAddress(utils::align_down(module.start_address() as usize, PAGE_SIZE));
     // Use start_address_aligned to calculate the number of pages, to avoid an off-by-one.
@@ -57,7 +58,7 @@ pub fn map_grub_module(module: &ModuleTag) -> Result<MappedGrubModule<'_>, Kerne
             // safe, they were not tracked before
             PhysicalMemRegion::reconstruct(start_address_aligned, module_len_aligned)
         };
-        page_table.map_phys_region_to(module_phys_location, vaddr, MappingAccessRights::k_r());
+        page_table.map_phys_region_to(module_phys_location, vaddr, MappingAccessRights::k_r())?;
 
         vaddr
     };
@@ -82,7 +83,7 @@ pub fn map_grub_module(module: &ModuleTag) -> Result<MappedGrubModule<'_>, Kerne
 impl<'a> Drop for MappedGrubModule<'a> {
     /// Unmap the module, but do not deallocate physical memory
     fn drop(&mut self) {
-        get_kernel_memory().unmap_no_dealloc( self.mapping_addr,
+        get_kernel_memory().unmap_whole_mapping_no_dealloc( self.mapping_addr,
             utils::align_up(self.len, PAGE_SIZE)
         );
     }
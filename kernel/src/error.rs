@@ -21,8 +21,8 @@ pub enum KernelError {
         msg: &'static str,
         backtrace: Backtrace
     },
-    #[fail(display = "Frame allocation error: physical address space exhausted")]
-    PhysicalMemoryExhaustion {
+    #[fail(display = "Out of memory: physical memory exhausted")]
+    OutOfMemory {
         backtrace: Backtrace
     },
     #[fail(display = "Virtual allocation error: virtual address space exhausted")]
@@ -88,7 +88,7 @@ pub enum KernelError {
 impl From<KernelError> for UserspaceError {
     fn from(err: KernelError) -> UserspaceError {
         match err {
-            KernelError::PhysicalMemoryExhaustion { .. } => UserspaceError::MemoryFull,
+            KernelError::OutOfMemory { .. } => UserspaceError::MemoryFull,
             KernelError::VirtualMemoryExhaustion { .. } => UserspaceError::MemoryFull,
             KernelError::InvalidState { .. } => UserspaceError::InvalidState,
             KernelError::InvalidAddress { .. } => UserspaceError::InvalidAddress,
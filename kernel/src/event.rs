@@ -160,6 +160,12 @@ pub struct ReadableEvent {
 }
 
 impl ReadableEvent {
+    /// Gets an identifier of this event, shared by its readable and writable
+    /// parts. Used to match them when dumping handle tables.
+    pub fn id(&self) -> usize {
+        &*self.parent as *const Event as usize
+    }
+
     /// Clears the signaled state.
     ///
     /// # Errors
@@ -193,6 +199,12 @@ pub struct WritableEvent {
 }
 
 impl WritableEvent {
+    /// Gets an identifier of this event, shared by its readable and writable
+    /// parts. See [ReadableEvent::id].
+    pub fn id(&self) -> usize {
+        &*self.parent as *const Event as usize
+    }
+
     /// Signals the event, setting its state to signaled and waking up any
     /// thread waiting on its value.
     pub fn signal(&self) {
@@ -234,6 +246,13 @@ pub struct IRQEvent {
     ack: AtomicUsize,
 }
 
+impl IRQEvent {
+    /// Gets the IRQ this event is listening on.
+    pub fn irq(&self) -> usize {
+        self.state.irqnum
+    }
+}
+
 impl Waitable for IRQEvent {
     fn is_signaled(&self) -> bool {
         self.ack.fetch_update(|x| {
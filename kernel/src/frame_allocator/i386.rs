@@ -1,16 +1,28 @@
 //! i386 implementation of the frame allocator.
 //!
-//! It keeps tracks of the allocated frames by mean of a giant bitmap mapping every
-//! physical memory frame in the address space to a bit representing if it is free or not.
-//! This works because the address space in 32 bits is only 4GB, so ~1 million frames only
+//! It is a buddy allocator: free physical memory is split in blocks of 2^order
+//! frames, naturally aligned on their size, up to blocks of 2^[MAX_ORDER] frames.
+//! Allocating a region takes the smallest free block big enough, giving back
+//! the frames it does not need. Freeing a region merges its frames with their
+//! free buddies, to reform bigger blocks.
 //!
-//! During init we initialize the bitmap by parsing the information that the bootloader gives us and
+//! Since we can't use the heap to hold the free lists, the free blocks of each
+//! order are kept in a bitmap, with one bit per block. This works because the
+//! address space in 32 bits is only 4GB, so ~1 million frames only.
+//!
+//! On top of that, a giant bitmap maps every physical memory frame in the
+//! address space to a bit representing if it is free or not, and another one
+//! tells if an occupied frame was allocated, or reserved.
+//!
+//! The number of free, allocated and reserved blocks of every order are kept
+//! up to date on every allocation and free, so getting them is cheap enough to
+//! be exposed to userspace.
+//!
+//! During init we initialize the bitmaps by parsing the information that the bootloader gives us and
 //! marking some physical memory regions as reserved, either because of BIOS or MMIO.
 //!
 //! We also reserve everything that is mapped in KernelLand, assuming the bootstrap mapped it there
 //! for us, and we don't want to overwrite it.
-//!
-//! We do not distinguish between reserved and occupied frames.
 
 use super::{PhysicalMemRegion, FrameAllocatorTrait, FrameAllocatorTraitPrivate};
 
@@ -53,13 +65,23 @@ const FRAME_BASE_MASK:   usize = !FRAME_OFFSET_MASK;
 const FRAME_BASE_LOG: usize = 12;
 
 /// The size of the frames_bitmap (~128ko)
-#[cfg(not(any(test, rustdoc)))]
+#[cfg(not(any(all(test, not(target_os = "none")), rustdoc)))]
 const FRAMES_BITMAP_SIZE: usize = usize::max_value() / PAGE_SIZE / 8 + 1;
 
 /// For unit tests we use a much smaller array.
-#[cfg(any(test, rustdoc))]
+#[cfg(any(all(test, not(target_os = "none")), rustdoc))]
 const FRAMES_BITMAP_SIZE: usize = 32 / 8;
 
+/// The number of frames in the address space.
+const TOTAL_FRAMES: usize = FRAMES_BITMAP_SIZE * 8;
+
+/// The size of the free blocks bitmap. Order n has `TOTAL_FRAMES >> n` blocks,
+/// so all orders together have less than `2 * TOTAL_FRAMES` blocks.
+const FREE_BLOCKS_BITMAP_SIZE: usize = FRAMES_BITMAP_SIZE * 2;
+
+/// The biggest order of a block. Blocks hold at most 2^MAX_ORDER frames (4MiB).
+pub const MAX_ORDER: usize = 10;
+
 /// Gets the frame number from a physical address
 #[inline]
 fn addr_to_frame(addr: usize) -> usize {
@@ -72,7 +94,49 @@ fn frame_to_addr(frame: usize) -> usize {
     frame << FRAME_BASE_LOG
 }
 
-/// A frame allocator backed up by a giant bitmap.
+/// Gets the biggest order whose block holds at most `frames` frames.
+///
+/// `frames` must not be 0.
+#[inline]
+fn order_floor(frames: usize) -> usize {
+    core::mem::size_of::<usize>() * 8 - 1 - frames.leading_zeros() as usize
+}
+
+/// Gets the smallest order whose block holds at least `frames` frames.
+///
+/// `frames` must not be 0.
+#[inline]
+fn order_ceil(frames: usize) -> usize {
+    order_floor(frames) + if frames.is_power_of_two() { 0 } else { 1 }
+}
+
+/// Iterator splitting a range of frames in the biggest blocks possible, as
+/// `(first_frame, order)` tuples.
+struct AlignedBlocks {
+    /// The first frame of the next block.
+    start: usize,
+    /// The end of the range, exclusive.
+    end: usize,
+}
+
+impl Iterator for AlignedBlocks {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if self.start >= self.end {
+            return None;
+        }
+        // a block must be aligned on its size, and fit in the range.
+        let order = (self.start.trailing_zeros() as usize)
+            .min(order_floor(self.end - self.start))
+            .min(MAX_ORDER);
+        let block = (self.start, order);
+        self.start += 1 << order;
+        Some(block)
+    }
+}
+
+/// A buddy frame allocator backed up by bitmaps.
 pub struct FrameAllocatori386 {
     /// A big bitmap denoting for every frame if it is free or not
     ///
@@ -81,6 +145,40 @@ pub struct FrameAllocatori386 {
     /// and it can be put in the bss by the compiler
     memory_bitmap: [u8; FRAMES_BITMAP_SIZE],
 
+    /// A big bitmap denoting for every occupied frame if it was allocated,
+    /// or if it is reserved.
+    ///
+    /// 1 is allocated, 0 is free or reserved.
+    allocated_bitmap: [u8; FRAMES_BITMAP_SIZE],
+
+    /// The free blocks of every order, one bit per block.
+    ///
+    /// The bitmap of order n starts at bit [FrameAllocatori386::order_base]\(n\),
+    /// and its bit i is set if the block of frames `i << n..(i + 1) << n` is free,
+    /// and not part of a bigger free block.
+    free_blocks: [u8; FREE_BLOCKS_BITMAP_SIZE],
+
+    /// The number of free blocks of every order.
+    free_blocks_count: [usize; MAX_ORDER + 1],
+
+    /// The number of allocated blocks of every order.
+    ///
+    /// Allocated frames are counted in the blocks they would make up if they
+    /// were merged with their buddies, like free frames are.
+    used_blocks_count: [usize; MAX_ORDER + 1],
+
+    /// The number of reserved blocks of every order, counted like the
+    /// allocated ones.
+    reserved_blocks_count: [usize; MAX_ORDER + 1],
+
+    /// For every order, there is no free block with an index lower than this.
+    ///
+    /// Speeds up the search of a free block.
+    first_free_hint: [usize; MAX_ORDER + 1],
+
+    /// The number of free frames.
+    free_frames: usize,
+
     /// All operations have to check that the Allocator has been initialized
     initialized: bool
 }
@@ -90,9 +188,18 @@ const FRAME_FREE:     bool = true;
 /// In the the bitmap, 0 means the frame is occupied.
 const FRAME_OCCUPIED: bool = false;
 
+/// What an occupied frame is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Occupied {
+    /// The frame was given out by the allocator.
+    Allocated,
+    /// The frame is not available for allocation.
+    Reserved,
+}
+
 /// A physical memory manger to allocate and free memory frames
 // When running tests, each thread has its own view of the `FRAME_ALLOCATOR`.
-#[cfg_attr(test, thread_local)]
+#[cfg_attr(all(test, not(target_os = "none")), thread_local)]
 static FRAME_ALLOCATOR : SpinLock<FrameAllocatori386> = SpinLock::new(FrameAllocatori386::new());
 
 impl FrameAllocatori386 {
@@ -101,9 +208,301 @@ impl FrameAllocatori386 {
         FrameAllocatori386 {
             // 0 is allocated/reserved
             memory_bitmap: [0x00; FRAMES_BITMAP_SIZE],
+            allocated_bitmap: [0x00; FRAMES_BITMAP_SIZE],
+            free_blocks: [0x00; FREE_BLOCKS_BITMAP_SIZE],
+            free_blocks_count: [0; MAX_ORDER + 1],
+            used_blocks_count: [0; MAX_ORDER + 1],
+            reserved_blocks_count: [0; MAX_ORDER + 1],
+            first_free_hint: [0; MAX_ORDER + 1],
+            free_frames: 0,
             initialized: false
         }
     }
+
+    /// Gets the index of the first bit of the free blocks bitmap of this order.
+    fn order_base(order: usize) -> usize {
+        (0..order).map(|lower_order| TOTAL_FRAMES >> lower_order).sum()
+    }
+
+    /// Checks if the block of this order starting at `frame` is a free block.
+    ///
+    /// `frame` must be aligned on the block size.
+    fn is_free_block(&self, frame: usize, order: usize) -> bool {
+        let index = frame >> order;
+        index < TOTAL_FRAMES >> order
+            && self.free_blocks.get_bit(Self::order_base(order) + index)
+    }
+
+    /// Adds a block to the free blocks, merging it with its buddy if it is free
+    /// too, and so on.
+    ///
+    /// The frames of the block must already be marked free.
+    fn insert_free_block(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove_free_block(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        let index = frame >> order;
+        self.free_blocks.set_bit(Self::order_base(order) + index, true);
+        self.free_blocks_count[order] += 1;
+        self.first_free_hint[order] = self.first_free_hint[order].min(index);
+    }
+
+    /// Removes a block from the free blocks.
+    fn remove_free_block(&mut self, frame: usize, order: usize) {
+        debug_assert!(self.is_free_block(frame, order), "Removing a block that is not free");
+        self.free_blocks.set_bit(Self::order_base(order) + (frame >> order), false);
+        self.free_blocks_count[order] -= 1;
+    }
+
+    /// Finds the free block containing this free frame, as a `(first_frame, order)` tuple.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the frame is not part of any free block.
+    fn free_block_containing(&self, frame: usize) -> (usize, usize) {
+        (0..=MAX_ORDER)
+            .map(|order| (frame & !((1 << order) - 1), order))
+            .find(|&(block, order)| self.is_free_block(block, order))
+            .expect("Free frame is not part of any free block")
+    }
+
+    /// Finds the lowest free block of the smallest order at least `order`,
+    /// as a `(first_frame, order)` tuple.
+    fn find_free_block(&mut self, order: usize) -> Option<(usize, usize)> {
+        let found_order = (order..=MAX_ORDER).find(|&order| self.free_blocks_count[order] > 0)?;
+        let base = Self::order_base(found_order);
+        let index = (self.first_free_hint[found_order]..TOTAL_FRAMES >> found_order)
+            .find(|&index| self.free_blocks.get_bit(base + index))
+            .expect("Free blocks count is inconsistent");
+        self.first_free_hint[found_order] = index;
+        Some((index << found_order, found_order))
+    }
+
+    /// Counts all the frames as reserved, which they are when the allocator is
+    /// created.
+    ///
+    /// Must be called before any frame is freed or taken.
+    fn init_blocks_count(&mut self) {
+        for (_, order) in (AlignedBlocks { start: 0, end: TOTAL_FRAMES }) {
+            self.reserved_blocks_count[order] += 1;
+        }
+    }
+
+    /// Gets what this frame is used for, or None if it is free.
+    fn frame_state(&self, frame: usize) -> Option<Occupied> {
+        if self.memory_bitmap.get_bit(frame) == FRAME_FREE {
+            None
+        } else if self.allocated_bitmap.get_bit(frame) {
+            Some(Occupied::Allocated)
+        } else {
+            Some(Occupied::Reserved)
+        }
+    }
+
+    /// Gets the end of the run of frames starting at `frame` that are all in
+    /// the same state, stopping at `end`.
+    fn state_run_end(&self, frame: usize, end: usize) -> usize {
+        let state = self.frame_state(frame);
+        (frame..end).find(|&run_end| self.frame_state(run_end) != state)
+            .unwrap_or(end)
+    }
+
+    /// Checks if all the frames of the block of this order starting at `frame`
+    /// are in this state.
+    fn is_block_in_state(&self, frame: usize, order: usize, state: Occupied) -> bool {
+        frame + (1 << order) <= TOTAL_FRAMES
+            && (frame..frame + (1 << order)).all(|frame| self.frame_state(frame) == Some(state))
+    }
+
+    /// Finds the counted block containing this frame, as a `(first_frame, order)` tuple.
+    ///
+    /// This is the biggest block containing the frame whose frames are all in
+    /// the same state as it.
+    fn counted_block_containing(&self, frame: usize, state: Occupied) -> (usize, usize) {
+        let (mut block, mut order) = (frame, 0);
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if !self.is_block_in_state(buddy, order, state) {
+                break;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        (block, order)
+    }
+
+    /// Updates the used or reserved blocks count when the frames `start..end`
+    /// enter the `state`, or when they are about to leave it.
+    ///
+    /// The frames must be marked in this state in the bitmaps. When `entering`,
+    /// they are merged with the counted blocks around them. Otherwise, the
+    /// counted blocks containing them are split, and only the frames outside
+    /// of the range are still counted.
+    fn update_blocks_count(&mut self, start: usize, end: usize, state: Occupied, entering: bool) {
+        let mut frame = start;
+        while frame < end {
+            let (block, order) = self.counted_block_containing(frame, state);
+            let block_end = block + (1 << order);
+            // the parts of the block outside of the range are counted on their
+            // own when the range is not in the state.
+            let outside = (AlignedBlocks { start: block, end: start.max(block) })
+                .chain(AlignedBlocks { start: end.min(block_end), end: block_end });
+            let counts = match state {
+                Occupied::Allocated => &mut self.used_blocks_count,
+                Occupied::Reserved => &mut self.reserved_blocks_count,
+            };
+            if entering {
+                for (_, outside_order) in outside {
+                    counts[outside_order] -= 1;
+                }
+                counts[order] += 1;
+            } else {
+                counts[order] -= 1;
+                for (_, outside_order) in outside {
+                    counts[outside_order] += 1;
+                }
+            }
+            frame = block_end;
+        }
+    }
+
+    /// Finds the first run of `nr_frames` consecutive free frames, by going
+    /// through the frames bitmap.
+    ///
+    /// Slow, only used when no free block is big enough.
+    fn find_free_run(&self, nr_frames: usize) -> Option<usize> {
+        let mut start_index = 0usize;
+        while start_index + nr_frames <= TOTAL_FRAMES {
+            match (start_index..start_index + nr_frames)
+                .find(|&frame| self.memory_bitmap.get_bit(frame) == FRAME_OCCUPIED) {
+                // hole wasn't big enough, jump to its end
+                Some(occupied) => start_index = occupied + 1,
+                None => return Some(start_index)
+            }
+        }
+        None
+    }
+
+    /// Marks the frames free, and adds them to the free blocks.
+    ///
+    /// The frames must be occupied.
+    fn free_range(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+        while frame < end {
+            let run_end = self.state_run_end(frame, end);
+            let state = self.frame_state(frame).expect("Freeing a frame that is already free");
+            self.update_blocks_count(frame, run_end, state, false);
+            frame = run_end;
+        }
+        self.memory_bitmap.set_bits_area(start..end, FRAME_FREE);
+        self.allocated_bitmap.set_bits_area(start..end, false);
+        self.free_frames += end - start;
+        for (frame, order) in (AlignedBlocks { start, end }) {
+            self.insert_free_block(frame, order);
+        }
+    }
+
+    /// Marks the free frames of the range occupied by `state`, removing them
+    /// from the free blocks.
+    ///
+    /// The frames that were already occupied are left untouched. Free blocks
+    /// overlapping the range are split, and their frames outside of the range
+    /// are given back to the free blocks.
+    fn take_range(&mut self, start: usize, end: usize, state: Occupied) {
+        let mut frame = start;
+        while frame < end {
+            if self.memory_bitmap.get_bit(frame) == FRAME_OCCUPIED {
+                frame += 1;
+                continue;
+            }
+            let (block, order) = self.free_block_containing(frame);
+            let block_end = block + (1 << order);
+            let (taken_start, taken_end) = (block.max(start), block_end.min(end));
+            self.remove_free_block(block, order);
+            self.memory_bitmap.set_bits_area(taken_start..taken_end, FRAME_OCCUPIED);
+            self.allocated_bitmap.set_bits_area(taken_start..taken_end, state == Occupied::Allocated);
+            self.free_frames -= taken_end - taken_start;
+            self.update_blocks_count(taken_start, taken_end, state, true);
+            // give back the parts of the block outside of the range.
+            for (free_frame, free_order) in (AlignedBlocks { start: block, end: taken_start })
+                .chain(AlignedBlocks { start: taken_end, end: block_end }) {
+                self.insert_free_block(free_frame, free_order);
+            }
+            frame = block_end;
+        }
+    }
+
+    /// Marks all the frames of the range reserved, whether they were free or
+    /// allocated.
+    fn reserve_range(&mut self, start: usize, end: usize) {
+        self.take_range(start, end, Occupied::Reserved);
+        let mut frame = start;
+        while frame < end {
+            let run_end = self.state_run_end(frame, end);
+            if self.frame_state(frame) == Some(Occupied::Allocated) {
+                self.update_blocks_count(frame, run_end, Occupied::Allocated, false);
+                self.allocated_bitmap.set_bits_area(frame..run_end, false);
+                self.update_blocks_count(frame, run_end, Occupied::Reserved, true);
+            }
+            frame = run_end;
+        }
+    }
+
+    /// Allocates `nr_frames` consecutive frames, returning the first one.
+    fn allocate_consecutive(&mut self, nr_frames: usize) -> Option<usize> {
+        let start = match self.find_free_block(order_ceil(nr_frames)) {
+            Some((block, _)) => block,
+            // no block is big enough, but there may be enough free consecutive
+            // frames across several blocks.
+            None => self.find_free_run(nr_frames)?
+        };
+        self.take_range(start, start + nr_frames, Occupied::Allocated);
+        Some(start)
+    }
+
+    /// Allocates at most `nr_frames` consecutive frames, returning the first
+    /// one and the number of frames allocated.
+    ///
+    /// Prefers the biggest free block that does not need to be split.
+    fn allocate_fragment(&mut self, nr_frames: usize) -> Option<(usize, usize)> {
+        let max_order = order_floor(nr_frames).min(MAX_ORDER);
+        let (start, frames) = match (0..=max_order).rev().find(|&order| self.free_blocks_count[order] > 0) {
+            Some(order) => (self.find_free_block(order)?.0, 1 << order),
+            None => (self.find_free_block(max_order + 1)?.0, nr_frames)
+        };
+        self.take_range(start, start + frames, Occupied::Allocated);
+        Some((start, frames))
+    }
+
+    /// Gets the statistics of the allocator.
+    fn stats(&self) -> FrameAllocatorStats {
+        FrameAllocatorStats {
+            free: self.free_blocks_count,
+            used: self.used_blocks_count,
+            reserved: self.reserved_blocks_count,
+        }
+    }
+}
+
+/// Statistics of the frame allocator.
+///
+/// For every order, counts the blocks of 2^order frames. Free blocks are the
+/// ones of the buddy allocator, and used and reserved areas are split in blocks
+/// the same way they would be if they were freed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameAllocatorStats {
+    /// Number of free blocks of every order.
+    pub free: [usize; MAX_ORDER + 1],
+    /// Number of allocated blocks of every order.
+    pub used: [usize; MAX_ORDER + 1],
+    /// Number of reserved blocks of every order.
+    pub reserved: [usize; MAX_ORDER + 1],
 }
 
 /// The physical memory manager.
@@ -116,6 +515,28 @@ impl FrameAllocatori386 {
 #[derive(Debug)]
 pub struct FrameAllocator;
 
+impl FrameAllocator {
+    /// Gets the free, used and reserved blocks count of every order.
+    ///
+    /// # Panics
+    ///
+    /// * Panics if [FRAME_ALLOCATOR] was not initialized.
+    pub fn stats() -> FrameAllocatorStats {
+        let allocator = FRAME_ALLOCATOR.lock();
+        assert!(allocator.initialized, "The frame allocator was not initialized");
+        allocator.stats()
+    }
+
+    /// Prints the statistics of the allocator. Used for debugging purposes.
+    pub fn dump_stats() {
+        let stats = Self::stats();
+        info!("{:>5} {:>8} {:>8} {:>8}", "order", "free", "used", "reserved");
+        for order in 0..=MAX_ORDER {
+            info!("{:>5} {:>8} {:>8} {:>8}", order, stats.free[order], stats.used[order], stats.reserved[order]);
+        }
+    }
+}
+
 impl FrameAllocatorTraitPrivate for FrameAllocator {
     /// Frees an allocated physical region.
     ///
@@ -130,11 +551,9 @@ impl FrameAllocatorTraitPrivate for FrameAllocator {
             assert!(Self::check_is_allocated(region.address(), region.size()), "PhysMemRegion beeing freed was not allocated");
             let mut allocator = FRAME_ALLOCATOR.lock();
             assert!(allocator.initialized, "The frame allocator was not initialized");
-            allocator.memory_bitmap.set_bits_area(
-                addr_to_frame(region.address().addr())
-                    ..
-                addr_to_frame(region.address().addr() + region.size()),
-                FRAME_FREE);
+            allocator.free_range(
+                addr_to_frame(region.address().addr()),
+                addr_to_frame(region.address().addr() + region.size()));
         }
     }
 
@@ -155,8 +574,8 @@ impl FrameAllocatorTraitPrivate for FrameAllocator {
     }
 
     /// Checks that a physical region is marked reserved.
-    /// This implementation does not distinguish between allocated and reserved frames,
-    /// so for us it's equivalent to `check_is_allocated`.
+    /// Frames that were bootstrap allocated are used for MMIO too, so we
+    /// accept allocated frames as well, making it equivalent to `check_is_allocated`.
     ///
     /// Rounds address and length.
     ///
@@ -164,7 +583,6 @@ impl FrameAllocatorTraitPrivate for FrameAllocator {
     ///
     /// * Panics if FRAME_ALLOCATOR was not initialized.
     fn check_is_reserved(address: PhysicalAddress, length: usize) -> bool {
-        // we have no way to distinguish between 'allocated' and 'reserved'
         Self::check_is_allocated(address, length)
     }
 }
@@ -178,11 +596,11 @@ impl FrameAllocatorTrait for FrameAllocator {
     /// * `InvalidSize`
     ///     * `length` is not page size aligned.
     ///     * `length` is 0.
+    /// * `OutOfMemory`: not enough consecutive free frames.
     ///
     /// # Panics
     ///
     /// * Panics if [FRAME_ALLOCATOR] was not initialized.
-    #[allow(clippy::match_bool)]
     fn allocate_region(length: usize) -> Result<PhysicalMemRegion, KernelError> {
         check_nonzero_length(length)?;
         check_size_aligned(length, PAGE_SIZE)?;
@@ -190,36 +608,17 @@ impl FrameAllocatorTrait for FrameAllocator {
         let mut allocator = FRAME_ALLOCATOR.lock();
         assert!(allocator.initialized, "The frame allocator was not initialized");
 
-        let mut start_index = 0usize;
-        while start_index + nr_frames <= allocator.memory_bitmap.bit_length() {
-            let mut temp_len = 0usize;
-            loop {
-                match allocator.memory_bitmap.get_bit(start_index + temp_len) {
-                    FRAME_OCCUPIED => {
-                        // hole wasn't big enough, jump to its end
-                        start_index += temp_len + 1;
-                        break;
-                    }
-                    FRAME_FREE => {
-                        // hole is good til now, keep considering it
-                        temp_len += 1;
-                        if temp_len == nr_frames {
-                            // the hole was big enough, allocate all of its frames, and return it
-                            allocator.memory_bitmap.set_bits_area(start_index..start_index+temp_len, FRAME_OCCUPIED);
-                            let allocated = PhysicalMemRegion {
-                                start_addr: frame_to_addr(start_index),
-                                frames: nr_frames,
-                                should_free_on_drop: true
-                            };
-                            debug!("Allocated physical region: {:?}", allocated);
-                            return Ok(allocated);
-                        }
-                    }
-                }
-            }
+        if let Some(start_index) = allocator.allocate_consecutive(nr_frames) {
+            let allocated = PhysicalMemRegion {
+                start_addr: frame_to_addr(start_index),
+                frames: nr_frames,
+                should_free_on_drop: true
+            };
+            debug!("Allocated physical region: {:?}", allocated);
+            return Ok(allocated);
         }
         info!("Failed physical allocation for {} consecutive frames", nr_frames);
-        Err(KernelError::PhysicalMemoryExhaustion { backtrace: Backtrace::new() })
+        Err(KernelError::OutOfMemory { backtrace: Backtrace::new() })
     }
 
     /// Allocates physical frames, possibly fragmented across several physical regions.
@@ -229,6 +628,7 @@ impl FrameAllocatorTrait for FrameAllocator {
     /// * `InvalidSize`:
     ///     * `length` is not page size aligned.
     ///     * `length` is 0.
+    /// * `OutOfMemory`: not enough free frames.
     ///
     /// # Panics
     ///
@@ -242,70 +642,48 @@ impl FrameAllocatorTrait for FrameAllocator {
         assert!(allocator_lock.initialized, "The frame allocator was not initialized");
 
         let mut collected_frames = 0;
-        let mut collected_regions = Vec::new();
-        let mut current_hole = PhysicalMemRegion { start_addr: 0, frames: 0, should_free_on_drop: true };
+        let mut collected_regions: Vec<PhysicalMemRegion> = Vec::new();
         // while requested is still obtainable.
-        while addr_to_frame(current_hole.start_addr) + (requested - collected_frames) <= allocator_lock.memory_bitmap.bit_length() {
-            while current_hole.frames < requested - collected_frames {
-                // compute current hole's size
-                let considered_frame = addr_to_frame(current_hole.start_addr) + current_hole.frames;
-                if allocator_lock.memory_bitmap.get_bit(considered_frame) == FRAME_FREE {
-                    // expand current hole
-                    allocator_lock.memory_bitmap.set_bit(considered_frame, FRAME_OCCUPIED);
-                    current_hole.frames += 1;
-                } else {
-                    // we reached current hole's end
-                    break;
+        while allocator_lock.free_frames >= requested - collected_frames {
+            let (start, frames) = allocator_lock.allocate_fragment(requested - collected_frames)
+                .expect("Free frames count is inconsistent");
+            let region = PhysicalMemRegion { start_addr: frame_to_addr(start), frames, should_free_on_drop: true };
+
+            // dropping the lock here, in case pushing this region in the collected regions
+            // causes a heap expansion. This is ok, since we marked considered frames as allocated,
+            // we're in a stable state. This ensures heap expansion won't take one of those.
+            drop(allocator_lock);
+            collected_frames += frames;
+            match collected_regions.last_mut() {
+                // merge it with the previous region if they are adjacent.
+                Some(last) if last.start_addr + last.frames * PAGE_SIZE == region.start_addr => {
+                    last.frames += region.frames;
+                    core::mem::forget(region);
                 }
+                _ => collected_regions.push(region)
             }
-
-            // make a copy, we're about to move the PhysMemRegion to the vec.
-            let cur_hole_addr   = current_hole.start_addr;
-            let cur_hole_frames = current_hole.frames;
-
-            if current_hole.frames > 0 {
-                // add it to our collected regions
-
-                // dropping the lock here, in case pushing this region in the collected regions
-                // causes a heap expansion. This is ok, since we marked considered frames as allocated,
-                // we're in a stable state. This ensures heap expansion won't take one of those.
-                drop(allocator_lock);
-                collected_frames += current_hole.frames;
-                collected_regions.push(current_hole);
-                if collected_frames == requested {
-                    // we collected enough frames ! Succeed
-                    debug!("Allocated physical regions: {:?}", collected_regions);
-                    return Ok(collected_regions)
-                }
-                // re-take the lock. Still in a stable state, if heap-expansion
-                // happened frames were marked allocated, and won't be given by this allocation
-                allocator_lock = FRAME_ALLOCATOR.lock();
+            if collected_frames == requested {
+                // we collected enough frames ! Succeed
+                debug!("Allocated physical regions: {:?}", collected_regions);
+                return Ok(collected_regions)
             }
-            // advance the cursor
-            current_hole = PhysicalMemRegion {
-                start_addr: match cur_hole_addr.checked_add((cur_hole_frames + 1) * PAGE_SIZE) {
-                    Some(sum_addr) => sum_addr,
-                    None => break
-                    // if it was the last frame, and the last to be considered:
-                    // - it was free, and we already returned Ok.
-                    // - it was occupied, we arrived here, and the add would overflow. We break and return PhysicalMemoryExhaustion.
-                },
-                frames: 0,
-                should_free_on_drop: true
-            };
+            // re-take the lock. Still in a stable state, if heap-expansion
+            // happened frames were marked allocated, and won't be given by this allocation
+            allocator_lock = FRAME_ALLOCATOR.lock();
         }
         drop(allocator_lock);
         info!("Failed physical allocation for {} non consecutive frames", requested);
         // collected_regions is dropped, marking them free again
-        Err(KernelError::PhysicalMemoryExhaustion { backtrace: Backtrace::new() })
+        Err(KernelError::OutOfMemory { backtrace: Backtrace::new() })
     }
 }
 
 /// Initialize the [FrameAllocator] by parsing the multiboot information
 /// and marking some memory areas as unusable
-#[cfg(not(test))]
+#[cfg(any(not(test), target_os = "none"))]
 pub fn init(boot_info: &BootInformation) {
     let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.init_blocks_count();
 
     let memory_map_tag = boot_info.memory_map_tag()
         .expect("GRUB, you're drunk. Give us our memory_map_tag.");
@@ -315,11 +693,11 @@ pub fn init(boot_info: &BootInformation) {
         }
 
         if memarea.memory_type() == 1 {
-            mark_area_free(&mut allocator.memory_bitmap,
+            mark_area_free(&mut allocator,
                                         memarea.start_address() as usize,
                                         memarea.end_address() as usize);
         } else {
-            mark_area_reserved(&mut allocator.memory_bitmap,
+            mark_area_reserved(&mut allocator,
                                         memarea.start_address() as usize,
                                         memarea.end_address() as usize);
         }
@@ -333,12 +711,12 @@ pub fn init(boot_info: &BootInformation) {
 
     // Don't free the modules. We need to keep the kernel around so we get symbols in panics!
     for module in boot_info.module_tags() {
-        mark_area_reserved(&mut allocator.memory_bitmap,
+        mark_area_reserved(&mut allocator,
                                            module.start_address() as usize, module.end_address() as usize);
     }
 
     // Reserve the very first frame for null pointers when paging is off
-    mark_area_reserved(&mut allocator.memory_bitmap,
+    mark_area_reserved(&mut allocator,
                                        0x00000000,
                                        0x00000001);
 
@@ -379,7 +757,7 @@ pub fn init(boot_info: &BootInformation) {
     allocator.initialized = true
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 pub use self::test::init;
 
 /// Marks a physical memory area as reserved and will never give it when requesting a frame.
@@ -388,15 +766,13 @@ pub use self::test::init;
 /// # Panic
 ///
 /// Does not panic if it overwrites an existing reservation
-fn mark_area_reserved(bitmap: &mut [u8],
+fn mark_area_reserved(allocator: &mut FrameAllocatori386,
                       start_addr: usize,
                       end_addr: usize) {
     info!("Setting {:#010x}..{:#010x} to reserved", round_to_page(start_addr), round_to_page_upper(end_addr));
-    bitmap.set_bits_area(
-        addr_to_frame(round_to_page(start_addr))
-            ..
-        addr_to_frame(round_to_page_upper(end_addr)),
-        FRAME_OCCUPIED);
+    let start = addr_to_frame(round_to_page(start_addr));
+    let end = addr_to_frame(round_to_page_upper(end_addr));
+    allocator.reserve_range(start, end);
 }
 
 /// Marks a physical memory area as free for frame allocation
@@ -404,15 +780,23 @@ fn mark_area_reserved(bitmap: &mut [u8],
 /// # Panic
 ///
 /// Does not panic if it overwrites an existing reservation
-fn mark_area_free(bitmap: &mut [u8],
+fn mark_area_free(allocator: &mut FrameAllocatori386,
                   start_addr: usize,
                   end_addr: usize) {
     info!("Setting {:#010x}..{:#010x} to available", round_to_page(start_addr), round_to_page_upper(end_addr));
-    bitmap.set_bits_area(
-        addr_to_frame(round_to_page_upper(start_addr))
-            ..
-        addr_to_frame(round_to_page(end_addr)),
-        FRAME_FREE);
+    let end = addr_to_frame(round_to_page(end_addr));
+    let mut frame = addr_to_frame(round_to_page_upper(start_addr));
+    // only free the frames that are occupied, the others are already in the free blocks.
+    while frame < end {
+        if allocator.memory_bitmap.get_bit(frame) == FRAME_FREE {
+            frame += 1;
+            continue;
+        }
+        let run_end = (frame..end).find(|&run_end| allocator.memory_bitmap.get_bit(run_end) == FRAME_FREE)
+            .unwrap_or(end);
+        allocator.free_range(frame, run_end);
+        frame = run_end;
+    }
 }
 
 /// Marks a physical memory frame as already allocated
@@ -429,10 +813,10 @@ pub fn mark_frame_bootstrap_allocated(addr: PhysicalAddress) {
     if allocator.memory_bitmap.get_bit(bit) != FRAME_FREE {
         panic!("Frame being marked reserved was already allocated");
     }
-    allocator.memory_bitmap.set_bit(bit, FRAME_OCCUPIED);
+    allocator.take_range(bit, bit + 1, Occupied::Allocated);
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::*;
 
@@ -445,12 +829,13 @@ mod test {
     pub fn init() -> FrameAllocatorInitialized {
         let mut allocator = FRAME_ALLOCATOR.lock();
         assert_eq!(allocator.initialized, false, "frame_allocator::init() was called twice");
+        allocator.init_blocks_count();
 
         // make it all available
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
 
         // reserve one frame, in the middle, just for fun
-        mark_area_reserved(&mut allocator.memory_bitmap, PAGE_SIZE * 3, PAGE_SIZE * 3 + 1);
+        mark_area_reserved(&mut allocator, PAGE_SIZE * 3, PAGE_SIZE * 3 + 1);
 
         allocator.initialized = true;
 
@@ -489,10 +874,10 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all available
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
 
         // reserve some frames in the middle
-        mark_area_reserved(&mut allocator.memory_bitmap, 2 * PAGE_SIZE, 7 * PAGE_SIZE);
+        mark_area_reserved(&mut allocator, 2 * PAGE_SIZE, 7 * PAGE_SIZE);
         drop(allocator);
 
         // force a fragmented allocation
@@ -523,11 +908,11 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all reserved
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY);
         drop(allocator);
 
         match FrameAllocator::allocate_frame() {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
     }
@@ -537,9 +922,9 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all reserved
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY);
         // leave only the last frame
-        mark_area_free(&mut allocator.memory_bitmap, ALL_MEMORY - PAGE_SIZE, ALL_MEMORY);
+        mark_area_free(&mut allocator, ALL_MEMORY - PAGE_SIZE, ALL_MEMORY);
         drop(allocator);
 
         FrameAllocator::allocate_frame().unwrap();
@@ -550,15 +935,15 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all reserved
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY);
         // leave only the last 3 frames
-        mark_area_free(&mut allocator.memory_bitmap,
+        mark_area_free(&mut allocator,
                        ALL_MEMORY - 3 * PAGE_SIZE,
                        ALL_MEMORY);
         drop(allocator);
 
         match FrameAllocator::allocate_region(4 * PAGE_SIZE) {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
     }
@@ -568,9 +953,9 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all reserved
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY);
         // leave only the last 3 frames
-        mark_area_free(&mut allocator.memory_bitmap,
+        mark_area_free(&mut allocator,
                        ALL_MEMORY - 3 * PAGE_SIZE,
                        ALL_MEMORY);
         drop(allocator);
@@ -583,11 +968,11 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all available
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
         drop(allocator);
 
         match FrameAllocator::allocate_frames_fragmented(ALL_MEMORY + PAGE_SIZE) {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
     }
@@ -597,7 +982,7 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all available
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
         drop(allocator);
 
         FrameAllocator::allocate_frames_fragmented(ALL_MEMORY).unwrap();
@@ -608,10 +993,10 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all available
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
 
         // reserve all but last frame
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY - PAGE_SIZE);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY - PAGE_SIZE);
         drop(allocator);
 
         // check with allocate_frame
@@ -629,7 +1014,7 @@ mod test {
         // check we had really allocated *all* of it
         let frame = FrameAllocator::allocate_frame().unwrap();
         match FrameAllocator::allocate_frame() {
-            Err(KernelError::PhysicalMemoryExhaustion {..} ) => (),
+            Err(KernelError::OutOfMemory {..} ) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         };
         drop(frame);
@@ -640,33 +1025,96 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all reserved
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_reserved(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_reserved(&mut allocator, 0, ALL_MEMORY);
 
         // free only 1 frame in the middle
-        mark_area_free(&mut allocator.memory_bitmap, 2 * PAGE_SIZE, 3 * PAGE_SIZE);
+        mark_area_free(&mut allocator, 2 * PAGE_SIZE, 3 * PAGE_SIZE);
         drop(allocator);
 
         // check with allocate_region
         match FrameAllocator::allocate_region(2 * PAGE_SIZE) {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
 
         // check with allocate_frame_fragmented
         match FrameAllocator::allocate_frames_fragmented(2 * PAGE_SIZE) {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
 
         // check we can still take only one frame
         let frame = FrameAllocator::allocate_frame().unwrap();
         match FrameAllocator::allocate_frame() {
-            Err(KernelError::PhysicalMemoryExhaustion { .. }) => (),
+            Err(KernelError::OutOfMemory { .. }) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         }
         drop(frame);
     }
 
+    /// Freed frames are merged with their buddies to reform bigger blocks.
+    #[test]
+    fn buddies_merge() {
+        let _f = crate::frame_allocator::init();
+        // make it all available
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
+        drop(allocator);
+
+        let frames: Vec<_> = (0..ALL_MEMORY / PAGE_SIZE)
+            .map(|_| FrameAllocator::allocate_frame().unwrap())
+            .collect();
+        assert_eq!(FrameAllocator::stats().free, [0; MAX_ORDER + 1]);
+        drop(frames);
+
+        // everything is merged back in a single block.
+        let stats = FrameAllocator::stats();
+        assert_eq!(stats.free[5], 1);
+        assert_eq!(stats.free.iter().sum::<usize>(), 1);
+    }
+
+    /// Blocks are counted per order.
+    #[test]
+    fn stats() {
+        let _f = crate::frame_allocator::init();
+
+        // frame 3 is reserved, splitting the memory in 0..2, 2..3, 4..8, 8..16 and 16..32.
+        let stats = FrameAllocator::stats();
+        assert_eq!(&stats.free[..6], &[1, 1, 1, 1, 1, 0]);
+        assert_eq!(&stats.reserved[..6], &[1, 0, 0, 0, 0, 0]);
+        assert_eq!(&stats.used[..6], &[0, 0, 0, 0, 0, 0]);
+
+        // takes the 0..2 block.
+        let region = FrameAllocator::allocate_region(2 * PAGE_SIZE).unwrap();
+        assert_eq!(region.address(), PhysicalAddress(0));
+        let stats = FrameAllocator::stats();
+        assert_eq!(&stats.free[..6], &[1, 0, 1, 1, 1, 0]);
+        assert_eq!(&stats.used[..6], &[0, 1, 0, 0, 0, 0]);
+        drop(region);
+    }
+
+    /// Counts stay right when a region is freed in several parts.
+    #[test]
+    fn stats_split_region() {
+        use crate::utils::Splittable;
+        let _f = crate::frame_allocator::init();
+
+        // takes the 4..8 block, and frees 4..5 alone.
+        let mut region = FrameAllocator::allocate_region(4 * PAGE_SIZE).unwrap();
+        assert_eq!(region.address(), PhysicalAddress(4 * PAGE_SIZE));
+        let right = region.split_at(PAGE_SIZE).unwrap().unwrap();
+        drop(region);
+        let stats = FrameAllocator::stats();
+        assert_eq!(&stats.free[..6], &[2, 1, 0, 1, 1, 0]);
+        assert_eq!(&stats.used[..6], &[1, 1, 0, 0, 0, 0]);
+
+        drop(right);
+        let stats = FrameAllocator::stats();
+        assert_eq!(&stats.free[..6], &[1, 1, 1, 1, 1, 0]);
+        assert_eq!(&stats.used[..6], &[0, 0, 0, 0, 0, 0]);
+        assert_eq!(&stats.reserved[..6], &[1, 0, 0, 0, 0, 0]);
+    }
+
     /// This test checks the considered frames marked allocated by [allocate_frame_fragmented]
     /// are marked free again when the function fails.
     ///
@@ -684,7 +1132,7 @@ mod test {
         let _f = crate::frame_allocator::init();
         // make it all available
         let mut allocator = FRAME_ALLOCATOR.lock();
-        mark_area_free(&mut allocator.memory_bitmap, 0, ALL_MEMORY);
+        mark_area_free(&mut allocator, 0, ALL_MEMORY);
         drop(allocator);
 
         // allocate it all
@@ -693,7 +1141,7 @@ mod test {
 
         // check we have really allocated *all* of it
         match FrameAllocator::allocate_frame() {
-            Err(KernelError::PhysicalMemoryExhaustion {..} ) => (),
+            Err(KernelError::OutOfMemory {..} ) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         };
 
@@ -702,7 +1150,7 @@ mod test {
 
         // attempt to allocate more than the available half
         match FrameAllocator::allocate_frames_fragmented(ALL_MEMORY / 2 + PAGE_SIZE) {
-            Err(KernelError::PhysicalMemoryExhaustion {..} ) => (),
+            Err(KernelError::OutOfMemory {..} ) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         };
 
@@ -711,7 +1159,7 @@ mod test {
 
         // and now memory is fully allocated again
         match FrameAllocator::allocate_frame() {
-            Err(KernelError::PhysicalMemoryExhaustion {..} ) => (),
+            Err(KernelError::OutOfMemory {..} ) => (),
             unexpected_err => panic!("test failed: {:#?}", unexpected_err)
         };
 
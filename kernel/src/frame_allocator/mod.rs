@@ -11,7 +11,7 @@ pub use self::physical_mem_region::{PhysicalMemRegion, PhysicalMemRegionIter};
 
 /// Architecture specific-behaviour
 mod i386;
-pub use self::i386::{FrameAllocator, init, mark_frame_bootstrap_allocated};
+pub use self::i386::{FrameAllocator, FrameAllocatorStats, MAX_ORDER, init, mark_frame_bootstrap_allocated};
 
 /// An arch-specific FrameAllocator must expose the following functions
 pub trait FrameAllocatorTrait: FrameAllocatorTraitPrivate {
@@ -55,3 +55,28 @@ mod private {
         fn check_is_reserved(region: PhysicalAddress, length: usize) -> bool;
     }
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    //! Tests running on the real frame allocator, in kernel context.
+
+    use super::*;
+
+    /// Freed frames go back to the allocator, and are merged with their buddies again.
+    #[test_case]
+    fn allocate_and_free_region() {
+        let before = FrameAllocator::stats();
+        let region = FrameAllocator::allocate_region(4 * PAGE_SIZE).unwrap();
+        assert_eq!(region.size(), 4 * PAGE_SIZE);
+        assert!(FrameAllocator::check_is_allocated(region.address(), region.size()));
+        drop(region);
+        assert_eq!(FrameAllocator::stats().free, before.free);
+    }
+
+    /// Fragmented allocations give back the requested amount of memory.
+    #[test_case]
+    fn allocate_fragmented() {
+        let regions = FrameAllocator::allocate_frames_fragmented(8 * PAGE_SIZE).unwrap();
+        assert_eq!(regions.iter().map(PhysicalMemRegion::size).sum::<usize>(), 8 * PAGE_SIZE);
+    }
+}
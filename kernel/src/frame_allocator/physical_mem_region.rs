@@ -250,7 +250,7 @@ impl Splittable for Vec<PhysicalMemRegion> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::super::{FrameAllocator, FrameAllocatorTrait};
     use super::{PhysicalMemRegion, PhysicalMemRegionIter};
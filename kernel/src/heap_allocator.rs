@@ -2,51 +2,93 @@
 //!
 //! A simple wrapper around linked_list_allocator. We catch the OomError, and
 //! try to expand the heap with more pages in that case.
+//!
+//! Small allocations are first served from the [slab_allocator], and only fall
+//! back to the heap when their slab cache cannot be grown.
+//!
+//! When the heap cannot be expanded anymore, allocations are served from an
+//! emergency pool reserved at initialization, to give the kernel a chance to
+//! fail gracefully instead of panicking. This includes allocations made while
+//! the kernel memory is locked: we never wait for its lock, it is not re-entrant.
 use core::alloc::{GlobalAlloc, Layout, AllocErr};
 use crate::sync::{SpinLock, Once};
 use core::ops::Deref;
 use core::ptr::NonNull;
 use linked_list_allocator::{Heap, align_up};
-use crate::paging::{PAGE_SIZE, MappingAccessRights, kernel_memory::get_kernel_memory};
+use crate::paging::{PAGE_SIZE, MappingAccessRights};
+use crate::paging::kernel_memory::{KernelMemory, KERNEL_MEMORY, get_kernel_memory};
 use crate::frame_allocator::{FrameAllocator, FrameAllocatorTrait};
 use crate::mem::VirtualAddress;
+use crate::error::KernelError;
+use crate::slab_allocator::SlabAllocator;
+use failure::Backtrace;
 
 /// Simple wrapper around linked_list_allocator, growing heap by allocating pages
 /// with the frame allocator as necessary.
 #[allow(missing_debug_implementations)] // Heap does not implement Debug :/
-pub struct Allocator(Once<SpinLock<Heap>>);
+pub struct Allocator(Once<Heaps>);
+
+/// The heaps the allocator serves allocations from.
+struct Heaps {
+    /// The start of the virtual space reserved for the heap and the emergency
+    /// pool. Allocations outside of it come from the slab allocator.
+    space: VirtualAddress,
+    /// The slab caches, serving small allocations.
+    slabs: SlabAllocator,
+    /// The main heap, expanded as necessary.
+    heap: SpinLock<Heap>,
+    /// The emergency pool, only used when the main heap cannot be expanded.
+    emergency: SpinLock<Heap>,
+}
 
 // 512MB. Should be a multiple of PAGE_SIZE.
 /// Maximum size of our Kernel Heap.
 const RESERVED_HEAP_SIZE : usize = 512 * 1024 * 1024;
 
+// 64kB. Should be a multiple of PAGE_SIZE.
+/// Size of the emergency pool, taken from the end of the reserved heap space.
+const EMERGENCY_POOL_SIZE : usize = 64 * 1024;
+
 impl Allocator {
     /// Safely expands the heap if possible.
-    fn expand(&self, by: usize) {
-        let heap = self.0.call_once(Self::init);
+    ///
+    /// The heap is expanded page by page, it might have been partially expanded
+    /// when an error is returned.
+    ///
+    /// # Errors
+    ///
+    /// * `VirtualMemoryExhaustion`: the heap would grow over its reserved space.
+    /// * `OutOfMemory`: a frame or a page table could not be allocated.
+    fn expand(&self, by: usize, active_pages: &mut KernelMemory) -> Result<(), KernelError> {
+        let heap = &self.0.call_once(Self::init).heap;
         let heap_top = heap.lock().top();
         let heap_bottom = heap.lock().bottom();
-        let new_heap_top = align_up(by, PAGE_SIZE) + heap_top; // TODO: Checked add
-
-        assert!(new_heap_top - heap_bottom < RESERVED_HEAP_SIZE, "New heap grows over reserved heap size");
+        let new_heap_top = heap_top.checked_add(align_up(by, PAGE_SIZE))
+            .filter(|new_heap_top| new_heap_top - heap_bottom <= RESERVED_HEAP_SIZE - EMERGENCY_POOL_SIZE)
+            .ok_or_else(|| KernelError::VirtualMemoryExhaustion { backtrace: Backtrace::new() })?;
 
         debug!("EXTEND {:#010x}", new_heap_top);
 
         for new_page in (heap_top..new_heap_top).step_by(PAGE_SIZE) {
-            let frame = FrameAllocator::allocate_frame()
-                .expect("Cannot allocate physical memory for heap expansion");
-            let mut active_pages = get_kernel_memory();
-            active_pages.unmap(VirtualAddress(new_page), PAGE_SIZE);
-            active_pages.map_phys_region_to(frame, VirtualAddress(new_page), MappingAccessRights::k_rw());
-        }
-        unsafe {
-            // Safety: We just allocated the area.
-            heap.lock().extend(align_up(by, PAGE_SIZE));
+            let frame = FrameAllocator::allocate_frame()?;
+            // splitting the huge guard of the reserved heap can need a page table.
+            active_pages.unmap(VirtualAddress(new_page), PAGE_SIZE)?;
+            if let Err(err) = active_pages.map_phys_region_to(frame, VirtualAddress(new_page), MappingAccessRights::k_rw()) {
+                // the page table of this page exists, guarding it back can't fail.
+                active_pages.guard(VirtualAddress(new_page), PAGE_SIZE)
+                    .expect("Cannot guard the heap back");
+                return Err(err)
+            }
+            unsafe {
+                // Safety: We just allocated the page.
+                heap.lock().extend(PAGE_SIZE);
+            }
         }
+        Ok(())
     }
 
-    /// Create a new Heap of `RESERVED_HEAP_SIZE` bytes.
-    fn init() -> SpinLock<Heap> {
+    /// Create a new Heap of `RESERVED_HEAP_SIZE` bytes, and its emergency pool.
+    fn init() -> Heaps {
         let mut active_pages = get_kernel_memory();
         // Reserve 512MB of virtual memory for heap space. Don't actually allocate it.
         let heap_space = active_pages.find_virtual_space(RESERVED_HEAP_SIZE)
@@ -54,13 +96,27 @@ impl Allocator {
         // map only the first page
         let frame = FrameAllocator::allocate_frame()
             .expect("Cannot allocate first frame of heap");
-        active_pages.map_phys_region_to(frame, heap_space, MappingAccessRights::k_rw());
+        active_pages.map_phys_region_to(frame, heap_space, MappingAccessRights::k_rw())
+            .expect("Cannot map first frame of heap");
+        // the emergency pool lives at the end of the heap space, allocate it right away.
+        // It must be contiguous: allocating fragmented frames needs the heap we're creating.
+        let emergency_space = heap_space + RESERVED_HEAP_SIZE - EMERGENCY_POOL_SIZE;
+        let emergency_frames = FrameAllocator::allocate_region(EMERGENCY_POOL_SIZE)
+            .expect("Cannot allocate the heap emergency pool");
+        active_pages.map_phys_region_to(emergency_frames, emergency_space, MappingAccessRights::k_rw())
+            .expect("Cannot map the heap emergency pool");
         // guard the rest
-        active_pages.guard(heap_space + PAGE_SIZE, RESERVED_HEAP_SIZE - PAGE_SIZE);
+        active_pages.guard(heap_space + PAGE_SIZE, RESERVED_HEAP_SIZE - PAGE_SIZE - EMERGENCY_POOL_SIZE)
+            .expect("Cannot guard the heap");
         info!("Reserving {} pages at {:#010x}", RESERVED_HEAP_SIZE / PAGE_SIZE - 1, heap_space.addr() + PAGE_SIZE);
         unsafe {
-            // Safety: Size is of 0, and the address is freshly guard-paged.
-            SpinLock::new(Heap::new(heap_space.addr(), PAGE_SIZE))
+            // Safety: Both regions are freshly mapped, and the address after the heap is freshly guard-paged.
+            Heaps {
+                space: heap_space,
+                slabs: SlabAllocator::new(),
+                heap: SpinLock::new(Heap::new(heap_space.addr(), PAGE_SIZE)),
+                emergency: SpinLock::new(Heap::new(emergency_space.addr(), EMERGENCY_POOL_SIZE)),
+            }
         }
     }
 
@@ -68,30 +124,57 @@ impl Allocator {
     pub const fn new() -> Allocator {
         Allocator(Once::new())
     }
+
+    /// Prints the size of the heap and the statistics of the slab caches.
+    /// Used for debugging purposes.
+    pub fn dump_stats(&self) {
+        let heaps = self.0.call_once(Self::init);
+        let heap_size = heaps.heap.lock().size();
+        info!("Heap: {:#x} bytes, emergency pool: {:#x} bytes", heap_size, EMERGENCY_POOL_SIZE);
+        heaps.slabs.dump_stats();
+    }
 }
 
 impl Deref for Allocator {
     type Target = SpinLock<Heap>;
 
     fn deref(&self) -> &SpinLock<Heap> {
-        &self.0.call_once(Self::init)
+        &self.0.call_once(Self::init).heap
     }
 }
 
 unsafe impl<'a> GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // TODO: Race conditions.
-        let allocation = self.0.call_once(Self::init).lock().allocate_first_fit(layout);
+        let heaps = self.0.call_once(Self::init);
+        if let Some(object) = heaps.slabs.alloc(layout) {
+            debug!("ALLOC  {:#010x?}, size {:#x}, from slab", object, layout.size());
+            return object.as_ptr()
+        }
+        let allocation = heaps.heap.lock().allocate_first_fit(layout);
         let size = layout.size();
         // If the heap is exhausted, then extend and attempt the allocation another time.
         let alloc = match allocation {
             Err(AllocErr) => {
-                self.expand(size); // TODO: how much should I *really* expand by?
-                self.0.call_once(Self::init).lock().allocate_first_fit(layout)
+                // TODO: how much should I *really* expand by?
+                match KERNEL_MEMORY.try_lock() {
+                    Some(mut active_pages) => if let Err(err) = self.expand(size, &mut active_pages) {
+                        warn!("Cannot expand the kernel heap: {}", err);
+                    },
+                    None => warn!("Cannot expand the kernel heap while the kernel memory is locked")
+                }
+                heaps.heap.lock().allocate_first_fit(layout)
+                    // last resort, so the caller can still fail gracefully.
+                    .or_else(|_| heaps.emergency.lock().allocate_first_fit(layout))
             }
             _ => allocation
         }.ok().map_or(::core::ptr::null_mut(), |allocation| allocation.as_ptr());
 
+        if alloc.is_null() {
+            warn!("Kernel heap exhausted, failed to allocate {:#x} bytes", layout.size());
+            self.dump_stats();
+        }
+
         debug!("ALLOC  {:#010x?}, size {:#x}", alloc, layout.size());
         alloc
     }
@@ -104,7 +187,18 @@ unsafe impl<'a> GlobalAlloc for Allocator {
                 *(i as *mut u8) = 0x7F;
             }
         }
-        self.0.call_once(Self::init).lock().deallocate(NonNull::new(ptr).unwrap(), layout)
+        let heaps = self.0.call_once(Self::init);
+        if (ptr as usize).wrapping_sub(heaps.space.addr()) >= RESERVED_HEAP_SIZE {
+            heaps.slabs.dealloc(NonNull::new(ptr).unwrap(), layout);
+            return
+        }
+        let mut emergency = heaps.emergency.lock();
+        if emergency.bottom() <= ptr as usize && (ptr as usize) < emergency.top() {
+            emergency.deallocate(NonNull::new(ptr).unwrap(), layout)
+        } else {
+            drop(emergency);
+            heaps.heap.lock().deallocate(NonNull::new(ptr).unwrap(), layout)
+        }
     }
 }
 
@@ -120,7 +214,8 @@ unsafe impl<'a> GlobalAlloc for Allocator {
 // BODY: a try_new function on Arc/Rc that would return an AllocErr if it fails.
 // BODY:
 // BODY: Alternatively, we could start using our own Arc/Rc forks.
-/// Called when the kernel heap allocator detects Out Of Memory (OOM) condition.
+/// Called when the kernel heap allocator detects Out Of Memory (OOM) condition,
+/// which only happens once the emergency pool is exhausted too.
 ///
 /// It simply panics.
 #[cfg(target_os = "none")]
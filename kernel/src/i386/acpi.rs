@@ -57,7 +57,8 @@ impl AcpiHandler for MemoryHandler {
         let aligned_size = utils::align_up(offset + size, PAGE_SIZE);
     
         let physical_mem = unsafe { PhysicalMemRegion::new_unchecked(PhysicalAddress(physical_address_aligned), aligned_size) };
-        let virtual_address = paging::kernel_memory::get_kernel_memory().map_phys_region(physical_mem, MappingAccessRights::k_r());
+        let virtual_address = paging::kernel_memory::get_kernel_memory().map_phys_region(physical_mem, MappingAccessRights::k_r())
+            .expect("Cannot map the ACPI tables");
 
         PhysicalMapping {
             physical_start: physical_address,
@@ -69,7 +70,7 @@ impl AcpiHandler for MemoryHandler {
 
     fn unmap_physical_region<T>(&mut self, region: PhysicalMapping<T>) {
         let virtual_address_aligned = utils::align_down(region.virtual_start.as_ptr() as usize, PAGE_SIZE);
-        paging::kernel_memory::get_kernel_memory().unmap_no_dealloc(VirtualAddress(virtual_address_aligned), region.mapped_length);
+        paging::kernel_memory::get_kernel_memory().unmap_whole_mapping_no_dealloc(VirtualAddress(virtual_address_aligned), region.mapped_length);
     }
 }
 
@@ -33,7 +33,7 @@ use crate::mem::VirtualAddress;
 use crate::paging::kernel_memory::get_kernel_memory;
 use crate::i386::PrivilegeLevel;
 use crate::scheduler::{get_current_thread, get_current_process};
-use crate::process::{ProcessStruct, ThreadState};
+use crate::process::{ProcessStruct, ThreadState, CpuTimeState};
 use crate::sync::{SpinLock, SpinLockIRQ};
 use core::sync::atomic::Ordering;
 
@@ -44,7 +44,9 @@ use crate::panic::{kernel_panic, PanicOrigin};
 use crate::i386::structures::gdt::SegmentSelector;
 use crate::i386::registers::eflags::EFlags;
 use crate::mem::{UserSpacePtr, UserSpacePtrMut};
-use crate::error::UserspaceError;
+use crate::error::{UserspaceError, KernelError};
+use crate::paging::lands::{UserLand, VirtualSpaceLand};
+use crate::paging::MappingAccessRights;
 use crate::syscalls::*;
 use bit_field::BitArray;
 use sunrise_libkern::{nr, SYSCALL_NAMES};
@@ -377,7 +379,8 @@ macro_rules! trap_gate_asm {
 ///
 ///         // we come from userspace, backup the hardware context in the thread struct
 ///         {
-///             *get_current_thread().userspace_hwcontext.lock() = *userspace_context
+///             *get_current_thread().userspace_hwcontext.lock() = *userspace_context;
+///             get_current_thread().account_cpu_time(CpuTimeState::Kernel);
 ///         }
 ///
 ///         if cfg!(feature = "panic-on-exception") {
@@ -403,6 +406,7 @@ macro_rules! trap_gate_asm {
 ///     // if we're returning to userspace, check we haven't been killed
 ///     if comming from Ring == 3 {
 ///         check_thread_killed();
+///         get_current_thread().account_cpu_time(CpuTimeState::User);
 ///     }
 /// }
 /// ```
@@ -541,9 +545,12 @@ macro_rules! generate_trap_gate_handler {
             if let PrivilegeLevel::Ring0 = SegmentSelector(userspace_context.cs as u16).rpl() {
                 generate_trap_gate_handler!(__gen kernel_fault; name: $exception_name, userspace_context, errcode: $has_errcode, strategy: $kernel_fault_strategy);
             } else {
-                // we come from userspace, backup the hardware context in the thread struct
+                // we come from userspace, backup the hardware context in the thread struct,
+                // and start charging the time to the kernel.
                 {
-                    *get_current_thread().userspace_hwcontext.lock() = userspace_context.clone();
+                    let thread = get_current_thread();
+                    *thread.userspace_hwcontext.lock() = userspace_context.clone();
+                    thread.account_cpu_time(CpuTimeState::Kernel);
                     // don't leave an Arc in case we're killed in the handler.
                 }
 
@@ -558,6 +565,7 @@ macro_rules! generate_trap_gate_handler {
             // if we're returning to userspace, check we haven't been killed
             if let PrivilegeLevel::Ring3 = SegmentSelector(userspace_context.cs as u16).rpl() {
                 check_thread_killed();
+                get_current_thread().account_cpu_time(CpuTimeState::User);
             }
         }
     };
@@ -691,8 +699,60 @@ generate_trap_gate_handler!(name: "Page Fault Exception",
                 handler_strategy: user_page_fault_handler
 );
 
+/// Tries to resolve a page fault by committing the faulting page, if it is part of a
+/// lazily-committed mapping of the current process, or by giving it a private frame, if
+/// it is a copy-on-write page that was written to.
+///
+/// Returns true if the page is now mapped, and the faulting instruction can be retried.
+///
+/// The kernel can fault on a lazily-committed page when accessing a UserSpacePtr. If another thread
+/// holds the lock on the process memory, we wait for it to be released. If the faulting thread holds
+/// it, we can't commit the page and return false: the syscalls commit their userspace buffers before
+/// locking the process memory, so this is a kernel bug.
+fn commit_faulting_page(hwcontext: &UserspaceHardwareContext) -> bool {
+    let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
+    let cause_address = crate::paging::read_cr2();
+
+    let is_violation = errcode.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+
+    // the page was present, and this was not a write: this is an actual access violation.
+    if (is_violation && !errcode.contains(PageFaultErrorCode::CAUSED_BY_WRITE)) || !UserLand::contains_address(cause_address) {
+        return false;
+    }
+    let process = match scheduler::try_get_current_process() {
+        Some(process) => process,
+        None => return false
+    };
+    if process.pmemory.is_held_by_current_thread() {
+        return false;
+    }
+    let mut pmemory = process.pmemory.lock();
+    let res = if is_violation {
+        // only a write the mapping allows can be a write to a copy-on-write page.
+        let flags = pmemory.query_memory(cause_address).mapping().flags();
+        if !flags.contains(MappingAccessRights::WRITABLE) ||
+            (errcode.contains(PageFaultErrorCode::USER_MODE) && !flags.contains(MappingAccessRights::USER_ACCESSIBLE)) {
+            return false;
+        }
+        pmemory.unshare_page(cause_address)
+    } else {
+        pmemory.commit_page(cause_address)
+    };
+    match res {
+        Ok(()) => true,
+        Err(KernelError::InvalidMemState { .. }) => false,
+        Err(err) => {
+            error!("Failed committing page {:?}: {}", cause_address, err);
+            false
+        }
+    }
+}
+
 /// Overriding the default panic strategy so we can display cr2
 fn kernel_page_fault_panic(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if commit_faulting_page(hwcontext) {
+        return;
+    }
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
@@ -706,6 +766,9 @@ fn kernel_page_fault_panic(_exception_name: &'static str, hwcontext: &mut Usersp
 
 /// Overriding the default panic strategy so we can display cr2
 fn user_page_fault_panic(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if commit_faulting_page(hwcontext) {
+        return;
+    }
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
@@ -718,7 +781,12 @@ fn user_page_fault_panic(_exception_name: &'static str, hwcontext: &mut Userspac
 }
 
 /// Overriding the default kill strategy so we can display cr2
+///
+/// Faults on a lazily-committed page are resolved by committing it.
 fn user_page_fault_handler(_exception_name: &'static str, hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
+    if commit_faulting_page(hwcontext) {
+        return;
+    }
     let errcode = PageFaultErrorCode::from_bits_truncate(hwcontext.errcode as u32);
     let cause_address = crate::paging::read_cr2();
 
@@ -914,7 +982,7 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         // Horizon-inspired syscalls!
         (true, nr::SetHeapSize) => hwcontext.apply1(set_heap_size(x0)),
         (true, nr::QueryMemory) => hwcontext.apply1(query_memory(UserSpacePtrMut(x0 as _), x1, x2)),
-        (true, nr::ExitProcess) => hwcontext.apply0(exit_process()),
+        (true, nr::ExitProcess) => hwcontext.apply0(exit_process(x0 as _)),
         (true, nr::CreateThread) => hwcontext.apply1(create_thread(x0, x1, x2, x3 as _, x4 as _)),
         (true, nr::StartThread) => hwcontext.apply0(start_thread(x0 as _)),
         (true, nr::ExitThread) => hwcontext.apply0(exit_thread()),
@@ -929,14 +997,22 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         (true, nr::ConnectToNamedPort) => hwcontext.apply1(connect_to_named_port(UserSpacePtr(x0 as _))),
         (true, nr::SendSyncRequestWithUserBuffer) => hwcontext.apply0(send_sync_request_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), x2 as _)),
         (true, nr::GetProcessId) => hwcontext.apply1(get_process_id(x0 as _)),
+        (true, nr::GetThreadId) => hwcontext.apply1(get_thread_id(x0 as _)),
         (true, nr::OutputDebugString) => hwcontext.apply0(output_debug_string(UserSpacePtr::from_raw_parts(x0 as _, x1), x2, UserSpacePtr::from_raw_parts(x3 as _, x4))),
+        (true, nr::GetInfo) => hwcontext.apply2(get_info(x0 as _, x1 as _, (x2 as u64) | ((x3 as u64) << 32)).map(|v| (v as usize, (v >> 32) as usize))),
+        (true, nr::SetThreadActivity) => hwcontext.apply0(set_thread_activity(x0 as _, x1 as _)),
+        (true, nr::GetThreadContext3) => hwcontext.apply0(get_thread_context3(UserSpacePtrMut(x0 as _), x1 as _)),
+        (true, nr::DumpInfo) => hwcontext.apply0(dump_info(x0 as _, x1)),
+        (true, nr::DumpInfoNew) => hwcontext.apply0(dump_info_new(x0 as _, x1)),
         (true, nr::CreateSession) => hwcontext.apply2(create_session(x0 != 0, x1 as _)),
         (true, nr::AcceptSession) => hwcontext.apply1(accept_session(x0 as _)),
         (true, nr::ReplyAndReceiveWithUserBuffer) => hwcontext.apply1(reply_and_receive_with_user_buffer(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), UserSpacePtr::from_raw_parts(x2 as _, x3), x4 as _, x5)),
         (true, nr::CreateEvent) => hwcontext.apply2(create_event()),
+        (true, nr::SetProcessActivity) => hwcontext.apply0(set_process_activity(x0 as _, x1 as _)),
         (true, nr::CreateSharedMemory) => hwcontext.apply1(create_shared_memory(x0 as _, x1 as _, x2 as _)),
         (true, nr::CreateInterruptEvent) => hwcontext.apply1(create_interrupt_event(x0, x1 as u32)),
         (true, nr::QueryPhysicalAddress) => hwcontext.apply3(query_physical_address(x0 as _)),
+        (true, nr::GetProcessList) => hwcontext.apply1(get_process_list(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1))),
         (true, nr::CreatePort) => hwcontext.apply2(create_port(x0 as _, x1 != 0, UserSpacePtr(x2 as _))),
         (true, nr::ManageNamedPort) => hwcontext.apply1(manage_named_port(UserSpacePtr(x0 as _), x1 as _)),
         (true, nr::ConnectToPort) => hwcontext.apply1(connect_to_port(x0 as _)),
@@ -951,6 +1027,14 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
         (true, nr::MapFramebuffer) => hwcontext.apply4(map_framebuffer()),
         (true, nr::MapMmioRegion) => hwcontext.apply0(map_mmio_region(x0, x1, x2, x3 != 0)),
         (true, nr::SetThreadArea) => hwcontext.apply0(set_thread_area(x0)),
+        (true, nr::SetThreadName) => hwcontext.apply0(set_thread_name(x0 as _, UserSpacePtr::from_raw_parts(x1 as _, x2))),
+        (true, nr::GetProcessName) => hwcontext.apply1(get_process_name(x0, UserSpacePtrMut::from_raw_parts_mut(x1 as _, x2))),
+        (true, nr::ShareProcessMemory) => hwcontext.apply0(share_process_memory(x0 as _, x1 as _, x2 as _, x3 as _)),
+        (true, nr::ReadKernelLog) => hwcontext.apply1(read_kernel_log(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1), (x2 as u64) | ((x3 as u64) << 32))),
+        (true, nr::SetKernelLogFilter) => hwcontext.apply0(set_kernel_log_filter(UserSpacePtr::from_raw_parts(x0 as _, x1))),
+        (true, nr::GetKernelLogFilter) => hwcontext.apply1(get_kernel_log_filter(UserSpacePtrMut::from_raw_parts_mut(x0 as _, x1))),
+        (true, nr::CreatePortDeathEvent) => hwcontext.apply1(create_port_death_event(x0 as _)),
+        (true, nr::GetProcessCpuTime) => hwcontext.apply4(get_process_cpu_time(x0)),
 
         // Unknown/unauthorized syscall.
         (false, _) => {
@@ -976,7 +1060,8 @@ fn syscall_interrupt_dispatcher(_exception_name: &'static str, hwcontext: &mut U
 /// For each irq number it is given, this macro will generate an irq handler that:
 ///
 /// 1. acknowledges the irq
-/// 2. dispatches the event for this irq line
+/// 2. lets the timer handle it, if it is the timer irq
+/// 3. dispatches the event for this irq line
 ///
 /// It uses [`generate_trap_gate_handler`] internally to generate the asm and low-level rust wrappers.
 /// You must give it an ident for both of those functions that will be passed on to `generate_trap_gate_handler`,
@@ -992,6 +1077,7 @@ macro_rules! irq_handler {
             /// Auto generated irq handler. See [`irq_handler`].
             fn $handler_name(_exception_name: &'static str, _hwcontext: &mut UserspaceHardwareContext, _has_errcode: bool) {
                 crate::i386::interrupt::acknowledge($irq_nbr);
+                crate::timer::handle_irq($irq_nbr);
                 crate::event::dispatch_event($irq_nbr);
             }
 
@@ -1053,7 +1139,7 @@ pub unsafe fn init() {
     crate::i386::interrupt::init();
 
     {
-        let page = get_kernel_memory().get_page();
+        let page = get_kernel_memory().get_page().expect("Cannot allocate the IDT");
         let idt = page.addr() as *mut u8 as *mut Idt;
         unsafe {
             (*idt).init();
@@ -181,6 +181,14 @@ pub mod instructions {
             asm!("hlt" :::: "volatile");
         }
 
+        /// Enables interrupts, waits until an interrupt is fired, and disables them again.
+        ///
+        /// `sti` only takes effect after the next instruction, so an interrupt cannot
+        /// fire between the `sti` and the `hlt`, which would leave us halted until the next one.
+        pub unsafe fn sti_hlt_cli() {
+            asm!("sti; hlt; cli" :::: "volatile");
+        }
+
         /// Returns whether interrupts are enabled.
         pub fn are_enabled() -> bool {
             use crate::i386::registers::eflags::{self, EFlags};
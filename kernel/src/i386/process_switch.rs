@@ -2,11 +2,12 @@
 //!
 //! This modules describe low-level functions and structures needed to perform a process switch
 
-use crate::process::ThreadStruct;
+use crate::process::{ThreadStruct, CpuTimeState};
 use alloc::sync::Arc;
 use core::mem::size_of;
 use crate::i386::gdt::{GDT, MAIN_TASK};
 use crate::i386::gdt::GdtIndex;
+use crate::i386::stack::KernelStack;
 
 /// The hardware context of a paused thread. It contains just enough registers to get the thread
 /// running again.
@@ -21,6 +22,25 @@ pub struct ThreadHardwareContext {
     esp: usize,
 }
 
+impl ThreadHardwareContext {
+    /// Gets the `$ebp` and `$eip` of a scheduled-out thread, from the registers [process_switch]
+    /// saved on its kernel stack.
+    ///
+    /// `$eip` points to the schedule-in routine, or to [first_schedule] if the thread was never
+    /// scheduled. Returns None if the saved `$esp` doesn't point into `kstack`.
+    ///
+    /// # Safety
+    ///
+    /// The thread must be scheduled-out, and stay so while the result is used.
+    /// Otherwise, the saved registers are stale.
+    pub unsafe fn saved_frame(&self, kstack: &KernelStack) -> Option<(usize, usize)> {
+        // Same layout as RegistersOnStack: eflags, edi, esi, ebp, esp, ebx, edx, ecx, eax, callback eip.
+        let ebp = kstack.read_word(self.esp.wrapping_add(3 * size_of::<usize>()))?;
+        let eip = kstack.read_word(self.esp.wrapping_add(9 * size_of::<usize>()))?;
+        Some((ebp, eip))
+    }
+}
+
 impl Default for ThreadHardwareContext {
     /// Creates an empty ThreadHardwareContext.
     fn default() -> Self {
@@ -88,6 +108,9 @@ impl Default for ThreadHardwareContext {
 #[inline(never)] // we need that sweet saved ebp + eip on the stack
 pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_current: Arc<ThreadStruct>) -> Arc<ThreadStruct> {
 
+    // stop charging CPU time to the thread we're scheduling out.
+    thread_current.account_cpu_time(CpuTimeState::Stopped);
+
     let esp_to_load = {
         // todo do not try to change cr3 if thread_b belongs to the same process.
         //let mut thread_current_lock_pmemory = thread_current.pmemory.try_lock()
@@ -201,6 +224,11 @@ pub unsafe extern "C" fn process_switch(thread_b: Arc<ThreadStruct>, thread_curr
         main_tss.iopb[ioport / 8] &= !(1 << (ioport % 8));
     }
 
+    // we're back running in the kernel. Time spent idling is not charged to anyone.
+    if !crate::scheduler::is_idle_thread(&me) {
+        me.account_cpu_time(CpuTimeState::Kernel);
+    }
+
     me
 }
 
@@ -317,6 +345,9 @@ unsafe fn first_schedule() {
 
         drop(main_tss); // unlock it
 
+        // we're about to jump to userspace, start charging CPU time to it.
+        current.account_cpu_time(CpuTimeState::User);
+
         // call the scheduler to finish the high-level process switch mechanics
         unsafe {
             // safe: interrupts are off
@@ -51,6 +51,9 @@ const STACK_SIZE_WITH_GUARD_IN_BYTES: usize = STACK_SIZE_WITH_GUARD * PAGE_SIZE;
 /// The alignment of the stack.
 const STACK_ALIGNMENT: usize = log2_ceil(STACK_SIZE_WITH_GUARD_IN_BYTES);
 
+/// The maximum number of frames [KernelStack::walk] goes through, in case the stack is corrupted.
+const MAX_WALKED_FRAMES: usize = 64;
+
 /// A structure representing a kernel stack.
 #[derive(Debug)]
 pub struct KernelStack {
@@ -68,8 +71,11 @@ impl KernelStack {
                                                    2usize.pow(STACK_ALIGNMENT as u32))?;
         let region = FrameAllocator::allocate_region(STACK_SIZE * PAGE_SIZE)?;
 
-        memory.map_phys_region_to(region, va + PAGE_SIZE, MappingAccessRights::k_rw());
-        memory.guard(va, PAGE_SIZE);
+        memory.map_phys_region_to(region, va + PAGE_SIZE, MappingAccessRights::k_rw())?;
+        if let Err(err) = memory.guard(va, PAGE_SIZE) {
+            memory.unmap_whole_mapping(va + PAGE_SIZE, STACK_SIZE * PAGE_SIZE);
+            return Err(err)
+        }
 
         let mut me = KernelStack { stack_address: va };
 
@@ -145,6 +151,35 @@ impl KernelStack {
                                    - Self::STACK_POISON_SIZE
     }
 
+    /// Reads the word at `addr`, if it falls in the usable part of this stack.
+    ///
+    /// # Safety
+    ///
+    /// The stack may be in use by its thread while we read it. The caller must make sure this
+    /// thread is either scheduled-out or the current one, and stays so while the value is used.
+    pub unsafe fn read_word(&self, addr: usize) -> Option<usize> {
+        let start = self.stack_address.addr() + PAGE_SIZE;
+        let end = self.stack_address.addr() + STACK_SIZE_WITH_GUARD * PAGE_SIZE;
+        if addr < start || addr > end - size_of::<usize>() {
+            return None;
+        }
+        Some(::core::ptr::read_unaligned(addr as *const usize))
+    }
+
+    /// Walks this stack frame by frame, calling `f` with the pc of every frame, innermost first.
+    ///
+    /// The walk starts at the frame whose base is `ebp`, and whose pc is `eip`. See [walk_stack].
+    /// It stops on the poison pointers at the base of the stack, or on a saved ebp falling
+    /// outside of it.
+    ///
+    /// # Safety
+    ///
+    /// The thread owning this stack must be either scheduled-out or the current one, and stay so
+    /// until this function returns.
+    pub unsafe fn walk<F: FnMut(usize)>(&self, ebp: usize, eip: usize, f: F) {
+        walk_stack(|addr| self.read_word(addr), ebp, eip, f)
+    }
+
     /// Dumps the stack, displaying it in a frame-by-frame format.
     ///
     /// It can accepts an elf symbols which will be used to enhance the stack dump.
@@ -179,12 +214,45 @@ impl Drop for KernelStack {
     /// We deallocate the stack when it is dropped
     fn drop(&mut self) {
         debug!("Dropping KernelStack {:?}", self);
-        get_kernel_memory().unmap(self.stack_address, STACK_SIZE_WITH_GUARD * PAGE_SIZE);
+        get_kernel_memory().unmap_whole_mapping(self.stack_address, STACK_SIZE_WITH_GUARD * PAGE_SIZE);
     }
 }
 
 /* ********************************************************************************************** */
 
+/// Walks a stack frame by frame, calling `f` with the pc of every frame, innermost first.
+///
+/// The walk starts at the frame whose base is `ebp`, and whose pc is `eip`. The saved ebp and eip
+/// of each frame are only read through `read_word`, which must return None for any address that
+/// cannot be read safely.
+///
+/// Unlike [dump_stack], this takes no lock and doesn't allocate, so the panic handler can use
+/// it on every thread. The walk stops on a null eip, on a word `read_word` refuses to read, on a
+/// saved ebp going down the stack, or after [MAX_WALKED_FRAMES] frames.
+pub fn walk_stack<R, F>(mut read_word: R, mut ebp: usize, mut eip: usize, mut f: F)
+where
+    R: FnMut(usize) -> Option<usize>,
+    F: FnMut(usize),
+{
+    for _ in 0..MAX_WALKED_FRAMES {
+        if eip == 0x00000000 { break; } // reached end of stack
+        f(eip);
+
+        let saved_ebp = read_word(ebp);
+        let saved_eip = read_word(ebp.wrapping_add(size_of::<usize>()));
+        let (saved_ebp, saved_eip) = match (saved_ebp, saved_eip) {
+            (Some(saved_ebp), Some(saved_eip)) => (saved_ebp, saved_eip),
+            _ => break
+        };
+
+        // Frames only go up the stack. Anything else means it is corrupted, and we could loop.
+        if saved_ebp != 0x00000000 && saved_ebp <= ebp { break; }
+
+        ebp = saved_ebp;
+        eip = saved_eip;
+    }
+}
+
 /// The minimal information needed to perform a stack dump.
 #[derive(Debug)]
 pub struct StackDumpSource {
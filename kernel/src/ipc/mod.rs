@@ -24,8 +24,9 @@
 //!
 //! Additionally, a ServerPort implements the Waitable trait, allowing it to be
 //! used with the `event::wait` function. This will wait until the associated
-//! ClientPort had its connect operation called. TODO: The ClientPort should also
-//! implement Waitable, I believe. In Horizon/NX, it implements KSynchronization.
+//! ClientPort had its connect operation called. The ClientPort implements
+//! Waitable too: it waits until a session becomes available on the port, or
+//! until all its ServerPorts are closed.
 //!
 //! ```rust
 //! let (server, client) = Port::new();
@@ -42,7 +43,9 @@
 //! `receive` operation (again, with various variants).
 //!
 //! ServerSession implements the Waitable trait, allowing it to be used with the
-//! `event::wait` function. TODO: The ClientSession should also implement Waitable.
+//! `event::wait` function. The ClientSession implements Waitable too: it waits
+//! until all its ServerSessions are closed, allowing the client to notice that
+//! the service died.
 //!
 //! ```rust
 //! use kernel::ipc::session;
@@ -15,6 +15,14 @@
 //! used with the `event::wait` function. This will wait until the associated
 //! ClientPort had its connect operation called.
 //!
+//! A ClientPort also implements Waitable. It is signaled when a new session can
+//! be opened on the port without exceeding its maximum session count, or when
+//! all the ServerPorts were closed, so `connect` would fail right away. Ports
+//! without a session limit are only signaled once their ServerPorts are closed.
+//!
+//! To only wait for the ServerPorts to be closed, whatever the session limit,
+//! a ClientPort can hand out death events with [ClientPort::death_event].
+//!
 //! ```rust
 //! let (server, client) = Port::new();
 //! let client_sess = client.connect();
@@ -27,7 +35,7 @@ use alloc::vec::Vec;
 use alloc::sync::{Arc, Weak};
 use crate::sync::SpinLock;
 use crate::error::UserspaceError;
-use crate::event::{self, Waitable};
+use crate::event::{self, Waitable, WritableEvent, ReadableEvent};
 use crate::process::ThreadStruct;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::ipc::session::{self, ClientSession, ServerSession};
@@ -42,11 +50,24 @@ struct Port {
     /// Number of active ServerPort. When it drops to 0, future connection
     /// attempts will faill with [UserspaceError::PortRemoteDead].
     servercount: AtomicUsize,
+    /// Maximum number of sessions that can be open at the same time on this
+    /// port. 0 means there is no limit.
+    max_sessions: u32,
+    /// Number of sessions currently open on this port, including the ones
+    /// waiting to be accepted.
+    num_sessions: AtomicUsize,
+    /// List of threads waiting on the ClientPort, for a session to become
+    /// available or for the port to die.
+    client_waiters: SpinLock<Vec<Weak<ThreadStruct>>>,
+    /// Events to signal when the last ServerPort is closed.
+    /// See [ClientPort::death_event].
+    death_events: SpinLock<Vec<WritableEvent>>,
 }
 
 /// The client side of a Port.
 ///
-/// This side can call connect().
+/// This side can call connect(). It implements Waitable, which waits until a
+/// session becomes available on the port, or until the port dies.
 #[derive(Debug, Clone)]
 pub struct ClientPort(Arc<Port>);
 
@@ -69,16 +90,58 @@ impl Port {
         this.servercount.fetch_add(1, Ordering::SeqCst);
         ServerPort(this)
     }
+
+    /// Reserves a session on this port.
+    ///
+    /// # Errors
+    ///
+    /// Returns PortMaxSessions if the maximum number of sessions of this port
+    /// is already reached.
+    fn reserve_session(&self) -> Result<(), UserspaceError> {
+        let mut count = self.num_sessions.load(Ordering::SeqCst);
+        loop {
+            if self.max_sessions != 0 && count >= self.max_sessions as usize {
+                return Err(UserspaceError::PortMaxSessions);
+            }
+            match self.num_sessions.compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(new_count) => count = new_count,
+            }
+        }
+    }
+
+    /// Releases a session previously reserved with [Port::reserve_session],
+    /// waking up the threads waiting on the ClientPort.
+    fn release_session(&self) {
+        let count = self.num_sessions.fetch_sub(1, Ordering::SeqCst);
+        assert!(count != 0, "Overflow when decrementing num_sessions");
+        self.wake_client_waiters();
+    }
+
+    /// Wakes up all the threads waiting on the ClientPort.
+    fn wake_client_waiters(&self) {
+        let mut waiters = self.client_waiters.lock();
+        for item in waiters.drain(..) {
+            if let Some(thread) = item.upgrade() {
+                scheduler::add_to_schedule_queue(thread);
+            }
+        }
+    }
 }
 
 /// Create a new Port pair. Those ports are linked to each-other: The server will
 /// receive connections from the client.
-/// A port may only have max_sessions sessions active at a given time.
-pub fn new(_max_sessions: u32) -> (ServerPort, ClientPort) {
+/// A port may only have max_sessions sessions active at a given time. If
+/// max_sessions is 0, the number of sessions is not limited.
+pub fn new(max_sessions: u32) -> (ServerPort, ClientPort) {
     let port = Arc::new(Port {
         servercount: AtomicUsize::new(0),
         incoming_connections: SpinLock::new(Vec::new()),
-        accepters: SpinLock::new(Vec::new())
+        accepters: SpinLock::new(Vec::new()),
+        max_sessions,
+        num_sessions: AtomicUsize::new(0),
+        client_waiters: SpinLock::new(Vec::new()),
+        death_events: SpinLock::new(Vec::new()),
     });
     (Port::server(port.clone()), Port::client(port.clone()))
 }
@@ -99,6 +162,24 @@ impl Waitable for ServerPort {
     }
 }
 
+// Wait for a session to become available, or for the port to die.
+impl Waitable for ClientPort {
+    fn is_signaled(&self) -> bool {
+        let port = &self.0;
+        port.servercount.load(Ordering::SeqCst) == 0 ||
+            (port.max_sessions != 0 && port.num_sessions.load(Ordering::SeqCst) < port.max_sessions as usize)
+    }
+
+    fn register(&self) {
+        let mut waiters = self.0.client_waiters.lock();
+        let curproc = scheduler::get_current_thread();
+
+        if !waiters.iter().filter_map(|v| v.upgrade()).any(|v| Arc::ptr_eq(&curproc, &v)) {
+            waiters.push(Arc::downgrade(&curproc));
+        }
+    }
+}
+
 impl Clone for ServerPort {
     fn clone(&self) -> Self {
         assert!(self.0.servercount.fetch_add(1, Ordering::SeqCst) != usize::max_value(), "Overflow when incrementing servercount");
@@ -118,6 +199,14 @@ impl Drop for ServerPort {
             for request in internal.drain(..) {
                 scheduler::add_to_schedule_queue(request.creator.clone());
             }
+            drop(internal);
+
+            // The ClientPort is now signaled.
+            self.0.wake_client_waiters();
+
+            for event in self.0.death_events.lock().drain(..) {
+                event.signal();
+            }
         }
     }
 }
@@ -132,6 +221,12 @@ struct IncomingConnection {
 }
 
 impl ServerPort {
+    /// Gets an identifier of this port, shared by its client and server
+    /// sides. See [ClientPort::id].
+    pub fn id(&self) -> usize {
+        &*self.0 as *const Port as usize
+    }
+
     /// Accept a new connection on the Port.
     pub fn accept(&self) -> Result<ServerSession, UserspaceError> {
         loop {
@@ -146,8 +241,9 @@ impl ServerPort {
                 // This shouldn't happen since we pop it from the queue above.
                 assert!(lock.is_none(), "Handled connection request still in incoming conn queue.");
 
-                // We can associate a session to this now.
-                let (server, client) = session::new();
+                // We can associate a session to this now. The session gives
+                // its slot back to the port once it is closed.
+                let (server, client) = session::new_for_port(ClientPort(self.0.clone()));
                 *lock = Some(client);
 
                 // Wake up the creator.
@@ -163,8 +259,49 @@ impl ServerPort {
 }
 
 impl ClientPort {
+    /// Gets an identifier of this port, shared by its client and server
+    /// sides, and by the sessions created from it. Used to match them when
+    /// dumping handle tables.
+    pub fn id(&self) -> usize {
+        &*self.0 as *const Port as usize
+    }
+
+    /// Creates an event that gets signaled once all the ServerPorts of this
+    /// port are closed. It is signaled right away if they already are.
+    ///
+    /// Unlike the ClientPort itself, the event is not signaled when a session
+    /// becomes available.
+    pub fn death_event(&self) -> ReadableEvent {
+        let (writable, readable) = event::new_pair();
+        let mut death_events = self.0.death_events.lock();
+        // checked with the lock held, so the last ServerPort can't be dropped
+        // without seeing our event.
+        if self.0.servercount.load(Ordering::SeqCst) == 0 {
+            writable.signal();
+        } else {
+            death_events.push(writable);
+        }
+        readable
+    }
+
+    /// Gives back the slot of a session created from this port, once that
+    /// session is destroyed.
+    pub(super) fn release_session(&self) {
+        self.0.release_session();
+    }
+
     /// Connects to this port.
+    ///
+    /// # Errors
+    ///
+    /// Returns PortMaxSessions if the maximum number of sessions of this port
+    /// is reached.
+    ///
+    /// Returns PortRemoteDead if all handles to the associated ServerPort are
+    /// closed.
     pub fn connect(&self) -> Result<ClientSession, UserspaceError> {
+        self.0.reserve_session()?;
+
         let incoming = Arc::new(IncomingConnection {
             session: SpinLock::new(None),
             creator: scheduler::get_current_thread()
@@ -176,6 +313,8 @@ impl ClientPort {
         let session = loop {
             // If no handle to the server exist anymore, give up.
             if self.0.servercount.load(Ordering::SeqCst) == 0 {
+                // No session will be created, give the slot back.
+                self.0.release_session();
                 return Err(UserspaceError::PortRemoteDead);
             }
 
@@ -199,3 +338,27 @@ impl ClientPort {
         Ok(session)
     }
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    //! Tests running on ports of the current process, in kernel context.
+
+    use super::*;
+
+    /// A death event is signaled once the last ServerPort is closed, and not
+    /// when a session is available.
+    #[test_case]
+    fn death_event() {
+        let (server, client) = new(1);
+        let server_clone = server.clone();
+        let death_event = client.death_event();
+        assert!(client.is_signaled(), "A session is available on the port");
+        assert!(!death_event.is_signaled(), "Death event signaled on a live port");
+
+        drop(server);
+        assert!(!death_event.is_signaled(), "Death event signaled while a ServerPort is open");
+        drop(server_clone);
+        assert!(death_event.is_signaled(), "Death event not signaled on a dead port");
+        assert!(client.death_event().is_signaled(), "New death event not signaled on a dead port");
+    }
+}
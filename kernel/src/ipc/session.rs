@@ -10,6 +10,11 @@
 //! on the same handle, they will have to wait for the current request to be
 //! replied to before being able to receive the next request in line.
 //!
+//! Both sides implement Waitable. A ServerSession is signaled when a request
+//! is waiting to be received, while a ClientSession is signaled once all the
+//! ServerSessions were closed, allowing clients to notice the death of the
+//! service they talk to.
+//!
 //! ```rust
 //! use kernel::ipc::session;
 //! let (server, client) = session::new();
@@ -27,10 +32,12 @@ use crate::sync::SpinLock;
 use crate::error::UserspaceError;
 use crate::event::Waitable;
 use crate::process::ThreadStruct;
+use crate::ipc::port::ClientPort;
 use crate::sync::MutexGuard;
 use core::convert::TryInto;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use core::slice;
+use core::ops::DerefMut;
 use crate::paging::{PAGE_SIZE, MappingAccessRights, process_memory::ProcessMemory};
 use crate::paging::process_memory::QueryMemory;
 use crate::paging::mapping::MappingFrames;
@@ -38,7 +45,7 @@ use crate::mem::{UserSpacePtr, UserSpacePtrMut, VirtualAddress};
 use bit_field::BitField;
 use crate::error::KernelError;
 use crate::checks::check_lower_than_usize;
-use sunrise_libkern::MemoryType;
+use sunrise_libkern::{MemoryType, MemoryState, MemoryPermissions, MemoryAttributes};
 use sunrise_libutils::align_up;
 
 use failure::Backtrace;
@@ -61,10 +68,24 @@ struct Session {
     internal: SpinLock<SessionRequests>,
     /// List of threads waiting for a request.
     accepters: SpinLock<Vec<Weak<ThreadStruct>>>,
+    /// List of threads waiting on the ClientSession for the server side to be
+    /// closed.
+    client_waiters: SpinLock<Vec<Weak<ThreadStruct>>>,
     /// Count of live ServerSessions. Once it drops to 0, all attempts to call
     /// [ClientSession::send_request] will fail with
     /// [UserspaceError::PortRemoteDead].
     servercount: AtomicUsize,
+    /// Port this session was created from, if any. The session occupies one of
+    /// its slots until it is destroyed.
+    port: Option<ClientPort>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(port) = self.port.take() {
+            port.release_session();
+        }
+    }
 }
 
 /// The client side of a Session.
@@ -100,6 +121,14 @@ impl Drop for ServerSession {
                 *request.answered.lock() = Some(Err(UserspaceError::PortRemoteDead));
                 scheduler::add_to_schedule_queue(request.sender.clone());
             }
+            drop(internal);
+
+            // The ClientSessions are now signaled.
+            for item in self.0.client_waiters.lock().drain(..) {
+                if let Some(thread) = item.upgrade() {
+                    scheduler::add_to_schedule_queue(thread);
+                }
+            }
         }
     }
 }
@@ -146,13 +175,28 @@ impl Session {
 /// Create a new Session pair. Those sessions are linked to each-other: The
 /// server will receive requests sent through the client.
 pub fn new() -> (ServerSession, ClientSession) {
+    new_with(None)
+}
+
+/// Create a new Session pair for a connection to the given port. The session
+/// must already be reserved on the port, and will be released when both sides
+/// are closed.
+pub(super) fn new_for_port(port: ClientPort) -> (ServerSession, ClientSession) {
+    new_with(Some(port))
+}
+
+/// Create a new Session pair, optionally linked to the port it was created
+/// from.
+fn new_with(port: Option<ClientPort>) -> (ServerSession, ClientSession) {
     let sess = Arc::new(Session {
         internal: SpinLock::new(SessionRequests {
             incoming_requests: Vec::new(),
             active_request: None
         }),
         accepters: SpinLock::new(Vec::new()),
-        servercount: AtomicUsize::new(0)
+        client_waiters: SpinLock::new(Vec::new()),
+        servercount: AtomicUsize::new(0),
+        port,
     });
 
     (Session::server(sess.clone()), Session::client(sess))
@@ -183,6 +227,22 @@ impl Waitable for ServerSession {
     }
 }
 
+// Wait for the server side to be closed.
+impl Waitable for ClientSession {
+    fn is_signaled(&self) -> bool {
+        self.0.servercount.load(Ordering::SeqCst) == 0
+    }
+
+    fn register(&self) {
+        let mut waiters = self.0.client_waiters.lock();
+        let curproc = scheduler::get_current_thread();
+
+        if !waiters.iter().filter_map(|v| v.upgrade()).any(|v| Arc::ptr_eq(&curproc, &v)) {
+            waiters.push(Arc::downgrade(&curproc));
+        }
+    }
+}
+
 /// An incoming IPC request.
 #[derive(Debug)]
 struct Request {
@@ -219,6 +279,66 @@ struct Buffer {
     size: usize,
 }
 
+/// Locks held on the two processes taking part in an IPC message, in the
+/// direction of the message.
+///
+/// A process may send messages to itself, for instance to a server session it
+/// owns from another of its threads. Taking the lock of each end would deadlock
+/// in this case, so a single lock is taken and shared by both ends.
+#[derive(Debug)]
+enum LockPair<G> {
+    /// Both ends are the same process.
+    Same(G),
+    /// The ends are different processes.
+    Different {
+        /// Lock of the sender of the message.
+        from: G,
+        /// Lock of the receiver of the message.
+        to: G,
+    }
+}
+
+impl<G: DerefMut> LockPair<G> {
+    /// Locks `from` and `to` using the `lock` function.
+    ///
+    /// The locks are always taken in the same order, so two processes messaging
+    /// each other at the same time cannot deadlock.
+    fn lock<'a, T, F: Fn(&'a T) -> G>(from: &'a T, to: &'a T, lock: F) -> LockPair<G> {
+        if core::ptr::eq(from, to) {
+            LockPair::Same(lock(from))
+        } else if (from as *const T) < (to as *const T) {
+            let from = lock(from);
+            LockPair::Different { from, to: lock(to) }
+        } else {
+            let to = lock(to);
+            LockPair::Different { from: lock(from), to }
+        }
+    }
+
+    /// Gets the locked data of the sender.
+    fn from(&mut self) -> &mut G::Target {
+        match self {
+            LockPair::Same(lock) => &mut **lock,
+            LockPair::Different { from, .. } => &mut **from,
+        }
+    }
+
+    /// Gets the locked data of the receiver.
+    fn to(&mut self) -> &mut G::Target {
+        match self {
+            LockPair::Same(lock) => &mut **lock,
+            LockPair::Different { to, .. } => &mut **to,
+        }
+    }
+
+    /// Swaps the sender and the receiver.
+    fn reverse(&mut self) {
+        if let LockPair::Different { from, to } = self {
+            core::mem::swap(from, to);
+        }
+    }
+}
+
 /// Send an IPC Buffer from the sender into the receiver.
 ///
 /// There are two "families" of IPC buffers:
@@ -235,15 +355,39 @@ struct Buffer {
 /// requiring to flush the page table cache, so care must be taken when chosing
 /// between Buffer or Pointer family of IPC.
 ///
+/// The sender must be allowed to send the memory over IPC with the given
+/// buffer flags, and must have the permissions that the receiver will be
+/// given on the buffer: readable for A buffers, readable and writable for B and
+/// W buffers. W buffers are exchange buffers: their content is available to
+/// the receiver, and copied back to the sender on reply.
+///
+/// # Errors
+///
+/// - `InvalidCombination`
+///   - The buffer flags are invalid.
+/// - `InvalidMemState`
+///   - The buffer isn't in a state that allows sending it over IPC with the
+///     given flags, or the sender doesn't have the required permissions on it.
+///
 /// Should be called from the receiver process.
 #[allow(unused)]
-fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mut ProcessMemory, to_mem: &mut ProcessMemory, flags: MappingAccessRights, buffers: &mut Vec<Buffer>) -> Result<(), UserspaceError> {
+fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, mems: &mut LockPair<MutexGuard<'_, ProcessMemory>>, flags: MappingAccessRights, buffers: &mut Vec<Buffer>) -> Result<(), UserspaceError> {
     let lowersize = u32::from_le_bytes(from_buf[*curoff..*curoff + 4].try_into().unwrap());
     let loweraddr = u32::from_le_bytes(from_buf[*curoff + 4..*curoff + 8].try_into().unwrap());
     let rest = u32::from_le_bytes(from_buf[*curoff + 8..*curoff + 12].try_into().unwrap());
 
     let bufflags = rest.get_bits(0..2);
 
+    // The flags decide which memory states may be sent, and the type of the
+    // memory mapped in the receiver.
+    let (ty, state) = match bufflags {
+        0 => (MemoryType::Ipc, MemoryState::IPC_SEND_ALLOWED),
+        1 => (MemoryType::NonSecureIpc, MemoryState::NON_SECURE_IPC_SEND_ALLOWED),
+        3 => (MemoryType::NonDeviceIpc, MemoryState::NON_DEVICE_IPC_SEND_ALLOWED),
+        _ => return Err(UserspaceError::InvalidCombination)
+    };
+    let writable = flags.contains(MappingAccessRights::WRITABLE);
+
     let addr = *(u64::from(loweraddr))
         .set_bits(32..36, u64::from(rest.get_bits(28..32)))
         .set_bits(36..39, u64::from(rest.get_bits(2..5)));
@@ -259,19 +403,18 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
     let addr = addr as usize;
     let size = size as usize;
 
-    let to_addr = if addr == 0 {
+    let to_addr = if addr == 0 || size == 0 {
         // Null pointers shouldn't be mapped.
         0usize
     } else {
-        // TODO: buf_map: Check that from_mem has the right permissions
-        // BODY: buf_map currently remaps without checking the permissions. This
-        // BODY: could allow a user to read non-user-accessible memory, or
-        // BODY: write to non-writable memory.
-        // BODY:
-        // BODY: Whatever mechanism we setup for UserSpacePtr, we should probably
-        // BODY: reuse it here.
-
-        let to_addr_full = to_mem.find_available_space(align_up(size + (addr % PAGE_SIZE), PAGE_SIZE))?;
+        let perms = if writable { MemoryPermissions::RW } else { MemoryPermissions::READABLE };
+        mems.from().check_range(VirtualAddress(addr), size,
+            state, state,
+            perms, perms,
+            MemoryAttributes::empty(), MemoryAttributes::empty(),
+            MemoryAttributes::empty())?;
+
+        let to_addr_full = mems.to().find_available_space(align_up(size + (addr % PAGE_SIZE), PAGE_SIZE))?;
         let to_addr = to_addr_full + (addr % PAGE_SIZE);
 
         let mut first_page_info_opt: Option<(VirtualAddress, usize)> = None;
@@ -302,13 +445,13 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
             // memcpy the first page.
             let first_page_size = core::cmp::min(PAGE_SIZE - (addr % PAGE_SIZE), size);
 
-            let from_mapping = from_mem.mirror_mapping(VirtualAddress(addr), first_page_size)?;
+            let from_mapping = mems.from().mirror_mapping(VirtualAddress(addr), first_page_size)?;
             let from = UserSpacePtr::from_raw_parts(from_mapping.addr().addr() as *const u8, from_mapping.len());
 
-            let res_mapping = to_mem.create_regular_mapping(to_addr_full, PAGE_SIZE, MemoryType::Ipc, MappingAccessRights::u_rw());
+            let res_mapping = mems.to().create_regular_mapping(to_addr_full, PAGE_SIZE, ty, MappingAccessRights::u_rw());
 
             if let Err(error) = res_mapping {
-                return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
+                return mapping_error_handling_logic(mems.to(), error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
             }
 
             first_page_info_opt = Some((to_addr_full, PAGE_SIZE));
@@ -323,14 +466,14 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
             let last_page = (VirtualAddress(addr) + size).floor();
             let last_page_size = (addr + size) % PAGE_SIZE;
 
-            let from_mapping = from_mem.mirror_mapping(last_page, last_page_size)?;
+            let from_mapping = mems.from().mirror_mapping(last_page, last_page_size)?;
             let from = UserSpacePtr::from_raw_parts(from_mapping.addr().addr() as *const u8, from_mapping.len());
 
             let to_last_page = (to_addr + size).floor();
-            let res_mapping = to_mem.create_regular_mapping(to_last_page, PAGE_SIZE, MemoryType::Ipc, MappingAccessRights::u_rw());
+            let res_mapping = mems.to().create_regular_mapping(to_last_page, PAGE_SIZE, ty, MappingAccessRights::u_rw());
 
             if let Err(error) = res_mapping {
-                return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
+                return mapping_error_handling_logic(mems.to(), error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
             }
 
             last_page_info_opt = Some((to_last_page, PAGE_SIZE));
@@ -347,29 +490,40 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
             let addr = align_up(addr, PAGE_SIZE);
             let to_addr = to_addr.ceil();
 
-            let mapping = match from_mem.query_memory(VirtualAddress(addr)) {
-                QueryMemory::Used(mapping) => mapping,
-                QueryMemory::Available(mapping) =>
-                return mapping_error_handling_logic(to_mem, KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() },
-                                                    first_page_info_opt,
-                                                    middle_page_info_opt,
-                                                    last_page_info_opt),
+            // Grab the frames backing the sender's pages. The sender's mapping
+            // must not be borrowed anymore when mapping the frames in the
+            // receiver, as both might be the same process.
+            // Lazily-committed pages must be committed before we can share them.
+            // Copy-on-write pages can't be written to by both processes, they
+            // are only shared with the receiver if it can't write to them.
+            let frames_res = mems.from().commit_range(VirtualAddress(addr), size - size_handled)
+                .and_then(|()| match mems.from().query_memory(VirtualAddress(addr)) {
+                    QueryMemory::Used(mapping) => match mapping.frames() {
+                        MappingFrames::Shared(shared) => Ok(Some((MappingFrames::Shared(shared.clone()), mapping.phys_offset() + (addr - mapping.address().addr())))),
+                        MappingFrames::Lazy(slots) => Ok(Some((MappingFrames::Lazy(slots.clone()), mapping.phys_offset() + (addr - mapping.address().addr())))),
+                        MappingFrames::CopyOnWrite(_) if !writable => Ok(None),
+                        _ => Err(KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() }),
+                    },
+                    QueryMemory::Available(mapping) =>
+                        Err(KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() }),
+                })
+                .and_then(|frames| match frames {
+                    Some(frames) => Ok(frames),
+                    None => mems.from().copy_on_write_pages(VirtualAddress(addr), size - size_handled)
+                        .map(|pages| (MappingFrames::CopyOnWrite(pages), 0)),
+                });
+
+            let (frames, phys_offset) = match frames_res {
+                Ok(frames) => frames,
+                Err(error) => return mapping_error_handling_logic(mems.to(), error, first_page_info_opt, middle_page_info_opt, last_page_info_opt),
             };
 
-            let frames = match mapping.frames() {
-                MappingFrames::Shared(shared) => shared.clone(),
-                _ =>
-                return mapping_error_handling_logic(to_mem, KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() },
-                                                    first_page_info_opt,
-                                                    middle_page_info_opt,
-                                                    last_page_info_opt),
-            };
-
-            let offset = addr - mapping.address().addr();
-
-            let res_mapping = to_mem.map_partial_shared_mapping(frames, to_addr, mapping.phys_offset() + offset, size - size_handled, MemoryType::Ipc, MappingAccessRights::u_rw());
+            // Those pages are shared with the sender: only give write access
+            // if the sender allowed it.
+            let rights = if writable { MappingAccessRights::u_rw() } else { MappingAccessRights::u_r() };
+            let res_mapping = mems.to().map_partial_mapping(frames, to_addr, phys_offset, size - size_handled, ty, rights);
             if let Err(error) = res_mapping {
-                return mapping_error_handling_logic(to_mem, error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
+                return mapping_error_handling_logic(mems.to(), error, first_page_info_opt, middle_page_info_opt, last_page_info_opt);
             }
 
             middle_page_info_opt = Some((to_addr, size - size_handled));
@@ -389,19 +543,23 @@ fn buf_map(from_buf: &[u8], to_buf: &mut [u8], curoff: &mut usize, from_mem: &mu
     (&mut to_buf[*curoff + 4..*curoff + 8]).copy_from_slice(&loweraddr.to_le_bytes()[..]);
     (&mut to_buf[*curoff + 8..*curoff + 12]).copy_from_slice(&rest.to_le_bytes()[..]);
 
-    buffers.push(Buffer {
-        writable: flags.contains(MappingAccessRights::WRITABLE),
-        source_addr: VirtualAddress(addr),
-        dest_addr: VirtualAddress(to_addr),
-        size
-    });
+    if to_addr != 0 {
+        buffers.push(Buffer {
+            writable,
+            source_addr: VirtualAddress(addr),
+            dest_addr: VirtualAddress(to_addr),
+            size
+        });
+    }
 
     *curoff += 12;
     Ok(())
 }
 
 /// Unmap an IPC Buffer from the receiver.
-fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut ProcessMemory) -> Result<(), UserspaceError> {
+///
+/// `mems` goes from the server, where the buffer is mapped, to the client.
+fn buf_unmap(buffer: &Buffer, mems: &mut LockPair<MutexGuard<'_, ProcessMemory>>) -> Result<(), UserspaceError> {
     let addr = buffer.dest_addr;
     let size = buffer.size;
     let to_addr = buffer.source_addr;
@@ -418,7 +576,7 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
             let from = UserSpacePtr::from_raw_parts(addr.addr() as *const u8, first_page_size);
 
             // This needs explicit error handling since the user might unmap `to_addr` in-between sending the request and receiving the response.
-            result = match to_mem.mirror_mapping(to_addr, first_page_size) {
+            result = match mems.to().mirror_mapping(to_addr, first_page_size) {
                 Ok(to_mapping) => {
                     let mut to = UserSpacePtrMut::from_raw_parts_mut(to_mapping.addr().addr() as *mut u8, first_page_size);
                     to.copy_from_slice(&from);
@@ -428,7 +586,7 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
             };
         }
 
-        from_mem.unmap(addr.floor(), PAGE_SIZE).expect("Cannot unmap first unaligned page of buffer");
+        mems.from().unmap(addr.floor(), PAGE_SIZE).expect("Cannot unmap first unaligned page of buffer");
         size_handled += first_page_size;
     }
 
@@ -443,7 +601,7 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
             let to_last_page = (to_addr + size).floor();
 
             // This needs explicit error handling since the user might unmap `to_addr` in-between sending the request and receiving the response.
-            result = match to_mem.mirror_mapping(to_last_page, last_page_size) {
+            result = match mems.to().mirror_mapping(to_last_page, last_page_size) {
                 Ok(to_mapping) => {
                     let mut to = UserSpacePtrMut::from_raw_parts_mut(to_mapping.addr().addr() as *mut u8, last_page_size);
                     to.copy_from_slice(&from);
@@ -454,19 +612,31 @@ fn buf_unmap(buffer: &Buffer, from_mem: &mut ProcessMemory, to_mem: &mut Process
 
         }
 
-        from_mem.unmap((addr + size).floor(), PAGE_SIZE).expect("Cannot unmap last unaligned page of buffer");
+        mems.from().unmap((addr + size).floor(), PAGE_SIZE).expect("Cannot unmap last unaligned page of buffer");
         size_handled += last_page_size;
     }
 
     assert!((size - size_handled) % PAGE_SIZE == 0, "Remaining size should be a multiple of PAGE_SIZE");
-    if size < size_handled {
-        from_mem.unmap(addr.ceil(), size - size_handled).expect("Cannot unmap buffer");
+    if size > size_handled {
+        mems.from().unmap(addr.ceil(), size - size_handled).expect("Cannot unmap buffer");
     }
 
     result
 }
 
 impl ClientSession {
+    /// Gets an identifier of this session, shared by its client and server
+    /// sides. Used to match both sides of a session when dumping handle tables.
+    pub fn id(&self) -> usize {
+        &*self.0 as *const Session as usize
+    }
+
+    /// Gets the identifier of the port this session was created from, if any.
+    /// See [ClientPort::id].
+    pub fn port_id(&self) -> Option<usize> {
+        self.0.port.as_ref().map(ClientPort::id)
+    }
+
     /// Send an IPC request through the client pipe. Takes a userspace buffer
     /// containing the packed IPC request. When returning, the buffer will
     /// contain the IPC answer (unless an error occured).
@@ -516,61 +686,128 @@ impl ClientSession {
     }
 }
 
-/// Efficiently finds C Descriptor in a message.
-fn find_c_descriptors(buf: &mut [u8]) -> Result<CBufBehavior, KernelError> {
-    let mut curoff = 0;
+/// Reads a little-endian u32 at the given offset of an IPC message.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The offset is past the end of the buffer.
+fn read_u32(buf: &[u8], off: usize) -> Result<u32, KernelError> {
+    buf.get(off..off + 4)
+        .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+        .ok_or_else(|| KernelError::InvalidSize { size: buf.len(), backtrace: Backtrace::new() })
+}
 
-    let hdr = MsgPackedHdr(u64::from_le_bytes(buf[curoff..curoff + 8].try_into().unwrap()));
-    curoff += 8;
+/// Returns the number of C descriptors present in a message, given its C
+/// descriptor flags.
+fn c_descriptor_count(c_descriptor_flags: u8) -> usize {
+    match c_descriptor_flags {
+        0 | 1 => 0,
+        2 => 1,
+        x => usize::from(x - 2)
+    }
+}
+
+/// Computes the size of the message at the start of the given buffer, up to
+/// and including its C descriptors.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The message doesn't fit in the buffer.
+fn message_size(buf: &[u8]) -> Result<usize, KernelError> {
+    let hdr = MsgPackedHdr(u64::from(read_u32(buf, 0)?) | u64::from(read_u32(buf, 4)?) << 32);
+    let mut size = 8;
+
+    if hdr.enable_handle_descriptor() {
+        let descriptor = HandleDescriptorHeader(read_u32(buf, size)?);
+        size += 4;
+        if descriptor.send_pid() {
+            size += 8;
+        }
+        size += 4 * usize::from(descriptor.num_copy_handles() + descriptor.num_move_handles());
+    }
+
+    size += 8 * usize::from(hdr.num_x_descriptors());
+    size += 12 * usize::from(hdr.num_a_descriptors() + hdr.num_b_descriptors() + hdr.num_w_descriptors());
+    size += 4 * usize::from(hdr.raw_section_size());
+    size += 8 * c_descriptor_count(hdr.c_descriptor_flags());
+
+    if size > buf.len() {
+        return Err(KernelError::InvalidSize { size, backtrace: Backtrace::new() });
+    }
+
+    Ok(size)
+}
+
+/// Efficiently finds C Descriptor in a message.
+///
+/// `buf_addr` is the address of the message buffer in the address space of the
+/// process owning it. It is used to compute the address of inlined C Buffers.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The message doesn't fit in the buffer.
+fn find_c_descriptors(buf: &[u8], buf_addr: VirtualAddress) -> Result<CBufBehavior, KernelError> {
+    let size = message_size(buf)?;
+    let hdr = MsgPackedHdr(u64::from(read_u32(buf, 0)?) | u64::from(read_u32(buf, 4)?) << 32);
 
     let cflag = hdr.c_descriptor_flags();
 
     match cflag {
         0 => return Ok(CBufBehavior::Disabled),
-        1 => return Ok(CBufBehavior::Inlined),
+        1 => return Ok(CBufBehavior::Inlined(buf_addr)),
         _ => ()
     }
 
-    // Go grab C descriptors
+    // C descriptors are at the very end of the message.
+    let mut curoff = size - 8 * c_descriptor_count(cflag);
 
-    if hdr.enable_handle_descriptor() {
-        let descriptor = HandleDescriptorHeader(u32::from_le_bytes(buf[curoff..curoff + 4].try_into().unwrap()));
-        curoff += 4;
-        if descriptor.send_pid() {
-            curoff += 8;
-        }
-        curoff += 4 * usize::from(descriptor.num_copy_handles() + descriptor.num_move_handles());
-    }
-
-    curoff += 8 * usize::from(hdr.num_x_descriptors());
-    curoff += 12 * usize::from(hdr.num_a_descriptors() + hdr.num_b_descriptors() + hdr.num_w_descriptors());
-    curoff += 4 * usize::from(hdr.raw_section_size());
-
-
-    match hdr.c_descriptor_flags() {
-        0 | 1 => unreachable!(),
-        2 => {
-            let word1 = u32::from_le_bytes(buf[curoff..curoff + 4].try_into().unwrap());
-            let word2 = u32::from_le_bytes(buf[curoff + 4..curoff + 8].try_into().unwrap());
-            let addr = *u64::from(word1).set_bits(32..48, u64::from(word2.get_bits(0..16)));
-            let size = u64::from(word2.get_bits(16..32));
-            Ok(CBufBehavior::Single(addr, size))
-        },
-        x => {
-            let mut bufs = [(0, 0); 13];
-            for i in 0..x - 2 {
-                let word1 = u32::from_le_bytes(buf[curoff..curoff + 4].try_into().unwrap());
-                let word2 = u32::from_le_bytes(buf[curoff + 4..curoff + 8].try_into().unwrap());
-                let addr = *u64::from(word1).set_bits(32..48, u64::from(word2.get_bits(0..16)));
-                let size = u64::from(word2.get_bits(16..32));
-                bufs[i as usize] = (addr, size);
-            }
-            Ok(CBufBehavior::Numbered(bufs, (x - 2) as usize))
-        }
+    let mut bufs = [(0, 0); 13];
+    for buf_info in bufs.iter_mut().take(c_descriptor_count(cflag)) {
+        let word1 = read_u32(buf, curoff)?;
+        let word2 = read_u32(buf, curoff + 4)?;
+        let addr = *u64::from(word1).set_bits(32..48, u64::from(word2.get_bits(0..16)));
+        let buf_size = u64::from(word2.get_bits(16..32));
+        *buf_info = (addr, buf_size);
+        curoff += 8;
     }
+
+    if cflag == 2 {
+        Ok(CBufBehavior::Single(bufs[0].0, bufs[0].1))
+    } else {
+        Ok(CBufBehavior::Numbered(bufs, c_descriptor_count(cflag)))
+    }
+}
+
+/// Unmaps all the A/B/W Buffers of a request from the server's address space,
+/// copying the content of the writable ones back to the client.
+///
+/// `mems` goes from the server to the client. All the buffers are unmapped,
+/// even if an error occurs. The first error is returned.
+fn unmap_buffers(buffers: &mut Vec<Buffer>, mems: &mut LockPair<MutexGuard<'_, ProcessMemory>>) -> Result<(), UserspaceError> {
+    let mut result = Ok(());
+    for buffer in buffers.drain(..) {
+        let res = buf_unmap(&buffer, mems);
+        result = result.and(res);
+    }
+    result
 }
 
 impl ServerSession {
+    /// Gets an identifier of this session, shared by its client and server
+    /// sides. See [ClientSession::id].
+    pub fn id(&self) -> usize {
+        &*self.0 as *const Session as usize
+    }
+
+    /// Gets the identifier of the port this session was created from, if any.
+    /// See [ClientPort::id].
+    pub fn port_id(&self) -> Option<usize> {
+        self.0.port.as_ref().map(ClientPort::id)
+    }
+
     /// Receive an IPC request through the server pipe. Takes a userspace buffer
     /// containing an empty IPC message. The request may optionally contain a
     /// C descriptor in order to receive X descriptors. The buffer will be filled
@@ -578,6 +815,9 @@ impl ServerSession {
     ///
     /// This function does **not** wait. It assumes an active_request has already
     /// been set by a prior call to wait.
+    ///
+    /// If the request cannot be passed to the server, the client is answered
+    /// with the error, and the request is dropped.
     pub fn receive(&self, mut buf: UserSpacePtrMut<[u8]>, has_c_descriptors: bool) -> Result<(), UserspaceError> {
         // Read active session
         let mut internal = self.0.internal.lock();
@@ -587,22 +827,44 @@ impl ServerSession {
         let active = internal.active_request.as_mut().unwrap();
 
         let sender = active.sender.process.clone();
-        let memlock = sender.pmemory.lock();
+        let current_proc = scheduler::get_current_process();
 
-        let mapping = memlock.mirror_mapping(active.sender_buf, active.sender_bufsize)?;
-        let sender_buf = unsafe {
-            slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
-        };
+        // We can't commit the pages of buf once we hold the lock on our memory.
+        let committed = current_proc.pmemory.lock().unshare_range(VirtualAddress(buf.as_ptr() as usize), buf.len());
+        let mut mems = LockPair::lock(&*sender, &*current_proc, |process| process.pmemory.lock());
 
-        let c_bufs = if has_c_descriptors {
-            find_c_descriptors(&mut *buf)?
+        let c_bufs = committed.and_then(|()| if has_c_descriptors {
+            find_c_descriptors(&*buf, VirtualAddress(buf.as_ptr() as usize))
         } else {
-            CBufBehavior::Disabled
-        };
+            Ok(CBufBehavior::Disabled)
+        });
+
+        let res = c_bufs.and_then(|c_bufs| mems.from().mirror_mapping(active.sender_buf, active.sender_bufsize)
+            .map(|mapping| (c_bufs, mapping)))
+            .map_err(Into::into)
+            .and_then(|(c_bufs, mapping)| {
+                let sender_buf = unsafe {
+                    slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
+                };
+                pass_message(sender_buf, active.sender.clone(), &mut *buf, scheduler::get_current_thread(), false, &mut mems, &mut active.buffers, c_bufs)
+            });
+
+        if let Err(err) = res {
+            // The buffers that got mapped before the error are left untouched:
+            // don't copy anything back.
+            for buffer in active.buffers.iter_mut() {
+                buffer.writable = false;
+            }
+            mems.reverse();
+            let _ = unmap_buffers(&mut active.buffers, &mut mems);
+            drop(mems);
 
-        pass_message(sender_buf, active.sender.clone(), &mut *buf, scheduler::get_current_thread(), false, memlock, &mut active.buffers, c_bufs)?;
+            let active = internal.active_request.take().unwrap();
+            *active.answered.lock() = Some(Err(err));
+            scheduler::add_to_schedule_queue(active.sender.clone());
+        }
 
-        Ok(())
+        res
     }
 
     /// Replies to the currently active IPC request on the server pipe. Takes a
@@ -610,6 +872,13 @@ impl ServerSession {
     /// to the sender's IPC buffer, before waking the sender so it may return to
     /// userspace.
     ///
+    /// X descriptors of the reply are copied to the C Buffers the client
+    /// specified in its request. The A/B/W buffers of the request are unmapped
+    /// from the server.
+    ///
+    /// If the reply cannot be passed to the client, both the client and the
+    /// server get the error.
+    ///
     /// # Panics
     ///
     /// Panics if there is no currently active request on the pipe.
@@ -625,36 +894,56 @@ impl ServerSession {
         let mut active = self.0.internal.lock().active_request.take().unwrap();
 
         let sender = active.sender.process.clone();
+        let current_proc = scheduler::get_current_process();
 
-        let memlock = sender.pmemory.lock();
+        // We can't commit the pages of buf once we hold the lock on our memory.
+        let committed = current_proc.pmemory.lock().commit_range(VirtualAddress(buf.as_ptr() as usize), buf.len());
+        let mut mems = LockPair::lock(&*current_proc, &*sender, |process| process.pmemory.lock());
 
-        let mapping = memlock.mirror_mapping(active.sender_buf, active.sender_bufsize)?;
-        let sender_buf = unsafe {
-            slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
-        };
+        let res = committed.and_then(|()| mems.to().mirror_mapping(active.sender_buf, active.sender_bufsize))
+            .and_then(|mapping| {
+                let sender_buf = unsafe {
+                    slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
+                };
+                // The C descriptors of the request tell us where to copy the
+                // X descriptors of the reply. Find them before overwriting the
+                // request.
+                find_c_descriptors(sender_buf, active.sender_buf)
+                    .map(|c_bufs| (sender_buf, c_bufs))
+            })
+            .map_err(Into::into)
+            .and_then(|(sender_buf, c_bufs)| {
+                pass_message(&*buf, scheduler::get_current_thread(), sender_buf, active.sender.clone(), true, &mut mems, &mut active.buffers, c_bufs)
+            });
 
-        pass_message(&*buf, scheduler::get_current_thread(), sender_buf, active.sender.clone(), true, memlock, &mut active.buffers, CBufBehavior::Disabled)?;
+        // Always give the buffers back to the client.
+        let res = res.and(unmap_buffers(&mut active.buffers, &mut mems));
+        drop(mems);
 
-        *active.answered.lock() = Some(Ok(()));
+        *active.answered.lock() = Some(res);
 
         scheduler::add_to_schedule_queue(active.sender.clone());
 
-        Ok(())
+        res
     }
 }
 
 /// Defines how to handle X Buffer descriptors based on the C Buffer flags.
 #[allow(clippy::large_enum_variant)] // Expected.
 enum CBufBehavior {
-    /// No C Buffers are available. Presence of X Buffers should cause an error.
+    /// No C Buffers are available. Presence of X Buffers should cause an
+    /// OutOfResource error.
     Disabled,
-    /// X Buffers should be copied after the Raw Data.
-    Inlined,
+    /// X Buffers should be copied sequentially in the receiver's message
+    /// buffer, right after the message. The given address is the address of
+    /// the message buffer in the receiver's address space.
+    Inlined(VirtualAddress),
     /// X Buffers should be copied sequentially to the C Buffer represented by
-    /// the given address/size pair.
+    /// the given address/size pair. Each X Buffer starts 16-byte aligned.
     Single(u64, u64),
-    /// X Buffers should be copied to the appropriate C Buffer represented y
-    /// the given address/size pair, based on the counter.
+    /// X Buffers should be copied to the appropriate C Buffer represented by
+    /// the given address/size pair, based on the counter. Only the first `usize`
+    /// entries are valid.
     Numbered([(u64, u64); 13], usize)
 }
 
@@ -667,20 +956,44 @@ enum CBufBehavior {
 /// - Copy/Move handles are added to the receiver's Handle Table, and removed
 ///   from the sender's Handle Table when appropriate. The handle numbers are
 ///   rewritten to the receiver's.
+/// - X Buffers are copied to the receiver's C Buffers, as described by
+///   `c_bufs`, and their address is rewritten to the C Buffer's.
 /// - Buffers are appropriately mapped through the [buf_map] function, and the
 ///   address are rewritten to in the receiver's address space.
 ///
+/// `mems` holds the memory of the sender and of the receiver. They may be the
+/// same process.
+///
 /// This function should always be called from the context of the receiver/
 /// server.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The message doesn't fit in the sender's or the receiver's buffer.
+/// - `InvalidCombination`
+///   - A reply contains A/B/W Buffers.
+/// - `OutOfResource`
+///   - An X Buffer doesn't fit in the receiver's C Buffers, or the receiver
+///     has no C Buffers.
+/// - `InvalidMemState`
+///   - An X Buffer isn't readable by the sender, or a C Buffer isn't writable
+///     by the receiver.
 #[allow(unused, clippy::too_many_arguments)]
-fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8], to_proc: Arc<ThreadStruct>, is_reply: bool, mut other_memlock: MutexGuard<ProcessMemory>, buffers: &mut Vec<Buffer>, c_bufs: CBufBehavior) -> Result<(), UserspaceError> {
-    // TODO: pass_message deadlocks when sending message to the same process.
-    // BODY: If from_proc and to_proc are the same process, pass_message will
-    // BODY: deadlock trying to acquire the locks to the handle table or the
-    // BODY: page tables.
+fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8], to_proc: Arc<ThreadStruct>, is_reply: bool, mems: &mut LockPair<MutexGuard<'_, ProcessMemory>>, buffers: &mut Vec<Buffer>, c_bufs: CBufBehavior) -> Result<(), UserspaceError> {
+    let msg_size = message_size(from_buf)?;
+    if msg_size > to_buf.len() {
+        return Err(UserspaceError::InvalidSize);
+    }
 
     let mut curoff = 0;
     let hdr = MsgPackedHdr(u64::from_le_bytes(from_buf[curoff..curoff + 8].try_into().unwrap()));
+
+    if is_reply && (hdr.num_a_descriptors() != 0 || hdr.num_b_descriptors() != 0 || hdr.num_w_descriptors() != 0) {
+        // A/B/W buffers can only be sent in requests.
+        return Err(UserspaceError::InvalidCombination);
+    }
+
     (&mut to_buf[curoff..curoff + 8]).copy_from_slice(&hdr.0.to_le_bytes()[..]);
 
     curoff += 8;
@@ -701,26 +1014,27 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
     }
 
     if descriptor.num_copy_handles() != 0 || descriptor.num_move_handles() != 0 {
-        let mut from_handle_table = from_proc.process.phandles.lock();
-        let mut to_handle_table = to_proc.process.phandles.lock();
+        let mut handle_tables = LockPair::lock(&*from_proc.process, &*to_proc.process, |process| process.phandles.lock());
 
         for i in 0..descriptor.num_copy_handles() {
             let handle = u32::from_le_bytes(from_buf[curoff..curoff + 4].try_into().unwrap());
-            let handle = from_handle_table.get_handle(handle)?;
-            let handle = to_handle_table.add_handle(handle);
+            // Pseudo-handles designate the sender, not the receiver we run on.
+            let handle = handle_tables.from().get_handle_as(handle, &from_proc)?;
+            let handle = handle_tables.to().add_handle(handle);
             (&mut to_buf[curoff..curoff + 4]).copy_from_slice(&handle.to_le_bytes()[..]);
             curoff += 4;
         }
         for i in 0..descriptor.num_move_handles() {
             let handle = u32::from_le_bytes(from_buf[curoff..curoff + 4].try_into().unwrap());
-            let handle = from_handle_table.delete_handle(handle)?;
-            let handle = to_handle_table.add_handle(handle);
+            let handle = handle_tables.from().delete_handle(handle)?;
+            let handle = handle_tables.to().add_handle(handle);
             (&mut to_buf[curoff..curoff + 4]).copy_from_slice(&handle.to_le_bytes()[..]);
             curoff += 4;
         }
     }
 
     {
+        // Offset of the next X Buffer in the inlined or single C Buffer.
         let mut coff = 0;
         for i in 0..hdr.num_x_descriptors() {
             let word1 = u32::from_le_bytes(from_buf[curoff..curoff + 4].try_into().unwrap());
@@ -731,59 +1045,81 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
                 .set_bits(36..39, u64::from(word1.get_bits(6..9)));
             let from_size = u64::from(word1.get_bits(16..32));
 
-            let (to_addr, to_size) = match c_bufs {
-                CBufBehavior::Disabled => return Err(UserspaceError::PortRemoteDead),
-                CBufBehavior::Inlined => unimplemented!(),
+            // Check addresses fit in 32-bit kernel.
+            check_lower_than_usize(from_addr, UserspaceError::InvalidAddress)?;
+            check_lower_than_usize(from_addr.saturating_add(from_size), UserspaceError::InvalidAddress)?;
+
+            let from_addr = from_addr as usize;
+            let from_size = from_size as usize;
+
+            // Find the destination of the X Buffer: an address in the
+            // receiver's address space, along with the offset in to_buf if the
+            // C Buffer is inlined.
+            let (to_addr, inline_off) = match c_bufs {
+                CBufBehavior::Disabled => return Err(UserspaceError::OutOfResource),
+                CBufBehavior::Inlined(buf_addr) => {
+                    let off = align_up(msg_size, 16) + coff;
+                    if to_buf.len().checked_sub(off).map_or(true, |left| from_size > left) {
+                        return Err(UserspaceError::OutOfResource);
+                    }
+                    coff = align_up(coff + from_size, 16);
+                    ((buf_addr + off).addr() as u64, Some(off))
+                },
                 CBufBehavior::Single(addr, size) => {
-                    (addr + coff, size - coff)
+                    if from_size as u64 > size.saturating_sub(coff as u64) {
+                        return Err(UserspaceError::OutOfResource);
+                    }
+                    let to_addr = addr + coff as u64;
+                    coff = align_up(coff + from_size, 16);
+                    (to_addr, None)
                 },
                 CBufBehavior::Numbered(bufs, count) => {
-                    // TODO: IPC Type-X: Prevent multiple writes to a C-buffer?
-                    // BODY: Do I need to prevent multiple writes to the same
-                    // BODY: buffer ID? In theory, I could use coff as a bitmap
-                    // BODY: of used buffers, and prevent reuse this way, but I'm
-                    // BODY: unsure of how the nintendo switch behaves here.
-                    let (addr, size) = bufs[..count][counter as usize];
-                    (addr, size)
+                    // Nothing prevents multiple X Buffers from targeting the
+                    // same C Buffer. Like on the switch, the last one wins.
+                    let (addr, size) = *bufs[..count].get(counter as usize)
+                        .ok_or(UserspaceError::OutOfResource)?;
+                    if from_size as u64 > size {
+                        return Err(UserspaceError::OutOfResource);
+                    }
+                    (addr, None)
                 }
             };
 
-            // Check addresses fit in 32-bit kernel.
-            check_lower_than_usize(from_addr, UserspaceError::InvalidAddress)?;
-            check_lower_than_usize(from_size, UserspaceError::InvalidAddress)?;
-            check_lower_than_usize(from_addr.saturating_add(from_size), UserspaceError::InvalidAddress)?;
             check_lower_than_usize(to_addr, UserspaceError::InvalidAddress)?;
-            check_lower_than_usize(to_size, UserspaceError::InvalidAddress)?;
-            check_lower_than_usize(to_addr.saturating_add(to_size), UserspaceError::InvalidAddress)?;
-
-            let (mapping, mut uspaceptr) = if !is_reply {
-                // We're receiving: C Buffers are in our address space, X buffers
-                // are in the other address space
-                let mapping = other_memlock.mirror_mapping(VirtualAddress(from_addr as usize), from_size as usize)?;
-                let uspaceptr = UserSpacePtrMut::from_raw_parts_mut(to_addr as *mut u8, to_size as usize);
-                (mapping, uspaceptr)
-            } else {
-                // We're receiving: C Buffers are in our address space, X buffers
-                // are in the other address space
-                let mapping = other_memlock.mirror_mapping(VirtualAddress(to_addr as usize), to_size as usize)?;
-                let uspaceptr = UserSpacePtrMut::from_raw_parts_mut(from_addr as *mut u8, from_size as usize);
-                (mapping, uspaceptr)
-            };
-
-            let (from, to) = {
-                let ref_mapping = unsafe {
-                    slice::from_raw_parts_mut(mapping.addr().addr() as *mut u8, mapping.len())
+            check_lower_than_usize(to_addr.saturating_add(from_size as u64), UserspaceError::InvalidAddress)?;
+
+            if from_size != 0 {
+                // X Buffers live in the sender, C Buffers in the receiver.
+                let x_mem = mems.from();
+                x_mem.check_range(VirtualAddress(from_addr), from_size,
+                    MemoryState::empty(), MemoryState::empty(),
+                    MemoryPermissions::READABLE, MemoryPermissions::READABLE,
+                    MemoryAttributes::empty(), MemoryAttributes::empty(),
+                    MemoryAttributes::empty())?;
+
+                let x_mapping = x_mem.mirror_mapping(VirtualAddress(from_addr), from_size)?;
+                let from = unsafe {
+                    slice::from_raw_parts(x_mapping.addr().addr() as *const u8, x_mapping.len())
                 };
-                let ref_uspace = &mut *uspaceptr;
-                if !is_reply {
-                    (ref_mapping, ref_uspace)
+
+                if let Some(off) = inline_off {
+                    to_buf[off..off + from_size].copy_from_slice(from);
                 } else {
-                    (ref_uspace, ref_mapping)
+                    let to_addr = VirtualAddress(to_addr as usize);
+                    let c_mem = mems.to();
+                    c_mem.check_range(to_addr, from_size,
+                        MemoryState::empty(), MemoryState::empty(),
+                        MemoryPermissions::RW, MemoryPermissions::RW,
+                        MemoryAttributes::empty(), MemoryAttributes::empty(),
+                        MemoryAttributes::empty())?;
+
+                    let c_mapping = c_mem.mirror_mapping(to_addr, from_size)?;
+                    let to = unsafe {
+                        slice::from_raw_parts_mut(c_mapping.addr().addr() as *mut u8, c_mapping.len())
+                    };
+                    to.copy_from_slice(from);
                 }
-            };
-
-            to[..from.len()].copy_from_slice(from);
-            coff += from.len() as u64;
+            }
 
             let mut counter = counter;
             let counter = *counter
@@ -797,52 +1133,133 @@ fn pass_message(from_buf: &[u8], from_proc: Arc<ThreadStruct>, to_buf: &mut [u8]
         }
     }
 
-    if hdr.num_a_descriptors() != 0 || hdr.num_b_descriptors() != 0 {
-        if is_reply {
-            // TODO: Error to return when replying with A/B/W descriptors
-            // BODY: We currently reply with a broken ass error when replying
-            // BODY: with A/B/W descriptors (which is illegal).
-            return Err(UserspaceError::PortRemoteDead)
-        }
+    // Replies were checked not to contain any A/B/W buffers earlier.
+    for i in 0..hdr.num_a_descriptors() {
+        buf_map(from_buf, to_buf, &mut curoff, mems, MappingAccessRights::empty(), buffers)?;
+    }
 
-        let mut current_memlock = to_proc.process.pmemory.lock();
+    for i in 0..hdr.num_b_descriptors() {
+        buf_map(from_buf, to_buf, &mut curoff, mems, MappingAccessRights::WRITABLE, buffers)?;
+    }
 
-        let (mut from_mem, mut to_mem) = (&mut *other_memlock, &mut *current_memlock);
+    for i in 0..hdr.num_w_descriptors() {
+        buf_map(from_buf, to_buf, &mut curoff, mems, MappingAccessRights::WRITABLE, buffers)?;
+    }
 
-        for i in 0..hdr.num_a_descriptors() {
-            buf_map(from_buf, to_buf, &mut curoff, &mut *from_mem, &mut *to_mem, MappingAccessRights::empty(), buffers)?;
-        }
+    // Copy the raw data, followed by the C descriptors. The C descriptors of a
+    // request let the server know how much data it may send back in X Buffers.
+    (&mut to_buf[curoff..msg_size]).copy_from_slice(&from_buf[curoff..msg_size]);
 
-        for i in 0..hdr.num_b_descriptors() {
-            buf_map(from_buf, to_buf, &mut curoff, &mut *from_mem, &mut *to_mem, MappingAccessRights::WRITABLE, buffers)?;
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    //! Tests running on the memory of the current process, in kernel context.
+
+    use super::*;
+
+    /// Encodes an A/B/W buffer descriptor of `size` bytes at `addr`, with the given buffer flags.
+    fn descriptor(addr: usize, size: usize, bufflags: u32) -> [u8; 12] {
+        let mut desc = [0; 12];
+        desc[0..4].copy_from_slice(&(size as u32).to_le_bytes());
+        desc[4..8].copy_from_slice(&(addr as u32).to_le_bytes());
+        desc[8..12].copy_from_slice(&bufflags.to_le_bytes());
+        desc
+    }
+
+    /// Maps a page of the given type, and sends it to ourselves as a buffer with the given
+    /// flags. Returns whether the buffer was accepted.
+    fn send_buffer(ty: MemoryType, bufflags: u32) -> bool {
+        let process = scheduler::get_current_process();
+        let mut mems = LockPair::Same(process.pmemory.lock());
+
+        let addr = mems.from().find_available_space(PAGE_SIZE).unwrap();
+        mems.from().create_regular_mapping(addr, PAGE_SIZE, ty, MappingAccessRights::u_rw()).unwrap();
+
+        let desc = descriptor(addr.addr(), PAGE_SIZE, bufflags);
+        let mut to_buf = [0; 12];
+        let mut curoff = 0;
+        let mut buffers = Vec::new();
+        let res = buf_map(&desc, &mut to_buf, &mut curoff, &mut mems, MappingAccessRights::u_r(), &mut buffers);
+
+        for buffer in &buffers {
+            buf_unmap(buffer, &mut mems).unwrap();
         }
+        mems.from().unmap(addr, PAGE_SIZE).unwrap();
 
-        for i in 0..hdr.num_w_descriptors() {
-            buf_map(from_buf, to_buf, &mut curoff, &mut *from_mem, &mut *to_mem, MappingAccessRights::WRITABLE, buffers)?;
+        if res.is_ok() {
+            assert_eq!(buffers.len(), 1, "an accepted buffer must be mapped in the receiver");
         }
+        res.is_ok()
     }
 
-    if is_reply && !buffers.is_empty() {
-        let (mut from_mem, mut to_mem) = (from_proc.process.pmemory.lock(), other_memlock);
+    /// Heap memory may be sent with every buffer flag.
+    #[test_case]
+    fn buffer_flags_heap() {
+        assert!(send_buffer(MemoryType::Heap, 0));
+        assert!(send_buffer(MemoryType::Heap, 1));
+        assert!(send_buffer(MemoryType::Heap, 3));
+    }
 
-        // Unmap A-B-W buffers
-        for buffer in buffers {
-            buf_unmap(buffer, &mut *from_mem, &mut *to_mem)?;
-        }
+    /// Alias memory only allows non-secure IPC: flag 1 is accepted, 0 and 3 are refused.
+    #[test_case]
+    fn buffer_flags_alias() {
+        assert!(!send_buffer(MemoryType::Alias, 0));
+        assert!(send_buffer(MemoryType::Alias, 1));
+        assert!(!send_buffer(MemoryType::Alias, 3));
     }
 
-    (&mut to_buf[curoff..curoff + (hdr.raw_section_size() as usize) * 4])
-        .copy_from_slice(&from_buf[curoff..curoff + (hdr.raw_section_size() as usize) * 4]);
+    /// Flag 2 is not a valid buffer flag.
+    #[test_case]
+    fn buffer_flags_invalid() {
+        assert!(!send_buffer(MemoryType::Heap, 2));
+    }
+
+    /// A CurrentProcess pseudo-handle copied over IPC designates the sender,
+    /// not the receiver that passes the message.
+    #[test_case]
+    fn copy_current_process_pseudo_handle() {
+        use crate::process::{ProcessStruct, Handle};
+        use sunrise_libkern::CURRENT_PROCESS_PSEUDO_HANDLE;
+        use sunrise_libkern::process::{ProcInfo, ProcInfoFlags, ProcessCategory};
+
+        let procinfo = ProcInfo {
+            name: *b"pseudohandle",
+            process_category: ProcessCategory::RegularTitle,
+            title_id: 0,
+            code_addr: 0x400000,
+            code_num_pages: 0,
+            flags: ProcInfoFlags(0),
+            resource_limit_handle: None,
+            system_resource_num_pages: 0
+        };
+        let sender_proc = ProcessStruct::new(&procinfo, None).unwrap();
+        let sender = ThreadStruct::new(&sender_proc, VirtualAddress(0x400000), VirtualAddress(0), Some(0))
+            .unwrap().upgrade().unwrap();
+        let receiver = scheduler::get_current_thread();
+
+        let mut hdr = MsgPackedHdr(0);
+        hdr.set_enable_handle_descriptor(true);
+        let mut descriptor = HandleDescriptorHeader(0);
+        descriptor.set_num_copy_handles(1);
+        let mut from_buf = [0; 16];
+        from_buf[0..8].copy_from_slice(&hdr.0.to_le_bytes());
+        from_buf[8..12].copy_from_slice(&descriptor.0.to_le_bytes());
+        from_buf[12..16].copy_from_slice(&CURRENT_PROCESS_PSEUDO_HANDLE.to_le_bytes());
+        let mut to_buf = [0; 16];
+
+        {
+            let mut mems = LockPair::lock(&*sender.process, &*receiver.process, |process| process.pmemory.lock());
+            let mut buffers = Vec::new();
+            pass_message(&from_buf, sender.clone(), &mut to_buf, receiver.clone(), false, &mut mems, &mut buffers, CBufBehavior::Disabled).unwrap();
+        }
 
-    if hdr.c_descriptor_flags() == 1 {
-        unimplemented!("Inline C Descriptor");
-    } else if hdr.c_descriptor_flags() == 2 {
-        unimplemented!("Single C Descriptor");
-    } else if hdr.c_descriptor_flags() != 0 {
-        unimplemented!("Multi C Descriptor");
-        for i in 0..hdr.c_descriptor_flags() - 2 {
+        let handle = u32::from_le_bytes(to_buf[12..16].try_into().unwrap());
+        let handle = receiver.process.phandles.lock().delete_handle(handle).unwrap();
+        match &*handle {
+            Handle::Process(process) => assert!(Arc::ptr_eq(process, &sender_proc), "pseudo-handle resolved to the receiver"),
+            _ => panic!("pseudo-handle resolved to something else than a process"),
         }
     }
-
-    Ok(())
 }
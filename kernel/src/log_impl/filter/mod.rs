@@ -280,6 +280,29 @@ impl fmt::Debug for Filter {
     }
 }
 
+/// Writes the directives of this filter back in the syntax accepted by
+/// [`Builder::parse`], e.g. `info,sunrise_kernel::ipc=trace/foo`.
+///
+/// [`Builder::parse`]: struct.Builder.html#method.parse
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, directive) in self.directives.iter().enumerate() {
+            if idx != 0 {
+                f.write_str(",")?;
+            }
+            let level = directive.level.to_string().to_lowercase();
+            match directive.name {
+                Some(ref name) => write!(f, "{}={}", name, level)?,
+                None => f.write_str(&level)?,
+            }
+        }
+        if let Some(filter) = self.filter.as_ref() {
+            write!(f, "/{}", filter)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Debug for Builder {
     fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result {
         f.debug_struct("Filter")
@@ -369,7 +392,7 @@ fn enabled(directives: &[Directive], level: Level, target: &str) -> bool {
     false
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod tests {
     use log::{Level, LevelFilter};
     use alloc::vec::Vec;
@@ -383,6 +406,15 @@ mod tests {
         logger
     }
 
+    #[test]
+    fn display_roundtrip() {
+        let spec = "warn,crate2=trace,crate1::mod1=error/abc";
+        let filter = Builder::new().parse(spec).build();
+        assert_eq!(filter.to_string(), spec);
+        let reparsed = Builder::new().parse(&filter.to_string()).build();
+        assert_eq!(reparsed.to_string(), spec);
+    }
+
     #[test]
     fn filter_info() {
         let logger = Builder::new().filter(None, LevelFilter::Info).build();
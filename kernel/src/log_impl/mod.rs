@@ -1,6 +1,7 @@
 //! A simple log implementation based on env_logger
 #![allow(clippy::missing_docs_in_private_items)]
 mod filter;
+pub mod ring_buffer;
 
 use log::{self, Log, Metadata, Record, LevelFilter};
 use crate::devices::rs232::SerialLogger;
@@ -8,6 +9,7 @@ use core::fmt::Write;
 use crate::i386::multiboot::get_boot_information;
 use crate::sync::{SpinRwLock, Once};
 use crate::scheduler;
+use alloc::string::{String, ToString};
 
 struct Logger {
     filter: SpinRwLock<filter::Filter>
@@ -30,10 +32,11 @@ impl Log for Logger {
         });
         if self.filter.read().matches(record) {
             if let Some(thread) = scheduler::try_get_current_thread() {
-                writeln!(SerialLogger, "[{}{}{}] - {} - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), thread.process.name, record.args());
+                writeln!(SerialLogger, "[{}{}{}] - {} - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), thread, record.args());
             } else {
                 writeln!(SerialLogger, "[{}{}{}] - {} - {}", color, record.level(), SerialAttributes::default(), record.target(), record.args());
             }
+            ring_buffer::push(record);
         }
     }
 
@@ -60,3 +63,17 @@ pub fn init() {
     let newfilter = filter::Builder::new().parse(cmdline).build();
     *logger.filter.write() = newfilter;
 }
+
+/// Replaces the active filter with the given env_logger-style directives, e.g.
+/// `info,sunrise_kernel::ipc=trace`. Invalid directives are ignored.
+pub fn set_filter(directives: &str) {
+    let logger = LOGGER.r#try().expect("early_init to be called before set_filter");
+    let newfilter = filter::Builder::new().parse(directives).build();
+    *logger.filter.write() = newfilter;
+}
+
+/// Gets the directives of the active filter, in the syntax accepted by [set_filter].
+pub fn filter_directives() -> String {
+    let logger = LOGGER.r#try().expect("early_init to be called before filter_directives");
+    logger.filter.read().to_string()
+}
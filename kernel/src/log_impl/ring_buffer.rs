@@ -0,0 +1,108 @@
+//! In-memory ring buffer of the last kernel log records
+//!
+//! The serial port is not readable from userspace, so the logger also keeps
+//! the last [CAPACITY] records it logged in memory. Privileged processes read
+//! them through the `read_kernel_log` syscall.
+
+use core::cmp;
+use core::fmt::{self, Write};
+use log::Record;
+use sunrise_libkern::{KernelLogRecord, KERNEL_LOG_TARGET_LEN, KERNEL_LOG_MESSAGE_LEN};
+use crate::sync::SpinLockIRQ;
+use crate::timer;
+
+/// Number of records kept in the ring buffer. Older records are overwritten.
+pub const CAPACITY: usize = 256;
+
+/// A record that was never written to.
+const EMPTY_RECORD: KernelLogRecord = KernelLogRecord {
+    sequence: 0,
+    timestamp: 0,
+    level: 0,
+    target_len: 0,
+    message_len: 0,
+    target: [0; KERNEL_LOG_TARGET_LEN],
+    message: [0; KERNEL_LOG_MESSAGE_LEN],
+};
+
+/// The ring buffer.
+///
+/// Record with sequence number `n` lives at index `n % CAPACITY`.
+struct LogBuffer {
+    /// The records, overwritten in a circle.
+    records: [KernelLogRecord; CAPACITY],
+    /// Sequence number of the next record to be logged.
+    next_sequence: u64,
+    /// Timestamp of the last record, used when the timer can't be read.
+    last_timestamp: u64,
+}
+
+static LOG_BUFFER: SpinLockIRQ<LogBuffer> = SpinLockIRQ::new(LogBuffer {
+    records: [EMPTY_RECORD; CAPACITY],
+    next_sequence: 0,
+    last_timestamp: 0,
+});
+
+/// A fmt::Write that writes to a fixed buffer, silently dropping what does not
+/// fit. Never cuts an UTF-8 character in half.
+struct TruncatingWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for TruncatingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = cmp::min(s.len(), self.buf.len() - self.len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/// Appends a record to the ring buffer, overwriting the oldest one if it is full.
+///
+/// If the ring buffer is already locked (e.g. we're logging from an interrupt
+/// that fired while reading it), the record is dropped instead of deadlocking.
+pub fn push(record: &Record<'_>) {
+    let mut buffer = match LOG_BUFFER.try_lock() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
+    let timestamp = timer::try_now_ns().unwrap_or(buffer.last_timestamp);
+    let sequence = buffer.next_sequence;
+    buffer.next_sequence += 1;
+    buffer.last_timestamp = timestamp;
+
+    let entry = &mut buffer.records[sequence as usize % CAPACITY];
+    entry.sequence = sequence;
+    entry.timestamp = timestamp;
+    entry.level = record.level() as usize as u8;
+
+    let mut target = TruncatingWriter { buf: &mut entry.target, len: 0 };
+    let _ = target.write_str(record.target());
+    entry.target_len = target.len as u8;
+
+    let mut message = TruncatingWriter { buf: &mut entry.message, len: 0 };
+    let _ = write!(message, "{}", record.args());
+    entry.message_len = message.len as u16;
+}
+
+/// Gets the oldest record still in the ring buffer whose sequence number is
+/// greater or equal to `sequence`.
+///
+/// If the requested record was already overwritten, the oldest record is
+/// returned instead: the caller can tell by looking at its sequence number.
+/// Returns None if no such record was logged yet.
+pub fn read(sequence: u64) -> Option<KernelLogRecord> {
+    let buffer = LOG_BUFFER.lock();
+    if sequence >= buffer.next_sequence {
+        return None;
+    }
+    let oldest = buffer.next_sequence.saturating_sub(CAPACITY as u64);
+    let sequence = cmp::max(sequence, oldest);
+    Some(buffer.records[sequence as usize % CAPACITY])
+}
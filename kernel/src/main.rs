@@ -13,6 +13,9 @@
 #![feature(lang_items, start, asm, global_asm, compiler_builtins_lib, naked_functions, core_intrinsics, const_fn, abi_x86_interrupt, allocator_api, box_syntax, no_more_cas, const_vec_new, step_trait, thread_local, nll, doc_cfg, exclusive_range_pattern)]
 #![no_std]
 #![cfg_attr(target_os = "none", no_main)]
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::test_runner::run_tests))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
 #![recursion_limit = "1024"]
 
 // rustc warnings
@@ -45,7 +48,7 @@ extern crate log;
 extern crate failure;
 #[macro_use]
 extern crate bitfield;
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 #[macro_use]
 extern crate mashup;
 
@@ -63,6 +66,7 @@ pub mod syscalls;
 pub mod frame_allocator;
 
 pub mod heap_allocator;
+pub mod slab_allocator;
 pub mod devices;
 pub mod sync;
 pub mod timer;
@@ -75,6 +79,8 @@ pub mod utils;
 pub mod checks;
 pub mod cpu_locals;
 pub mod panic;
+#[cfg(all(test, target_os = "none"))]
+pub mod test_runner;
 
 #[cfg(target_os = "none")]
 // Make rust happy about rust_oom being no_mangle...
@@ -84,7 +90,7 @@ pub use crate::heap_allocator::rust_oom;
 ///
 /// Creation of a Box, Vec, Arc, ... will use its API.
 /// See the [heap_allocator] module for more info.
-#[cfg(not(test))]
+#[cfg(any(not(test), target_os = "none"))]
 #[global_allocator]
 static ALLOCATOR: heap_allocator::Allocator = heap_allocator::Allocator::new();
 
@@ -176,11 +182,8 @@ fn main() {
             .expect("failed creating process");
     }
 
-    let lock = sync::SpinLockIRQ::new(());
-    loop {
-        // TODO: Exit process.
-        let _ = scheduler::unschedule(&lock, lock.lock());
-    }
+    // We're done with the boot, this thread now runs when nobody else wants to.
+    scheduler::become_idle_thread()
 }
 
 /// The entry point of our kernel.
@@ -236,6 +239,9 @@ pub extern "C" fn common_start(multiboot_info_addr: usize) -> ! {
     frame_allocator::init(&boot_info);
     info!("Initialized frame allocator");
 
+    // Switch to PAE paging if available, before the TSSs save the current cr3.
+    paging::init_pae();
+
     // Set up (read: inhibit) the GDT.
     info!("Initializing gdt...");
     i386::gdt::init_gdt();
@@ -262,9 +268,16 @@ pub extern "C" fn common_start(multiboot_info_addr: usize) -> ! {
     info!("Becoming the first process");
     unsafe { scheduler::create_first_process() };
 
-    info!("Calling main()");
+    // In test mode, run the test cases instead of starting the system.
+    // test_main exits QEMU, and only returns if there's no QEMU to exit.
+    #[cfg(test)]
+    test_main();
 
-    main();
+    #[cfg(not(test))]
+    {
+        info!("Calling main()");
+        main();
+    }
     // Die !
     // We shouldn't reach this...
     loop {
@@ -1,9 +1,9 @@
-//! i386 page table entry
+//! Legacy i386 page table entry
 
 use crate::mem::PhysicalAddress;
 use core::fmt::{Debug, Formatter, Error};
-use super::super::super::hierarchical_table::{HierarchicalEntry, PageState};
-use super::super::super::MappingAccessRights;
+use crate::paging::hierarchical_table::{HierarchicalEntry, PageState};
+use crate::paging::MappingAccessRights;
 
 bitflags! {
     /// The flags of a table entry
@@ -27,7 +27,8 @@ impl From<MappingAccessRights> for I386EntryFlags {
     fn from(flags: MappingAccessRights) -> I386EntryFlags {
         let mut newflags = I386EntryFlags::empty();
 
-        // i386 does not support write-only or execute-only page.
+        // legacy i386 paging does not support write-only or execute-only page,
+        // nor non-executable pages.
         // this means that if a mappping is either read, write, or execute,
         // we mark it PRESENT in the page tables, and it will be readable.
         if flags.intersects(MappingAccessRights::READABLE | MappingAccessRights::WRITABLE | MappingAccessRights::EXECUTABLE) {
@@ -109,11 +110,28 @@ impl HierarchicalEntry for I386Entry {
         }
         assert_eq!(frame_phys_addr.addr() & !ENTRY_PHYS_ADDRESS_MASK, 0);
 
-        self.0 = (frame_phys_addr.addr() as u32) | flags.bits();
+        // a regular entry is never a huge page, even when reusing the flags of a huge page to split it.
+        self.0 = (frame_phys_addr.addr() as u32) | (flags - I386EntryFlags::HUGE_PAGE).bits();
     }
 
     /// Make this entry a page guard
     fn set_guard(&mut self) {
         self.0 = 0x00000000 | I386EntryFlags::GUARD_PAGE.bits;
     }
+
+    /// Is the entry a huge page ?
+    fn is_huge(&self) -> bool {
+        self.flags().contains(I386EntryFlags::PRESENT | I386EntryFlags::HUGE_PAGE)
+    }
+
+    /// Sets the entry as a huge page
+    fn set_huge(&mut self, frame_phys_addr: PhysicalAddress, flags: I386EntryFlags) {
+        if flags.contains(I386EntryFlags::GUARD_PAGE) {
+            // a non-present huge page is just a huge guard
+            self.set_guard();
+            return;
+        }
+        self.set(frame_phys_addr, flags);
+        self.0 |= I386EntryFlags::HUGE_PAGE.bits;
+    }
 }
@@ -0,0 +1,14 @@
+//! Legacy i386 paging
+//!
+//! No PAE, no PSE, just regular 2-level paging, with simple 4kB tables and pages.
+//!
+//! Used when the CPU does not support PAE and NX.
+
+pub mod entry;
+pub mod table;
+
+use super::PAGE_SIZE;
+
+/// The number of entries a page table has.
+/// On i386 a page table/directory is 1024 entries * 4 bytes per entry = 4kB, fits in 1 page.
+pub const ENTRY_COUNT: usize = PAGE_SIZE / ::core::mem::size_of::<entry::I386Entry>();
@@ -1,22 +1,128 @@
 //! Paging implementation on i386
 //!
-//! No PAE, no PSE, just regular 2-level paging, with simple 4kB tables and pages.
+//! Two paging modes are supported, both with simple 4kB tables and pages:
+//!
+//! * [legacy] 2-level paging, with 32-bit entries, which has no NX bit.
+//! * [pae] 3-level PAE paging, with 64-bit entries, and the NX bit.
+//!
+//! The bootstrap always enables legacy paging. At boot, [init_pae] switches to PAE if the CPU
+//! supports it, and the [ActiveHierarchy] and [InactiveHierarchy] dispatch to the right one.
+//!
+//! Physically contiguous regions can also be mapped with large pages, directly from the
+//! page directory: 2MB pages on PAE, and 4MB pages on legacy paging if the CPU supports PSE.
+//!
+//! [ActiveHierarchy]: self::table::ActiveHierarchy
+//! [InactiveHierarchy]: self::table::InactiveHierarchy
 
-pub mod entry;
+pub mod legacy;
+pub mod pae;
 pub mod table;
 pub mod lands;
 
 use crate::mem::{VirtualAddress, PhysicalAddress};
+use crate::paging::hierarchical_table::PagingCacheFlusher;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// The page size. Dictated by the MMU.
 /// In simple, elegant, sane i386 paging, a page is 4kB.
 pub const PAGE_SIZE: usize = 4096;
 
-/// The number of entries a page table has.
-/// On i386 a page table/directory is 1024 entries * 4 bytes per entry = 4kB, fits in 1 page.
-pub const ENTRY_COUNT: usize = PAGE_SIZE / ::core::mem::size_of::<entry::I386Entry>();
+/// Set once we switched to PAE paging.
+static PAE_ENABLED: AtomicBool = AtomicBool::new(false);
 
-//pub static mut ACTIVE_PAGE_TABLES: ActiveHierarchy = ActiveHierarchy;
+/// Checks if we're using PAE paging, or legacy paging.
+pub fn is_pae_enabled() -> bool {
+    PAE_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Set once we enabled 4MB pages on legacy paging.
+static PSE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Checks if 4MB pages can be used on legacy paging.
+pub fn is_pse_enabled() -> bool {
+    PSE_ENABLED.load(Ordering::SeqCst)
+}
+
+/// The size of a large page in the current paging mode, if large pages are available.
+pub fn large_page_size() -> Option<usize> {
+    if is_pae_enabled() {
+        Some(2 * 1024 * 1024)
+    } else if is_pse_enabled() {
+        Some(4 * 1024 * 1024)
+    } else {
+        None
+    }
+}
+
+/// Executes the CPUID instruction for the given leaf, returning eax, ebx, ecx and edx.
+fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx);
+    unsafe {
+        // Safety: this is just querying the CPU features
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(0)
+             :
+             : "intel", "volatile");
+    }
+    (eax, ebx, ecx, edx)
+}
+
+/// Checks the CPUID feature flags for PAE (leaf 1, edx bit 6) and NX (leaf 0x80000001, edx bit 20).
+fn cpu_supports_pae_nx() -> bool {
+    let (max_extended_leaf, _, _, _) = cpuid(0x8000_0000);
+    if max_extended_leaf < 0x8000_0001 {
+        return false;
+    }
+    let (_, _, _, features) = cpuid(1);
+    let (_, _, _, extended_features) = cpuid(0x8000_0001);
+    features & (1 << 6) != 0 && extended_features & (1 << 20) != 0
+}
+
+/// Checks the CPUID feature flags for PSE (leaf 1, edx bit 3).
+fn cpu_supports_pse() -> bool {
+    let (_, _, _, features) = cpuid(1);
+    features & (1 << 3) != 0
+}
+
+/// Enables 4MB pages on legacy paging by setting CR4.PSE.
+fn init_pse() {
+    unsafe {
+        // Safety: the CPU supports PSE, and no entry of the active hierarchy has the PS bit set.
+        asm!("mov eax, cr4
+              or eax, 0x10
+              mov cr4, eax"
+              :
+              :
+              : "eax"
+              : "intel", "volatile");
+    }
+    PSE_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Switches to PAE paging if the CPU supports PAE and NX. Stays on legacy paging otherwise,
+/// with 4MB pages enabled if the CPU supports PSE.
+///
+/// Once on PAE, mappings without `MappingAccessRights::EXECUTABLE` are not executable anymore.
+///
+/// Must be called early at boot, before the TSSs are initialized (they save the current cr3),
+/// and before anything is mapped in UserLand.
+pub fn init_pae() {
+    assert!(!is_pae_enabled(), "PAE is already enabled");
+    if !cpu_supports_pae_nx() {
+        info!("CPU does not support PAE and NX, staying on legacy paging");
+        if cpu_supports_pse() {
+            init_pse();
+            info!("4MB pages enabled");
+        }
+        return;
+    }
+    unsafe {
+        // Safety: the legacy hierarchy of the bootstrap is active, and the CPU supports PAE and NX.
+        pae::switch_from_legacy();
+    }
+    info!("Switched to PAE paging, NX enabled");
+}
 
 /// Check if the paging is currently active.
 ///
@@ -48,7 +154,7 @@ pub unsafe fn enable_paging(page_directory_address: PhysicalAddress) {
 
 /// Flush the Translation Lookaside Buffer [https://wiki.osdev.org/TLB]
 fn flush_tlb() {
-    #[cfg(not(test))]
+    #[cfg(any(not(test), target_os = "none"))]
     unsafe {
         asm!("mov eax, cr3
           mov cr3, eax  "
@@ -90,3 +196,7 @@ pub fn read_cr2() -> VirtualAddress {
     }
     VirtualAddress(cr2_value)
 }
+
+/// When passing this struct the TLB will be flushed. Used by the active page tables.
+pub struct TlbFlush;
+impl PagingCacheFlusher for TlbFlush { fn flush_whole_cache() { flush_tlb(); } }
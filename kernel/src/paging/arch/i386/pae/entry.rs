@@ -0,0 +1,145 @@
+//! PAE page table entry
+
+use crate::mem::PhysicalAddress;
+use core::fmt::{Debug, Formatter, Error};
+use crate::paging::hierarchical_table::{HierarchicalEntry, PageState};
+use crate::paging::MappingAccessRights;
+
+bitflags! {
+    /// The flags of a table entry
+    pub struct PaeEntryFlags: u64 {
+        const PRESENT =         1 << 0;
+        const WRITABLE =        1 << 1;
+        const USER_ACCESSIBLE = 1 << 2;
+        const WRITE_THROUGH =   1 << 3;
+        const NO_CACHE =        1 << 4;
+        const ACCESSED =        1 << 5;
+        const DIRTY =           1 << 6;
+        const HUGE_PAGE =       1 << 7;
+        const GLOBAL =          1 << 8;
+        const GUARD_PAGE =      1 << 9;     // user_defined_1
+        const USER_DEFINED_2 =  1 << 10;    // user_defined_2
+        const USER_DEFINED_3 =  1 << 11;    // user_defined_3
+        /// Instruction fetches are not allowed from this page. Requires EFER.NXE.
+        ///
+        /// Only valid in page tables and page directories, this bit is reserved in the
+        /// page directory pointer table.
+        const NO_EXECUTE =      1 << 63;
+    }
+}
+
+impl From<MappingAccessRights> for PaeEntryFlags {
+    fn from(flags: MappingAccessRights) -> PaeEntryFlags {
+        let mut newflags = PaeEntryFlags::empty();
+
+        // PAE does not support write-only or execute-only page.
+        // this means that if a mappping is either read, write, or execute,
+        // we mark it PRESENT in the page tables, and it will be readable.
+        if flags.intersects(MappingAccessRights::READABLE | MappingAccessRights::WRITABLE | MappingAccessRights::EXECUTABLE) {
+            newflags |= PaeEntryFlags::PRESENT
+        } else {
+            // if it is not present, then we're basically a guard page.
+            return PaeEntryFlags::GUARD_PAGE;
+        }
+        if flags.contains(MappingAccessRights::WRITABLE) {
+            newflags |= PaeEntryFlags::WRITABLE
+        };
+        if flags.contains(MappingAccessRights::USER_ACCESSIBLE) {
+            newflags |= PaeEntryFlags::USER_ACCESSIBLE
+        };
+        if !flags.contains(MappingAccessRights::EXECUTABLE) {
+            newflags |= PaeEntryFlags::NO_EXECUTE
+        };
+        newflags
+    }
+}
+
+/// The part of an entry that encodes the physical address.
+///
+/// You can retrieve the frame by just `and`ing an entry with this mask.
+/// We only support physical memory below 4GB, the higher bits of the address must be 0.
+const ENTRY_PHYS_ADDRESS_MASK: u64 = 0xffff_f000;
+
+/// An entry in a page table, page directory, or page directory pointer table. An unused entry is 0.
+#[repr(transparent)]
+#[derive(Clone, Copy)]
+pub struct PaeEntry(u64);
+
+impl Debug for PaeEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("Entry")
+            .field("flags", &self.flags())
+            .field("frame", &self.pointed_frame().as_option())
+            .finish()
+    }
+}
+
+impl HierarchicalEntry for PaeEntry {
+    type EntryFlagsType = PaeEntryFlags;
+
+    /// Is the entry unused ?
+    fn is_unused(&self) -> bool { self.0 == 0 }
+
+    /// Clear the entry
+    fn set_unused(&mut self) -> PageState<PhysicalAddress> {
+        let ret = self.pointed_frame();
+        self.0 = 0;
+        ret
+    }
+
+    /// Is the entry a page guard ?
+    fn is_guard(&self) -> bool { self.flags().contains(PaeEntryFlags::GUARD_PAGE) }
+
+    /// Get the current entry flags
+    fn flags(&self) -> PaeEntryFlags { PaeEntryFlags::from_bits_truncate(self.0) }
+
+    /// Get the associated physical address, if available
+    fn pointed_frame(&self) -> PageState<PhysicalAddress> {
+        if self.flags().contains(PaeEntryFlags::PRESENT) {
+            let frame_phys_addr = (self.0 & ENTRY_PHYS_ADDRESS_MASK) as usize;
+            PageState::Present(PhysicalAddress(frame_phys_addr))
+        } else if self.flags().contains(PaeEntryFlags::GUARD_PAGE) {
+            PageState::Guarded
+        } else {
+            PageState::Available
+        }
+    }
+
+    /// Sets the entry
+    fn set(&mut self, frame_phys_addr: PhysicalAddress, flags: PaeEntryFlags) {
+        assert_eq!(flags.contains(PaeEntryFlags::PRESENT)
+                && flags.contains(PaeEntryFlags::GUARD_PAGE), false,
+                "a GUARD_PAGE cannot also be PRESENT");
+
+        if flags.contains(PaeEntryFlags::GUARD_PAGE) {
+            // if we're mapping a guard page, do not store the frame in it because of L1TF
+            self.set_guard();
+            return;
+        }
+        assert_eq!(frame_phys_addr.addr() as u64 & !ENTRY_PHYS_ADDRESS_MASK, 0);
+
+        // a regular entry is never a huge page, even when reusing the flags of a huge page to split it.
+        self.0 = (frame_phys_addr.addr() as u64) | (flags - PaeEntryFlags::HUGE_PAGE).bits();
+    }
+
+    /// Make this entry a page guard
+    fn set_guard(&mut self) {
+        self.0 = 0x00000000 | PaeEntryFlags::GUARD_PAGE.bits;
+    }
+
+    /// Is the entry a huge page ?
+    fn is_huge(&self) -> bool {
+        self.flags().contains(PaeEntryFlags::PRESENT | PaeEntryFlags::HUGE_PAGE)
+    }
+
+    /// Sets the entry as a huge page
+    fn set_huge(&mut self, frame_phys_addr: PhysicalAddress, flags: PaeEntryFlags) {
+        if flags.contains(PaeEntryFlags::GUARD_PAGE) {
+            // a non-present huge page is just a huge guard
+            self.set_guard();
+            return;
+        }
+        self.set(frame_phys_addr, flags);
+        self.0 |= PaeEntryFlags::HUGE_PAGE.bits;
+    }
+}
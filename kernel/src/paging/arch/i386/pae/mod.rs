@@ -0,0 +1,193 @@
+//! PAE paging on i386
+//!
+//! 3-level paging with 64-bit entries, which gives us the NX bit. Still with simple 4kB tables
+//! and pages, and we only use physical memory below 4GB.
+//!
+//! The bootstrap always hands us a legacy hierarchy, [switch_from_legacy] converts it
+//! to a PAE one at boot.
+
+pub mod entry;
+pub mod table;
+
+use super::PAGE_SIZE;
+use super::legacy;
+use super::legacy::entry::{I386Entry, I386EntryFlags};
+use self::entry::{PaeEntry, PaeEntryFlags};
+use self::table::{DIRECTORY_VM_SIZE, TABLE_VM_SIZE, KERNELLAND_DIRECTORY, RECURSIVE_START_TABLE};
+use crate::paging::hierarchical_table::{HierarchicalEntry, HierarchicalTable, TableHierarchy, PageState};
+use crate::paging::kernel_memory::get_kernel_memory;
+use crate::paging::MappingAccessRights;
+use crate::mem::VirtualAddress;
+use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
+
+/// The number of entries a page table or directory has.
+/// With PAE a page table/directory is 512 entries * 8 bytes per entry = 4kB, fits in 1 page.
+pub const ENTRY_COUNT: usize = PAGE_SIZE / ::core::mem::size_of::<PaeEntry>();
+
+/// The number of entries of the page directory pointer table.
+pub const PDPT_ENTRY_COUNT: usize = 4;
+
+/// Gets the nth frame of the window used by [switch_from_legacy], as a table.
+unsafe fn window_table<'a>(window: VirtualAddress, index: usize) -> &'a mut [PaeEntry; ENTRY_COUNT] {
+    &mut *((window + index * PAGE_SIZE).addr() as *mut [PaeEntry; ENTRY_COUNT])
+}
+
+/// Sets EFER.NXE, enabling the NO_EXECUTE bit of PAE entries.
+///
+/// Without it, a NO_EXECUTE bit is a reserved bit, and causes a page fault.
+unsafe fn enable_nxe() {
+    #[cfg(any(not(test), target_os = "none"))]
+    asm!("mov ecx, 0xC0000080
+          rdmsr
+          or eax, 0x800
+          wrmsr"
+          :
+          :
+          : "eax", "ecx", "edx"
+          : "intel", "volatile");
+}
+
+/// Switches from the legacy hierarchy set up by the bootstrap to a PAE hierarchy.
+///
+/// Every legacy kernel table is split in two PAE tables, and the whole KernelLand is converted.
+/// The existing mappings are kept executable, as we can't tell the code from the data at this
+/// point. Only the mappings created afterwards will have NO_EXECUTE set.
+///
+/// UserLand is not converted, the identity mappings of the bootstrap are dropped,
+/// along with all the legacy tables.
+///
+/// We can't allocate any memory on the heap while doing this, as the kernel memory is locked.
+/// We reserve a window of frames holding the PDPT, the four directories and the tables,
+/// and fill them through it.
+///
+/// The CPU reads cr3 as a legacy directory until CR4.PAE is set, so the PDPT frame is also
+/// filled as a copy of the legacy directory. It only uses the first 32 bytes, which only
+/// map UserLand when read as a legacy directory.
+///
+/// # Unsafety
+///
+/// Must be called only once, when the legacy hierarchy is the active one, and the CPU
+/// supports PAE and NX. Nothing in UserLand must still be in use.
+///
+/// The TSSs are not updated, this must be called before they are initialized.
+pub unsafe fn switch_from_legacy() {
+    let mut memory = get_kernel_memory();
+
+    /// Counts the legacy tables mapping KernelLand.
+    fn count_kernel_tables() -> usize {
+        legacy::table::ActiveHierarchy.get_top_level_table()
+            .entries()[legacy::table::KERNELLAND_START_TABLE..=legacy::table::KERNELLAND_END_TABLE]
+            .iter()
+            .filter(|entry| entry.pointed_frame().as_option().is_some())
+            .count()
+    }
+
+    // 1: Reserve and map the window. Mapping it might create up to two legacy tables.
+    let tables_count = count_kernel_tables() + 2;
+    let window_frames_count = 1 + PDPT_ENTRY_COUNT + 2 * tables_count;
+    let window_size = window_frames_count * PAGE_SIZE;
+    let window_frames = FrameAllocator::allocate_region(window_size)
+        .expect("Cannot allocate the PAE tables");
+    let window_phys = window_frames.address();
+    let window = memory.map_phys_region(window_frames, MappingAccessRights::k_rw())
+        .expect("Cannot map the PAE tables");
+    assert!(count_kernel_tables() <= tables_count, "Mapping the PAE tables created too many tables");
+    ::core::ptr::write_bytes(window.addr() as *mut u8, 0, window_size);
+
+    // 2: Convert the legacy tables. The window is converted too, so we can unmap it afterwards.
+    let mut legacy_directory = legacy::table::ActiveHierarchy.get_top_level_table();
+    assert!(legacy_directory.entries()[legacy::table::KERNELLAND_END_TABLE + 1].is_unused(),
+            "RecursiveTablesLand is already in use");
+    let mut next_table = 1 + PDPT_ENTRY_COUNT;
+    for legacy_index in legacy::table::KERNELLAND_START_TABLE..=legacy::table::KERNELLAND_END_TABLE {
+        let mut legacy_table = match legacy_directory.entries()[legacy_index].pointed_frame() {
+            PageState::Available => continue,
+            PageState::Guarded => None,
+            PageState::Present(_) => Some(legacy_directory.get_child_table(legacy_index).unwrap())
+        };
+        for half in 0..2 {
+            let address = legacy_index * legacy::ENTRY_COUNT * PAGE_SIZE + half * TABLE_VM_SIZE;
+            let directory = window_table(window, 1 + address / DIRECTORY_VM_SIZE);
+            let directory_entry = &mut directory[(address % DIRECTORY_VM_SIZE) / TABLE_VM_SIZE];
+            match legacy_table {
+                // a huge guard
+                None => directory_entry.set_guard(),
+                Some(ref mut legacy_table) => {
+                    let table = window_table(window, next_table);
+                    let legacy_entries = &legacy_table.entries()[half * ENTRY_COUNT..(half + 1) * ENTRY_COUNT];
+                    for (entry, legacy_entry) in table.iter_mut().zip(legacy_entries) {
+                        match legacy_entry.pointed_frame() {
+                            PageState::Present(frame) => {
+                                // the low 12 bits of the flags are the same.
+                                let flags = PaeEntryFlags::from_bits_truncate(u64::from(legacy_entry.flags().bits()));
+                                entry.set(frame, flags)
+                            },
+                            PageState::Guarded => entry.set_guard(),
+                            PageState::Available => ()
+                        }
+                    }
+                    directory_entry.set(window_phys + next_table * PAGE_SIZE,
+                                        PaeEntryFlags::PRESENT | PaeEntryFlags::WRITABLE);
+                    next_table += 1;
+                }
+            }
+        }
+    }
+
+    // 3: Recursive mapping.
+    let kernel_directory = window_table(window, 1 + KERNELLAND_DIRECTORY);
+    for index in 0..PDPT_ENTRY_COUNT {
+        kernel_directory[RECURSIVE_START_TABLE + index].set(window_phys + (1 + index) * PAGE_SIZE,
+                                                            PaeEntryFlags::PRESENT | PaeEntryFlags::WRITABLE);
+    }
+
+    // 4: The PDPT, doubling as a legacy directory until we set CR4.PAE.
+    let legacy_copy = &mut *(window.addr() as *mut [I386Entry; legacy::ENTRY_COUNT]);
+    legacy_copy[legacy::table::KERNELLAND_START_TABLE..=legacy::table::KERNELLAND_END_TABLE]
+        .clone_from_slice(&legacy_directory.entries()[legacy::table::KERNELLAND_START_TABLE..=legacy::table::KERNELLAND_END_TABLE]);
+    legacy_copy[legacy::ENTRY_COUNT - 1].set(window_phys, I386EntryFlags::PRESENT | I386EntryFlags::WRITABLE);
+    let pdpt = &mut *(window.addr() as *mut [PaeEntry; PDPT_ENTRY_COUNT]);
+    for (index, entry) in pdpt.iter_mut().enumerate() {
+        // PDPT entries only accept the PRESENT flag, all the others are reserved.
+        entry.set(window_phys + (1 + index) * PAGE_SIZE, PaeEntryFlags::PRESENT);
+    }
+    drop(legacy_directory);
+
+    // 5: Switch. Setting CR4.PAE loads the PDPT entries from cr3.
+    let legacy_directory_address = super::read_cr3();
+    enable_nxe();
+    #[cfg(any(not(test), target_os = "none"))]
+    asm!("mov cr3, $0
+          mov eax, cr4
+          or eax, 0x20
+          mov cr4, eax"
+          :
+          : "r"(window_phys.addr())
+          : "eax", "memory"
+          : "intel", "volatile");
+    super::PAE_ENABLED.store(true, ::core::sync::atomic::Ordering::SeqCst);
+
+    // 6: The tables are now mapped through recursive mapping, unmap the window,
+    // and give back the frames we did not use.
+    memory.unmap_no_dealloc(window, window_size)
+        .expect("Cannot unmap the PAE tables window");
+    for index in next_table..window_frames_count {
+        drop(PhysicalMemRegion::reconstruct(window_phys + index * PAGE_SIZE, PAGE_SIZE));
+    }
+
+    // 7: Free the legacy directory and all its tables, except the recursive entry.
+    let legacy_directory_frame = PhysicalMemRegion::reconstruct_no_dealloc(legacy_directory_address, PAGE_SIZE);
+    let legacy_directory_va = memory.map_phys_region(legacy_directory_frame, MappingAccessRights::k_r())
+        .expect("Cannot map the legacy page directory");
+    let legacy_entries = &*(legacy_directory_va.addr() as *const [I386Entry; legacy::ENTRY_COUNT]);
+    for legacy_entry in &legacy_entries[..legacy::ENTRY_COUNT - 1] {
+        if let PageState::Present(table) = legacy_entry.pointed_frame() {
+            // the legacy tables were marked allocated when initializing the frame allocator,
+            // they're only tracked by the legacy directory.
+            drop(PhysicalMemRegion::reconstruct(table, PAGE_SIZE));
+        }
+    }
+    memory.unmap_no_dealloc(legacy_directory_va, PAGE_SIZE)
+        .expect("Cannot unmap the legacy page directory");
+    drop(PhysicalMemRegion::reconstruct(legacy_directory_address, PAGE_SIZE));
+}
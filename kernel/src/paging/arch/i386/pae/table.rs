@@ -0,0 +1,554 @@
+//! PAE Page Tables hierarchy
+//!
+//! A PAE hierarchy is made of a page directory pointer table of 4 entries, pointing to 4 page
+//! directories, each mapping 1GB of virtual memory with 512 page tables of 512 entries.
+//!
+//! The four page directories are always allocated, and the last one maps KernelLand and
+//! RecursiveTablesLand. Its entries 508 to 511 point to the four page directories,
+//! which gives us a recursive mapping:
+//!
+//! ```
+//! 0xff800000 - 0xffffffff: the page tables, table j of directory i being at 0xff800000 + i * 2MB + j * 4kB.
+//! 0xffffc000 - 0xffffffff: the page directories, directory i being at 0xffffc000 + i * 4kB.
+//! 0xffffffe0 - 0xffffffff: the last directory's recursive entries, used as a view of the PDPT.
+//! ```
+
+use super::{ENTRY_COUNT, PDPT_ENTRY_COUNT};
+use super::super::{PAGE_SIZE, TlbFlush};
+use super::super::lands::{KernelLand, UserLand, RecursiveTablesLand};
+use super::entry::{PaeEntry, PaeEntryFlags};
+use crate::paging::hierarchical_table::{HierarchicalTable, SmartHierarchicalTable,
+                                        TableHierarchy, InactiveHierarchyTrait,
+                                        PageState, NoFlush, HierarchicalEntry};
+use crate::paging::kernel_memory::get_kernel_memory;
+use crate::paging::lands::VirtualSpaceLand;
+use crate::paging::MappingAccessRights;
+use crate::mem::{VirtualAddress, PhysicalAddress};
+use crate::error::KernelError;
+use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
+use core::fmt::{Debug, Formatter, Error};
+
+/// The virtual memory spanned by a page directory.
+pub const DIRECTORY_VM_SIZE: usize = ENTRY_COUNT * ENTRY_COUNT * PAGE_SIZE;
+/// The virtual memory spanned by a page table.
+pub const TABLE_VM_SIZE: usize = ENTRY_COUNT * PAGE_SIZE;
+
+/// The index in the PDPT of the last directory mapping UserLand.
+pub const USERLAND_END_DIRECTORY: usize = UserLand::END.addr() / DIRECTORY_VM_SIZE;
+
+/// The index in the PDPT of the directory mapping KernelLand and RecursiveTablesLand.
+pub const KERNELLAND_DIRECTORY: usize = KernelLand::START.addr() / DIRECTORY_VM_SIZE;
+/// The index in the kernel directory of the first table of KernelLand.
+pub const KERNELLAND_START_TABLE: usize = (KernelLand::START.addr() % DIRECTORY_VM_SIZE) / TABLE_VM_SIZE;
+/// The index in the kernel directory of the last table of KernelLand.
+pub const KERNELLAND_END_TABLE: usize = (KernelLand::END.addr() % DIRECTORY_VM_SIZE) / TABLE_VM_SIZE;
+/// The index in the kernel directory of the first recursive entry. The next
+/// [PDPT_ENTRY_COUNT] entries point to the page directories, in order.
+pub const RECURSIVE_START_TABLE: usize = (RecursiveTablesLand::START.addr() % DIRECTORY_VM_SIZE) / TABLE_VM_SIZE;
+
+/// When paging is on, the page tables of directory `i` are at this address + `i` * 2MB.
+pub const TABLES_RECURSIVE_ADDRESS: VirtualAddress = RecursiveTablesLand::START;
+/// When paging is on, directory `i` is at this address + `i` * 4kB.
+pub const DIRECTORIES_RECURSIVE_ADDRESS: VirtualAddress =
+    VirtualAddress(TABLES_RECURSIVE_ADDRESS.0 + KERNELLAND_DIRECTORY * TABLE_VM_SIZE + RECURSIVE_START_TABLE * PAGE_SIZE);
+/// When paging is on, the recursive entries of the kernel directory are at this address.
+/// They point to the same frames as the PDPT, and we use them as a read-only view of it.
+pub const PDPT_RECURSIVE_ADDRESS: VirtualAddress =
+    VirtualAddress(DIRECTORIES_RECURSIVE_ADDRESS.0 + KERNELLAND_DIRECTORY * PAGE_SIZE
+                   + RECURSIVE_START_TABLE * ::core::mem::size_of::<PaeEntry>());
+
+// Assertions to check that UserLand, KernelLand and RecursiveTablesLand fall on distinct
+// directories and tables, and that the recursive entries are the last ones of the kernel directory.
+const_assert!(USERLAND_END_DIRECTORY < KERNELLAND_DIRECTORY);
+const_assert!(KERNELLAND_DIRECTORY == PDPT_ENTRY_COUNT - 1);
+const_assert!(KernelLand::START.0 % TABLE_VM_SIZE == 0);
+const_assert!(RecursiveTablesLand::START.0 % TABLE_VM_SIZE == 0);
+const_assert!(RECURSIVE_START_TABLE == ENTRY_COUNT - PDPT_ENTRY_COUNT);
+const_assert!(KERNELLAND_END_TABLE + 1 == RECURSIVE_START_TABLE);
+const_assert!(DIRECTORIES_RECURSIVE_ADDRESS.0 == 0xffff_c000);
+const_assert!(PDPT_RECURSIVE_ADDRESS.0 == 0xffff_ffe0);
+
+/// A page table or directory in memory.
+///
+/// A page table/directory is just an array of 512 [PaeEntry].
+struct Table {
+    /// The array of entries making up this table.
+    entries: [PaeEntry; ENTRY_COUNT]
+}
+
+impl Debug for Table {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Debug::fmt(&&self.entries[..], f)
+    }
+}
+
+/// A page directory pointer table in memory.
+///
+/// Just an array of 4 [PaeEntry]. Must be 32 bytes aligned, we always give it a whole frame.
+struct PointerTable {
+    /// The array of entries making up this table.
+    entries: [PaeEntry; PDPT_ENTRY_COUNT]
+}
+
+impl Debug for PointerTable {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Debug::fmt(&&self.entries[..], f)
+    }
+}
+
+/// The flags of an entry pointing to a page table.
+///
+/// A directory entry is always WRITABLE and executable, permissions are handled at table level.
+/// If we're in user land, we should create the table as USER_ACCESSIBLE.
+fn directory_entry_flags(user_accessible: bool) -> PaeEntryFlags {
+    if user_accessible {
+        PaeEntryFlags::PRESENT | PaeEntryFlags::WRITABLE | PaeEntryFlags::USER_ACCESSIBLE
+    } else {
+        PaeEntryFlags::PRESENT | PaeEntryFlags::WRITABLE
+    }
+}
+
+/* ********************************************************************************************** */
+
+/// A currently active page table.
+///
+/// A [Table] with associated functions.
+#[derive(Debug)]
+pub struct ActivePageTable(Table);
+
+/// A currently active page directory.
+///
+/// A [Table] with associated functions, which gets its children [ActivePageTable]
+/// through recursive mapping.
+#[derive(Debug)]
+pub struct ActivePageDirectory(Table);
+
+/// The currently active page directory pointer table.
+///
+/// A read-only view of it through the recursive entries of the kernel directory,
+/// which gets its children [ActivePageDirectory] through recursive mapping.
+#[derive(Debug)]
+pub struct ActivePageDirectoryPointerTable(PointerTable);
+
+/// The currently active hierarchy of tables. Gets its [ActivePageDirectoryPointerTable]
+/// through recursive mapping.
+#[derive(Debug)]
+pub struct ActiveHierarchy;
+
+impl HierarchicalTable for ActivePageTable {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = TlbFlush;
+    type ChildTableType = Self; // unused since we panic
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 0 }
+
+    fn entry_count() -> usize { ENTRY_COUNT }
+
+    /// Panics, a page table has no children.
+    fn get_child_table(&mut self, _index: usize) -> PageState<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>> {
+        panic!("An active page table has no children");
+    }
+
+    /// Panics, a page table has no children.
+    fn create_child_table(&mut self, _index: usize) -> Result<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>, KernelError> {
+        panic!("An active page table has no children");
+    }
+}
+
+impl ActivePageDirectory {
+    /// Gets the index in the PDPT of this directory, from its recursive address.
+    fn directory_index(&self) -> usize {
+        (self as *const _ as usize - DIRECTORIES_RECURSIVE_ADDRESS.addr()) / PAGE_SIZE
+    }
+
+    /// reduce recursive mapping by one time to get further down in table hierarchy
+    fn get_table_address(&mut self, index: usize) -> PageState<usize> {
+        match self.entries()[index].pointed_frame() {
+            PageState::Present(_) => {
+                let table_address = TABLES_RECURSIVE_ADDRESS.addr()
+                    + self.directory_index() * TABLE_VM_SIZE
+                    + index * PAGE_SIZE;
+                PageState::Present(table_address)
+            },
+            PageState::Available => PageState::Available,
+            PageState::Guarded => PageState::Guarded
+        }
+    }
+}
+
+impl HierarchicalTable for ActivePageDirectory {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = TlbFlush;
+    type ChildTableType = ActivePageTable;
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 1 }
+
+    fn entry_count() -> usize { ENTRY_COUNT }
+
+    /// 2MB pages can always be mapped from a PAE directory.
+    fn huge_entries_supported() -> bool { true }
+
+    /// Gets a child [ActivePageTable] through recursive mapping.
+    fn get_child_table(&mut self, index: usize) -> PageState<SmartHierarchicalTable<ActivePageTable>> {
+        // use recursive mapping to get the child table
+        self.get_table_address(index)
+            .map(|addr| SmartHierarchicalTable::new(unsafe { &mut * (addr as *mut _) }))
+    }
+
+    /// Creates a child [ActivePageTable], maps it at the given index, and returns it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entry was not available.
+    fn create_child_table(&mut self, index: usize) -> Result<SmartHierarchicalTable<ActivePageTable>, KernelError> {
+        assert!(self.entries()[index].is_unused(), "called create_child_table on a non available entry");
+        let table_frame = FrameAllocator::allocate_frame()?;
+
+        let flags = directory_entry_flags(self.directory_index() <= USERLAND_END_DIRECTORY);
+        self.map_nth_entry(index, table_frame.address(), flags);
+        // frame is mapped in RecursiveTablesLand
+        ::core::mem::forget(table_frame);
+
+        // Now that table is mapped in page directory we can write to it through recursive mapping
+        let mut table = self.get_child_table(index).unwrap();
+        table.zero();
+        Ok(table)
+    }
+}
+
+impl HierarchicalTable for ActivePageDirectoryPointerTable {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = TlbFlush;
+    type ChildTableType = ActivePageDirectory;
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 2 }
+
+    fn entry_count() -> usize { PDPT_ENTRY_COUNT }
+
+    /// Gets a child [ActivePageDirectory] through recursive mapping.
+    fn get_child_table(&mut self, index: usize) -> PageState<SmartHierarchicalTable<ActivePageDirectory>> {
+        self.entries()[index].pointed_frame().map(|_| {
+            let addr = DIRECTORIES_RECURSIVE_ADDRESS.addr() + index * PAGE_SIZE;
+            SmartHierarchicalTable::new(unsafe { &mut * (addr as *mut _) })
+        })
+    }
+
+    /// Panics, all page directories are allocated with the PDPT.
+    fn create_child_table(&mut self, _index: usize) -> Result<SmartHierarchicalTable<ActivePageDirectory>, KernelError> {
+        panic!("All page directories of a PAE hierarchy are always present");
+    }
+}
+
+impl TableHierarchy for ActiveHierarchy {
+    type TopLevelTableType = ActivePageDirectoryPointerTable;
+
+    /// Gets the [ActivePageDirectoryPointerTable] through recursive mapping.
+    ///
+    /// # Panics
+    ///
+    /// Panics if paging is not enabled.
+    fn get_top_level_table(&mut self) -> SmartHierarchicalTable<ActivePageDirectoryPointerTable> {
+        assert!(super::super::is_paging_on(), "Paging is disabled");
+        SmartHierarchicalTable::new(PDPT_RECURSIVE_ADDRESS.addr() as *mut ActivePageDirectoryPointerTable)
+    }
+}
+
+/* ********************************************************************************************** */
+
+/// A currently inactive page table.
+///
+/// A [Table] with associated functions. Must be temporarily mapped to be read and modified.
+/// See [SmartHierarchicalTable].
+#[derive(Debug)]
+pub struct InactivePageTable(Table);
+
+/// A currently inactive page directory.
+///
+/// A [Table] with associated functions. Must be temporarily mapped to be read and modified.
+///
+/// Gets its children [InactivePageTable] by temporarily mapping them.
+///
+/// See [SmartHierarchicalTable].
+#[derive(Debug)]
+pub struct InactivePageDirectory(Table);
+
+/// A currently inactive page directory pointer table.
+///
+/// Must be temporarily mapped to be read and modified.
+///
+/// Gets its children [InactivePageDirectory] by temporarily mapping them.
+///
+/// See [SmartHierarchicalTable].
+#[derive(Debug)]
+pub struct InactivePageDirectoryPointerTable(PointerTable);
+
+/// A currently inactive hierarchy of tables.
+///
+/// Can be read and modified by temporarily mapping its [InactivePageDirectoryPointerTable].
+#[derive(Debug)]
+pub struct InactiveHierarchy {
+    /// The address we must put in cr3 to switch to these pages.
+    pdpt_physical_address: PhysicalAddress,
+}
+
+/// Temporarily maps a table of an inactive hierarchy in KernelLand.
+///
+/// The table will be unmapped when the returned [SmartHierarchicalTable] is dropped.
+fn map_inactive_table<'a, T: HierarchicalTable>(frame: PhysicalAddress) -> SmartHierarchicalTable<'a, T> {
+    let phys_region = unsafe {
+        // safe: we're only remapping an existing frame, and we hold the locks on both
+        // the active and inactive hierarchies. It will be gone before we free those locks.
+        PhysicalMemRegion::reconstruct_no_dealloc(frame, PAGE_SIZE)
+    };
+    let mut active_pages = get_kernel_memory();
+    let va = active_pages.find_virtual_space(PAGE_SIZE).unwrap();
+    active_pages.map_phys_region_to(phys_region, va, MappingAccessRights::k_rw())
+        .expect("Cannot map an inactive table");
+    SmartHierarchicalTable::new(va.addr() as *mut T)
+}
+
+impl HierarchicalTable for InactivePageTable {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = NoFlush;
+    type ChildTableType = Self; // ignored since we panic
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 0 }
+
+    fn entry_count() -> usize { ENTRY_COUNT }
+
+    /// Panics, a page table has no children.
+    fn get_child_table(&mut self, _index: usize) -> PageState<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>> {
+        panic!("An inactive page table has no children");
+    }
+
+    /// Panics, a page table has no children.
+    fn create_child_table(&mut self, _index: usize) -> Result<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>, KernelError> {
+        panic!("An inactive page table has no children");
+    }
+}
+
+impl HierarchicalTable for InactivePageDirectory {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = NoFlush;
+    type ChildTableType = InactivePageTable;
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 1 }
+
+    fn entry_count() -> usize { ENTRY_COUNT }
+
+    /// 2MB pages can always be mapped from a PAE directory.
+    fn huge_entries_supported() -> bool { true }
+
+    /// Gets the child [InactivePageTable] at the given index. Temporarily maps it if it is present.
+    fn get_child_table(&mut self, index: usize) -> PageState<SmartHierarchicalTable<InactivePageTable>> {
+        self.entries()[index].pointed_frame().map(map_inactive_table)
+    }
+
+    /// Creates a child [InactivePageTable] at the given index, temporarily maps it, and returns it.
+    ///
+    /// KernelLand is only ever modified in the active hierarchy, and copied to inactive ones
+    /// when switching to them, so the tables we create here are always USER_ACCESSIBLE.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entry was not available.
+    fn create_child_table(&mut self, index: usize) -> Result<SmartHierarchicalTable<InactivePageTable>, KernelError> {
+        assert!(self.entries()[index].is_unused());
+        let table_frame = FrameAllocator::allocate_frame()?;
+
+        // 1: Map it in our page tables
+        let mut mapped_table = map_inactive_table::<InactivePageTable>(table_frame.address());
+        mapped_table.zero();
+
+        // 2: Map it in other's page tables
+        self.map_nth_entry(index, table_frame.address(), directory_entry_flags(true));
+        // frame is mapped in the inactive hierarchy
+        ::core::mem::forget(table_frame);
+
+        Ok(mapped_table)
+    }
+}
+
+impl HierarchicalTable for InactivePageDirectoryPointerTable {
+    type EntryType = PaeEntry;
+    type CacheFlusherType = NoFlush;
+    type ChildTableType = InactivePageDirectory;
+
+    fn entries(&mut self) -> &mut [PaeEntry] { &mut self.0.entries }
+
+    fn table_level() -> usize { 2 }
+
+    fn entry_count() -> usize { PDPT_ENTRY_COUNT }
+
+    /// Gets the child [InactivePageDirectory] at the given index by temporarily mapping it.
+    fn get_child_table(&mut self, index: usize) -> PageState<SmartHierarchicalTable<InactivePageDirectory>> {
+        self.entries()[index].pointed_frame().map(map_inactive_table)
+    }
+
+    /// Panics, all page directories are allocated with the PDPT.
+    fn create_child_table(&mut self, _index: usize) -> Result<SmartHierarchicalTable<InactivePageDirectory>, KernelError> {
+        panic!("All page directories of a PAE hierarchy are always present");
+    }
+}
+
+impl Drop for InactivePageDirectoryPointerTable {
+    /// When the temporary inactive pdpt is drop, we unmap it.
+    fn drop(&mut self) {
+        get_kernel_memory().unmap_whole_mapping_no_dealloc(VirtualAddress(self as *mut _ as usize), PAGE_SIZE);
+    }
+}
+
+impl Drop for InactivePageDirectory {
+    /// When the temporary inactive directory is drop, we unmap it.
+    fn drop(&mut self) {
+        get_kernel_memory().unmap_whole_mapping_no_dealloc(VirtualAddress(self as *mut _ as usize), PAGE_SIZE);
+    }
+}
+
+impl Drop for InactivePageTable {
+    /// When the temporary inactive table is drop, we unmap it.
+    fn drop(&mut self) {
+        get_kernel_memory().unmap_whole_mapping_no_dealloc(VirtualAddress(self as *mut _ as usize), PAGE_SIZE);
+    }
+}
+
+impl TableHierarchy for InactiveHierarchy {
+    type TopLevelTableType = InactivePageDirectoryPointerTable;
+
+    /// Gets the [InactivePageDirectoryPointerTable] by temporarily mapping it.
+    fn get_top_level_table(&mut self) -> SmartHierarchicalTable<InactivePageDirectoryPointerTable> {
+        map_inactive_table(self.pdpt_physical_address)
+    }
+}
+
+impl InactiveHierarchyTrait for InactiveHierarchy {
+    /// Allocates the PDPT and the four directories, and makes the last entries
+    /// of the kernel directory recursive.
+    fn new() -> Result<Self, KernelError> {
+        // Allocate every table before taking ownership of any of them, so the frames
+        // already allocated are freed if we run out of memory.
+        let pdpt_frame = FrameAllocator::allocate_frame()?;
+        let directory_frames: [PhysicalMemRegion; PDPT_ENTRY_COUNT] = [
+            FrameAllocator::allocate_frame()?,
+            FrameAllocator::allocate_frame()?,
+            FrameAllocator::allocate_frame()?,
+            FrameAllocator::allocate_frame()?,
+        ];
+
+        let mut pageset = InactiveHierarchy {
+            pdpt_physical_address: pdpt_frame.address()
+        };
+        // don't deallocate it, it is our cr3 now.
+        ::core::mem::forget(pdpt_frame);
+
+        let mut directories = [PhysicalAddress(0); PDPT_ENTRY_COUNT];
+        for (directory, frame) in directories.iter_mut().zip(directory_frames.iter()) {
+            *directory = frame.address();
+        }
+        // don't deallocate them, they will be mapped in the pdpt.
+        ::core::mem::forget(directory_frames);
+
+        {
+            let mut pdpt = pageset.get_top_level_table();
+            pdpt.zero();
+            for (index, directory) in directories.iter().enumerate() {
+                // PDPT entries only accept the PRESENT flag, all the others are reserved.
+                pdpt.map_nth_entry(index, *directory, PaeEntryFlags::PRESENT);
+                pdpt.get_child_table(index).unwrap().zero();
+            }
+            let mut kernel_directory = pdpt.get_child_table(KERNELLAND_DIRECTORY).unwrap();
+            for (index, directory) in directories.iter().enumerate() {
+                kernel_directory.map_nth_entry(RECURSIVE_START_TABLE + index, *directory, directory_entry_flags(false));
+            }
+        }
+
+        Ok(pageset)
+    }
+
+    fn switch_to(&mut self) {
+        // Copy the kernel space tables
+        self.copy_active_kernel_space();
+        super::super::swap_cr3(self.pdpt_physical_address);
+        // Update the cr3 DOUBLE_FAULT_TSS will switch to when we double fault
+        // DOUBLE_FAULT_TASK should only be locked during init and update, and switch_to is not re-entrant.
+        crate::i386::gdt::DOUBLE_FAULT_TASK
+            .try_lock().expect("Cannot update DOUBLE_FAULT_TASK's cr3")
+            .cr3 = self.pdpt_physical_address.addr() as u32;
+    }
+
+    fn copy_active_kernel_space(&mut self) {
+        let mut pdpt = self.get_top_level_table();
+        let mut dir = pdpt.get_child_table(KERNELLAND_DIRECTORY).unwrap();
+        // holding the lock on the kernel memory gives us exclusive access to the active hierarchy.
+        let _memory = get_kernel_memory();
+        let mut active_pdpt = ActiveHierarchy.get_top_level_table();
+        let mut active_dir = active_pdpt.get_child_table(KERNELLAND_DIRECTORY).unwrap();
+        dir.entries()[KERNELLAND_START_TABLE..=KERNELLAND_END_TABLE]
+            .clone_from_slice(&active_dir.entries()[KERNELLAND_START_TABLE..=KERNELLAND_END_TABLE]);
+    }
+
+    fn is_currently_active(&self) -> bool {
+        super::super::read_cr3() == self.pdpt_physical_address
+    }
+
+    unsafe fn from_currently_active() -> Self {
+        InactiveHierarchy {
+            pdpt_physical_address: super::super::read_cr3(),
+        }
+    }
+}
+
+impl Drop for InactiveHierarchy {
+    /// When a process dies, its InactiveHierarchy is dropped.
+    /// The pages themselves have already been freed by the bookkeeping,
+    /// we just have to free the tables, the directories and the PDPT of this hierarchy.
+    ///
+    /// However we must free only the tables that map UserLand memory, as the ones mapping
+    /// KernelLand are shared with other processes and are still in use.
+    fn drop(&mut self) {
+        debug_assert!(!self.is_currently_active(), "Dropped the currently active paging hierarchy");
+
+        {
+            let mut pdpt = self.get_top_level_table();
+            // free the userland tables
+            for directory_index in 0..=USERLAND_END_DIRECTORY {
+                let mut directory = pdpt.get_child_table(directory_index).unwrap();
+                for table_entry in directory.entries().iter() {
+                    match table_entry.pointed_frame() {
+                        PageState::Available | PageState::Guarded => (),
+                        // a huge page is not a table, its frames are owned by the bookkeeping.
+                        PageState::Present(_) if table_entry.is_huge() => (),
+                        PageState::Present(paddr) => unsafe {
+                            // safe because they were existing frames, and not tracked by any one except the page tables.
+                            PhysicalMemRegion::reconstruct(paddr, PAGE_SIZE);
+                            // dropping the region deallocates it
+                        }
+                    }
+                }
+            }
+            // then the directories
+            for directory_entry in pdpt.entries().iter() {
+                if let PageState::Present(paddr) = directory_entry.pointed_frame() {
+                    unsafe {
+                        // safe because they were only tracked by the pdpt.
+                        PhysicalMemRegion::reconstruct(paddr, PAGE_SIZE);
+                    }
+                }
+            }
+        }
+        // and finally the pdpt
+        unsafe {
+            PhysicalMemRegion::reconstruct(self.pdpt_physical_address, PAGE_SIZE);
+        }
+    }
+}
@@ -113,6 +113,27 @@ impl UserspaceBookkeeping {
         }
     }
 
+    /// Returns a mutable reference to the mapping `address` falls into.
+    ///
+    /// Fails if there is no occupied mapping at `address`.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * mapping pointed to by address is vacant.
+    pub fn occupied_mapping_at_mut(&mut self, address: VirtualAddress) -> Result<&mut Mapping, KernelError> {
+        match self.mappings.range_mut(VirtualAddress(0)..=address).rev().next() {
+            // check cannot overflow
+            Some((_, m)) if m.length() - 1 + m.address() >= address => Ok(m),
+            _ => Err(KernelError::InvalidAddress { address: address.addr(), backtrace: Backtrace::new() })
+        }
+    }
+
+    /// Returns an iterator over the tracked mappings, in ascending address order.
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.values()
+    }
+
     /// Checks that a given range is unoccupied.
     ///
     /// # Errors
@@ -61,19 +61,23 @@ impl CrossProcessMapping {
     ///
     /// # Error
     ///
-    /// * Error if the mapping is not Shared, as only refcounted mappings can be owned.
+    /// * Error if the mapping is not Shared, Lazy or CopyOnWrite, as only refcounted mappings can be owned.
     /// * Error if `offset` + `len` > `mapping` length.
     /// * Error if `offset` + `len` would overflow.
     // todo: should be offset + (len - 1), but need to check that it wouldn't overflow in our function
     /// * Error if `len` is 0.
+    /// * Error if a page table could not be allocated to map it in KernelLand.
     ///
     /// # Panics
     ///
     /// * Panics if `mapping.phys_offset()` + `offset` overflows.
+    /// * Panics if the mapping is Lazy or CopyOnWrite, and the mirrored pages were not committed.
     pub fn mirror_mapping(mapping: &Mapping, offset: usize, len: usize) -> Result<CrossProcessMapping, KernelError> {
         // Ensure we have Shared frames.
         let frames = match mapping.frames() {
             MappingFrames::Shared(frames) => MappingFrames::Shared(frames.clone()),
+            MappingFrames::Lazy(frames) => MappingFrames::Lazy(frames.clone()),
+            MappingFrames::CopyOnWrite(pages) => MappingFrames::CopyOnWrite(pages.clone()),
             _ => return Err(KernelError::InvalidMemState { address: mapping.address(), ty: mapping.state().ty(), backtrace: Backtrace::new() })
         };
 
@@ -91,7 +95,7 @@ impl CrossProcessMapping {
         let new_mapping = Mapping::new(kernel_map_start, frames, full_offset, full_len, mapping.state().ty(), MappingAccessRights::k_rw())?;
         unsafe {
             // safe, the frames won't be dropped, they still are tracked by the userspace mapping.
-            kmem.map_frame_iterator_to(new_mapping.frames_it(), kernel_map_start, MappingAccessRights::k_rw());
+            kmem.map_frame_iterator_to(new_mapping.frames_it(), kernel_map_start, MappingAccessRights::k_rw())?;
         }
         Ok(CrossProcessMapping {
             kernel_address: kernel_map_start + (offset % PAGE_SIZE),
@@ -114,6 +118,6 @@ impl CrossProcessMapping {
 impl Drop for CrossProcessMapping {
     fn drop(&mut self) {
         // don't dealloc the frames, they are tracked by the Arc.
-        get_kernel_memory().unmap_no_dealloc(self.mapping.address(), self.mapping.length())
+        get_kernel_memory().unmap_whole_mapping_no_dealloc(self.mapping.address(), self.mapping.length());
     }
 }
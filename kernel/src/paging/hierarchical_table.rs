@@ -1,10 +1,11 @@
 //! Arch-independent traits for architectures that implement paging as a hierarchy of page tables
 
 // what the architecture code still has define
-use super::arch::{PAGE_SIZE, ENTRY_COUNT};
+use super::arch::PAGE_SIZE;
 use super::MappingAccessRights;
 
 use crate::mem::{VirtualAddress, PhysicalAddress};
+use crate::error::KernelError;
 use crate::utils::align_up_checked;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
@@ -70,7 +71,7 @@ impl<T> PageState<T> {
 pub trait HierarchicalEntry {
 
     /// An entry comports some flags. They are often represented by a structure.
-    type EntryFlagsType: From<MappingAccessRights>;
+    type EntryFlagsType: From<MappingAccessRights> + Copy;
 
     /// Is the entry unused ?
     fn is_unused(&self) -> bool;
@@ -92,6 +93,21 @@ pub trait HierarchicalEntry {
 
     /// Make this entry a page guard
     fn set_guard(&mut self);
+
+    /// Is the entry a huge page, directly mapping a physical region from a parent table ?
+    ///
+    /// Only meaningful for entries of a parent table.
+    fn is_huge(&self) -> bool { false }
+
+    /// Sets the entry as a huge page, mapping a physical region the size of
+    /// a whole child table.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the entry does not support huge pages.
+    fn set_huge(&mut self, _frame: PhysicalAddress, _flags: Self::EntryFlagsType) {
+        panic!("This kind of entry does not support huge pages");
+    }
 }
 
 /// A hierarchical paging is composed of tables. All tables must implement the following trait
@@ -105,7 +121,10 @@ pub trait HierarchicalTable {
     type CacheFlusherType : PagingCacheFlusher;
     /// If we're a parent table, the type of our child tables.
     /// If we're not a parent, this type will never be used and you can set it to Self.
-    type ChildTableType : HierarchicalTable;
+    ///
+    /// Child tables have the same kind of entries as their parent, so that a huge page
+    /// can be split in a child table mapping the same region with the same flags.
+    type ChildTableType : HierarchicalTable<EntryType = Self::EntryType>;
 
     /// gets the raw array of entries
     fn entries(&mut self) -> &mut [Self::EntryType];
@@ -132,6 +151,12 @@ pub trait HierarchicalTable {
         Self::CacheFlusherType::flush_whole_cache();
     }
 
+    /// Creates a huge page mapping on the nth entry of a parent table
+    fn map_nth_entry_huge(&mut self, entry: usize, paddr: PhysicalAddress, flags: <Self::EntryType as HierarchicalEntry>::EntryFlagsType) {
+        self.entries()[entry].set_huge(paddr, flags);
+        Self::CacheFlusherType::flush_whole_cache();
+    }
+
     /// Marks the nth entry as guard page
     fn guard_nth_entry(&mut self, entry: usize) {
         self.entries()[entry].set_guard();
@@ -148,10 +173,27 @@ pub trait HierarchicalTable {
     /// Level 0 = simple table, level 1 = parent of simple tables, level 2 = parent of parent of simple tables, ...
     fn table_level() -> usize;
 
+    /// The number of entries this table has.
+    ///
+    /// Not necessarily the same for every level of the hierarchy, e.g. a PAE page directory
+    /// pointer table only has 4 entries.
+    fn entry_count() -> usize;
+
+    /// Can the entries of this parent table be huge pages, mapping a whole
+    /// `entry_vm_size()` physical region instead of pointing to a child table ?
+    ///
+    /// Defaults to false.
+    fn huge_entries_supported() -> bool { false }
+
     /// the size an entry in this table spans in virtual memory.
-    /// should be something like PAGE_SIZE * (ENTRY_COUNT ^ table level)
+    /// PAGE_SIZE for a simple table, the whole span of a child table for a parent table.
     fn entry_vm_size() -> usize {
-        ENTRY_COUNT.pow(Self::table_level() as u32) * PAGE_SIZE
+        if Self::table_level() == 0 {
+            PAGE_SIZE
+        } else {
+            <Self::ChildTableType as HierarchicalTable>::entry_count()
+                * <Self::ChildTableType as HierarchicalTable>::entry_vm_size()
+        }
     }
 
     /// Gets a reference to a child page table.
@@ -163,25 +205,33 @@ pub trait HierarchicalTable {
 
     /// Allocates a child page table, zero it and add an entry pointing to it.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: no frame was available for the child table.
+    ///
     /// # Panics
     ///
     /// Should panic if called on a table which isn't a parent table.
     /// Should panic if entry was not available.
-    // todo: paging: return PhysicalMemoryExhaustion when allocating page table failed
-    // body: Right now we panic. This is terrible.
-    fn create_child_table(&mut self, index: usize) -> SmartHierarchicalTable<Self::ChildTableType>;
+    fn create_child_table(&mut self, index: usize) -> Result<SmartHierarchicalTable<Self::ChildTableType>, KernelError>;
 
     /// Gets the child page table at given index, or creates it if it does not exist
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: no frame was available for the child table.
+    ///
     /// # Panics
     ///
     /// Should panic if called on a table which isn't a parent table.
-    fn get_child_table_or_create(&mut self, index: usize) -> PageState<SmartHierarchicalTable<Self::ChildTableType>> {
+    /// Panics if the entry is a huge page.
+    fn get_child_table_or_create(&mut self, index: usize) -> Result<PageState<SmartHierarchicalTable<Self::ChildTableType>>, KernelError> {
         assert!(Self::table_level() >= 1, "get_child_table_or_create() called on non-parent table");
+        assert!(!self.entries()[index].is_huge(), "get_child_table_or_create() called on a huge page");
         match self.entries()[index].pointed_frame() {
-            PageState::Present(_) => self.get_child_table(index),
-            PageState::Available => PageState::Present(self.create_child_table(index)),
-            PageState::Guarded => PageState::Guarded
+            PageState::Present(_) => Ok(self.get_child_table(index)),
+            PageState::Available => Ok(PageState::Present(self.create_child_table(index)?)),
+            PageState::Guarded => Ok(PageState::Guarded)
         }
     }
 }
@@ -243,6 +293,153 @@ impl<'a, T: HierarchicalTable> Drop for SmartHierarchicalTable<'a, T> {
     }
 }
 
+/// The frames a mapping is created from.
+///
+/// Lets [TableHierarchy::map_to_from_iterator] and [TableHierarchy::map_contiguous_to]
+/// share the same recursive mapping function, the latter being able to hand out
+/// whole huge pages.
+trait FrameSource {
+    /// Checks if all the frames have been consumed.
+    fn is_depleted(&mut self) -> bool;
+
+    /// Consumes the next frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the source is depleted.
+    fn next_frame(&mut self) -> PhysicalAddress;
+
+    /// Consumes the next `size` bytes of frames, if they are physically contiguous
+    /// and the first one is aligned on `size`. Returns the address of the first frame.
+    fn next_huge_frame(&mut self, _size: usize) -> Option<PhysicalAddress> { None }
+}
+
+impl<I: Iterator<Item=PhysicalAddress>> FrameSource for Peekable<I> {
+    fn is_depleted(&mut self) -> bool { self.peek().is_none() }
+
+    fn next_frame(&mut self) -> PhysicalAddress { self.next().unwrap() }
+}
+
+/// A physically contiguous region, consumed from its start.
+struct ContiguousFrames {
+    /// The first frame not consumed yet.
+    address: PhysicalAddress,
+    /// The length left to consume.
+    length: usize,
+}
+
+impl FrameSource for ContiguousFrames {
+    fn is_depleted(&mut self) -> bool { self.length == 0 }
+
+    fn next_frame(&mut self) -> PhysicalAddress {
+        assert!(self.length >= PAGE_SIZE, "ContiguousFrames is depleted");
+        let frame = self.address;
+        self.address = self.address + PAGE_SIZE;
+        self.length -= PAGE_SIZE;
+        frame
+    }
+
+    fn next_huge_frame(&mut self, size: usize) -> Option<PhysicalAddress> {
+        if self.length < size || self.address.addr() % size != 0 {
+            return None;
+        }
+        let frame = self.address;
+        self.address = self.address + size;
+        self.length -= size;
+        Some(frame)
+    }
+}
+
+/// Maps the frames of `frames` from `start_address`, for [TableHierarchy::map_to_from_iterator]
+/// and [TableHierarchy::map_contiguous_to].
+///
+/// Huge pages are used wherever the table supports them, the virtual address is aligned
+/// on the span of an entry, and `frames` can hand out a whole huge frame.
+///
+/// # Errors
+///
+/// * `OutOfMemory`: a page table could not be allocated. The pages that were already
+///   mapped are unmapped before returning.
+///
+/// # Panics
+///
+/// Panics if address is not page-aligned.
+/// Panics if any encountered entry was already in use
+fn map_from_source<H, F>(hierarchy: &mut H,
+                         frames: &mut F,
+                         start_address: VirtualAddress,
+                         flags: MappingAccessRights) -> Result<(), KernelError>
+where H: TableHierarchy + ?Sized,
+      F: FrameSource
+{
+    assert_eq!(start_address.addr() % PAGE_SIZE, 0, "Address is not page aligned");
+
+    /// Delay work to child tables, and map it ourselves when we have no more children.
+    /// Counts the number of pages mapped in `mapped`.
+    /// Panics if any entry was already in use
+    fn rec_map_to<T, F>(table: &mut SmartHierarchicalTable<'_, T>,
+                        frames: &mut F,
+                        start_address: usize,
+                        flags: MappingAccessRights,
+                        mapped: &mut usize) -> Result<(), KernelError>
+    where T: HierarchicalTable,
+          F: FrameSource
+    {
+        let entry_offset : usize = start_address / T::entry_vm_size();
+        assert!(entry_offset < T::entry_count(), "rec_map_to computed an entry offset > entry count,
+                                            is your arch-specific paging valid ?");
+        // our first child table will have to map to it's nth entry
+        let mut child_start_address = start_address % T::entry_vm_size();
+
+        for index in entry_offset..T::entry_count() {
+            if frames.is_depleted() { return Ok(()); }
+            let is_huge = table.entries()[index].is_huge();
+            match (T::table_level(), table.entries()[index].pointed_frame()) {
+                (0, PageState::Available) => {
+                    // we're a simple table, map it ourselves.
+                    table.map_nth_entry(index, frames.next_frame(),
+                                        <T::EntryType as HierarchicalEntry>::EntryFlagsType::from(flags));
+                    *mapped += 1;
+                },
+                (_, PageState::Present(_)) if is_huge => { panic!("rec_map_to was asked to map a non-available entry"); },
+                (level, PageState::Available) | (level, PageState::Present(_)) if level > 0 => {
+                    let huge_frame = if child_start_address == 0 && T::huge_entries_supported()
+                        && table.entries()[index].is_unused() {
+                        frames.next_huge_frame(T::entry_vm_size())
+                    } else {
+                        None
+                    };
+                    if let Some(huge_frame) = huge_frame {
+                        // map the whole entry as a huge page.
+                        table.map_nth_entry_huge(index, huge_frame,
+                                                 <T::EntryType as HierarchicalEntry>::EntryFlagsType::from(flags));
+                        *mapped += T::entry_vm_size() / PAGE_SIZE;
+                    } else {
+                        // we're a parent table, delay work to our childs !
+                        let mut child_table = table.get_child_table_or_create(index)?.unwrap();
+                        rec_map_to(&mut child_table, frames, child_start_address, flags, mapped)?;
+                    }
+                    // all other child tables will start mapping from their first entry
+                    child_start_address = 0;
+                },
+                _ => { panic!("rec_map_to was asked to map a non-available entry"); }
+            }
+        }
+        Ok(())
+    }
+
+    let mut mapped = 0;
+    let res = rec_map_to(&mut hierarchy.get_top_level_table(),
+                      frames,
+                      start_address.addr(), flags, &mut mapped);
+    if res.is_err() && mapped != 0 {
+        // don't leave a partial mapping behind.
+        hierarchy.unmap(start_address, mapped * PAGE_SIZE, |_| ())
+            .expect("Unmapping the pages we just mapped never splits a huge page");
+    }
+    res
+}
+
 /// A trait operating on a whole hierarchy of tables.
 ///
 /// Implementer only has to provide a function to map the top level table,
@@ -269,6 +466,11 @@ pub trait TableHierarchy {
     /// `frames_iterator` every time.
     /// When `frames_iterator` is depleted, the mapping stops.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated. The pages that were already
+    ///   mapped are unmapped before returning.
+    ///
     /// # Panics
     ///
     /// Panics if address is not page-aligned.
@@ -276,49 +478,39 @@ pub trait TableHierarchy {
     fn map_to_from_iterator<I>(&mut self,
                                frames_iterator: I,
                                start_address: VirtualAddress,
-                               flags: MappingAccessRights)
+                               flags: MappingAccessRights) -> Result<(), KernelError>
     where I: Iterator<Item=PhysicalAddress>
     {
-        assert_eq!(start_address.addr() % PAGE_SIZE, 0, "Address is not page aligned");
-
-        /// Delay work to child tables, and map it ourselves when we have no more children.
-        /// Panics if any entry was already in use
-        fn rec_map_to<T, I>(table: &mut SmartHierarchicalTable<'_, T>,
-                            frames_iterator: &mut Peekable<I>,
-                            start_address: usize,
-                            flags: MappingAccessRights)
-        where T: HierarchicalTable,
-              I: Iterator<Item=PhysicalAddress>
-        {
-            let entry_offset : usize = start_address / T::entry_vm_size();
-            assert!(entry_offset < ENTRY_COUNT, "rec_map_to computed an entry offset > ENTRY_COUNT,
-                                                is your arch-specific paging valid ?");
-            // our first child table will have to map to it's nth entry
-            let mut child_start_address = start_address % T::entry_vm_size();
-
-            for index in entry_offset..ENTRY_COUNT {
-                if frames_iterator.peek().is_none() { return; }
-                match (T::table_level(), table.entries()[index].pointed_frame()) {
-                    (0, PageState::Available) => {
-                        // we're a simple table, map it ourselves.
-                        table.map_nth_entry(index, frames_iterator.next().unwrap(),
-                                            <T::EntryType as HierarchicalEntry>::EntryFlagsType::from(flags));
-                    },
-                    (level, PageState::Available) | (level, PageState::Present(_)) if level > 0 => {
-                        // we're a parent table, delay work to our childs !
-                        let mut child_table = table.get_child_table_or_create(index).unwrap();
-                        rec_map_to(&mut child_table, frames_iterator, child_start_address, flags);
-                        // all other child tables will start mapping from their first entry
-                        child_start_address = 0;
-                    },
-                    _ => { panic!("rec_map_to was asked to map a non-available entry"); }
-                }
-            }
-        }
+        map_from_source(self, &mut frames_iterator.peekable(), start_address, flags)
+    }
 
-        rec_map_to(&mut self.get_top_level_table(),
-                          &mut frames_iterator.peekable(),
-                          start_address.addr(), flags)
+    /// Maps a physically contiguous region in the page tables with the given flags.
+    ///
+    /// Behaves like [map_to_from_iterator], but wherever both `start_address + offset` and
+    /// `phys_start + offset` are aligned on the span of a parent table entry supporting huge
+    /// pages, and the remaining length covers it, a single huge page is mapped instead
+    /// of a child table.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated. The pages that were already
+    ///   mapped are unmapped before returning.
+    ///
+    /// # Panics
+    ///
+    /// Panics if address, phys_start or length is not page-aligned.
+    /// Panics if any encountered entry was already in use
+    ///
+    /// [map_to_from_iterator]: TableHierarchy::map_to_from_iterator
+    fn map_contiguous_to(&mut self,
+                         phys_start: PhysicalAddress,
+                         start_address: VirtualAddress,
+                         length: usize,
+                         flags: MappingAccessRights) -> Result<(), KernelError>
+    {
+        assert_eq!(phys_start.addr() % PAGE_SIZE, 0, "Physical address is not page aligned");
+        assert_eq!(length            % PAGE_SIZE, 0, "Length is not page aligned");
+        map_from_source(self, &mut ContiguousFrames { address: phys_start, length }, start_address, flags)
     }
 
     /// Creates a span of guard pages
@@ -326,12 +518,17 @@ pub trait TableHierarchy {
     /// This function will avoid creating child tables filled only with guarded entry,
     /// and instead guard a single entry in the parent. This is called a HUGE guard.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated. The pages that were already
+    ///   guarded are made available again before returning.
+    ///
     /// # Panics
     ///
     /// Panics if any encountered entry was already in use
     /// Panics if address is not page-aligned.
     /// Panics if length is not page-aligned.
-    fn guard(&mut self, address: VirtualAddress, mut length: usize) {
+    fn guard(&mut self, address: VirtualAddress, mut length: usize) -> Result<(), KernelError> {
         assert_eq!(address.addr() % PAGE_SIZE, 0, "Guarding : address is not page aligned");
         assert_eq!(length         % PAGE_SIZE, 0, "Guarding : length is not page aligned");
 
@@ -339,22 +536,24 @@ pub trait TableHierarchy {
         /// Panics if any entry was already in use
         fn rec_guard<T>(table : &mut SmartHierarchicalTable<'_, T>,
                         start_address: usize,
-                        length: &mut usize)
+                        length: &mut usize) -> Result<(), KernelError>
         where T: HierarchicalTable
         {
             let start_entry: usize = start_address / T::entry_vm_size();
-            assert!(start_entry < ENTRY_COUNT, "rec_guard computed an entry offset > ENTRY_COUNT,
+            assert!(start_entry < T::entry_count(), "rec_guard computed an entry offset > entry count,
                                                 is your arch-specific paging valid ?");
             let mut child_start_address = start_address % T::entry_vm_size();
-            for entry_index in start_entry..ENTRY_COUNT {
-                if *length == 0 { return; }
+            for entry_index in start_entry..T::entry_count() {
+                if *length == 0 { return Ok(()); }
+                let is_huge = table.entries()[entry_index].is_huge();
                 match (T::table_level(), table.entries()[entry_index].pointed_frame()) {
                     (_, PageState::Guarded) => panic!("rec_guard encountered an already guarded entry"),
                     (0, PageState::Present(_)) => panic!("rec_guard was asked to guard a non-available entry"),
+                    (_, PageState::Present(_)) if is_huge => panic!("rec_guard was asked to guard a non-available entry"),
                     (_, PageState::Present(_)) => {
                         // delay work to our child
                         let mut child_table = table.get_child_table(entry_index).unwrap();
-                        rec_guard(&mut child_table, child_start_address, length);
+                        rec_guard(&mut child_table, child_start_address, length)?;
                     },
                     (_, PageState::Available) if *length >= T::entry_vm_size() && child_start_address == 0 => {
                         // map a (huge ?) guard here
@@ -366,16 +565,24 @@ pub trait TableHierarchy {
                         assert!(T::table_level() > 0, "rec_guard encountered an error,
                                                            is your arch-specific paging valid ?");
                         // create a child table, and recurse into it.
-                        let mut child_table = table.create_child_table(entry_index);
-                        rec_guard(&mut child_table, child_start_address, length);
+                        let mut child_table = table.create_child_table(entry_index)?;
+                        rec_guard(&mut child_table, child_start_address, length)?;
                     }
                 }
                 // all other children will start guarding from their first entry
                 child_start_address = 0;
             }
+            Ok(())
         }
 
-        rec_guard(&mut self.get_top_level_table(), address.addr(), &mut length)
+        let total_length = length;
+        let res = rec_guard(&mut self.get_top_level_table(), address.addr(), &mut length);
+        if res.is_err() && length != total_length {
+            // don't leave a partial guard behind.
+            self.unmap(address, total_length - length, |_| ())
+                .expect("Unmapping the guards we just mapped never splits a huge guard");
+        }
+        res
     }
 
     /// Unmaps a range of virtual address.
@@ -384,21 +591,92 @@ pub trait TableHierarchy {
     /// If unmap encounters a guard page, it is unmapped, and the closure is not called.
     /// If unmap encounters a HUGE guard page, it decides if it must split it and might
     /// create a child table which is only partly guarded.
+    /// Huge pages are handled the same way: the closure is called on every frame of a
+    /// huge page that is entirely unmapped, and a huge page that is only partly unmapped is
+    /// first split in a child table mapping the same frames.
     /// If unmap encounters a non-mapped entry, it panics, as this is probably a bug.
     ///
+    /// Only the huge pages and huge guards at either end of the range can have to be split.
+    /// They are split before anything is unmapped, so that unmap either unmaps the whole range,
+    /// or fails leaving it mapped.
+    ///
     /// If a table is left empty after an unmap, it is never deallocated, and left as is.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a HUGE guard or a huge page must be split, and no frame is available
+    ///    for the child table. Nothing was unmapped. The huge entries that were already split
+    ///    are left as child tables, mapping or guarding the same range as before.
+    ///
     /// # Panics
     ///
     /// Panics if encounters any entry that was not mapped.
     /// Panics if address is not page-aligned.
     /// Panics if length  is not page-aligned.
-    fn unmap<C>(&mut self, address: VirtualAddress, mut length: usize, mut callback: C)
+    fn unmap<C>(&mut self, address: VirtualAddress, mut length: usize, mut callback: C) -> Result<(), KernelError>
     where C: FnMut(PhysicalAddress)
     {
         assert_eq!(address.addr() % PAGE_SIZE, 0, "Address is not page aligned");
         assert_eq!(length         % PAGE_SIZE, 0, "Length is not page aligned");
 
+        /// Splits the huge page or HUGE guard `address` falls in, if it does not start at `address`,
+        /// so that the range can be unmapped up to or from `address` without touching the rest of it.
+        fn rec_split<T>(table: &mut SmartHierarchicalTable<'_, T>,
+                        address: usize) -> Result<(), KernelError>
+        where T: HierarchicalTable
+        {
+            let entry_index = address / T::entry_vm_size();
+            if T::table_level() == 0 || entry_index >= T::entry_count() {
+                // regular pages never need to be split, and the end of the range can be the
+                // end of the address space.
+                return Ok(());
+            }
+            let child_start_address = address % T::entry_vm_size();
+            let is_huge = table.entries()[entry_index].is_huge();
+            match table.entries()[entry_index].pointed_frame() {
+                // rec_unmap will panic on it.
+                PageState::Available => Ok(()),
+                PageState::Present(_) if !is_huge => {
+                    // recurse into child table
+                    let mut child_table = table.get_child_table(entry_index).unwrap();
+                    rec_split(&mut child_table, child_start_address)
+                },
+                // the huge page or huge guard starts here, it is either wholly unmapped or untouched.
+                _ if child_start_address == 0 => Ok(()),
+                PageState::Present(paddr) => {
+                    // we have to split the huge page
+                    let flags = table.entries()[entry_index].flags();
+                    table.unmap_nth_entry(entry_index);
+                    let mut child_table = match table.create_child_table(entry_index) {
+                        Ok(child_table) => child_table,
+                        Err(err) => {
+                            // put the huge page back.
+                            table.map_nth_entry_huge(entry_index, paddr, flags);
+                            return Err(err)
+                        }
+                    };
+                    for (index, offset) in (0..T::entry_vm_size()).step_by(PAGE_SIZE).enumerate() {
+                        child_table.map_nth_entry(index, paddr + offset, flags);
+                    }
+                    Ok(())
+                },
+                PageState::Guarded => {
+                    // we have to split the huge guard
+                    table.unmap_nth_entry(entry_index);
+                    let mut child_table = match table.create_child_table(entry_index) {
+                        Ok(child_table) => child_table,
+                        Err(err) => {
+                            // put the huge guard back.
+                            table.guard_nth_entry(entry_index);
+                            return Err(err)
+                        }
+                    };
+                    child_table.guard_all_entries();
+                    rec_split(&mut child_table, child_start_address)
+                }
+            }
+        }
+
         /// Delay work to child tables, and unmap it ourselves when we have no more children.
         fn rec_unmap<T, C>(table: &mut SmartHierarchicalTable<'_, T>,
                         start_address: usize,
@@ -408,12 +686,13 @@ pub trait TableHierarchy {
               C: FnMut(PhysicalAddress)
         {
             let start_offset: usize = start_address / T::entry_vm_size();
-            assert!(start_offset < ENTRY_COUNT, "rec_unmap computed an entry offset > ENTRY_COUNT,
+            assert!(start_offset < T::entry_count(), "rec_unmap computed an entry offset > entry count,
                                                  is your arch-specific paging valid ?");
             let mut child_start_address = start_address % T::entry_vm_size();
 
-            for entry_index in start_offset..ENTRY_COUNT {
+            for entry_index in start_offset..T::entry_count() {
                 if *length == 0 { return; }
+                let is_huge = table.entries()[entry_index].is_huge();
                 match (T::table_level(), table.entries()[entry_index].pointed_frame()) {
                     (_, PageState::Available) => panic!("unmap encountered a non-mapped entry, is this a bug ?"),
                     (0, PageState::Present(paddr)) => {
@@ -422,22 +701,27 @@ pub trait TableHierarchy {
                         callback(paddr);
                         *length -= T::entry_vm_size();
                     },
+                    (_, PageState::Present(paddr)) if is_huge => {
+                        // unmap the whole huge page and call callback on each of its frames
+                        assert!(*length >= T::entry_vm_size() && child_start_address == 0,
+                                "rec_unmap encountered a huge page rec_split did not split");
+                        table.unmap_nth_entry(entry_index);
+                        for offset in (0..T::entry_vm_size()).step_by(PAGE_SIZE) {
+                            callback(paddr + offset);
+                        }
+                        *length -= T::entry_vm_size();
+                    },
                     (_, PageState::Present(_)) => {
                         // recurse into child table
                         let mut child_table = table.get_child_table(entry_index).unwrap();
                         rec_unmap(&mut child_table, child_start_address, length, callback)
                     },
-                    (_, PageState::Guarded) if *length >= T::entry_vm_size() => {
+                    (_, PageState::Guarded) => {
                         // make the (huge ?) guard available
+                        assert!(*length >= T::entry_vm_size() && child_start_address == 0,
+                                "rec_unmap encountered a huge guard rec_split did not split");
                         table.unmap_nth_entry(entry_index);
                         *length -= T::entry_vm_size();
-                    },
-                    (_, PageState::Guarded) => {
-                        // we have to split the huge guard
-                        table.unmap_nth_entry(entry_index);
-                        let mut child_table = table.create_child_table(entry_index);
-                        child_table.guard_all_entries();
-                        rec_unmap(&mut child_table, child_start_address, length, callback)
                     }
                 }
                 // next child table will start on its first entry
@@ -445,11 +729,20 @@ pub trait TableHierarchy {
             }
         }
 
+        if length == 0 {
+            return Ok(());
+        }
+        rec_split(&mut self.get_top_level_table(), address.addr())?;
+        if let Some(end_address) = address.addr().checked_add(length) {
+            rec_split(&mut self.get_top_level_table(), end_address)?;
+        }
         rec_unmap(&mut self.get_top_level_table(), address.addr(), &mut length, &mut callback);
+        Ok(())
     }
 
     /// Iters in the page tables, applying closure on every mapping.
     /// On every entry, the closure will be called with its state and the length it maps.
+    /// Huge pages are reported as a run of regular pages.
     ///
     /// # Panics
     ///
@@ -470,13 +763,22 @@ pub trait TableHierarchy {
               C: FnMut(PageState<PhysicalAddress>, usize)
         {
             let start_offset: usize = start_address / T::entry_vm_size();
-            assert!(start_offset < ENTRY_COUNT, "rec_iter computed an entry offset > ENTRY_COUNT,
+            assert!(start_offset < T::entry_count(), "rec_iter computed an entry offset > entry count,
                                                  is your arch-specific paging valid ?");
             let mut child_start_address = start_address % T::entry_vm_size();
 
-            for entry_index in start_offset..ENTRY_COUNT {
+            for entry_index in start_offset..T::entry_count() {
                 if *length == 0 { return; }
+                let is_huge = table.entries()[entry_index].is_huge();
                 match (T::table_level(), table.entries()[entry_index].pointed_frame()) {
+                    (_, PageState::Present(paddr)) if is_huge => {
+                        // iter the huge page as if it was made of regular pages
+                        for offset in (child_start_address..T::entry_vm_size()).step_by(PAGE_SIZE) {
+                            if *length == 0 { return; }
+                            callback(PageState::Present(paddr + offset), PAGE_SIZE);
+                            *length = length.saturating_sub(PAGE_SIZE);
+                        }
+                    },
                     (level, PageState::Present(_)) if level != 0 => {
                         // recurse into child table
                         let mut child_table = table.get_child_table(entry_index).unwrap();
@@ -546,17 +848,19 @@ pub trait TableHierarchy {
             while {
                 next_entry_index = (hole.start_addr.saturating_add(hole.len) - table_addr) / T::entry_vm_size();
 
-                next_entry_index < ENTRY_COUNT // does this still concern my table ?
+                next_entry_index < T::entry_count() // does this still concern my table ?
                 && hole.len < desired_length // are we done yet ?
                 && hole.start_addr.checked_add(desired_length) // is length still obtainable ?
                     .filter(|minimun_end| *minimun_end <= end_addr).is_some() }
             {
-                match (T::table_level(), table.entries()[next_entry_index].pointed_frame()) {
+                // a huge page is as occupied as a regular one
+                let is_leaf = T::table_level() == 0 || table.entries()[next_entry_index].is_huge();
+                match (is_leaf, table.entries()[next_entry_index].pointed_frame()) {
                     (_, PageState::Available) => {
                         // hole is still growing
                         hole.len += T::entry_vm_size();
                     },
-                    (0, PageState::Present(_)) | (_, PageState::Guarded) => {
+                    (true, PageState::Present(_)) | (_, PageState::Guarded) => {
                         // hole was not big enough :(
                         // start a new hole on the next aligned address
                         hole.start_addr = (hole.start_addr + hole.len)
@@ -569,7 +873,7 @@ pub trait TableHierarchy {
                         // the checks will see that desired_length is no longer obtainable, and return.
                         hole.len = 0;
                     },
-                    (_, PageState::Present(_)) => {
+                    (false, PageState::Present(_)) => {
                         // we must look into child table
                         let mut child_table = table.get_child_table(next_entry_index).unwrap();
                         let child_table_addr = table_addr + next_entry_index * T::entry_vm_size();
@@ -614,7 +918,11 @@ pub trait TableHierarchy {
 pub trait InactiveHierarchyTrait : TableHierarchy {
     /// Creates a hierarchy. Allocates at least a top level directory,
     /// makes all its entries unmapped, and makes its last entry recursive.
-    fn new() -> Self;
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: cannot allocate the tables of the hierarchy.
+    fn new() -> Result<Self, KernelError> where Self: Sized;
 
     /// Switches to this hierarchy.
     ///
@@ -23,7 +23,7 @@
 //! current process yet.
 
 use super::lands::{KernelLand, RecursiveTablesLand, VirtualSpaceLand};
-use super::arch::{PAGE_SIZE, ActiveHierarchy};
+use super::arch::{PAGE_SIZE, ActiveHierarchy, large_page_size};
 use super::hierarchical_table::{TableHierarchy, PageState};
 use super::MappingAccessRights;
 use crate::mem::{VirtualAddress, PhysicalAddress};
@@ -31,6 +31,7 @@ use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTr
                       mark_frame_bootstrap_allocated};
 use crate::sync::{SpinLockIRQ, SpinLockIRQGuard};
 use crate::error::KernelError;
+use crate::utils::align_up_checked;
 use failure::Backtrace;
 
 /// A struct that acts on KernelLand and RecursiveTablesLand.
@@ -74,26 +75,58 @@ impl KernelMemory {
 
     /// Maps a single physical regions to a given virtual address.
     ///
+    /// Parts of the region that are aligned on a large page both physically and virtually
+    /// are mapped with large pages.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated. `phys` is dropped.
+    ///
     /// # Panics
     ///
     /// Panics if virtual region is not in KernelLand.
     // todo check va alignment
-    pub fn map_phys_region_to(&mut self, phys: PhysicalMemRegion, address: VirtualAddress, flags: MappingAccessRights) {
+    pub fn map_phys_region_to(&mut self, phys: PhysicalMemRegion, address: VirtualAddress, flags: MappingAccessRights) -> Result<(), KernelError> {
         assert!(KernelLand::contains_region(address, phys.size()));
-        self.tables.map_to_from_iterator(phys.into_iter(), address, flags);
+        self.tables.map_contiguous_to(phys.address(), address, phys.size(), flags)?;
         // physical region must not be deallocated while it is mapped
         ::core::mem::forget(phys);
+        Ok(())
     }
 
     /// Maps a single physical region anywhere.
     ///
-    /// # Panics
+    /// If the region contains a whole large page, it is mapped at an address with the same
+    /// offset in a large page as the region, so it can be mapped with large pages.
     ///
-    /// Panics if encounters virtual space exhaustion.
-    pub fn map_phys_region(&mut self, phys: PhysicalMemRegion, flags: MappingAccessRights) -> VirtualAddress {
-        let va = self.find_virtual_space(phys.size()).unwrap();
-        self.map_phys_region_to(phys, va, flags);
-        va
+    /// # Errors
+    ///
+    /// * `VirtualMemoryExhaustion`: no hole big enough was found in KernelLand.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    ///
+    /// In both cases `phys` is dropped.
+    pub fn map_phys_region(&mut self, phys: PhysicalMemRegion, flags: MappingAccessRights) -> Result<VirtualAddress, KernelError> {
+        let va = self.find_virtual_space_for_phys_region(&phys)?;
+        self.map_phys_region_to(phys, va, flags)?;
+        Ok(va)
+    }
+
+    /// Finds a hole in the virtual space to map `phys`, at the same offset in a large page as
+    /// `phys` if it contains a whole large page. Falls back to any hole big enough.
+    fn find_virtual_space_for_phys_region(&mut self, phys: &PhysicalMemRegion) -> Result<VirtualAddress, KernelError> {
+        let start = phys.address().addr();
+        if let Some(large_page) = large_page_size() {
+            let contains_large_page = align_up_checked(start, large_page)
+                .and_then(|aligned| aligned.checked_add(large_page))
+                .map_or(false, |end| end - start <= phys.size());
+            if contains_large_page {
+                let offset = start % large_page;
+                if let Ok(va) = self.find_virtual_space_aligned(offset + phys.size(), large_page) {
+                    return Ok(va + offset);
+                }
+            }
+        }
+        self.find_virtual_space(phys.size())
     }
 
     /// Maps a list of physical region anywhere.
@@ -102,14 +135,15 @@ impl KernelMemory {
     ///
     /// This function cannot ensure that the frames won't be dropped while still mapped.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if encounters virtual space exhaustion.
-    pub(super) unsafe fn map_phys_regions(&mut self, phys: &[PhysicalMemRegion], flags: MappingAccessRights) -> VirtualAddress {
+    /// * `VirtualMemoryExhaustion`: no hole big enough was found in KernelLand.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    pub(super) unsafe fn map_phys_regions(&mut self, phys: &[PhysicalMemRegion], flags: MappingAccessRights) -> Result<VirtualAddress, KernelError> {
         let length = phys.iter().flatten().count() * PAGE_SIZE;
-        let va = self.find_virtual_space(length).unwrap();
-        self.tables.map_to_from_iterator(phys.iter().flatten(), va, flags);
-        va
+        let va = self.find_virtual_space(length)?;
+        self.tables.map_to_from_iterator(phys.iter().flatten(), va, flags)?;
+        Ok(va)
     }
 
     /// Maps a list of physical region yielded by an iterator.
@@ -118,17 +152,20 @@ impl KernelMemory {
     ///
     /// This function cannot ensure that the frames won't be dropped while still mapped.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated.
+    ///
     /// # Panics
     ///
     /// Panics if virtual region is not in KernelLand.
-    /// Panics if encounters virtual space exhaustion.
     // todo check va alignment
-    pub(super) unsafe fn map_frame_iterator_to<I>(&mut self, iterator: I, address: VirtualAddress, flags: MappingAccessRights)
+    pub(super) unsafe fn map_frame_iterator_to<I>(&mut self, iterator: I, address: VirtualAddress, flags: MappingAccessRights) -> Result<(), KernelError>
     where I: Iterator<Item=PhysicalAddress> + Clone
     {
         assert!(KernelLand::contains_region(address,
                                             iterator.clone().count() * PAGE_SIZE));
-        self.tables.map_to_from_iterator(iterator, address, flags);
+        self.tables.map_to_from_iterator(iterator, address, flags)
     }
 
     /// Maps a list of physical region yielded by the iterator.
@@ -138,77 +175,85 @@ impl KernelMemory {
     ///
     /// This function cannot ensure that the frames won't be dropped while still mapped.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if encounters virtual space exhaustion.
-    pub(super) unsafe fn map_frame_iterator<I>(&mut self, iterator: I, flags: MappingAccessRights) -> VirtualAddress
+    /// * `VirtualMemoryExhaustion`: no hole big enough was found in KernelLand.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    pub(super) unsafe fn map_frame_iterator<I>(&mut self, iterator: I, flags: MappingAccessRights) -> Result<VirtualAddress, KernelError>
     where I: Iterator<Item=PhysicalAddress> + Clone
     {
         let length = iterator.clone().count() * PAGE_SIZE;
-        // TODO: Don't unwrap on OOM in map_frame_iterator.
-        // BODY: map_frame_iterator should return an error on OOM instead of
-        // BODY: making the whole kernel panic...
-        let va = self.find_virtual_space(length).unwrap();
-        self.tables.map_to_from_iterator(iterator, va, flags);
-        va
+        let va = self.find_virtual_space(length)?;
+        self.tables.map_to_from_iterator(iterator, va, flags)?;
+        Ok(va)
     }
 
     /// Allocates and maps a single page, choosing a spot in VMEM for it.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if encounters physical memory exhaustion.
-    /// Panics if encounters virtual space exhaustion.
-    pub fn get_page(&mut self) -> VirtualAddress {
-        let pr = FrameAllocator::allocate_frame().unwrap();
+    /// * `OutOfMemory`: a frame or a page table could not be allocated.
+    /// * `VirtualMemoryExhaustion`: no hole big enough was found in KernelLand.
+    pub fn get_page(&mut self) -> Result<VirtualAddress, KernelError> {
+        let pr = FrameAllocator::allocate_frame()?;
         self.map_phys_region(pr, MappingAccessRights::k_rw())
     }
 
     /// Allocates non-contiguous frames, and map them at the given address.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: frames or page tables could not be allocated.
+    ///
     /// # Panics
     ///
-    /// Panics if encounters physical memory exhaustion.
-    /// Panics if encounters virtual space exhaustion.
     /// Panics if destination was already mapped.
     /// Panics if `length` is not a multiple of PAGE_SIZE.
     // todo check va alignment
-    pub fn map_allocate_to(&mut self, va: VirtualAddress, length: usize, flags: MappingAccessRights) {
+    pub fn map_allocate_to(&mut self, va: VirtualAddress, length: usize, flags: MappingAccessRights) -> Result<(), KernelError> {
         assert!(KernelLand::contains_region(va, length));
         assert!(length % PAGE_SIZE == 0, "length must be a multiple of PAGE_SIZE");
-        let mut prs = FrameAllocator::allocate_frames_fragmented(length).unwrap();
-        self.tables.map_to_from_iterator(prs.iter().flatten(), va, flags);
+        let mut prs = FrameAllocator::allocate_frames_fragmented(length)?;
+        self.tables.map_to_from_iterator(prs.iter().flatten(), va, flags)?;
 
         // do not drop the frames, they are mapped in the page tables !
         while let Some(region) = prs.pop() {
             ::core::mem::forget(region);
         }
+        Ok(())
     }
 
     /// Allocates and maps the given length, chosing a spot in VMEM for it.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: frames or page tables could not be allocated.
+    /// * `VirtualMemoryExhaustion`: no hole big enough was found in KernelLand.
+    ///
     /// # Panics
     ///
-    /// Panics if encounters physical memory exhaustion.
-    /// Panics if encounters virtual space exhaustion.
     /// Panics if `length` is not a multiple of PAGE_SIZE.
-    pub fn get_pages(&mut self, length: usize) -> VirtualAddress {
+    pub fn get_pages(&mut self, length: usize) -> Result<VirtualAddress, KernelError> {
         assert!(length % PAGE_SIZE == 0, "length must be a multiple of PAGE_SIZE");
-        let va = self.find_virtual_space(length).unwrap();
-        self.map_allocate_to(va, length, MappingAccessRights::k_rw());
-        va
+        let va = self.find_virtual_space(length)?;
+        self.map_allocate_to(va, length, MappingAccessRights::k_rw())?;
+        Ok(va)
     }
 
     /// Guards a range of addresses.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated.
+    ///
     /// # Panics
     ///
     /// Panics if destination was already mapped.
     /// Panics if `length` is not a multiple of PAGE_SIZE.
     // todo check va alignment
-    pub fn guard(&mut self, address: VirtualAddress, length: usize) {
+    pub fn guard(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
         assert!(length % PAGE_SIZE == 0, "length must be a multiple of PAGE_SIZE");
-        self.get_hierarchy().guard(address, length);
+        self.get_hierarchy().guard(address, length)
     }
 
     /// Reads the state of the mapping at a given address.
@@ -229,6 +274,11 @@ impl KernelMemory {
     /// Deletes a mapping in the page tables.
     /// This functions assumes the frames were not tracked anywhere else, and drops them.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated to split a huge page or a huge guard
+    ///    the region only partly covers. Nothing was unmapped.
+    ///
     /// # Panics
     ///
     ///
@@ -236,7 +286,7 @@ impl KernelMemory {
     /// Panics if virtual region is not in KernelLand.
     /// Panics if `length` is not page aligned.
     // todo check va alignment
-    pub fn unmap(&mut self, address: VirtualAddress, length: usize) {
+    pub fn unmap(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
         assert!(KernelLand::contains_region(address, length));
         assert!(length % PAGE_SIZE == 0, "length must be a multiple of PAGE_SIZE");
         self.tables.unmap(address, length, |paddr| {
@@ -245,21 +295,55 @@ impl KernelMemory {
                 PhysicalMemRegion::reconstruct(paddr, PAGE_SIZE)
             };
             drop(pr)
-        });
+        })
     }
 
     /// Deletes a mapping in the page tables, but does not free the underlying physical memory.
     ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated to split a huge page or a huge guard
+    ///    the region only partly covers. Nothing was unmapped.
+    ///
     /// # Panics
     ///
     /// Panics if encounters any entry that was not mapped.
     /// Panics if virtual region is not in KernelLand.
     /// Panics if `length` is not page aligned.
     // todo check va alignment
-    pub fn unmap_no_dealloc(&mut self, address: VirtualAddress, length: usize) {
+    pub fn unmap_no_dealloc(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
         assert!(KernelLand::contains_region(address, length));
         assert!(length % PAGE_SIZE == 0, "length must be a multiple of PAGE_SIZE");
-        self.tables.unmap(address, length, |_paddr| { /* leak the frame */ });
+        self.tables.unmap(address, length, |_paddr| { /* leak the frame */ })
+    }
+
+    /// Deletes whole mappings made by the map and guard functions, and drops their frames.
+    ///
+    /// Unlike [unmap](KernelMemory::unmap), this cannot fail: a huge page is
+    /// only split when the region covers part of it, which never happens when
+    /// unmapping everything that was mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region splits a huge page, because it does not cover whole mappings.
+    /// Panics for the same reasons as [unmap](KernelMemory::unmap).
+    pub fn unmap_whole_mapping(&mut self, address: VirtualAddress, length: usize) {
+        self.unmap(address, length)
+            .expect("Unmapping a whole mapping never splits a huge page");
+    }
+
+    /// Deletes whole mappings made by the map and guard functions, but does not free
+    /// the underlying physical memory.
+    ///
+    /// Like [unmap_whole_mapping](KernelMemory::unmap_whole_mapping), this cannot fail.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region splits a huge page, because it does not cover whole mappings.
+    /// Panics for the same reasons as [unmap_no_dealloc](KernelMemory::unmap_no_dealloc).
+    pub fn unmap_whole_mapping_no_dealloc(&mut self, address: VirtualAddress, length: usize) {
+        self.unmap_no_dealloc(address, length)
+            .expect("Unmapping a whole mapping never splits a huge page");
     }
 
     /// Marks all frames mapped in KernelLand as reserve
@@ -357,3 +441,64 @@ impl KernelMemory {
         }
     }
 }
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    //! Tests running on the active page tables, in kernel context.
+
+    use super::*;
+
+    /// Pages allocated in KernelLand are usable, and gone once unmapped.
+    #[test_case]
+    fn map_write_unmap() {
+        let mut memory = get_kernel_memory();
+        let length = 2 * PAGE_SIZE;
+        let va = memory.get_pages(length).unwrap();
+        match memory.mapping_state(va) {
+            PageState::Present(_) => (),
+            _ => panic!("Allocated page is not mapped")
+        }
+
+        let slice = unsafe {
+            // safe: we just mapped it, and nobody else knows about it.
+            ::core::slice::from_raw_parts_mut(va.addr() as *mut u8, length)
+        };
+        for (i, byte) in slice.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert!(slice.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+
+        memory.unmap(va, length).unwrap();
+        match memory.mapping_state(va) {
+            PageState::Available => (),
+            _ => panic!("Unmapped page is still mapped")
+        }
+    }
+
+    /// Unmapping part of a guarded region leaves the rest of it guarded, even when it was
+    /// guarded with huge guards that have to be split.
+    #[test_case]
+    fn unmap_part_of_huge_guard() {
+        // spans a whole directory entry in every paging mode.
+        const LENGTH: usize = 4 * 1024 * 1024;
+        let mut memory = get_kernel_memory();
+        let va = memory.find_virtual_space_aligned(LENGTH, LENGTH).unwrap();
+        memory.guard(va, LENGTH).unwrap();
+
+        memory.unmap(va + PAGE_SIZE, PAGE_SIZE).unwrap();
+        for &(offset, guarded) in &[(0, true), (PAGE_SIZE, false), (2 * PAGE_SIZE, true), (LENGTH - PAGE_SIZE, true)] {
+            match memory.mapping_state(va + offset) {
+                PageState::Guarded => assert!(guarded, "page {:#x} is still guarded", offset),
+                PageState::Available => assert!(!guarded, "page {:#x} is not guarded anymore", offset),
+                PageState::Present(_) => panic!("page {:#x} is mapped", offset)
+            }
+        }
+
+        memory.unmap(va, PAGE_SIZE).unwrap();
+        memory.unmap(va + 2 * PAGE_SIZE, LENGTH - 2 * PAGE_SIZE).unwrap();
+        match memory.mapping_state(va + LENGTH - PAGE_SIZE) {
+            PageState::Available => (),
+            _ => panic!("Unmapped guard is still guarded")
+        }
+    }
+}
@@ -48,10 +48,69 @@ pub enum MappingFrames {
     Shared(Arc<SpinRwLock<Vec<PhysicalMemRegion>>>),
     /// The frames are Owned by this mapping.
     Owned(Vec<PhysicalMemRegion>),
+    /// The frames are committed lazily, on first access to their page, and are shared
+    /// between multiple mappings like [MappingFrames::Shared].
+    ///
+    /// There is a slot for every page, holding a single frame, or None if the page was never
+    /// accessed.
+    Lazy(Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>),
+    /// The frames are shared copy-on-write with other mappings, one page at a time.
+    ///
+    /// There is a slot for every page, holding a single frame, or None if the page was never
+    /// accessed. A page is mapped read-only until its frame is private to this mapping, and
+    /// gets a private copy of the frame on the first write to it.
+    CopyOnWrite(Vec<Option<Arc<CowFrame>>>),
     /// This Mapping has no frames.
     None,
 }
 
+impl MappingFrames {
+    /// Returns another reference to these frames, to map them in another mapping.
+    ///
+    /// [MappingFrames::CopyOnWrite] pages are shared with the returned ones, the first write to
+    /// one of them will give it a private copy.
+    ///
+    /// Returns None for [MappingFrames::Owned] and [MappingFrames::None], which cannot be shared.
+    pub fn share(&self) -> Option<MappingFrames> {
+        match self {
+            MappingFrames::Shared(frames) => Some(MappingFrames::Shared(frames.clone())),
+            MappingFrames::Lazy(frames) => Some(MappingFrames::Lazy(frames.clone())),
+            MappingFrames::CopyOnWrite(pages) => Some(MappingFrames::CopyOnWrite(pages.clone())),
+            MappingFrames::Owned(_) | MappingFrames::None => None
+        }
+    }
+}
+
+/// The frame backing a single page of a [MappingFrames::CopyOnWrite] mapping.
+#[derive(Debug)]
+pub enum CowFrame {
+    /// The frame was allocated for this page.
+    Owned(PhysicalMemRegion),
+    /// The page was borrowed from the frames of another mapping, which are kept alive
+    /// for as long as the page is referenced.
+    ///
+    /// The frame is never written to through this page: the first write always gets
+    /// a private copy, even when no other page references it anymore.
+    Borrowed(MappingFrames, PhysicalAddress),
+}
+
+impl CowFrame {
+    /// Returns the address of the frame.
+    pub fn address(&self) -> PhysicalAddress {
+        match self {
+            CowFrame::Owned(frame) => frame.address(),
+            CowFrame::Borrowed(_, address) => *address,
+        }
+    }
+
+    /// Returns true if the frame can be written to through a page referencing it.
+    ///
+    /// This is only the case of an Owned frame that is referenced by a single page.
+    pub fn is_private(this: &Arc<CowFrame>) -> bool {
+        Arc::strong_count(this) == 1 && if let CowFrame::Owned(_) = **this { true } else { false }
+    }
+}
+
 impl Mapping {
     /// Tries to construct a mapping.
     ///
@@ -77,6 +136,8 @@ impl Mapping {
         let frames_len = match &frames {
             MappingFrames::Owned(v) => v.iter().flatten().count() * PAGE_SIZE,
             MappingFrames::Shared(v) => v.read().iter().flatten().count() * PAGE_SIZE,
+            MappingFrames::Lazy(v) => v.read().len() * PAGE_SIZE,
+            MappingFrames::CopyOnWrite(v) => v.len() * PAGE_SIZE,
             MappingFrames::None => usize::max_value()
         };
 
@@ -97,6 +158,10 @@ impl Mapping {
             (MappingFrames::None, _, MemoryType::Reserved) => (),
             (MappingFrames::None, _, MemoryType::KernelStack) => (),
             (MappingFrames::Shared(_), true, _) => (),
+            (MappingFrames::Lazy(_), true, _) => (),
+            (MappingFrames::Lazy(_), _, MemoryType::Normal) => (),
+            (MappingFrames::CopyOnWrite(_), true, _) => (),
+            (MappingFrames::CopyOnWrite(_), _, MemoryType::Normal) => (),
             (MappingFrames::Owned(_), false, _) => (),
             _ => return Err(KernelError::WrongMappingFramesForTy { ty, backtrace: Backtrace::new() })
         }
@@ -120,6 +185,11 @@ impl Mapping {
     /// Returns an iterator over the Physical Addresses mapped by this region.
     /// This takes into account the physical offset and the length of the
     /// mapping.
+    ///
+    /// # Panics
+    ///
+    /// For [MappingFrames::Lazy] and [MappingFrames::CopyOnWrite], panics when reaching a page
+    /// that was not committed.
     pub fn frames_it(&self) -> impl Iterator<Item = PhysicalAddress> + Clone + core::fmt::Debug + '_ {
         /// Anonymous iterator over mapping frames' PhysicalAddresses.
         #[derive(Debug)]
@@ -128,10 +198,25 @@ impl Mapping {
             None,
             Owned(&'a [PhysicalMemRegion], usize, StepBy<Range<usize>>),
             Shared(&'a Arc<SpinRwLock<Vec<PhysicalMemRegion>>>, SpinRwLockReadGuard<'a, Vec<PhysicalMemRegion>>, usize, StepBy<Range<usize>>),
+            Lazy(&'a Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>, SpinRwLockReadGuard<'a, Vec<Option<PhysicalMemRegion>>>, usize),
+            CopyOnWrite(&'a [Option<Arc<CowFrame>>], usize),
         }
         impl<'a> Iterator for MappingFramesIt<'a> {
             type Item = PhysicalAddress;
             fn next(&mut self) -> Option<Self::Item> {
+                if let MappingFramesIt::Lazy(_, slots, curslot) = self {
+                    let frame = slots.get(*curslot)
+                        .map(|slot| slot.as_ref().expect("Iterating over a page that was not committed").address());
+                    *curslot += 1;
+                    return frame;
+                }
+                if let MappingFramesIt::CopyOnWrite(slots, curslot) = self {
+                    let frame = slots.get(*curslot)
+                        .map(|slot| slot.as_ref().expect("Iterating over a page that was not committed").address());
+                    *curslot += 1;
+                    return frame;
+                }
+
                 let (frames, curframe, rangeit) = match self {
                     MappingFramesIt::Owned(ref frames, ref mut curframe, ref mut rangeit) => {
                         (*frames, curframe, rangeit)
@@ -160,18 +245,24 @@ impl Mapping {
                 match self {
                     MappingFramesIt::Owned(frames, curframe, rangeit) => MappingFramesIt::Owned(frames, *curframe, rangeit.clone()),
                     MappingFramesIt::Shared(frames, _lock, curframe, rangeit) => MappingFramesIt::Shared(frames, frames.read(), *curframe, rangeit.clone()),
+                    MappingFramesIt::Lazy(frames, _lock, curslot) => MappingFramesIt::Lazy(frames, frames.read(), *curslot),
+                    MappingFramesIt::CopyOnWrite(slots, curslot) => MappingFramesIt::CopyOnWrite(slots, *curslot),
                     MappingFramesIt::None => MappingFramesIt::None,
                 }
             }
         }
 
-        let it = match self.frames() {
-            MappingFrames::Owned(frames) => MappingFramesIt::Owned(&frames[..], 0, (0..0).step_by(1)),
-            MappingFrames::Shared(frames) => MappingFramesIt::Shared(frames, frames.read(), 0, (0..0).step_by(1)),
-            MappingFrames::None => MappingFramesIt::None,
+        // a lazy or copy-on-write iterator starts directly at the offset, so we never go through
+        // the pages preceding it, which might not be committed.
+        let (it, skipped) = match self.frames() {
+            MappingFrames::Owned(frames) => (MappingFramesIt::Owned(&frames[..], 0, (0..0).step_by(1)), self.phys_offset() / PAGE_SIZE),
+            MappingFrames::Shared(frames) => (MappingFramesIt::Shared(frames, frames.read(), 0, (0..0).step_by(1)), self.phys_offset() / PAGE_SIZE),
+            MappingFrames::Lazy(frames) => (MappingFramesIt::Lazy(frames, frames.read(), self.phys_offset() / PAGE_SIZE), 0),
+            MappingFrames::CopyOnWrite(slots) => (MappingFramesIt::CopyOnWrite(&slots[..], self.phys_offset() / PAGE_SIZE), 0),
+            MappingFrames::None => (MappingFramesIt::None, 0),
         };
         it
-            .skip(self.phys_offset() / PAGE_SIZE)
+            .skip(skipped)
             .take(self.length() / PAGE_SIZE)
     }
 
@@ -182,6 +273,46 @@ impl Mapping {
     /// an IPC buffer).
     pub fn phys_offset(&self) -> usize { self.offset }
 
+    /// Returns how much of this mapping is backed by physical memory.
+    ///
+    /// This is the whole length of the mapping, unless its frames are committed lazily.
+    pub fn committed_length(&self) -> usize {
+        match self.frames() {
+            MappingFrames::Lazy(frames) => {
+                frames.read()[self.phys_offset() / PAGE_SIZE..][..self.length() / PAGE_SIZE]
+                    .iter()
+                    .filter(|slot| slot.is_some())
+                    .count() * PAGE_SIZE
+            },
+            MappingFrames::CopyOnWrite(slots) => {
+                slots[self.phys_offset() / PAGE_SIZE..][..self.length() / PAGE_SIZE]
+                    .iter()
+                    .filter(|slot| slot.is_some())
+                    .count() * PAGE_SIZE
+            },
+            MappingFrames::None => 0,
+            MappingFrames::Owned(_) | MappingFrames::Shared(_) => self.length()
+        }
+    }
+
+    /// Returns the slot of the page `address` falls in, if the frames of this mapping are
+    /// [MappingFrames::CopyOnWrite].
+    ///
+    /// Replacing the frame of a slot does not change the amount of pages in the frames, the
+    /// guarantees of the mapping are preserved.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `address` does not fall in this mapping.
+    pub fn cow_slot_mut(&mut self, address: VirtualAddress) -> Option<&mut Option<Arc<CowFrame>>> {
+        assert!(self.address <= address && address - self.address < self.length, "Address does not fall in the mapping");
+        let index = (self.offset + (address.floor() - self.address)) / PAGE_SIZE;
+        match &mut self.frames {
+            MappingFrames::CopyOnWrite(slots) => Some(&mut slots[index]),
+            _ => None
+        }
+    }
+
     /// Returns the [MemoryState] of this mapping.
     pub fn state(&self) -> MemoryState { self.state }
 
@@ -191,11 +322,12 @@ impl Mapping {
     pub fn flags(&self) -> MappingAccessRights { self.flags }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod test {
     use super::Mapping;
     use super::MappingAccessRights;
     use super::MappingFrames;
+    use super::CowFrame;
     use super::MemoryType;
     use crate::mem::{VirtualAddress, PhysicalAddress};
     use crate::paging::PAGE_SIZE;
@@ -398,4 +530,67 @@ mod test {
         let flags = MappingAccessRights::u_rw();
         let _mapping_err = Mapping::new(VirtualAddress(0), MappingFrames::Shared(frames), 1 * PAGE_SIZE, 2 * PAGE_SIZE, MemoryType::Stack, flags).unwrap_err();
     }
+
+    #[test]
+    fn mapping_lazy_ok() {
+        let frames = Arc::new(SpinRwLock::new((0..2).map(|_| None).collect()));
+        let flags = MappingAccessRights::u_rw();
+        let mapping = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Lazy(frames), 0, 2 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert_eq!(mapping.committed_length(), 0);
+    }
+
+    #[test]
+    fn mapping_lazy_wrong_ty() {
+        let frames = Arc::new(SpinRwLock::new((0..2).map(|_| None).collect()));
+        let flags = MappingAccessRights::u_rw();
+        let _mapping_err = Mapping::new(VirtualAddress(0x40000000), MappingFrames::Lazy(frames), 0, 2 * PAGE_SIZE, MemoryType::Io, flags).unwrap_err();
+    }
+
+    #[test]
+    fn mapping_lazy_offset() {
+        let _f = crate::frame_allocator::init();
+        let frame = FrameAllocator::allocate_frame().unwrap();
+        let test_addr = frame.address();
+
+        // the first page is not committed, we must not go through it.
+        let frames = Arc::new(SpinRwLock::new(vec![None, Some(frame)]));
+        let flags = MappingAccessRights::u_rw();
+        let mapping = Mapping::new(VirtualAddress(0), MappingFrames::Lazy(frames), 1 * PAGE_SIZE, 1 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert_eq!(mapping.committed_length(), PAGE_SIZE);
+        assert!(mapping.frames_it().count() == 1, "Frames_it has the wrong size.");
+        assert!(mapping.frames_it().next().unwrap() == test_addr, "Frames_it has the wrong value.");
+    }
+
+    #[test]
+    fn mapping_cow_ok() {
+        let flags = MappingAccessRights::u_rw();
+        let mapping = Mapping::new(VirtualAddress(0x40000000), MappingFrames::CopyOnWrite(vec![None, None]), 0, 2 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert_eq!(mapping.committed_length(), 0);
+    }
+
+    #[test]
+    fn mapping_cow_wrong_ty() {
+        let flags = MappingAccessRights::u_rw();
+        let _mapping_err = Mapping::new(VirtualAddress(0x40000000), MappingFrames::CopyOnWrite(vec![None, None]), 0, 2 * PAGE_SIZE, MemoryType::Io, flags).unwrap_err();
+    }
+
+    #[test]
+    fn mapping_cow_shared_page() {
+        let _f = crate::frame_allocator::init();
+        let frame = FrameAllocator::allocate_frame().unwrap();
+        let test_addr = frame.address();
+        let page = Arc::new(CowFrame::Owned(frame));
+        assert!(CowFrame::is_private(&page), "A page referenced once should be private.");
+
+        let flags = MappingAccessRights::u_rw();
+        let mut mapping = Mapping::new(VirtualAddress(0), MappingFrames::CopyOnWrite(vec![None, Some(page.clone())]), 1 * PAGE_SIZE, 1 * PAGE_SIZE, MemoryType::Heap, flags).unwrap();
+        assert!(!CowFrame::is_private(&page), "A page referenced by two mappings should not be private.");
+        assert_eq!(mapping.committed_length(), PAGE_SIZE);
+        assert!(mapping.frames_it().next().unwrap() == test_addr, "Frames_it has the wrong value.");
+
+        // dropping the reference of the mapping to the page.
+        *mapping.cow_slot_mut(VirtualAddress(0x10)).unwrap() = None;
+        assert!(CowFrame::is_private(&page), "The page should be private again.");
+        assert_eq!(mapping.committed_length(), 0);
+    }
 }
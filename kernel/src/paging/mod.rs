@@ -25,7 +25,7 @@ mod hierarchical_table;
 mod arch;
 mod bookkeeping;
 
-pub use self::arch::{PAGE_SIZE, read_cr2, read_cr3, InactiveHierarchy};
+pub use self::arch::{PAGE_SIZE, read_cr2, read_cr3, InactiveHierarchy, init_pae};
 pub use self::hierarchical_table::PageState;
 pub use self::hierarchical_table::{InactiveHierarchyTrait};
 use sunrise_libkern;
@@ -21,15 +21,16 @@ use super::hierarchical_table::*;
 use super::arch::{PAGE_SIZE, InactiveHierarchy, ActiveHierarchy};
 use super::lands::{UserLand, VirtualSpaceLand};
 use super::bookkeeping::UserspaceBookkeeping;
-use super::mapping::{Mapping, MappingFrames};
+use super::mapping::{Mapping, MappingFrames, CowFrame};
 use sunrise_libkern::{MemoryType, MemoryState, MemoryAttributes, MemoryPermissions};
 use super::cross_process::CrossProcessMapping;
 use super::MappingAccessRights;
+use super::kernel_memory::get_kernel_memory;
 use crate::mem::{VirtualAddress, PhysicalAddress};
 use crate::frame_allocator::{FrameAllocator, FrameAllocatorTrait, PhysicalMemRegion};
 use crate::paging::arch::Entry;
 use crate::error::KernelError;
-use crate::utils::{check_size_aligned, check_nonzero_length};
+use crate::utils::{check_size_aligned, check_nonzero_length, align_down};
 use crate::sync::SpinRwLock;
 use alloc::{vec::Vec, sync::Arc};
 use failure::Backtrace;
@@ -86,11 +87,15 @@ impl HierarchicalTable for () {
         unimplemented!()
     }
 
+    fn entry_count() -> usize {
+        unimplemented!()
+    }
+
     fn get_child_table(&mut self, _index: usize) -> PageState<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>> {
         unimplemented!()
     }
 
-    fn create_child_table(&mut self, _index: usize) -> SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType> {
+    fn create_child_table(&mut self, _index: usize) -> Result<SmartHierarchicalTable<<Self as HierarchicalTable>::ChildTableType>, KernelError> {
         unimplemented!()
     }
 }
@@ -105,7 +110,7 @@ impl<'b> TableHierarchy for DynamicHierarchy<'b> {
     fn map_to_from_iterator<I>(&mut self,
                                frames_iterator: I,
                                start_address: VirtualAddress,
-                               flags: MappingAccessRights)
+                               flags: MappingAccessRights) -> Result<(), KernelError>
     where I: Iterator<Item=PhysicalAddress>
     {
         match *self {
@@ -114,14 +119,26 @@ impl<'b> TableHierarchy for DynamicHierarchy<'b> {
         }
     }
 
-    fn guard(&mut self, address: VirtualAddress, length: usize) {
+    fn map_contiguous_to(&mut self,
+                         phys_start: PhysicalAddress,
+                         start_address: VirtualAddress,
+                         length: usize,
+                         flags: MappingAccessRights) -> Result<(), KernelError>
+    {
+        match *self {
+            DynamicHierarchy::Active(ref mut hierarchy) => hierarchy.map_contiguous_to(phys_start, start_address, length, flags),
+            DynamicHierarchy::Inactive(ref mut hierarchy) => hierarchy.map_contiguous_to(phys_start, start_address, length, flags),
+        }
+    }
+
+    fn guard(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
         match *self {
             DynamicHierarchy::Active(ref mut hierarchy) => hierarchy.guard(address, length),
             DynamicHierarchy::Inactive(ref mut hierarchy) => hierarchy.guard(address, length),
         }
     }
 
-    fn unmap<C>(&mut self, address: VirtualAddress, length: usize, callback: C) where C: FnMut(PhysicalAddress) {
+    fn unmap<C>(&mut self, address: VirtualAddress, length: usize, callback: C) -> Result<(), KernelError> where C: FnMut(PhysicalAddress) {
         match *self {
             DynamicHierarchy::Active(ref mut hierarchy) => hierarchy.unmap(address, length, callback),
             DynamicHierarchy::Inactive(ref mut hierarchy) => hierarchy.unmap(address, length, callback),
@@ -143,22 +160,23 @@ impl<'b> TableHierarchy for DynamicHierarchy<'b> {
     }
 }
 
-impl Default for ProcessMemory {
+impl ProcessMemory {
     /// Creates a ProcessMemory, allocating the userspace-bookkeeping,
     /// and the top-level table of the table hierarchy.
-    fn default() -> Self {
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: cannot allocate the tables of the hierarchy.
+    pub fn new() -> Result<Self, KernelError> {
         // we don't have ASRL yet :(
         let heap_base_address = VirtualAddress(0x80000000);
 
-        ProcessMemory {
+        Ok(ProcessMemory {
             userspace_bookkeping: UserspaceBookkeeping::new(),
-            table_hierarchy: InactiveHierarchy::new(),
+            table_hierarchy: InactiveHierarchy::new()?,
             heap_base_address,
-        }
+        })
     }
-}
-
-impl ProcessMemory {
 
     /// If these tables are the one currently in use, we return them as an ActiveHierarchy instead.
     fn get_hierarchy(&mut self) -> DynamicHierarchy<'_> {
@@ -176,12 +194,16 @@ impl ProcessMemory {
     /// Maps a single physical regions to a given virtual address.
     /// Used to map mmio regions in UserSpace.
     ///
+    /// Parts of the region that are aligned on a large page both physically and virtually
+    /// are mapped with large pages.
+    ///
     /// # Errors
     ///
     /// * `InvalidAddress`:
     ///     * there was already a mapping in the range.
     ///     * range does not fall in UserLand.
     ///     * `address` is not page aligned.
+    /// * `OutOfMemory`: a page table could not be allocated.
     pub fn map_phys_region_to(&mut self,
                               phys: PhysicalMemRegion,
                               address: VirtualAddress,
@@ -194,7 +216,7 @@ impl ProcessMemory {
         self.userspace_bookkeping.check_vacant(address, length)?;
         // ok, everything seems good, from now on treat errors as unexpected
 
-        self.get_hierarchy().map_to_from_iterator(phys.into_iter(), address, flags);
+        self.get_hierarchy().map_contiguous_to(phys.address(), address, length, flags)?;
         let mapping = Mapping::new(address, MappingFrames::Owned(vec![phys]), 0, length, ty, flags)
             .expect("We checked everything, but bookkeeping refuses to create the mapping");
         self.userspace_bookkeping.add_mapping(mapping)
@@ -204,6 +226,11 @@ impl ProcessMemory {
 
     /// Allocates the physical regions, and maps them to specified address.
     ///
+    /// Heap, Normal and CodeStatic mappings are committed lazily: no frame is allocated here,
+    /// the page fault handler calls [commit_page] on the first access to each page.
+    ///
+    /// [commit_page]: ProcessMemory::commit_page
+    ///
     /// # Errors
     ///
     /// * `InvalidAddress`:
@@ -213,17 +240,28 @@ impl ProcessMemory {
     /// * `InvalidSize` :
     ///     * `length` is not page aligned.
     ///     * `length` is 0.
-    /// * `PhysicalMemoryExhaustion`: Frames could not be allocated.
+    /// * `OutOfMemory`: Frames or page tables could not be allocated.
     pub fn create_regular_mapping(&mut self, address: VirtualAddress, length: usize, ty: MemoryType, flags: MappingAccessRights) -> Result<(), KernelError> {
         address.check_aligned_to(PAGE_SIZE)?;
         check_size_aligned(length, PAGE_SIZE)?;
         check_nonzero_length(length)?;
         UserLand::check_contains_region(address, length)?;
         self.userspace_bookkeping.check_vacant(address, length)?;
+
+        if let MemoryType::Heap | MemoryType::Normal | MemoryType::CodeStatic = ty {
+            // nothing to map for now, the pages will be committed on first access.
+            let slots = (0..length / PAGE_SIZE).map(|_| None).collect();
+            let mapping = Mapping::new(address, MappingFrames::Lazy(Arc::new(SpinRwLock::new(slots))), 0, length, ty, flags)
+                .expect("We checked everything, but bookkeeping refuses to create the mapping");
+            self.userspace_bookkeping.add_mapping(mapping)
+                .expect("We checked everything, but bookkeeping refuses to add the mapping");
+            return Ok(())
+        }
+
         let frames = FrameAllocator::allocate_frames_fragmented(length)?;
         // ok, everything seems good, from now on treat errors as unexpected
 
-        self.get_hierarchy().map_to_from_iterator(frames.iter().flatten(), address, flags);
+        self.get_hierarchy().map_to_from_iterator(frames.iter().flatten(), address, flags)?;
         let frames = if ty.get_memory_state().contains(MemoryState::IS_REFERENCE_COUNTED) {
             MappingFrames::Shared(Arc::new(SpinRwLock::new(frames)))
         } else {
@@ -256,10 +294,46 @@ impl ProcessMemory {
                                       ty: MemoryType,
                                       flags: MappingAccessRights)
                                      -> Result<(), KernelError> {
+        self.map_partial_mapping(MappingFrames::Shared(shared_mapping), address, phys_offset, length, ty, flags)
+    }
+
+    /// Maps a part of the frames of another mapping to specified address.
+    ///
+    /// The frames must be either [MappingFrames::Shared], [MappingFrames::Lazy] or
+    /// [MappingFrames::CopyOnWrite]. Only the pages that are already committed are mapped in the
+    /// page tables, the others are committed on first access.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * there was already a mapping in the range.
+    ///     * range does not fall in UserLand.
+    ///     * `address` is not page aligned.
+    /// * `InvalidSize` :
+    ///     * `length` is not page aligned.
+    ///     * `length` is 0.
+    /// * `WrongMappingFramesForTy`:
+    ///     * `frames` is not refcounted.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    pub fn map_partial_mapping(&mut self,
+                               frames: MappingFrames,
+                               address: VirtualAddress,
+                               phys_offset: usize,
+                               length: usize,
+                               ty: MemoryType,
+                               flags: MappingAccessRights)
+                              -> Result<(), KernelError> {
         address.check_aligned_to(PAGE_SIZE)?;
         check_nonzero_length(length)?;
         check_size_aligned(length, PAGE_SIZE)?;
-        let max_length = shared_mapping.read().iter().flatten().count() * PAGE_SIZE - phys_offset;
+        let frames_length = match &frames {
+            MappingFrames::Shared(frames) => frames.read().iter().flatten().count() * PAGE_SIZE,
+            MappingFrames::Lazy(frames) => frames.read().len() * PAGE_SIZE,
+            MappingFrames::CopyOnWrite(pages) => pages.len() * PAGE_SIZE,
+            MappingFrames::Owned(_) | MappingFrames::None =>
+                return Err(KernelError::WrongMappingFramesForTy { ty, backtrace: Backtrace::new() })
+        };
+        let max_length = frames_length - phys_offset;
         if max_length < length {
             return Err(KernelError::InvalidSize { size: length, backtrace: Backtrace::new() })
         }
@@ -267,14 +341,75 @@ impl ProcessMemory {
         self.userspace_bookkeping.check_vacant(address, length)?;
         // ok, everything seems good, from now on treat errors as unexpected
 
-        let mapping = Mapping::new(address, MappingFrames::Shared(shared_mapping), phys_offset, length, ty, flags)
+        // don't keep references to copy-on-write pages outside of the mapping,
+        // they would prevent them from ever becoming private.
+        let (frames, phys_offset) = match frames {
+            MappingFrames::CopyOnWrite(mut pages) => {
+                pages.truncate((phys_offset + length) / PAGE_SIZE);
+                pages.drain(..phys_offset / PAGE_SIZE);
+                (MappingFrames::CopyOnWrite(pages), 0)
+            },
+            frames => (frames, phys_offset)
+        };
+
+        let mapping = Mapping::new(address, frames, phys_offset, length, ty, flags)
             .expect("We checked everything, but bookkeeping refuses to create the mapping");
-        self.get_hierarchy().map_to_from_iterator(mapping.frames_it(), address, flags);
         self.userspace_bookkeping.add_mapping(mapping)
             .expect("We checked everything, but bookkeeping refuses to add the mapping");
+        if let Err(err) = self.map_committed_pages(address, length) {
+            // don't keep a mapping whose pages are not all in the page tables.
+            self.unmap(address, length).expect("Failed removing a mapping we just added");
+            return Err(err)
+        }
         Ok(())
     }
 
+    /// Maps the pages in `address..address + length` that are backed by a frame in the page
+    /// tables. The range must fall in a single mapping, which is already tracked.
+    ///
+    /// The pages of lazily-committed mappings that were never accessed are left unmapped, and the
+    /// pages of copy-on-write mappings are mapped read-only until their frame is private.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated. Some pages might have been mapped.
+    fn map_committed_pages(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
+        let mapping = self.userspace_bookkeping.occupied_mapping_at(address)
+            .expect("Mapping the pages of a mapping that is not tracked");
+        let flags = mapping.flags();
+        let first_page = (mapping.phys_offset() + (address - mapping.address())) / PAGE_SIZE;
+        let page_count = length / PAGE_SIZE;
+        let pages: Vec<(VirtualAddress, PhysicalAddress, MappingAccessRights)> = match mapping.frames() {
+            MappingFrames::Lazy(slots) => slots.read()[first_page..][..page_count].iter()
+                .enumerate()
+                .filter_map(|(i, slot)| slot.as_ref().map(|frame| (address + i * PAGE_SIZE, frame.address(), flags)))
+                .collect(),
+            MappingFrames::CopyOnWrite(slots) => slots[first_page..][..page_count].iter()
+                .enumerate()
+                .filter_map(|(i, slot)| slot.as_ref().map(|page| (address + i * PAGE_SIZE, page.address(), cow_page_rights(page, flags))))
+                .collect(),
+            _ => mapping.frames_it()
+                .skip((address - mapping.address()) / PAGE_SIZE)
+                .take(page_count)
+                .enumerate()
+                .map(|(i, frame)| (address + i * PAGE_SIZE, frame, flags))
+                .collect()
+        };
+        for (page, frame, rights) in pages {
+            self.get_hierarchy().map_to_from_iterator(core::iter::once(frame), page, rights)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if `page` is mapped in the page tables.
+    pub fn is_page_mapped(&mut self, page: VirtualAddress) -> bool {
+        let mut mapped = false;
+        self.get_hierarchy().for_every_entry(page, PAGE_SIZE, |state, _| {
+            mapped = state.as_option().is_some()
+        });
+        mapped
+    }
+
     /// Guards a range of addresses
     ///
     /// # Errors
@@ -286,13 +421,18 @@ impl ProcessMemory {
     /// * `InvalidSize` :
     ///     * `length` is not page aligned.
     ///     * `length` is 0.
+    /// * `OutOfMemory`: a page table could not be allocated.
     pub fn guard(&mut self, address: VirtualAddress, length: usize, ty: MemoryType) -> Result<(), KernelError>{
         UserLand::check_contains_region(address, length)?;
         let mapping = Mapping::new(address, MappingFrames::None, 0, length, ty, MappingAccessRights::empty())?;
         self.userspace_bookkeping.add_mapping(mapping)?;
 
         // everything is ok, actually map the guard
-        self.get_hierarchy().guard(address, length);
+        if let Err(err) = self.get_hierarchy().guard(address, length) {
+            self.userspace_bookkeping.remove_mapping(address, length)
+                .expect("Failed removing a mapping we just added");
+            return Err(err)
+        }
         Ok(())
     }
 
@@ -309,21 +449,345 @@ impl ProcessMemory {
     ///     * range does not fall in UserLand.
     /// * `InvalidSize`:
     ///     * `length` is not the size of the mapping at `address`.
+    /// * `OutOfMemory`: a page table could not be allocated to split a huge page.
+    ///    The mapping is left untouched.
     pub fn unmap(&mut self, address: VirtualAddress, length: usize) -> Result<Mapping, KernelError> {
         UserLand::check_contains_region(address, length)?;
         // allow address and length to be unaligned, remove_mapping will just not find anything.
         let mapping = self.userspace_bookkeping.remove_mapping(address, length)?;
-        self.get_hierarchy().unmap(address, length, |_| {
-            /* leak the mapped frames here, we still have them in `mapping` */
-        });
+        if let Err(err) = self.unmap_pages(address, length) {
+            // its pages are all still mapped, keep tracking them.
+            self.userspace_bookkeping.add_mapping(mapping)
+                .expect("Re-adding the mapping we just removed failed");
+            return Err(err)
+        }
         Ok(mapping)
     }
 
+    /// Unmaps the range `address..address + length` from the page tables.
+    ///
+    /// The pages of lazily-committed mappings that were never accessed are not in the page
+    /// tables, they are skipped.
+    ///
+    /// The mapped frames are leaked, the caller must still be tracking them in a mapping.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a page table could not be allocated to split a huge page.
+    ///    Huge pages only back physical memory mappings, which are never lazily committed,
+    ///    and are thus unmapped in a single run: nothing was unmapped.
+    fn unmap_pages(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
+        // find the runs of entries that are in use.
+        let end_addr = address.addr() + length;
+        let mut cur_addr = address.addr();
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        self.get_hierarchy().for_every_entry(address, length, |state, entry_size| {
+            // the first entry can start before `address`.
+            let entry_end = core::cmp::min(align_down(cur_addr, entry_size) + entry_size, end_addr);
+            if let PageState::Available = state {
+                // nothing to unmap.
+            } else {
+                match runs.last_mut() {
+                    Some((_, run_end)) if *run_end == cur_addr => *run_end = entry_end,
+                    _ => runs.push((cur_addr, entry_end))
+                }
+            }
+            cur_addr = entry_end;
+        });
+        for (run_start, run_end) in runs {
+            self.get_hierarchy().unmap(VirtualAddress(run_start), run_end - run_start, |_| {
+                /* leak the mapped frames here, we still have them in the mapping */
+            })?;
+        }
+        Ok(())
+    }
+
     /// Reads the state of the mapping at a given address.
     pub fn query_memory(&self, address: VirtualAddress) -> QueryMemory<'_> {
         self.userspace_bookkeping.mapping_at(address)
     }
 
+    /// Gets the lazily-committed frames backing the page `address` falls in,
+    /// and the index of its slot.
+    ///
+    /// Returns None if `address` does not fall in a lazily-committed mapping.
+    fn lazy_slot_at(&self, address: VirtualAddress) -> Option<(Arc<SpinRwLock<Vec<Option<PhysicalMemRegion>>>>, usize)> {
+        let mapping = self.userspace_bookkeping.occupied_mapping_at(address).ok()?;
+        match mapping.frames() {
+            MappingFrames::Lazy(frames) => {
+                let index = (mapping.phys_offset() + (address.floor() - mapping.address())) / PAGE_SIZE;
+                Some((frames.clone(), index))
+            },
+            _ => None
+        }
+    }
+
+    /// Commits the page `address` falls in, which is part of a lazily-committed or a
+    /// copy-on-write mapping.
+    ///
+    /// If the page was never accessed, a zeroed frame is allocated for it. The frame is then
+    /// mapped in the page tables. Does nothing if the page was already mapped.
+    ///
+    /// This is called by the page fault handler.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidMemState`:
+    ///     * `address` does not fall in a lazily-committed or copy-on-write mapping.
+    /// * `OutOfMemory`: a frame could not be allocated.
+    pub fn commit_page(&mut self, address: VirtualAddress) -> Result<(), KernelError> {
+        let page = address.floor();
+        let ty = self.query_memory(address).mapping().state().ty();
+        let not_committable = || KernelError::InvalidMemState { address, ty, backtrace: Backtrace::new() };
+
+        if let Some((frames, index)) = self.lazy_slot_at(address) {
+            // the frame might already have been committed by another mapping of these frames.
+            let mut slots = frames.write();
+            if slots[index].is_none() {
+                slots[index] = Some(allocate_filled_frame(None)?);
+            }
+        } else {
+            let mapping = self.userspace_bookkeping.occupied_mapping_at_mut(address)
+                .map_err(|_| not_committable())?;
+            let slot = mapping.cow_slot_mut(address).ok_or_else(not_committable)?;
+            if slot.is_none() {
+                *slot = Some(Arc::new(CowFrame::Owned(allocate_filled_frame(None)?)));
+            }
+        }
+
+        if !self.is_page_mapped(page) {
+            self.map_committed_pages(page, PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Commits all the pages of lazily-committed and copy-on-write mappings in the range
+    /// `address..address + length`.
+    ///
+    /// The pages of other mappings are left as is.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a frame could not be allocated.
+    pub fn commit_range(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
+        let end = address.addr().saturating_add(length);
+        for page in (address.floor().addr()..end).step_by(PAGE_SIZE) {
+            match self.commit_page(VirtualAddress(page)) {
+                Ok(()) | Err(KernelError::InvalidMemState { .. }) => (),
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the page `address` falls in, which is part of a copy-on-write mapping, a private
+    /// frame, and maps it with the access rights of its mapping.
+    ///
+    /// If the frame of the page is shared, it is copied to a newly allocated frame. If the page
+    /// was never accessed, it is committed with a zeroed frame.
+    ///
+    /// This is called by the page fault handler, on a write to a copy-on-write page.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidMemState`:
+    ///     * `address` does not fall in a copy-on-write mapping.
+    /// * `OutOfMemory`: a frame could not be allocated.
+    pub fn unshare_page(&mut self, address: VirtualAddress) -> Result<(), KernelError> {
+        let page = address.floor();
+        let ty = self.query_memory(address).mapping().state().ty();
+        let not_cow = || KernelError::InvalidMemState { address, ty, backtrace: Backtrace::new() };
+
+        let mapping = self.userspace_bookkeping.occupied_mapping_at_mut(address)
+            .map_err(|_| not_cow())?;
+        let slot = mapping.cow_slot_mut(address).ok_or_else(not_cow)?;
+        if !slot.as_ref().map(CowFrame::is_private).unwrap_or(false) {
+            let frame = allocate_filled_frame(slot.as_ref().map(|frame| frame.address()))?;
+            *slot = Some(Arc::new(CowFrame::Owned(frame)));
+        }
+
+        // remap the page, it is now writable if its mapping is.
+        self.unmap_pages(page, PAGE_SIZE)?;
+        self.map_committed_pages(page, PAGE_SIZE)
+    }
+
+    /// Makes sure all the pages in the range `address..address + length` are backed by a frame
+    /// that only this address space can write to.
+    ///
+    /// The pages of lazily-committed mappings are committed, and the pages of copy-on-write
+    /// mappings are given a private frame. The pages of other mappings are left as is.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: a frame could not be allocated.
+    pub fn unshare_range(&mut self, address: VirtualAddress, length: usize) -> Result<(), KernelError> {
+        let end = address.addr().saturating_add(length);
+        for page in (address.floor().addr()..end).step_by(PAGE_SIZE) {
+            let page = VirtualAddress(page);
+            let is_cow = if let MappingFrames::CopyOnWrite(_) = self.query_memory(page).mapping().frames() { true } else { false };
+            if is_cow {
+                self.unshare_page(page)?;
+            } else {
+                self.commit_range(page, PAGE_SIZE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets copy-on-write references to the pages in `address..address + length`, to map them
+    /// in another mapping with [map_partial_mapping]. The range must fall in a single mapping.
+    ///
+    /// If the mapping is copy-on-write, its pages are now shared with the returned ones, and they
+    /// are remapped read-only in this address space.
+    ///
+    /// Otherwise, the returned pages borrow the frames of the mapping, which is left as is. The
+    /// frames must not be written to through it anymore, or the changes would be visible through
+    /// the returned pages. Pages that were never accessed are not borrowed, they are committed
+    /// separately.
+    ///
+    /// [map_partial_mapping]: ProcessMemory::map_partial_mapping
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidAddress`:
+    ///     * `address` is not page aligned.
+    ///     * `address` does not fall in a mapping.
+    /// * `InvalidSize`:
+    ///     * `length` is not page aligned.
+    ///     * `length` is 0.
+    ///     * the range does not fall in a single mapping.
+    /// * `InvalidMemState`:
+    ///     * the mapping is not backed by frames, or its frames are not refcounted.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    pub fn copy_on_write_pages(&mut self, address: VirtualAddress, length: usize) -> Result<Vec<Option<Arc<CowFrame>>>, KernelError> {
+        address.check_aligned_to(PAGE_SIZE)?;
+        check_size_aligned(length, PAGE_SIZE)?;
+        check_nonzero_length(length)?;
+        let mapping = self.userspace_bookkeping.occupied_mapping_at(address)?;
+        let offset_in_mapping = address - mapping.address();
+        if mapping.length() - offset_in_mapping < length {
+            return Err(KernelError::InvalidSize { size: length, backtrace: Backtrace::new() })
+        }
+        let first_page = (mapping.phys_offset() + offset_in_mapping) / PAGE_SIZE;
+        let page_count = length / PAGE_SIZE;
+        let is_cow = if let MappingFrames::CopyOnWrite(_) = mapping.frames() { true } else { false };
+
+        let pages = match mapping.frames() {
+            MappingFrames::CopyOnWrite(slots) => slots[first_page..][..page_count].to_vec(),
+            MappingFrames::Lazy(slots) => slots.read()[first_page..][..page_count].iter()
+                .map(|slot| slot.as_ref().map(|frame| Arc::new(CowFrame::Borrowed(MappingFrames::Lazy(slots.clone()), frame.address()))))
+                .collect(),
+            MappingFrames::Shared(frames) => mapping.frames_it()
+                .skip(offset_in_mapping / PAGE_SIZE)
+                .take(page_count)
+                .map(|frame| Some(Arc::new(CowFrame::Borrowed(MappingFrames::Shared(frames.clone()), frame))))
+                .collect(),
+            MappingFrames::Owned(_) | MappingFrames::None =>
+                return Err(KernelError::InvalidMemState { address, ty: mapping.state().ty(), backtrace: Backtrace::new() })
+        };
+
+        if is_cow {
+            // the frames are not private anymore, we must not write to them.
+            // pages that fail to be remapped are mapped again on their next access.
+            self.unmap_pages(address, length)?;
+            self.map_committed_pages(address, length)?;
+        }
+        Ok(pages)
+    }
+
+    /// Prints the mappings of this address space, with their type and permissions,
+    /// to the kernel log. Used for debugging purposes.
+    pub fn dump_mappings(&self) {
+        for mapping in self.userspace_bookkeping.mappings() {
+            info!("{:#010x} - {:#010x} - {:?} {:?} ({} bytes committed)",
+                  mapping.address(), mapping.address() + (mapping.length() - 1),
+                  mapping.state().ty(), mapping.flags(), mapping.committed_length());
+        }
+    }
+
+    /// Creates a copy of this address space, for fork-style process creation.
+    ///
+    /// Shared memory, IPC buffers and the memory of other processes stay shared between both
+    /// address spaces. The pages of every other mapping become copy-on-write in both address
+    /// spaces: they share their frames read-only, and get a private copy on the first write.
+    ///
+    /// The new address space is built first. This one is only converted once nothing can fail
+    /// anymore, so it is left as is on error.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidMemState`:
+    ///     * a mapping has frames that are not refcounted, such as device memory, which can
+    ///       neither be shared nor copied.
+    /// * `OutOfMemory`: a page table could not be allocated.
+    pub fn fork(&mut self) -> Result<ProcessMemory, KernelError> {
+        let mut forked = ProcessMemory {
+            heap_base_address: self.heap_base_address,
+            ..ProcessMemory::new()?
+        };
+
+        // copy_on_write_pages needs self mutably, only keep the bounds of the mappings.
+        let mappings: Vec<(VirtualAddress, usize)> = self.userspace_bookkeping.mappings()
+            .filter(|mapping| UserLand::contains_region(mapping.address(), mapping.length()))
+            .map(|mapping| (mapping.address(), mapping.length()))
+            .collect();
+
+        // the mappings of this address space that must become copy-on-write, with their pages.
+        let mut conversions = Vec::new();
+
+        for (address, length) in mappings {
+            let mapping = self.userspace_bookkeping.occupied_mapping_at(address)
+                .expect("Mapping disappeared while forking");
+            let (ty, flags, phys_offset) = (mapping.state().ty(), mapping.flags(), mapping.phys_offset());
+            let is_cow = if let MappingFrames::CopyOnWrite(_) = mapping.frames() { true } else { false };
+            let is_shared_memory = match ty {
+                MemoryType::SharedMemory | MemoryType::TransferMemory | MemoryType::TransferMemoryIsolated |
+                MemoryType::Ipc | MemoryType::NonSecureIpc | MemoryType::NonDeviceIpc |
+                MemoryType::ProcessMemory => true,
+                _ => false
+            };
+            let shared_frames = match mapping.frames() {
+                MappingFrames::None => {
+                    forked.guard(address, length, ty)?;
+                    continue;
+                },
+                MappingFrames::Owned(_) =>
+                    return Err(KernelError::InvalidMemState { address, ty, backtrace: Backtrace::new() }),
+                MappingFrames::Shared(frames) if is_shared_memory => Some(MappingFrames::Shared(frames.clone())),
+                MappingFrames::Lazy(frames) if is_shared_memory => Some(MappingFrames::Lazy(frames.clone())),
+                _ => None
+            };
+            if let Some(frames) = shared_frames {
+                forked.map_partial_mapping(frames, address, phys_offset, length, ty, flags)?;
+                continue;
+            }
+
+            let pages = self.copy_on_write_pages(address, length)?;
+            forked.map_partial_mapping(MappingFrames::CopyOnWrite(pages.clone()), address, 0, length, ty, flags)?;
+            if !is_cow {
+                // this address space keeps using the same pages, copy-on-write too.
+                conversions.push((address, length, pages, ty, flags));
+            }
+        }
+
+        // the forked address space is complete, convert this one without failing.
+        for (address, length, pages, ty, flags) in conversions {
+            let mapping = Mapping::new(address, MappingFrames::CopyOnWrite(pages), 0, length, ty, flags)
+                .expect("Failed creating a mapping with the bounds of an existing one");
+            let old_mapping = self.userspace_bookkeping.remove_mapping(address, length)
+                .expect("Mapping disappeared while forking");
+            self.userspace_bookkeping.add_mapping(mapping)
+                .expect("Failed adding a mapping in place of the one we just removed");
+            // only Owned mappings contain huge pages, and we refused to fork them.
+            self.unmap_pages(address, length)
+                .expect("Forked a mapping containing huge pages");
+            // pages that fail to be remapped read-only are mapped again on their next access.
+            let _ = self.map_committed_pages(address, length);
+            drop(old_mapping);
+        }
+        Ok(forked)
+    }
+
     /*/// Shrink the mapping at `address` to `new_size`.
     ///
     /// If `new_size` == 0, the mapping is unmapped entirely.
@@ -383,7 +847,7 @@ impl ProcessMemory {
 
     /// Expand the Heap at `address` to `new_size`.
     ///
-    /// New frames are allocated to match `new_size`.
+    /// The added part is committed lazily, like the rest of the heap.
     ///
     /// If `new_size` is equal to old size, nothing is done.
     ///
@@ -399,11 +863,12 @@ impl ProcessMemory {
     ///     * `new_size` is not page aligned.
     /// * `InvalidMemState`:
     ///     * `address` does not point to a Heap memory mapping.
+    /// * `OutOfMemory`: frames or page tables could not be allocated.
     pub fn expand_mapping(&mut self, address: VirtualAddress, new_size: usize) -> Result<(), KernelError> {
         check_size_aligned(new_size, PAGE_SIZE)?;
         // 1. get the previous mapping's address and size.
         let old_mapping_ref = self.userspace_bookkeping.occupied_mapping_at(address)?;
-        let (start_addr, old_size) = {
+        let (start_addr, old_size, flags, is_lazy) = {
             // Check we're resizing the heap.
             if old_mapping_ref.state().ty() != MemoryType::Heap {
                 return Err(KernelError::InvalidMemState { address: address, ty: old_mapping_ref.state().ty(), backtrace: Backtrace::new() });
@@ -412,7 +877,8 @@ impl ProcessMemory {
             if let MappingFrames::Owned(..) | MappingFrames::None = old_mapping_ref.frames() {
                 return Err(KernelError::InvalidAddress { address: address.addr(), backtrace: Backtrace::new() });
             }
-            (old_mapping_ref.address(), old_mapping_ref.length())
+            let is_lazy = if let MappingFrames::Lazy(..) | MappingFrames::CopyOnWrite(..) = old_mapping_ref.frames() { true } else { false };
+            (old_mapping_ref.address(), old_mapping_ref.length(), old_mapping_ref.flags(), is_lazy)
         };
 
         // 2. Check the area we're extending to is available.
@@ -426,25 +892,42 @@ impl ProcessMemory {
         let added_length = new_size - old_size;
         self.userspace_bookkeping.check_vacant(start_addr + old_size, added_length)?;
 
-        // 3. allocate the new frames.
-        let mut new_frames = FrameAllocator::allocate_frames_fragmented(added_length)?;
+        // 3. allocate the new frames, if the heap is not lazily-committed.
+        let mut new_frames = if is_lazy {
+            Vec::new()
+        } else {
+            let new_frames = FrameAllocator::allocate_frames_fragmented(added_length)?;
+            // map them right away, the frames are freed if it fails.
+            self.get_hierarchy().map_to_from_iterator(new_frames.iter().flatten(), start_addr + old_size, flags)?;
+            new_frames
+        };
 
         // 4. remove old mapping from the bookkeeping.
         let old_mapping = self.userspace_bookkeping.remove_mapping(start_addr, old_size)
             .expect("expand_mapping: removing the mapping failed.");
-        let flags = old_mapping.flags();
 
         // 5. construct a new bigger mapping, with the same type and flags.
-        let new_mapping = if let MappingFrames::Shared(frames) = old_mapping.frames() {
-            // 6. map the added part accordingly.
-            self.get_hierarchy().map_to_from_iterator(new_frames.iter().flatten(), start_addr + old_size, flags);
-            // create a mapping from the freshly allocated frames and the flags.
-            frames.write().append(&mut new_frames);
-            Mapping::new(start_addr, MappingFrames::Shared(frames.clone()), 0, new_size, MemoryType::Heap, flags)
-                .expect("expand_mapping: couldn't recreate mapping")
-        } else {
-            unreachable!("We checked we could only get a MappingFrames earlier.");
+        let frames = match old_mapping.frames() {
+            MappingFrames::Lazy(frames) => {
+                // 6. the added part will be committed on first access.
+                frames.write().extend((0..added_length / PAGE_SIZE).map(|_| None));
+                MappingFrames::Lazy(frames.clone())
+            },
+            MappingFrames::CopyOnWrite(pages) => {
+                // 6. the added part will be committed on first access.
+                let mut pages = pages.clone();
+                pages.extend((0..added_length / PAGE_SIZE).map(|_| None));
+                MappingFrames::CopyOnWrite(pages)
+            },
+            MappingFrames::Shared(frames) => {
+                // 6. the added part was already mapped.
+                frames.write().append(&mut new_frames);
+                MappingFrames::Shared(frames.clone())
+            },
+            _ => unreachable!("We checked we could only get a MappingFrames earlier.")
         };
+        let new_mapping = Mapping::new(start_addr, frames, 0, new_size, MemoryType::Heap, flags)
+            .expect("expand_mapping: couldn't recreate mapping");
         self.userspace_bookkeping.add_mapping(new_mapping)
             .expect("expand_mapping: failed re-adding the mapping to the bookkeeping");
         Ok(())
@@ -463,11 +946,17 @@ impl ProcessMemory {
     /// Retrieves the mapping that `address` falls into, and mirror it in KernelLand.
     /// The mapping will be kept alive until the `CrossProcessMapping` is dropped.
     ///
+    /// The mirrored pages are made private to this address space first, as the kernel might
+    /// write to them. See [unshare_range].
+    ///
+    /// [unshare_range]: ProcessMemory::unshare_range
+    ///
     /// # Error
     ///
     /// Returns an Error if the mapping is not RefCounted.
-    pub fn mirror_mapping(&self, address: VirtualAddress, length: usize) -> Result<CrossProcessMapping, KernelError> {
+    pub fn mirror_mapping(&mut self, address: VirtualAddress, length: usize) -> Result<CrossProcessMapping, KernelError> {
         UserLand::check_contains_address(address)?;
+        self.unshare_range(address, length)?;
         let mapping = self.userspace_bookkeping.occupied_mapping_at(address)?;
         let offset = address - mapping.address();
         CrossProcessMapping::mirror_mapping(mapping, offset, length)
@@ -587,3 +1076,192 @@ impl ProcessMemory {
     }
 }
 
+/// Returns the access rights a page of a copy-on-write mapping with `flags` is mapped with.
+///
+/// The page is read-only until its frame is private, so that the first write to it faults.
+fn cow_page_rights(page: &Arc<CowFrame>, flags: MappingAccessRights) -> MappingAccessRights {
+    if CowFrame::is_private(page) {
+        flags
+    } else {
+        flags - MappingAccessRights::WRITABLE
+    }
+}
+
+/// Allocates a frame, and fills it with a copy of the frame at `source`, or with zeroes.
+///
+/// # Errors
+///
+/// * `OutOfMemory`: a frame or a page table could not be allocated.
+/// * `VirtualMemoryExhaustion`: the frames could not be mapped in KernelLand.
+fn allocate_filled_frame(source: Option<PhysicalAddress>) -> Result<PhysicalMemRegion, KernelError> {
+    let frame = FrameAllocator::allocate_frame()?;
+    let mut kernel_memory = get_kernel_memory();
+    // one page for the new frame, one for the source.
+    let dest_addr = kernel_memory.find_virtual_space(2 * PAGE_SIZE)?;
+    let source_addr = dest_addr + PAGE_SIZE;
+    unsafe {
+        // safe: the frame was just allocated, no one else is referencing it.
+        kernel_memory.map_frame_iterator_to(core::iter::once(frame.address()), dest_addr, MappingAccessRights::k_rw())?;
+    }
+    let res = match source {
+        None => unsafe {
+            core::ptr::write_bytes(dest_addr.addr() as *mut u8, 0, PAGE_SIZE);
+            Ok(())
+        },
+        Some(source) => unsafe {
+            // safe: the source frame is only read, and is kept alive by the caller.
+            kernel_memory.map_frame_iterator_to(core::iter::once(source), source_addr, MappingAccessRights::k_r())
+                .map(|()| {
+                    core::ptr::copy_nonoverlapping(source_addr.addr() as *const u8, dest_addr.addr() as *mut u8, PAGE_SIZE);
+                    kernel_memory.unmap_whole_mapping_no_dealloc(source_addr, PAGE_SIZE);
+                })
+        }
+    };
+    kernel_memory.unmap_whole_mapping_no_dealloc(dest_addr, PAGE_SIZE);
+    res.map(|()| frame)
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    //! Tests running on inactive address spaces, in kernel context.
+
+    use super::*;
+
+    /// Writes `value` to the first byte of the page at `address`, giving it a private frame.
+    fn write_byte(memory: &mut ProcessMemory, address: VirtualAddress, value: u8) {
+        let mirror = memory.mirror_mapping(address, 1).unwrap();
+        unsafe {
+            // safe: the page is mapped in KernelLand for as long as the mirror lives.
+            *(mirror.addr().addr() as *mut u8) = value;
+        }
+    }
+
+    /// Reads the first byte of the page at `address`, without unsharing it.
+    fn read_byte(memory: &ProcessMemory, address: VirtualAddress) -> u8 {
+        let query = memory.query_memory(address);
+        let mapping = query.mapping();
+        let mirror = CrossProcessMapping::mirror_mapping(mapping, address - mapping.address(), 1).unwrap();
+        unsafe {
+            // safe: the page is mapped in KernelLand for as long as the mirror lives.
+            *(mirror.addr().addr() as *const u8)
+        }
+    }
+
+    /// Gets the frame of the page at `address`, which must be part of a copy-on-write mapping.
+    /// None if the page was never committed.
+    fn cow_frame(memory: &ProcessMemory, address: VirtualAddress) -> Option<PhysicalAddress> {
+        let query = memory.query_memory(address);
+        let mapping = query.mapping();
+        match mapping.frames() {
+            MappingFrames::CopyOnWrite(slots) => {
+                let index = (mapping.phys_offset() + (address - mapping.address())) / PAGE_SIZE;
+                slots[index].as_ref().map(|frame| frame.address())
+            },
+            frames => panic!("Expected a copy-on-write mapping, got {:?}", frames)
+        }
+    }
+
+    /// Creates an address space with a Heap mapping of `length` bytes, and writes 1 to its
+    /// first page.
+    fn heap_memory(length: usize) -> (ProcessMemory, VirtualAddress) {
+        let mut memory = ProcessMemory::new().unwrap();
+        let address = memory.find_available_space(length).unwrap();
+        memory.create_regular_mapping(address, length, MemoryType::Heap, MappingAccessRights::u_rw()).unwrap();
+        write_byte(&mut memory, address, 1);
+        (memory, address)
+    }
+
+    /// Mappings owning their frames can't be forked.
+    #[test_case]
+    fn fork_owned() {
+        let mut memory = ProcessMemory::new().unwrap();
+        let address = memory.find_available_space(PAGE_SIZE).unwrap();
+        let frame = FrameAllocator::allocate_frame().unwrap();
+        memory.map_phys_region_to(frame, address, MemoryType::Io, MappingAccessRights::u_rw()).unwrap();
+
+        match memory.fork() {
+            Err(KernelError::InvalidMemState { .. }) => (),
+            res => panic!("Forking an Owned mapping gave {:?}", res.map(|_| ()))
+        }
+    }
+
+    /// A failed fork leaves the address space as is, even the mappings it went through before
+    /// failing.
+    #[test_case]
+    fn fork_failed() {
+        let (mut memory, address) = heap_memory(PAGE_SIZE);
+        let io_address = memory.find_available_space(PAGE_SIZE).unwrap();
+        assert!(io_address > address, "The heap must be forked before the failing mapping");
+        let frame = FrameAllocator::allocate_frame().unwrap();
+        memory.map_phys_region_to(frame, io_address, MemoryType::Io, MappingAccessRights::u_rw()).unwrap();
+
+        assert!(memory.fork().is_err(), "Forking an Owned mapping succeeded");
+        match memory.query_memory(address).mapping().frames() {
+            MappingFrames::Lazy(_) => (),
+            frames => panic!("Failed fork converted the heap to {:?}", frames)
+        }
+        write_byte(&mut memory, address, 2);
+        assert_eq!(read_byte(&memory, address), 2);
+    }
+
+    /// Shared memory stays shared: each address space sees the writes of the other.
+    #[test_case]
+    fn fork_shared() {
+        let mut memory = ProcessMemory::new().unwrap();
+        let address = memory.find_available_space(PAGE_SIZE).unwrap();
+        memory.create_regular_mapping(address, PAGE_SIZE, MemoryType::SharedMemory, MappingAccessRights::u_rw()).unwrap();
+        let mut forked = memory.fork().unwrap();
+
+        match (memory.query_memory(address).mapping().frames(), forked.query_memory(address).mapping().frames()) {
+            (MappingFrames::Shared(frames), MappingFrames::Shared(forked_frames)) =>
+                assert!(Arc::ptr_eq(frames, forked_frames), "Shared memory was copied"),
+            frames => panic!("Shared memory is not Shared anymore: {:?}", frames)
+        }
+
+        write_byte(&mut forked, address, 42);
+        assert_eq!(read_byte(&memory, address), 42);
+    }
+
+    /// Lazily-committed memory becomes copy-on-write: committed pages are shared until written
+    /// to, and the pages never accessed stay uncommitted.
+    #[test_case]
+    fn fork_lazy() {
+        let (mut memory, address) = heap_memory(2 * PAGE_SIZE);
+        let mut forked = memory.fork().unwrap();
+
+        let frame = cow_frame(&memory, address);
+        assert!(frame.is_some(), "Committed page was lost");
+        assert_eq!(cow_frame(&forked, address), frame);
+        assert_eq!(cow_frame(&memory, address + PAGE_SIZE), None);
+        assert_eq!(cow_frame(&forked, address + PAGE_SIZE), None);
+
+        write_byte(&mut forked, address, 2);
+        assert_ne!(cow_frame(&forked, address), frame);
+        assert_eq!(read_byte(&forked, address), 2);
+        assert_eq!(read_byte(&memory, address), 1);
+
+        write_byte(&mut memory, address, 3);
+        assert_eq!(read_byte(&memory, address), 3);
+        assert_eq!(read_byte(&forked, address), 2);
+    }
+
+    /// Copy-on-write memory stays copy-on-write: its pages are shared by every fork until
+    /// written to.
+    #[test_case]
+    fn fork_copy_on_write() {
+        let (mut memory, address) = heap_memory(PAGE_SIZE);
+        let mut first = memory.fork().unwrap();
+        let second = first.fork().unwrap();
+
+        let frame = cow_frame(&memory, address);
+        assert_eq!(cow_frame(&first, address), frame);
+        assert_eq!(cow_frame(&second, address), frame);
+
+        write_byte(&mut memory, address, 2);
+        write_byte(&mut first, address, 3);
+        assert_eq!(read_byte(&memory, address), 2);
+        assert_eq!(read_byte(&first, address), 3);
+        assert_eq!(read_byte(&second, address), 1);
+        assert_eq!(cow_frame(&second, address), frame);
+    }
+}
@@ -9,8 +9,15 @@ use crate::syscalls::map_framebuffer;
 use crate::devices::rs232::SerialLogger;
 use crate::i386::gdt::MAIN_TASK;
 use crate::scheduler::try_get_current_thread;
-use core::fmt::Write;
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+use core::mem::size_of;
+use core::ptr::read_volatile;
+use alloc::sync::{Arc, Weak};
 use crate::i386::registers::eflags::EFlags;
+use crate::process::{ProcessStruct, ThreadStruct};
+use crate::mem::VirtualAddress;
+use crate::paging::lands::{UserLand, VirtualSpaceLand};
 
 /// Reason for a kernel panic. Must be passed to [kernel_panic].
 #[allow(missing_debug_implementations)] // want to display it ? pass it to kernel_panic() !
@@ -72,10 +79,8 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
         SerialLogger.force_unlock();
     }
 
-    // Get the process we were running, and its name. Gonna be quite useful.
+    // Get the thread we were running, and its process. Gonna be quite useful.
     let current_thread = try_get_current_thread();
-    let current_process = current_thread.as_ref().map(|t| t.process.clone());
-    let current_process_name = current_process.as_ref().map(|p| &p.name);
 
     //todo: force unlock the KernelMemory lock
     //      and also the process memory lock for userspace stack dumping (only if panic-on-excetpion ?).
@@ -97,8 +102,13 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
                                             ! Good luck.");
         }
         PanicOrigin::UserspaceFault { exception_message: msg, ..} => {
-            let _ = writeln!(SerialLogger, "! Userspace exception in {:?}.\n\
-                                            ! {}", current_process_name, msg);
+            if let Some(t) = &current_thread {
+                let _ = writeln!(SerialLogger, "! Userspace exception in {}.\n\
+                                                ! {}", t, msg);
+            } else {
+                let _ = writeln!(SerialLogger, "! Userspace exception in an unknown thread.\n\
+                                                ! {}", msg);
+            }
         }
     }
 
@@ -115,9 +125,12 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
         _ => { /* You're not desperate enough */ }
     }
 
-    // Show the name of the process we were running
-    // should show thread id in the future.
-    let _ = writeln!(SerialLogger, "Process: {:?}", current_process_name);
+    // Show the process and thread we were running, as `process:thread`.
+    if let Some(t) = &current_thread {
+        let _ = writeln!(SerialLogger, "Thread: {}", t);
+    } else {
+        let _ = writeln!(SerialLogger, "Thread: None");
+    }
 
     // Show hardware context
     match panic_origin {
@@ -151,6 +164,9 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
         let _ = writeln!(SerialLogger, "Current thread: {:#?}", t);
     }
 
+    // Emit the machine-readable summary, before anything that could fault again.
+    write_crash_record(panic_origin, current_thread.as_ref());
+
     // display a stack dump
 
     // Parse the ELF to get the symbol table.
@@ -185,15 +201,38 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
 
     // Then print the stack
     match panic_origin {
-        PanicOrigin::UserspaceFault { userspace_hardware_context: register, .. } =>
-            unsafe {
-                // TODO: Dump stack in a thread-safe way.
-                // BODY: dump_stack should allow reading the stack in a
-                // BODY: thread-safe way through the use of volatile reads. This
-                // BODY: would allow having a totally safe dump_stack function
-                // BODY: that can be used at will.
-                crate::stack::dump_stack(&crate::stack::StackDumpSource::new(register.esp, register.ebp, register.eip), elf_and_st)
-            },
+        PanicOrigin::UserspaceFault { userspace_hardware_context: register, .. } => {
+            let _ = writeln!(SerialLogger, "---------- Userspace backtrace ---------");
+            // Interrupts are disabled, the other threads of the process cannot run anymore.
+            // Holding the lock makes sure we didn't interrupt a change to its address space.
+            let pmemory = current_thread.as_ref()
+                .and_then(|thread| thread.process.pmemory.try_lock().ok());
+            match pmemory {
+                Some(mut pmemory) => {
+                    let mut frame_nb = 0;
+                    crate::stack::walk_stack(|addr| {
+                        // Reading a page that isn't mapped would fault again.
+                        let addr = VirtualAddress(addr);
+                        if addr.addr() % size_of::<usize>() != 0
+                            || !UserLand::contains_address(addr)
+                            || !pmemory.is_page_mapped(addr.floor()) {
+                            return None;
+                        }
+                        Some(unsafe {
+                            // safe: the page is mapped, and can't be unmapped while we hold the lock.
+                            read_volatile(addr.addr() as *const usize)
+                        })
+                    }, register.ebp, register.eip, |eip| {
+                        let _ = writeln!(SerialLogger, "> Frame #{} - eip: {:#010x}", frame_nb, eip);
+                        frame_nb += 1;
+                    });
+                },
+                None => {
+                    let _ = writeln!(SerialLogger, "# Cannot lock the address space of the process");
+                }
+            }
+            let _ = writeln!(SerialLogger, "-------- End of userspace backtrace --------");
+        },
         _ => crate::stack::KernelStack::dump_current_stack(elf_and_st)
     }
 
@@ -202,10 +241,221 @@ pub fn kernel_panic(panic_origin: &PanicOrigin) -> ! {
 
     let _ = writeln!(SerialLogger, "!!!!!!!!!!!!!!!END PANIC!!!!!!!!!!!!!!");
 
+    // When running the kernel tests, this fails the test that was running.
+    #[cfg(all(test, target_os = "none"))]
+    crate::test_runner::exit_qemu(crate::test_runner::QemuExitCode::Failed);
+
     loop { unsafe { asm!("HLT"); } }
 }
 
 
+/// Line opening a crash record on the serial port. See [write_crash_record].
+pub const CRASH_RECORD_BEGIN: &str = "===== BEGIN SUNRISE CRASH RECORD =====";
+
+/// Line closing a crash record on the serial port. See [write_crash_record].
+pub const CRASH_RECORD_END: &str = "===== END SUNRISE CRASH RECORD =====";
+
+/// Version of the crash record format. Bumped on every incompatible change.
+pub const CRASH_RECORD_VERSION: u32 = 1;
+
+/// A fmt::Write escaping backslashes and line breaks, so that any string fits on a single line
+/// of the crash record.
+struct EscapingWriter<W: Write>(W);
+
+impl<W: Write> Write for EscapingWriter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            let escaped = match c {
+                '\\' => "\\\\",
+                '\n' => "\\n",
+                '\r' => "\\r",
+                _ => continue
+            };
+            self.0.write_str(&s[start..i])?;
+            self.0.write_str(escaped)?;
+            start = i + c.len_utf8();
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Gets the build id the linker put in the `.note.gnu.build-id` section of the kernel.
+///
+/// Returns an empty slice if the note is missing or malformed.
+#[cfg(target_os = "none")]
+fn build_id() -> &'static [u8] {
+    extern "C" {
+        /// Start of the build id note, defined by the linker script.
+        static BUILD_ID_NOTE_START: u8;
+        /// End of the build id note, defined by the linker script.
+        static BUILD_ID_NOTE_END: u8;
+    }
+
+    let note = unsafe {
+        // safe: the linker script places both symbols around the note, in .rodata.
+        let start = &BUILD_ID_NOTE_START as *const u8;
+        let end = &BUILD_ID_NOTE_END as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    // An ELF note is namesz, descsz, type, then the name and the desc, both padded to 4 bytes.
+    let word = |offset: usize| note.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+    let (namesz, descsz) = match (word(0), word(4)) {
+        (Some(namesz), Some(descsz)) => (namesz, descsz),
+        _ => return &[]
+    };
+    let desc_start = 12 + ((namesz + 3) & !3);
+    note.get(desc_start..desc_start + descsz).unwrap_or(&[])
+}
+
+/// Gets the build id of the kernel. Not available outside of the kernel image.
+#[cfg(not(target_os = "none"))]
+fn build_id() -> &'static [u8] {
+    &[]
+}
+
+/// Writes a `registers` line of the crash record.
+#[allow(unused_must_use)]
+fn write_crash_registers(registers: &[(&str, usize)]) {
+    write!(SerialLogger, "registers");
+    for (name, value) in registers {
+        write!(SerialLogger, " {}={:#010x}", name, value);
+    }
+    writeln!(SerialLogger);
+}
+
+/// Writes a `thread` line of the crash record, followed by the backtrace of its kernel stack,
+/// starting from the `(ebp, eip)` frame.
+#[allow(unused_must_use)]
+fn write_crash_thread(pid: usize, thread: &ThreadStruct, is_current: bool, frame: Option<(usize, usize)>) {
+    write!(SerialLogger, "thread {} {} {:?} {} ", pid, thread.id, thread.state.load(Ordering::SeqCst),
+           if is_current { "current" } else { "saved" });
+    write!(EscapingWriter(SerialLogger), "{}", thread);
+    writeln!(SerialLogger);
+
+    if let Some((ebp, eip)) = frame {
+        unsafe {
+            // safe: interrupts are disabled forever, so the thread is either the current one,
+            //       or scheduled-out for good.
+            thread.kstack.walk(ebp, eip, |pc| { writeln!(SerialLogger, "frame {:#010x}", pc); });
+        }
+    }
+}
+
+/// Writes the crash record: a machine-readable summary of the panic, meant to be parsed and
+/// symbolized on the host by the `crash-symbolizer` tool.
+///
+/// It is delimited by [CRASH_RECORD_BEGIN] and [CRASH_RECORD_END], and is made of `key value`
+/// lines. Lines starting with `#` are comments.
+///
+/// ```text
+/// version <CRASH_RECORD_VERSION>
+/// build-id <hex, empty if unknown>
+/// reason <kernel-assert|kernel-fault|double-fault|userspace-fault>
+/// message <escaped panic message>
+/// registers eip=<hex> esp=<hex> ...           (only if known)
+/// thread <pid> <tid> <state> <current|saved> <escaped process:thread>
+/// frame <hex pc>                              (backtrace of the thread above, innermost first)
+/// ```
+///
+/// Every thread of every process gets a `thread` line, and the backtrace of its kernel stack:
+/// from where it was scheduled-out, or from the panic for the current thread.
+///
+/// This takes no blocking lock and doesn't allocate. Whatever is locked is skipped.
+#[inline(never)] // we need our own frame, to start walking the current stack from it
+#[allow(unused_must_use)]
+#[allow(clippy::fn_to_numeric_cast)]
+fn write_crash_record(panic_origin: &PanicOrigin, current_thread: Option<&Arc<ThreadStruct>>) {
+    writeln!(SerialLogger, "{}", CRASH_RECORD_BEGIN);
+    writeln!(SerialLogger, "version {}", CRASH_RECORD_VERSION);
+
+    write!(SerialLogger, "build-id ");
+    for byte in build_id() {
+        write!(SerialLogger, "{:02x}", byte);
+    }
+    writeln!(SerialLogger);
+
+    let (reason, message) = match panic_origin {
+        PanicOrigin::KernelAssert { panic_message: msg } => ("kernel-assert", Some(msg)),
+        PanicOrigin::KernelFault { exception_message: msg, .. } => ("kernel-fault", Some(msg)),
+        PanicOrigin::DoubleFault => ("double-fault", None),
+        PanicOrigin::UserspaceFault { exception_message: msg, .. } => ("userspace-fault", Some(msg)),
+    };
+    writeln!(SerialLogger, "reason {}", reason);
+    write!(SerialLogger, "message ");
+    if let Some(msg) = message {
+        write!(EscapingWriter(SerialLogger), "{}", msg);
+    }
+    writeln!(SerialLogger);
+
+    match panic_origin {
+        PanicOrigin::KernelFault { kernel_hardware_context: r, .. } |
+        PanicOrigin::UserspaceFault { userspace_hardware_context: r, .. } => {
+            write_crash_registers(&[("eip", r.eip), ("esp", r.esp), ("ebp", r.ebp),
+                ("eax", r.eax), ("ebx", r.ebx), ("ecx", r.ecx), ("edx", r.edx),
+                ("esi", r.esi), ("edi", r.edi), ("eflags", r.eflags)]);
+        },
+        PanicOrigin::DoubleFault => {
+            if let Some(main) = MAIN_TASK.try_lock() {
+                let tss = &main.tss;
+                write_crash_registers(&[("eip", tss.eip as usize), ("esp", tss.esp as usize),
+                    ("ebp", tss.ebp as usize), ("eax", tss.eax as usize), ("ebx", tss.ebx as usize),
+                    ("ecx", tss.ecx as usize), ("edx", tss.edx as usize), ("esi", tss.esi as usize),
+                    ("edi", tss.edi as usize), ("eflags", tss.eflags as usize), ("cr3", tss.cr3 as usize)]);
+            }
+        },
+        PanicOrigin::KernelAssert { .. } => { /* nothing interesting */ }
+    }
+
+    // Where the backtrace of the current thread starts.
+    let current_frame = match panic_origin {
+        PanicOrigin::KernelFault { kernel_hardware_context: r, .. } => Some((r.ebp, r.eip)),
+        PanicOrigin::DoubleFault => MAIN_TASK.try_lock()
+            .map(|main| (main.tss.ebp as usize, main.tss.eip as usize)),
+        // We're running on the current thread's kernel stack, start from our own frame.
+        _ => {
+            let ebp: usize;
+            unsafe { asm!("mov $0, ebp" : "=r"(ebp) ::: "intel") };
+            Some((ebp, write_crash_record as usize))
+        }
+    };
+
+    let mut current_written = false;
+    let all_processes = ProcessStruct::try_for_each(|process| {
+        let threads = match process.threads.try_lock() {
+            Some(threads) => threads,
+            None => {
+                writeln!(SerialLogger, "# threads of process {} are locked", process.pid);
+                return;
+            }
+        };
+        for thread in threads.iter().filter_map(Weak::upgrade) {
+            let is_current = current_thread.map_or(false, |current| Arc::ptr_eq(current, &thread));
+            let frame = if is_current {
+                current_written = true;
+                current_frame
+            } else {
+                // safe: interrupts are disabled forever, the thread is scheduled-out for good.
+                thread.hwcontext.try_lock()
+                    .and_then(|hwcontext| unsafe { hwcontext.saved_frame(&thread.kstack) })
+            };
+            write_crash_thread(process.pid, &thread, is_current, frame);
+        }
+    });
+    if !all_processes {
+        writeln!(SerialLogger, "# process list is locked");
+    }
+
+    // The current thread may not be in the process list, e.g. the idle thread.
+    if let (Some(current), false) = (current_thread, current_written) {
+        write_crash_thread(current.process.pid, current, true, current_frame);
+    }
+
+    writeln!(SerialLogger, "{}", CRASH_RECORD_END);
+}
+
 /// The "Blue Screen Of Death"
 ///
 /// Stored as an uncompressed BMP, so we don't have to do decompression in the panic handler,
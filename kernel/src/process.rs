@@ -9,8 +9,10 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use crate::event::{IRQEvent, ReadableEvent, WritableEvent, Waitable};
 use crate::sync::{SpinLockIRQ, SpinLock, Mutex};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::fmt;
 use crate::scheduler;
+use crate::timer;
 use crate::error::{KernelError, UserspaceError};
 use crate::ipc::{ServerPort, ClientPort, ServerSession, ClientSession};
 use crate::mem::VirtualAddress;
@@ -26,8 +28,9 @@ pub use self::capabilities::ProcessCapabilities;
 use crate::paging::{InactiveHierarchy, InactiveHierarchyTrait, PAGE_SIZE, MappingAccessRights};
 use self::thread_local_storage::TLSManager;
 use crate::i386::interrupt_service_routines::UserspaceHardwareContext;
-use sunrise_libkern::process::{ProcessState, ProcInfo};
+use sunrise_libkern::process::{ProcessState, ProcInfo, ExitReason};
 use sunrise_libkern::MemoryType;
+use sunrise_libkern::{CURRENT_THREAD_PSEUDO_HANDLE, CURRENT_PROCESS_PSEUDO_HANDLE};
 
 /// Data related to the (user-visible) state the current process is in. The
 /// maternity is stored here to ensure there is no race condition between
@@ -126,6 +129,29 @@ pub struct ProcessStruct {
 
     /// Tracks used and free allocated Thread Local Storage regions of this process.
     pub tls_manager: Mutex<TLSManager>,
+
+    /// Whether this process was paused through `svcSetProcessActivity`.
+    ///
+    /// None of the threads of a paused process get scheduled until it is resumed.
+    /// Only modified by the scheduler, with the schedule queue locked.
+    pub activity_paused: AtomicBool,
+
+    /// The reason this process exited with, as passed to `svcExitProcess`.
+    ///
+    /// Stays `ExitReason::Exited` if the process was killed or is still alive.
+    pub exit_reason: Atomic<ExitReason>,
+
+    /// CPU time used by the threads of this process that were already dropped.
+    ///
+    /// See [ProcessStruct::cpu_time] for the total CPU time of the process.
+    dead_threads_cpu_time: SpinLockIRQ<CpuTime>,
+}
+
+lazy_static! {
+    /// All the processes currently alive, indexed by PID.
+    ///
+    /// Holds only weak references: a process is removed from it when dropped.
+    static ref PROCESSES: SpinLockIRQ<BTreeMap<usize, Weak<ProcessStruct>>> = SpinLockIRQ::new(BTreeMap::new());
 }
 
 /// Next available PID.
@@ -133,9 +159,23 @@ pub struct ProcessStruct {
 /// PIDs are just allocated sequentially in ascending order, and reaching usize::max_value() causes a panic.
 static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
+/// Next available thread ID.
+///
+/// Thread IDs are global to all processes, and are allocated sequentially in
+/// ascending order, so they are never reused.
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+
 /// The struct representing a thread. A process may own multiple threads.
 #[derive(Debug)]
 pub struct ThreadStruct {
+    /// The unique identifier of this thread.
+    pub id: usize,
+
+    /// The name of this thread, set by userspace through `svcSetThreadName`.
+    ///
+    /// Empty if the thread was never named.
+    pub name: SpinLockIRQ<String>,
+
     /// The state of this thread.
     pub state: Atomic<ThreadState>,
 
@@ -174,12 +214,88 @@ pub struct ThreadStruct {
     /// Registers are backed up every time we enter the kernel via a syscall/exception, for debug purposes.
     pub userspace_hwcontext: SpinLock<UserspaceHardwareContext>,
 
+    /// Whether this thread was paused through `svcSetThreadActivity`.
+    ///
+    /// Only modified by the scheduler, with the schedule queue locked.
+    pub activity_paused: AtomicBool,
+
+    /// Set when the thread was woken up while it (or its process) was paused.
+    ///
+    /// The scheduler will put it back in the schedule queue once it is resumed.
+    /// Only modified by the scheduler, with the schedule queue locked.
+    pub wakeup_pending: AtomicBool,
+
+    /// CPU time accounting of this thread. See [ThreadStruct::account_cpu_time].
+    cpu_time: SpinLockIRQ<CpuTimeAccounting>,
+
     /// Thread state event
     ///
     /// This is used when signaling that this thread as exited.
     state_event: ThreadStateEvent
 }
 
+/// CPU time used by a thread or a process, in nanoseconds.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuTime {
+    /// Time spent running userspace code.
+    pub user_ns: u64,
+    /// Time spent running in the kernel, handling syscalls, exceptions and IRQs.
+    pub kernel_ns: u64,
+}
+
+impl core::ops::AddAssign for CpuTime {
+    fn add_assign(&mut self, other: CpuTime) {
+        self.user_ns += other.user_ns;
+        self.kernel_ns += other.kernel_ns;
+    }
+}
+
+/// What a thread is doing, for CPU time accounting. See [ThreadStruct::account_cpu_time].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuTimeState {
+    /// The thread is not running.
+    Stopped,
+    /// The thread is running userspace code.
+    User,
+    /// The thread is running in the kernel.
+    Kernel,
+}
+
+/// The CPU time accounting state of a thread.
+#[derive(Debug)]
+struct CpuTimeAccounting {
+    /// CPU time used by the thread until `since`.
+    total: CpuTime,
+    /// What the thread has been doing since `since`.
+    state: CpuTimeState,
+    /// When the thread entered its current state, in nanoseconds. See [timer::now_ns].
+    since: u64,
+}
+
+impl Default for CpuTimeAccounting {
+    fn default() -> CpuTimeAccounting {
+        CpuTimeAccounting {
+            total: CpuTime::default(),
+            state: CpuTimeState::Stopped,
+            since: 0,
+        }
+    }
+}
+
+impl CpuTimeAccounting {
+    /// Gets the total CPU time, including the time spent in the current state until `now`.
+    fn total_at(&self, now: u64) -> CpuTime {
+        let mut total = self.total;
+        let elapsed = now.saturating_sub(self.since);
+        match self.state {
+            CpuTimeState::Stopped => (),
+            CpuTimeState::User => total.user_ns += elapsed,
+            CpuTimeState::Kernel => total.kernel_ns += elapsed,
+        }
+        total
+    }
+}
+
 /// A handle to a userspace-accessible resource.
 ///
 /// # Description
@@ -267,7 +383,9 @@ impl Handle {
             Handle::ReadableEvent(ref waitable) => Ok(waitable),
             Handle::InterruptEvent(ref waitable) => Ok(waitable),
             Handle::ServerPort(ref serverport) => Ok(serverport),
+            Handle::ClientPort(ref clientport) => Ok(clientport),
             Handle::ServerSession(ref serversession) => Ok(serversession),
+            Handle::ClientSession(ref clientsession) => Ok(clientsession),
             Handle::Thread(ref thread) => Ok(thread),
             Handle::Process(ref process) => Ok(process),
             _ => Err(UserspaceError::InvalidHandle),
@@ -366,10 +484,10 @@ impl Handle {
 /// goes up.
 ///
 /// There exists two "meta-handles": 0xFFFF8000 and 0xFFFF8001, which always
-/// point to the current process and thread, respectively. Those handles are not
+/// point to the current thread and process, respectively. Those handles are not
 /// *actually* stored in the handle table to avoid creating a reference cycle.
 /// Instead, they are retrieved dynamically at runtime by the get_handle
-/// function.
+/// function, and cannot be closed.
 #[derive(Debug)]
 pub struct HandleTable {
     /// Internal mapping from a handle number to a Kernel Object.
@@ -404,6 +522,9 @@ impl HandleTable {
         loop {
             let handlenum = self.counter;
             self.counter += 1;
+            if handlenum == CURRENT_THREAD_PSEUDO_HANDLE || handlenum == CURRENT_PROCESS_PSEUDO_HANDLE {
+                continue;
+            }
             if !self.table.contains_key(&handlenum) {
                 self.table.insert(handlenum, handle);
                 break handlenum;
@@ -413,14 +534,32 @@ impl HandleTable {
 
     /// Gets the Kernel Handle associated with the given userspace handle number.
     ///
+    /// The pseudo-handles 0xFFFF8000 and 0xFFFF8001 are resolved to the current
+    /// thread and process.
+    ///
     /// # Errors
     ///
     /// - `InvalidHandle`
     ///    - The provided handle does not exist in the handle table.
     pub fn get_handle(&self, handle: u32) -> Result<Arc<Handle>, UserspaceError> {
+        self.get_handle_as(handle, &scheduler::get_current_thread())
+    }
+
+    /// Gets the Kernel Handle associated with the given userspace handle number,
+    /// as seen by `thread`.
+    ///
+    /// The pseudo-handles 0xFFFF8000 and 0xFFFF8001 are resolved to `thread`
+    /// and its process. Used when the handle table is accessed on behalf of
+    /// another thread, e.g. by the receiver of an IPC message.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidHandle`
+    ///    - The provided handle does not exist in the handle table.
+    pub fn get_handle_as(&self, handle: u32, thread: &Arc<ThreadStruct>) -> Result<Arc<Handle>, UserspaceError> {
         match handle {
-            0xFFFF8000 => Ok(Arc::new(Handle::Thread(Arc::downgrade(&scheduler::get_current_thread())))),
-            0xFFFF8001 => Ok(Arc::new(Handle::Process(scheduler::get_current_process()))),
+            CURRENT_THREAD_PSEUDO_HANDLE => Ok(Arc::new(Handle::Thread(Arc::downgrade(thread)))),
+            CURRENT_PROCESS_PSEUDO_HANDLE => Ok(Arc::new(Handle::Process(thread.process.clone()))),
             handle => self.table.get(&handle).cloned().ok_or(UserspaceError::InvalidHandle)
         }
     }
@@ -440,10 +579,49 @@ impl HandleTable {
     /// Deletes the mapping from the given userspace handle number. Returns the
     /// underlying Kernel Handle, in case it needs to be used (e.g. for sending
     /// to another process in an IPC move).
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidHandle`
+    ///    - The provided handle does not exist in the handle table.
+    ///    - The provided handle is one of the pseudo-handles 0xFFFF8000 and
+    ///      0xFFFF8001, which cannot be deleted.
     pub fn delete_handle(&mut self, handle: u32) -> Result<Arc<Handle>, UserspaceError> {
-        // TODO: Handle 0xFFFF8000 and 0xFFFF8001 ?
         self.table.remove(&handle).ok_or(UserspaceError::InvalidHandle)
     }
+
+    /// Prints every handle of this table to the kernel log, with the type of
+    /// its object and what the object is connected to. Used for debugging purposes.
+    ///
+    /// Both sides of a session, port or event are printed with the same
+    /// identifier, so they can be matched across processes.
+    pub fn dump(&self) {
+        for (handlenum, handle) in self.table.iter() {
+            let (ty, peer) = match **handle {
+                Handle::InterruptEvent(ref event) => ("InterruptEvent", format!("irq {}", event.irq())),
+                Handle::ReadableEvent(ref event) => ("ReadableEvent", format!("event {:#x}", event.id())),
+                Handle::WritableEvent(ref event) => ("WritableEvent", format!("event {:#x}", event.id())),
+                Handle::ServerPort(ref port) => ("ServerPort", format!("port {:#x}", port.id())),
+                Handle::ClientPort(ref port) => ("ClientPort", format!("port {:#x}", port.id())),
+                Handle::ServerSession(ref session) => ("ServerSession", match session.port_id() {
+                    Some(port) => format!("session {:#x} of port {:#x}", session.id(), port),
+                    None => format!("session {:#x}", session.id()),
+                }),
+                Handle::ClientSession(ref session) => ("ClientSession", match session.port_id() {
+                    Some(port) => format!("session {:#x} of port {:#x}", session.id(), port),
+                    None => format!("session {:#x}", session.id()),
+                }),
+                Handle::Thread(ref thread) => ("Thread", match thread.upgrade() {
+                    Some(thread) => format!("thread {} of process {}", thread.id, thread.process.pid),
+                    None => String::from("dead thread"),
+                }),
+                Handle::Process(ref process) => ("Process", format!("process {} ({})", process.pid, process.name)),
+                Handle::SharedMemory(ref frames) => ("SharedMemory", format!("{} bytes",
+                    frames.read().iter().map(PhysicalMemRegion::size).sum::<usize>())),
+            };
+            info!("{:#010x}: {:<14} {}", handlenum, ty, peer);
+        }
+    }
 }
 
 /// The state of a thread.
@@ -488,10 +666,15 @@ impl ProcessStruct {
     /// # Panics
     ///
     /// Panics if max PID has been reached, which it shouldn't have since we're the first process.
+    ///
+    /// # Errors
+    ///
+    /// * `OutOfMemory`: cannot allocate the page tables of the process.
+    /// * Any error returned by [ProcessCapabilities::parse_kcaps].
     // todo: return an error instead of panicking
     pub fn new(procinfo: &ProcInfo, kacs: Option<&[u8]>) -> Result<Arc<ProcessStruct>, KernelError> {
         // allocate its memory space
-        let pmemory = Mutex::new(ProcessMemory::default());
+        let pmemory = Mutex::new(ProcessMemory::new()?);
 
         // The PID.
         let pid = NEXT_PROCESS_ID.fetch_add(1, Ordering::SeqCst);
@@ -521,10 +704,15 @@ impl ProcessStruct {
                 threads: SpinLockIRQ::new(Vec::new()),
                 phandles: SpinLockIRQ::new(HandleTable::default()),
                 tls_manager: Mutex::new(TLSManager::default()),
+                activity_paused: AtomicBool::new(false),
+                exit_reason: Atomic::new(ExitReason::Exited),
+                dead_threads_cpu_time: SpinLockIRQ::new(CpuTime::default()),
                 capabilities
             }
         );
 
+        PROCESSES.lock().insert(pid, Arc::downgrade(&p));
+
         Ok(p)
     }
 
@@ -638,7 +826,8 @@ impl ProcessStruct {
         let bootstrap_pages = InactiveHierarchy::from_currently_active();
 
         // create a new page table hierarchy for this process
-        let mut pmemory = ProcessMemory::default();
+        let mut pmemory = ProcessMemory::new()
+            .expect("Cannot allocate the page tables of the first process");
         pmemory.switch_to();
 
         // free the bootstrap page tables
@@ -663,6 +852,9 @@ impl ProcessStruct {
                     thread_maternity: Vec::new(),
                 }),
                 tls_manager: Mutex::new(TLSManager::default()),
+                activity_paused: AtomicBool::new(false),
+                exit_reason: Atomic::new(ExitReason::Exited),
+                dead_threads_cpu_time: SpinLockIRQ::new(CpuTime::default()),
                 capabilities: ProcessCapabilities::default(),
         }
     }
@@ -727,6 +919,40 @@ impl ProcessStruct {
 
         this.state.lock().set_state(ProcessState::Exited);
     }
+
+    /// Gets the process with the given PID, if it is still alive.
+    pub fn by_pid(pid: usize) -> Option<Arc<ProcessStruct>> {
+        PROCESSES.lock().get(&pid).and_then(Weak::upgrade)
+    }
+
+    /// Gets the PIDs of all the processes currently alive, in ascending order.
+    pub fn pids() -> Vec<usize> {
+        PROCESSES.lock().keys().cloned().collect()
+    }
+
+    /// Calls `f` on every process currently alive, in ascending PID order.
+    ///
+    /// Doesn't block nor allocate, so it can be used from the panic handler: returns false
+    /// without calling `f` if the list of processes is locked.
+    pub fn try_for_each<F: FnMut(&Arc<ProcessStruct>)>(mut f: F) -> bool {
+        let processes = match PROCESSES.try_lock() {
+            Some(processes) => processes,
+            None => return false
+        };
+        for process in processes.values().filter_map(Weak::upgrade) {
+            f(&process);
+        }
+        true
+    }
+
+    /// Gets the CPU time used by all the threads of this process, dead or alive.
+    pub fn cpu_time(&self) -> CpuTime {
+        let mut total = *self.dead_threads_cpu_time.lock();
+        for thread in self.threads.lock().iter().filter_map(Weak::upgrade) {
+            total += thread.cpu_time();
+        }
+        total
+    }
 }
 
 impl Waitable for Arc<ProcessStruct> {
@@ -740,6 +966,7 @@ impl Waitable for Arc<ProcessStruct> {
 
 impl Drop for ProcessStruct {
     fn drop(&mut self) {
+        PROCESSES.lock().remove(&self.pid);
         // todo this should be a debug !
         info!("☠️ Dropped a process : {}", self.name)
     }
@@ -788,8 +1015,13 @@ impl ThreadStruct {
         // allocate its thread local storage region
         let tls = belonging_process.tls_manager.lock().allocate_tls(&mut pmemory)?;
 
+        // the main thread is named after its role, other threads are named by userspace.
+        let name = if arg.is_none() { String::from("main") } else { String::new() };
+
         let t = Arc::new(
             ThreadStruct {
+                id: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
+                name: SpinLockIRQ::new(name),
                 state,
                 kstack,
                 hwcontext : empty_hwcontext,
@@ -797,6 +1029,9 @@ impl ThreadStruct {
                 tls_region: tls,
                 tls_elf: SpinLock::new(VirtualAddress(0x00000000)),
                 userspace_hwcontext: SpinLock::new(UserspaceHardwareContext::default()),
+                activity_paused: AtomicBool::new(false),
+                wakeup_pending: AtomicBool::new(false),
+                cpu_time: SpinLockIRQ::new(CpuTimeAccounting::default()),
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
@@ -888,6 +1123,8 @@ impl ThreadStruct {
 
         let t = Arc::new(
             ThreadStruct {
+                id: NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst),
+                name: SpinLockIRQ::new(String::from("main")),
                 state,
                 kstack,
                 hwcontext,
@@ -895,12 +1132,22 @@ impl ThreadStruct {
                 tls_region: tls,
                 tls_elf: SpinLock::new(VirtualAddress(0x00000000)),
                 userspace_hwcontext: SpinLock::new(UserspaceHardwareContext::default()),
+                activity_paused: AtomicBool::new(false),
+                wakeup_pending: AtomicBool::new(false),
+                // we're already running in the kernel.
+                cpu_time: SpinLockIRQ::new(CpuTimeAccounting {
+                    state: CpuTimeState::Kernel,
+                    since: timer::now_ns(),
+                    ..CpuTimeAccounting::default()
+                }),
                 state_event: ThreadStateEvent {
                     waiting_threads: SpinLock::new(Vec::new())
                 },
             }
         );
 
+        PROCESSES.lock().insert(process.pid, Arc::downgrade(&process));
+
         // first process now has one thread
         process.threads.lock().push(Arc::downgrade(&t));
 
@@ -969,19 +1216,66 @@ impl ThreadStruct {
 
         scheduler::add_to_schedule_queue(this);
     }
+
+    /// Charges the time elapsed since the last call to the thread's user or
+    /// kernel time, depending on what it was doing, and records that it is now
+    /// doing `next`.
+    ///
+    /// Called on every context switch, and on every entry and exit of the kernel
+    /// from userspace.
+    pub fn account_cpu_time(&self, next: CpuTimeState) {
+        let now = timer::now_ns();
+        let mut accounting = self.cpu_time.lock();
+        accounting.total = accounting.total_at(now);
+        accounting.state = next;
+        accounting.since = now;
+    }
+
+    /// Gets the CPU time used by this thread so far.
+    pub fn cpu_time(&self) -> CpuTime {
+        let accounting = self.cpu_time.lock();
+        match accounting.state {
+            // No need to read the timer, which keeps Drop from ever locking the timer queue.
+            CpuTimeState::Stopped => accounting.total,
+            _ => accounting.total_at(timer::now_ns())
+        }
+    }
+
+    /// Prints the state of this thread, and the userspace registers saved the
+    /// last time it entered the kernel, to the kernel log. Used for debugging purposes.
+    pub fn dump(&self) {
+        info!("Thread {} ({}): {:?}", self.id, self, self.state.load(Ordering::SeqCst));
+        info!("{}", *self.userspace_hwcontext.lock());
+    }
+}
+
+impl fmt::Display for ThreadStruct {
+    /// Displays the thread as `process:thread`, where `thread` is the name of
+    /// the thread, or its id if it has no name.
+    ///
+    /// Doesn't block if the name is locked, so it can be used from the panic
+    /// handler.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.try_lock() {
+            Some(ref name) if !name.is_empty() => write!(f, "{}:{}", self.process.name, &**name),
+            _ => write!(f, "{}:{}", self.process.name, self.id)
+        }
+    }
 }
 
 impl Drop for ThreadStruct {
     /// Late thread death notifications:
     ///
     /// * notifies our process that our TLS can be re-used.
+    /// * adds our CPU time to our process' dead threads CPU time.
     fn drop(&mut self) {
         unsafe {
             // safe: we're being dropped, our TLS will not be reused by us.
             self.process.tls_manager.lock().free_tls(self.tls_region);
         }
+        *self.process.dead_threads_cpu_time.lock() += self.cpu_time();
         // todo this should be a debug !
-        info!("💀 Dropped a thread : {}", self.process.name)
+        info!("💀 Dropped a thread : {}", self)
     }
 }
 
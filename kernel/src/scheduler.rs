@@ -1,17 +1,20 @@
 //! The Completly Unfair Scheduler
 
 use alloc::sync::Arc;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::mem;
 
-use crate::process::{ProcessStruct, ThreadStruct, ThreadState};
+use crate::process::{ProcessStruct, ThreadStruct, ThreadState, CpuTimeState};
 use crate::i386::process_switch::process_switch;
 use crate::sync::{Lock, SpinLockIRQ, SpinLockIRQGuard};
 use core::sync::atomic::Ordering;
 use crate::error::{UserspaceError};
 use sunrise_libkern::TLS;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET;
+use crate::sync::Once;
+use crate::timer;
 
 /// An Arc to the currently running thread.
 ///
@@ -31,6 +34,13 @@ use crate::cpu_locals::ARE_CPU_LOCALS_INITIALIZED_YET;
 #[thread_local] // this is a cpu_local
 static CURRENT_THREAD: RefCell<Option<Arc<ThreadStruct>>> = RefCell::new(None);
 
+/// The idle thread, run when no other thread is runnable. See [become_idle_thread].
+static IDLE_THREAD: Once<Arc<ThreadStruct>> = Once::new();
+
+/// Time this CPU spent halted in the idle thread, in nanoseconds.
+#[thread_local] // this is a cpu_local
+static IDLE_TIME_NS: Cell<u64> = Cell::new(0);
+
 /// Gets the current ThreadStruct, incrementing its refcount.
 /// Will return None if we're in an early boot state, and it has not yet been initialized.
 pub fn try_get_current_thread() -> Option<Arc<ThreadStruct>> {
@@ -116,6 +126,13 @@ pub fn add_to_schedule_queue(thread: Arc<ThreadStruct>) {
         return;
     }
 
+    if is_suspended(&thread) && thread.state.load(Ordering::SeqCst) != ThreadState::TerminationPending {
+        // The thread is paused. Remember it wants to run, and only schedule it once resumed.
+        thread.wakeup_pending.store(true, Ordering::SeqCst);
+        return;
+    }
+    thread.wakeup_pending.store(false, Ordering::SeqCst);
+
     let oldstate = thread.state.compare_exchange(ThreadState::Paused, ThreadState::Scheduled, Ordering::SeqCst, Ordering::SeqCst);
     let oldstate = match oldstate {
         Ok(v) => v,
@@ -136,6 +153,102 @@ pub fn is_in_schedule_queue(queue: &SpinLockIRQGuard<'_, Vec<Arc<ThreadStruct>>>
     }).chain(queue.iter()).any(|elem| Arc::ptr_eq(thread, elem))
 }
 
+/// Checks if a thread is paused, either by itself or through its process.
+pub fn is_suspended(thread: &ThreadStruct) -> bool {
+    thread.activity_paused.load(Ordering::SeqCst) || thread.process.activity_paused.load(Ordering::SeqCst)
+}
+
+/// Takes a thread that was just paused out of the schedule queue.
+///
+/// If the thread was waiting to run, it is marked as having a pending wakeup, so it gets
+/// rescheduled once it is resumed. Threads that are being terminated are left alone.
+fn suspend_locked(queue: &mut SpinLockIRQGuard<'_, Vec<Arc<ThreadStruct>>>, thread: &Arc<ThreadStruct>) {
+    if let Some(index) = queue.iter().position(|elem| Arc::ptr_eq(thread, elem)) {
+        if thread.state.compare_exchange(ThreadState::Scheduled, ThreadState::Paused, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            queue.remove(index);
+            thread.wakeup_pending.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Puts a thread that was just resumed back in the schedule queue, if it was woken up while paused.
+fn resume_locked(queue: &mut SpinLockIRQGuard<'_, Vec<Arc<ThreadStruct>>>, thread: &Arc<ThreadStruct>) {
+    if is_suspended(thread) || !thread.wakeup_pending.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if is_in_schedule_queue(queue, thread) {
+        return;
+    }
+    // A thread being terminated is always in the queue, so it can only be Paused here.
+    let _ = thread.state.compare_exchange(ThreadState::Paused, ThreadState::Scheduled, Ordering::SeqCst, Ordering::SeqCst);
+    queue.push(thread.clone());
+}
+
+/// Pauses or resumes a thread, implementing `svcSetThreadActivity`.
+///
+/// A paused thread is taken out of the schedule queue, and will not be put back in
+/// until it is resumed, even if the event it was waiting on gets signaled in the meantime.
+///
+/// # Errors
+///
+/// * `InvalidState`:
+///     * `thread` is the current thread.
+///     * `thread` was already paused when pausing it, or was not paused when resuming it.
+pub fn set_thread_paused(thread: &Arc<ThreadStruct>, paused: bool) -> Result<(), UserspaceError> {
+    if Arc::ptr_eq(thread, &get_current_thread()) {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    let mut queue = SCHEDULE_QUEUE.lock();
+    if thread.activity_paused.load(Ordering::SeqCst) == paused {
+        return Err(UserspaceError::InvalidState);
+    }
+    thread.activity_paused.store(paused, Ordering::SeqCst);
+
+    if paused {
+        suspend_locked(&mut queue, thread);
+    } else {
+        resume_locked(&mut queue, thread);
+    }
+    Ok(())
+}
+
+/// Pauses or resumes all the threads of a process, implementing `svcSetProcessActivity`.
+///
+/// Threads of a paused process behave as if they had been paused individually, and threads
+/// that were paused individually stay paused when their process is resumed.
+///
+/// # Errors
+///
+/// * `InvalidState`:
+///     * `process` is the current process.
+///     * `process` was already paused when pausing it, or was not paused when resuming it.
+pub fn set_process_paused(process: &Arc<ProcessStruct>, paused: bool) -> Result<(), UserspaceError> {
+    if Arc::ptr_eq(process, &get_current_process()) {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    // Collect the threads before taking the queue lock, killing a process locks them the other way around.
+    let threads: Vec<Arc<ThreadStruct>> = process.threads.lock().iter()
+        .filter_map(|weak| weak.upgrade())
+        .collect();
+
+    let mut queue = SCHEDULE_QUEUE.lock();
+    if process.activity_paused.load(Ordering::SeqCst) == paused {
+        return Err(UserspaceError::InvalidState);
+    }
+    process.activity_paused.store(paused, Ordering::SeqCst);
+
+    for thread in &threads {
+        if paused {
+            suspend_locked(&mut queue, thread);
+        } else {
+            resume_locked(&mut queue, thread);
+        }
+    }
+    Ok(())
+}
+
 /// Removes the current thread from the schedule queue, and schedule.
 ///
 /// The passed lock will remain locked until the thread is safely removed from the schedule queue.
@@ -235,6 +348,67 @@ pub fn schedule() {
     internal_schedule(&NoopLock, false);
 }
 
+/// Yields the rest of the current thread's time slice.
+///
+/// The current thread is moved to the back of the schedule queue, and runs again
+/// immediately if nobody else is waiting to run.
+///
+/// `svcSleepThread` distinguishes three yield types: without core migration, with
+/// core migration, and to any thread (including lower-priority ones). This scheduler
+/// has neither thread priorities nor SMP, so they are all the same and all end up here.
+pub fn yield_thread() {
+    schedule()
+}
+
+/// Checks if the given thread is the idle thread.
+pub fn is_idle_thread(thread: &Arc<ThreadStruct>) -> bool {
+    IDLE_THREAD.r#try().map(|idle| Arc::ptr_eq(idle, thread)).unwrap_or(false)
+}
+
+/// Turns the current thread into the idle thread, and runs it forever.
+///
+/// The idle thread is never in the schedule queue: the scheduler only switches to it when
+/// no other thread is runnable. It halts the CPU until an interrupt makes a thread runnable,
+/// and accounts the time spent halted as idle time. Its CPU time is not charged to its process.
+///
+/// # Panics
+///
+/// Panics if there already is an idle thread.
+pub fn become_idle_thread() -> ! {
+    let thread = get_current_thread();
+    assert!(IDLE_THREAD.r#try().is_none(), "There already is an idle thread");
+    *thread.name.lock() = String::from("idle");
+    thread.account_cpu_time(CpuTimeState::Stopped);
+    IDLE_THREAD.call_once(|| thread);
+
+    let interrupt_manager = SpinLockIRQ::new(());
+    loop {
+        {
+            let _interrupt_lock = interrupt_manager.lock();
+            if SCHEDULE_QUEUE.lock().is_empty() {
+                let start = timer::now_ns();
+                unsafe {
+                    // safety: interrupts are disabled by the interrupt_lock, they will be
+                    // disabled again when we return.
+                    crate::i386::instructions::interrupts::sti_hlt_cli();
+                }
+                IDLE_TIME_NS.set(IDLE_TIME_NS.get() + timer::now_ns().saturating_sub(start));
+            }
+        }
+        schedule();
+    }
+}
+
+/// Gets the time this CPU spent idle, in nanoseconds.
+pub fn idle_time_ns() -> u64 {
+    IDLE_TIME_NS.get()
+}
+
+/// Gets the time this CPU spent running threads, in nanoseconds.
+pub fn busy_time_ns() -> u64 {
+    timer::now_ns().saturating_sub(idle_time_ns())
+}
+
 /// Parses the queue to find the first unlocked process.
 /// Returns the index of found process
 fn find_next_thread_to_run(queue: &[Arc<ThreadStruct>]) -> Option<usize> {
@@ -257,41 +431,49 @@ where
     // TODO: Ensure the global counter is <= 1
 
     let interrupt_manager = SpinLockIRQ::new(());
-    let mut interrupt_lock = interrupt_manager.lock();
+    let _interrupt_lock = interrupt_manager.lock();
 
     loop {
         let mut queue = SCHEDULE_QUEUE.lock();
 
         let candidate_index = find_next_thread_to_run(&queue);
+        let idle_thread = match (candidate_index, remove_self) {
+            (None, true) => IDLE_THREAD.r#try().cloned(),
+            _ => None
+        };
         let retguard = match (candidate_index, remove_self) {
-            (None, true) => {
-                // There's nobody to schedule. Let's drop all the locks, HLT, and run internal_schedule again.
+            (None, true) if idle_thread.is_none() => {
+                // There's nobody to schedule, and the idle thread doesn't exist yet.
+                // Let's drop all the locks, HLT, and run internal_schedule again.
                 // NOTE: There's nobody running at this point. :O
                 drop(queue);
-                // Temporarily revive interrupts for hlt.
-                drop(interrupt_lock);
                 unsafe {
-                    crate::i386::instructions::interrupts::hlt();
+                    // safety: interrupts are disabled by the interrupt_lock, and disabled again when we return.
+                    crate::i386::instructions::interrupts::sti_hlt_cli();
                 }
 
-                // Kill interrupts again.
-                interrupt_lock = interrupt_manager.lock();
-
                 // Rerun internal_schedule.
                 continue;
             },
             (None, false) => {
                 // There's nobody else to run. Let's keep running ourselves...
+                // If we are the idle thread, we will halt again.
                 drop(queue);
                 lock.lock()
             }
-            (Some(index_b), _) => {
-                // 1. remove canditate from the queue, pushing remaining of the queue to the front
-                let process_b = queue.remove(index_b);
+            (index_b, _) => {
+                // 1. remove canditate from the queue, pushing remaining of the queue to the front.
+                //    If there is none, we're unscheduling ourselves: switch to the idle thread.
+                let process_b = match (index_b, idle_thread) {
+                    (Some(index_b), _) => queue.remove(index_b),
+                    (None, Some(idle_thread)) => idle_thread,
+                    (None, None) => unreachable!("Handled above")
+                };
 
                 // 2. push current at the back of the queue, unless we want to unschedule it.
+                //    The idle thread never goes in the queue.
                 let proc = get_current_thread();
-                if !remove_self {
+                if !remove_self && !is_idle_thread(&proc) {
                     queue.push(proc.clone());
                 }
 
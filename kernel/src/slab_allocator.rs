@@ -0,0 +1,344 @@
+//! Slab allocator for small kernel objects.
+//!
+//! Small allocations (ThreadStructs, Handles, IPC Buffers, ...) are hot and
+//! short-lived. Instead of walking the linked list of the heap for each of them,
+//! they are served from caches of fixed-size objects, one cache per size class.
+//!
+//! Each cache is made of slabs: single pages taken from the frame allocator,
+//! split into objects of the cache's size. The slab header lives at the end of
+//! its page, so finding the slab of an object is just a matter of masking its
+//! address. Free objects of a slab are threaded in an intrusive free list.
+//!
+//! When a slab becomes empty it is given back to the frame allocator, unless it
+//! is the only empty slab of its cache, which is kept around to avoid
+//! thrashing when an object is repeatedly allocated and freed.
+//!
+//! Slabs are mapped in the kernel memory, whose lock is held while mapping
+//! things, which can allocate. The caches never wait for this lock: when it is
+//! already held, they don't grow, and the allocation falls back to the heap,
+//! and empty slabs are kept until a later free.
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::NonNull;
+use crate::sync::SpinLock;
+use crate::paging::PAGE_SIZE;
+use crate::paging::kernel_memory::{KernelMemory, KERNEL_MEMORY};
+use crate::mem::VirtualAddress;
+
+/// Object sizes of the caches, in ascending order.
+///
+/// Must be powers of two, so objects placed at multiples of their size in a
+/// page are naturally aligned on their size.
+const SIZE_CLASSES: [usize; SIZE_CLASSES_COUNT] = [8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// Number of size classes, and thus of caches.
+const SIZE_CLASSES_COUNT: usize = 8;
+
+/// A free object, linked to the next free object of its slab.
+struct FreeObject {
+    /// The next free object of the slab.
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Header of a slab, living at the end of its page.
+struct SlabHeader {
+    /// The previous slab in the list this slab is in.
+    prev: Option<NonNull<SlabHeader>>,
+    /// The next slab in the list this slab is in.
+    next: Option<NonNull<SlabHeader>>,
+    /// The free objects of this slab.
+    free_list: Option<NonNull<FreeObject>>,
+    /// The number of objects of this slab currently allocated.
+    in_use: usize,
+}
+
+impl SlabHeader {
+    /// Gets the header of the slab containing the given object.
+    fn of_object(object: NonNull<u8>) -> NonNull<SlabHeader> {
+        let page = object.as_ptr() as usize & !(PAGE_SIZE - 1);
+        // page + PAGE_SIZE - size_of::<SlabHeader>() is never null.
+        NonNull::new((page + PAGE_SIZE - size_of::<SlabHeader>()) as *mut SlabHeader).unwrap()
+    }
+
+    /// Gets the address of the page this slab lives in.
+    fn page(slab: NonNull<SlabHeader>) -> VirtualAddress {
+        VirtualAddress(slab.as_ptr() as usize & !(PAGE_SIZE - 1))
+    }
+}
+
+/// An intrusive doubly-linked list of slabs.
+struct SlabList {
+    /// The first slab of the list.
+    head: Option<NonNull<SlabHeader>>,
+}
+
+impl SlabList {
+    /// Pushes a slab at the front of the list.
+    ///
+    /// # Unsafety
+    ///
+    /// `slab` must point to a valid SlabHeader that is not in any list.
+    unsafe fn push(&mut self, slab: NonNull<SlabHeader>) {
+        (*slab.as_ptr()).prev = None;
+        (*slab.as_ptr()).next = self.head;
+        if let Some(head) = self.head {
+            (*head.as_ptr()).prev = Some(slab);
+        }
+        self.head = Some(slab);
+    }
+
+    /// Removes a slab from the list.
+    ///
+    /// # Unsafety
+    ///
+    /// `slab` must point to a valid SlabHeader that is in this list.
+    unsafe fn remove(&mut self, slab: NonNull<SlabHeader>) {
+        let prev = (*slab.as_ptr()).prev;
+        let next = (*slab.as_ptr()).next;
+        match prev {
+            Some(prev) => (*prev.as_ptr()).next = next,
+            None => self.head = next,
+        }
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = prev;
+        }
+    }
+}
+
+/// Statistics of a slab cache.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    /// Size of the objects of this cache.
+    pub object_size: usize,
+    /// Number of slabs currently owned by this cache.
+    pub slabs: usize,
+    /// Number of slabs without any allocated object.
+    pub empty_slabs: usize,
+    /// Number of objects currently allocated.
+    pub objects_in_use: usize,
+    /// Number of objects allocated since boot.
+    pub allocations: usize,
+    /// Number of objects freed since boot.
+    pub frees: usize,
+    /// Number of empty slabs given back to the frame allocator since boot.
+    pub slabs_released: usize,
+}
+
+/// A cache of objects of a single size.
+struct SlabCache {
+    /// Slabs with at least one free object.
+    partial: SlabList,
+    /// Slabs without any free object.
+    full: SlabList,
+    /// Statistics of this cache, also used to know its object size.
+    stats: CacheStats,
+}
+
+// Safety: the slabs are only ever accessed through the SpinLock wrapping the cache.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Creates an empty cache of objects of `object_size` bytes.
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            partial: SlabList { head: None },
+            full: SlabList { head: None },
+            stats: CacheStats {
+                object_size,
+                slabs: 0,
+                empty_slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+                slabs_released: 0,
+            },
+        }
+    }
+
+    /// Number of objects fitting in a slab, before its header.
+    fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - size_of::<SlabHeader>()) / self.stats.object_size
+    }
+
+    /// Allocates a new empty slab, with all its objects in its free list.
+    ///
+    /// Returns None if the page could not be allocated, or if the kernel memory
+    /// is already locked.
+    fn grow(&mut self) -> Option<NonNull<SlabHeader>> {
+        let page = KERNEL_MEMORY.try_lock()?.get_page().ok()?;
+        let mut free_list = None;
+        for i in (0..self.objects_per_slab()).rev() {
+            let object = (page.addr() + i * self.stats.object_size) as *mut FreeObject;
+            unsafe {
+                // Safety: the object is in the freshly mapped page, before the header.
+                object.write(FreeObject { next: free_list });
+            }
+            free_list = NonNull::new(object);
+        }
+        let slab = SlabHeader::of_object(NonNull::new(page.addr() as *mut u8)?);
+        unsafe {
+            // Safety: the header is at the end of the freshly mapped page.
+            slab.as_ptr().write(SlabHeader { prev: None, next: None, free_list, in_use: 0 });
+        }
+        self.stats.slabs += 1;
+        self.stats.empty_slabs += 1;
+        Some(slab)
+    }
+
+    /// Gives an empty slab back to the frame allocator.
+    ///
+    /// # Unsafety
+    ///
+    /// `slab` must be an empty slab of this cache, and not be in any list.
+    unsafe fn release(&mut self, slab: NonNull<SlabHeader>, kernel_memory: &mut KernelMemory) {
+        kernel_memory.unmap_whole_mapping(SlabHeader::page(slab), PAGE_SIZE);
+        self.stats.slabs -= 1;
+        self.stats.empty_slabs -= 1;
+        self.stats.slabs_released += 1;
+    }
+
+    /// Allocates an object from this cache, growing it if necessary.
+    ///
+    /// Returns None if the cache could not be grown.
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        let slab = match self.partial.head {
+            Some(slab) => slab,
+            None => {
+                let slab = self.grow()?;
+                unsafe {
+                    // Safety: the slab was just created.
+                    self.partial.push(slab);
+                }
+                slab
+            }
+        };
+        unsafe {
+            // Safety: slabs in the partial list are valid, and have a free object.
+            let (object, was_empty, is_full) = {
+                let header = &mut *slab.as_ptr();
+                let object = header.free_list.expect("Slab in the partial list has no free object");
+                header.free_list = (*object.as_ptr()).next;
+                header.in_use += 1;
+                (object, header.in_use == 1, header.free_list.is_none())
+            };
+            if was_empty {
+                self.stats.empty_slabs -= 1;
+            }
+            if is_full {
+                self.partial.remove(slab);
+                self.full.push(slab);
+            }
+            self.stats.objects_in_use += 1;
+            self.stats.allocations += 1;
+            Some(object.cast())
+        }
+    }
+
+    /// Frees an object of this cache, releasing its slab if it becomes empty,
+    /// the cache already has an empty slab, and the kernel memory is not locked.
+    ///
+    /// # Unsafety
+    ///
+    /// `object` must have been allocated from this cache, and not be freed already.
+    unsafe fn dealloc(&mut self, object: NonNull<u8>) {
+        let slab = SlabHeader::of_object(object);
+        let (was_full, is_empty) = {
+            let header = &mut *slab.as_ptr();
+            let was_full = header.free_list.is_none();
+            let object = object.cast::<FreeObject>();
+            object.as_ptr().write(FreeObject { next: header.free_list });
+            header.free_list = Some(object);
+            header.in_use -= 1;
+            (was_full, header.in_use == 0)
+        };
+        if was_full {
+            self.full.remove(slab);
+            self.partial.push(slab);
+        }
+        self.stats.objects_in_use -= 1;
+        self.stats.frees += 1;
+
+        if is_empty {
+            self.stats.empty_slabs += 1;
+            if self.stats.empty_slabs > 1 {
+                if let Some(mut kernel_memory) = KERNEL_MEMORY.try_lock() {
+                    self.partial.remove(slab);
+                    self.release(slab, &mut kernel_memory);
+                }
+            }
+        }
+    }
+}
+
+/// The slab caches of the kernel, one per size class.
+#[allow(missing_debug_implementations)] // SlabCache does not implement Debug
+pub struct SlabAllocator {
+    /// The caches, indexed like [SIZE_CLASSES].
+    caches: [SpinLock<SlabCache>; SIZE_CLASSES_COUNT],
+}
+
+impl SlabAllocator {
+    /// Creates a slab allocator, without any slab.
+    pub fn new() -> SlabAllocator {
+        SlabAllocator {
+            caches: [
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[0])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[1])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[2])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[3])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[4])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[5])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[6])),
+                SpinLock::new(SlabCache::new(SIZE_CLASSES[7])),
+            ],
+        }
+    }
+
+    /// Gets the cache serving allocations of this layout, if any.
+    fn cache_for(&self, layout: Layout) -> Option<&SpinLock<SlabCache>> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        SIZE_CLASSES.iter().position(|&class| size <= class)
+            .map(|index| &self.caches[index])
+    }
+
+    /// Allocates an object for this layout.
+    ///
+    /// Returns None if the layout is too big for the slab caches, or if
+    /// its cache could not be grown.
+    pub fn alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.cache_for(layout)?.lock().alloc()
+    }
+
+    /// Frees an object.
+    ///
+    /// # Unsafety
+    ///
+    /// `ptr` must have been returned by [SlabAllocator::alloc] for the same
+    /// layout, and not be freed already.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layout is too big for the slab caches.
+    pub unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.cache_for(layout)
+            .expect("Freeing an object that was not allocated from a slab")
+            .lock().dealloc(ptr)
+    }
+
+    /// Gets the statistics of every cache, in ascending object size.
+    pub fn stats(&self) -> impl Iterator<Item = CacheStats> + '_ {
+        self.caches.iter().map(|cache| cache.lock().stats)
+    }
+
+    /// Prints the statistics of every cache. Used for debugging purposes.
+    pub fn dump_stats(&self) {
+        info!("{:>6} {:>6} {:>6} {:>8} {:>10} {:>10} {:>8}",
+              "size", "slabs", "empty", "in use", "allocs", "frees", "released");
+        for stats in self.stats() {
+            info!("{:>6} {:>6} {:>6} {:>8} {:>10} {:>10} {:>8}",
+                  stats.object_size, stats.slabs, stats.empty_slabs, stats.objects_in_use,
+                  stats.allocations, stats.frees, stats.slabs_released);
+        }
+    }
+}
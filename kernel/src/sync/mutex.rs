@@ -211,6 +211,14 @@ impl<T> Mutex<T> {
         }
     }
 
+    /// Checks whether the current thread holds this mutex.
+    ///
+    /// Lets code that may run while its own thread holds the lock, like the page
+    /// fault handler, tell this case apart from another thread holding it.
+    pub fn is_held_by_current_thread(&self) -> bool {
+        self.inner.is_held_by_current_thread()
+    }
+
     /// Returns a mutable reference to the underlying data.
     ///
     /// Since this call borrows the `Mutex` mutably, no actual locking needs to
@@ -347,6 +355,12 @@ impl MutexInner {
         }
     }
 
+    /// Checks whether the current thread is the owner of the mutex.
+    fn is_held_by_current_thread(&self) -> bool {
+        let me = &*get_current_thread() as *const ThreadStruct as usize;
+        self.spin_lock.lock().owner == Some(me)
+    }
+
     /// Locks the mutex blocking the current thread until it is available.
     ///
     /// # Panics
@@ -411,7 +425,7 @@ impl MutexInner {
 
 /* Only tests what's trivially testable :/ */
 
-#[cfg(test)]
+#[cfg(all(test, not(target_os = "none")))]
 mod tests {
     /*
     use crate::sync::mpsc::channel;
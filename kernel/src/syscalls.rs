@@ -9,24 +9,28 @@ use crate::mem::{VirtualAddress, PhysicalAddress};
 use crate::mem::{UserSpacePtr, UserSpacePtrMut};
 use crate::paging::{MappingAccessRights, PAGE_SIZE};
 use crate::paging::lands::{UserLand, VirtualSpaceLand};
-use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait};
+use crate::frame_allocator::{PhysicalMemRegion, FrameAllocator, FrameAllocatorTrait, MAX_ORDER};
 use crate::paging::mapping::MappingFrames;
 use crate::process::{Handle, ThreadStruct, ProcessStruct};
 use crate::event::{self, Waitable};
 use crate::scheduler::{self, get_current_thread, get_current_process};
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use crate::ipc;
 use crate::error::{UserspaceError, KernelError};
 use crate::sync::SpinRwLock;
 use crate::timer;
+use crate::log_impl;
 use failure::Backtrace;
-use sunrise_libkern::{MemoryInfo, MemoryAttributes, MemoryPermissions, MemoryType, MemoryState};
+use sunrise_libkern::{MemoryInfo, MemoryAttributes, MemoryPermissions, MemoryType, MemoryState, MAX_THREAD_NAME_LEN};
+use sunrise_libkern::{ThreadActivity, ThreadContext, KernelLogRecord};
+use sunrise_libkern::{YIELD_WITHOUT_CORE_MIGRATION, YIELD_WITH_CORE_MIGRATION, YIELD_TO_ANY_THREAD};
 use sunrise_libkern::process::*;
 use bit_field::BitArray;
 use crate::i386::gdt::{GDT, GdtIndex};
 use core::convert::TryFrom;
+use core::sync::atomic::Ordering;
 
 /// Resize the heap of a process, just like a brk.
 /// It can both expand, and shrink the heap.
@@ -126,12 +130,26 @@ pub fn create_interrupt_event(irq_num: usize, _flag: u32) -> Result<usize, Users
 pub fn query_physical_address(virtual_address: usize) -> Result<(usize, usize, usize), UserspaceError> {
     let virtual_address = VirtualAddress(virtual_address);
     let proc = scheduler::get_current_process();
-    let mem = proc.pmemory.lock();
+    let mut mem = proc.pmemory.lock();
+    // the page must be backed by a frame that only this process can write to.
+    mem.unshare_range(virtual_address, 1)?;
     let mapping = mem.query_memory(virtual_address);
     let keep_region;
     let frames = match mapping.mapping().frames() {
         MappingFrames::Owned(regions) => regions,
         MappingFrames::Shared(arc_regions) => { keep_region = arc_regions.read(); keep_region.as_ref() },
+        MappingFrames::Lazy(slots) => {
+            // lazily-committed frames are not contiguous, only return the page.
+            let index = (mapping.mapping().phys_offset() + (virtual_address.floor() - mapping.mapping().address())) / PAGE_SIZE;
+            let frame = slots.read()[index].as_ref().expect("We just committed the page").address();
+            return Ok((frame.addr(), virtual_address.floor().addr(), PAGE_SIZE))
+        },
+        MappingFrames::CopyOnWrite(pages) => {
+            // neither are copy-on-write frames.
+            let index = (mapping.mapping().phys_offset() + (virtual_address.floor() - mapping.mapping().address())) / PAGE_SIZE;
+            let frame = pages[index].as_ref().expect("We just unshared the page").address();
+            return Ok((frame.addr(), virtual_address.floor().addr(), PAGE_SIZE))
+        },
         MappingFrames::None =>
             return Err(KernelError::InvalidAddress { address: virtual_address.addr(), backtrace: Backtrace::new() }.into()),
     };
@@ -171,7 +189,7 @@ pub fn query_physical_address(virtual_address: usize) -> Result<(usize, usize, u
 /// If timeout is 0, the function will not schedule or register intent, but merely check if the handles are currently
 /// signaled.
 ///
-/// Does not accept 0xFFFF8001 or 0xFFFF8000 as handles.
+/// Accepts the 0xFFFF8000 and 0xFFFF8001 pseudo-handles.
 ///
 /// # Result
 ///
@@ -251,7 +269,11 @@ pub fn output_debug_string(msg: UserSpacePtr<[u8]>, level: usize, target: UserSp
 }
 
 /// Kills our own process.
-pub fn exit_process() -> Result<(), UserspaceError> {
+///
+/// The reason is a Sunrise extension, and can later be queried by other
+/// processes through `get_process_info` with the `ExitReason` info type.
+pub fn exit_process(reason: u32) -> Result<(), UserspaceError> {
+    get_current_process().exit_reason.store(ExitReason(reason), Ordering::SeqCst);
     ProcessStruct::kill_current_process();
     Ok(())
 }
@@ -274,6 +296,29 @@ pub fn connect_to_port(handle: u32) -> Result<usize, UserspaceError> {
     Ok(hnd as _)
 }
 
+/// Sunrise extension: creates an event that gets signaled once all the
+/// ServerPort handles associated with the given ClientPort are closed, i.e.
+/// when the process hosting the port dies. It is signaled right away if they
+/// already are.
+///
+/// Waiting on the ClientPort itself can't tell the death of the port apart
+/// from a session becoming available.
+///
+/// # Returns
+///
+/// Returns a ReadableEvent handle.
+///
+/// # Error
+///
+/// - InvalidHandle: The passed handle does not exist, or is not a ClientPort.
+pub fn create_port_death_event(handle: u32) -> Result<usize, UserspaceError> {
+    let curproc = scheduler::get_current_process();
+    let clientport = curproc.phandles.lock().get_handle(handle)?.as_client_port()?;
+    let event = clientport.death_event();
+    let hnd = curproc.phandles.lock().add_handle(Arc::new(Handle::ReadableEvent(event)));
+    Ok(hnd as _)
+}
+
 /// Kills our own thread.
 pub fn exit_thread() -> Result<(), UserspaceError> {
     ThreadStruct::exit(get_current_thread());
@@ -422,7 +467,8 @@ pub fn reply_and_receive_with_user_buffer(buf: UserSpacePtrMut<[u8]>, handles: U
 
 /// Closed the passed handle.
 ///
-/// Does not accept 0xFFFF8001 or 0xFFFF8000 as handles.
+/// Does not accept 0xFFFF8001 or 0xFFFF8000 as handles: pseudo-handles cannot
+/// be closed, and InvalidHandle is returned.
 pub fn close_handle(handle: u32) -> Result<(), UserspaceError> {
     let proc = scheduler::get_current_process();
     proc.phandles.lock().delete_handle(handle)?;
@@ -436,12 +482,15 @@ pub fn close_handle(handle: u32) -> Result<(), UserspaceError> {
 /// - 0 Yielding without core migration
 /// - -1 Yielding with core migration
 /// - -2 Yielding to any other thread
+///
+/// See [scheduler::yield_thread].
 pub fn sleep_thread(nanos: usize) -> Result<(), UserspaceError> {
-    if nanos == 0 {
-        scheduler::schedule();
-        Ok(())
-    } else {
-        event::wait(Some(&timer::wait_ns(nanos) as &dyn Waitable)).map(|_| ())
+    match nanos {
+        YIELD_WITHOUT_CORE_MIGRATION | YIELD_WITH_CORE_MIGRATION | YIELD_TO_ANY_THREAD => {
+            scheduler::yield_thread();
+            Ok(())
+        },
+        _ => event::wait(Some(&timer::wait_ns(nanos) as &dyn Waitable)).map(|_| ())
     }
 }
 
@@ -575,7 +624,7 @@ pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr:
     let memlock = curproc.pmemory.lock();
     let qmem = memlock.query_memory(VirtualAddress(addr));
     let mapping = qmem.mapping();
-    *meminfo = MemoryInfo {
+    let info = MemoryInfo {
         baseaddr: mapping.address().addr(),
         size: mapping.length(),
         memtype: mapping.state(),
@@ -587,7 +636,12 @@ pub fn query_memory(mut meminfo: UserSpacePtrMut<MemoryInfo>, _unk: usize, addr:
         perms: mapping.flags().into(),
         ipc_ref_count: 0,
         device_ref_count: 0,
+        committed_size: mapping.committed_length(),
     };
+    // writing to meminfo might fault to commit its page, which needs the lock.
+    drop(qmem);
+    drop(memlock);
+    *meminfo = info;
     // TODO: PageInfo Handling
     // BODY: Properly return Page Information. The horizon/NX page-info stuff
     //       is not really documented yet, so this will require some RE work.
@@ -685,6 +739,54 @@ pub fn set_thread_area(segment_base_address: usize) -> Result<(), UserspaceError
     Ok(())
 }
 
+/// Sets the name of the given thread. The name is used by the kernel when
+/// logging, in the form `process:thread`. The 0xFFFF8000 pseudo-handle can be
+/// used to name the current thread.
+///
+/// Invalid UTF-8 sequences in the name are replaced with U+FFFD.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The name is longer than [MAX_THREAD_NAME_LEN] bytes.
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+pub fn set_thread_name(hnd: u32, name: UserSpacePtr<[u8]>) -> Result<(), UserspaceError> {
+    if name.len() > MAX_THREAD_NAME_LEN {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    let thread = get_current_process().phandles.lock()
+        .get_handle(hnd)?.as_thread_handle()?;
+    let thread = thread.upgrade().ok_or(UserspaceError::InvalidHandle)?;
+
+    let name = String::from_utf8_lossy(&*name).into_owned();
+    let _old_name = core::mem::replace(&mut *thread.name.lock(), name);
+    Ok(())
+}
+
+/// Gets the name of the process with the given PID, as given in its kip header
+/// or `svcCreateProcess`.
+///
+/// Copies as much of the name as fits in the given buffer, and returns the
+/// full length of the name, in bytes.
+///
+/// Like [dump_info], it is only available to the processes having it in their
+/// capabilities.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn get_process_name(pid: usize, mut buf: UserSpacePtrMut<[u8]>) -> Result<usize, UserspaceError> {
+    let process = ProcessStruct::by_pid(pid).ok_or(UserspaceError::NoSuchEntry)?;
+    let name = process.name.as_bytes();
+    let len = core::cmp::min(name.len(), buf.len());
+    buf[..len].copy_from_slice(&name[..len]);
+    Ok(name.len())
+}
+
 /// Change permission of a page-aligned memory region. Acceptable permissions
 /// are ---, r-- and rw-. In other words, it is not allowed to set the
 /// executable bit, nor is it acceptable to use write-only permissions.
@@ -747,19 +849,16 @@ pub fn set_process_memory_permission(proc_hnd: u32, addr: usize, size: usize, pe
         core::mem::drop(meminfo);
         let meminfo = dstmem.unmap(mapping_addr, mapping_length).expect("Unmap can't fail.");
 
-        let frames = if let MappingFrames::Shared(frames) = meminfo.frames() {
-            frames
-        } else {
-            panic!("Non-shared frames in mapping {:?}", meminfo);
-        };
+        let frames = || meminfo.frames().share()
+            .unwrap_or_else(|| panic!("Non-shared frames in mapping {:?}", meminfo));
 
         // Split mapping
         if meminfo.address() < addr {
-            dstmem.map_partial_shared_mapping(frames.clone(), meminfo.address(), meminfo.phys_offset(), addr - meminfo.address(), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
+            dstmem.map_partial_mapping(frames(), meminfo.address(), meminfo.phys_offset(), addr - meminfo.address(), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
         }
         if meminfo.address() + meminfo.length() > addr + size {
             let phys_offset = meminfo.phys_offset() + addr + size - meminfo.address();
-            dstmem.map_partial_shared_mapping(frames.clone(), addr + size, phys_offset, (meminfo.address() + meminfo.length()) - (addr + size), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
+            dstmem.map_partial_mapping(frames(), addr + size, phys_offset, (meminfo.address() + meminfo.length()) - (addr + size), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
         }
 
         // Handle middle mapping.
@@ -773,7 +872,7 @@ pub fn set_process_memory_permission(proc_hnd: u32, addr: usize, size: usize, pe
             _ => unreachable!("Got a state PROCESS_PERMISSION_CHANGE_ALLOWED that wasn't CodeStatic or ModuleCodeStatic, but a {:?}", meminfo.state().ty())
         };
 
-        dstmem.map_partial_shared_mapping(frames.clone(), addr, offset, curlen, out_type, perms.into())?;
+        dstmem.map_partial_mapping(frames(), addr, offset, curlen, out_type, perms.into())?;
 
         size -= curlen;
         addr += curlen;
@@ -859,14 +958,24 @@ pub fn map_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, size:
         MemoryAttributes::empty(), MemoryAttributes::empty(),
         MemoryAttributes::empty())?;
 
+    // Both processes must see the same pages, copy-on-write pages would diverge.
+    let mut cur_addr = src_addr;
+    while cur_addr < src_addr + size {
+        let meminfo = srcmem.query_memory(cur_addr);
+        if let MappingFrames::CopyOnWrite(_) = meminfo.mapping().frames() {
+            return Err(UserspaceError::InvalidMemState);
+        }
+        cur_addr = meminfo.mapping().address() + meminfo.mapping().length();
+    }
+
     while size != 0 {
         let meminfo = srcmem.query_memory(src_addr);
 
         let offset_in_mapping = src_addr - meminfo.mapping().address();
         let offset = offset_in_mapping + meminfo.mapping().phys_offset();
         let curlen = core::cmp::min(size, meminfo.mapping().length() - offset_in_mapping);
-        if let MappingFrames::Shared(frames) = meminfo.mapping().frames() {
-            dstmem.map_partial_shared_mapping(frames.clone(), dst_addr, offset, curlen,
+        if let Some(frames) = meminfo.mapping().frames().share() {
+            dstmem.map_partial_mapping(frames, dst_addr, offset, curlen,
                 MemoryType::ProcessMemory, MappingAccessRights::u_rw())
                 .unwrap_or_else(|err| panic!("Failed to map in dst mem: {:?}", err));
         } else {
@@ -983,18 +1092,18 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
         let offset_in_mapping = dst_addr - mapping.address();
         let curlen = core::cmp::min(size, mapping.length() - offset_in_mapping);
 
-        if let MappingFrames::Shared(frames) = mapping.frames() {
+        if let Some(frames) = mapping.frames().share() {
 
             // Remap left bit
             if offset_in_mapping != 0 {
-                dstmem.map_partial_shared_mapping(frames.clone(), mapping.address(),
+                dstmem.map_partial_mapping(frames, mapping.address(),
                     mapping.phys_offset(), offset_in_mapping, mapping.state().ty(),
                     mapping.flags()).unwrap();
             }
 
             // Remap right bit
             if curlen != mapping.length() - offset_in_mapping {
-                dstmem.map_partial_shared_mapping(frames.clone(),
+                dstmem.map_partial_mapping(mapping.frames().share().unwrap(),
                     mapping.address() + offset_in_mapping + size,
                     mapping.phys_offset() + offset_in_mapping + size,
                     mapping.length() - (offset_in_mapping + size),
@@ -1012,6 +1121,123 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
     Ok(())
 }
 
+/// Shares the given src memory range of the current process with a remote
+/// process, copy-on-write. This is used by the Loader to share the read-only
+/// segments of a binary between all the processes running it, instead of
+/// giving each of them a private copy.
+///
+/// The src region should be of the ProcessMemory type, and must not be written
+/// to anymore: the remote process only gets a private copy of a page when it
+/// writes to it.
+///
+/// The dst region should have the MAP_PROCESS state, which is only available on
+/// CodeStatic/CodeMutable and ModuleCodeStatic/ModuleCodeMutable. Its pages are
+/// replaced, but it keeps its memory type and permissions.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+///    - The src region spans several mappings.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src or dst region is outside of the UserLand address space.
+///    - The src memory pages are not of the ProcessMemory type.
+///    - The dst memory pages do not have the MAP_PROCESS state.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist or is not a Process
+///      handle.
+///    - The handle refers to the current process.
+pub fn share_process_memory(proc_hnd: u32, dst_addr: usize, src_addr: usize, size: usize) -> Result<(), UserspaceError> {
+    let dst_addr = VirtualAddress(dst_addr);
+    let src_addr = VirtualAddress(src_addr);
+
+    src_addr.check_aligned_to(PAGE_SIZE)?;
+    dst_addr.check_aligned_to(PAGE_SIZE)?;
+
+    if size == 0 || size & (PAGE_SIZE - 1) != 0 {
+        return Err(UserspaceError::InvalidSize);
+    }
+
+    if src_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+    if dst_addr.checked_add(size).is_none() {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let curproc = scheduler::get_current_process();
+    let dstproc = curproc.phandles.lock().get_handle(proc_hnd)?.as_process()?;
+
+    // Locking both address spaces would deadlock.
+    if Arc::ptr_eq(&curproc, &dstproc) {
+        return Err(UserspaceError::InvalidHandle);
+    }
+
+    if !UserLand::contains_region(src_addr, size) || !UserLand::contains_region(dst_addr, size) {
+        return Err(UserspaceError::InvalidMemState);
+    }
+
+    let mut srcmem = curproc.pmemory.lock();
+    let mut dstmem = dstproc.pmemory.lock();
+
+    srcmem.check_range(src_addr, size,
+        MemoryState::all(), MemoryType::ProcessMemory.get_memory_state(),
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::empty(), MemoryAttributes::empty(),
+        MemoryAttributes::empty())?;
+
+    dstmem.check_range(dst_addr, size,
+        MemoryState::MAP_PROCESS_ALLOWED, MemoryState::MAP_PROCESS_ALLOWED,
+        MemoryPermissions::empty(), MemoryPermissions::empty(),
+        MemoryAttributes::all(), MemoryAttributes::empty(),
+        MemoryAttributes::IPC_MAPPED | MemoryAttributes::DEVICE_MAPPED)?;
+
+    let pages = srcmem.copy_on_write_pages(src_addr, size)?;
+
+    let mut size = size;
+    let mut addr = dst_addr;
+    let mut pages_offset = 0;
+
+    while size != 0 {
+        let meminfo = dstmem.query_memory(addr);
+
+        let mapping_addr = meminfo.mapping().address();
+        let mapping_length = meminfo.mapping().length();
+        core::mem::drop(meminfo);
+        let meminfo = dstmem.unmap(mapping_addr, mapping_length).expect("Unmap can't fail.");
+
+        let frames = || meminfo.frames().share()
+            .unwrap_or_else(|| panic!("Non-shared frames in mapping {:?}", meminfo));
+
+        // Split mapping
+        if meminfo.address() < addr {
+            dstmem.map_partial_mapping(frames(), meminfo.address(), meminfo.phys_offset(), addr - meminfo.address(), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
+        }
+        if meminfo.address() + meminfo.length() > addr + size {
+            let phys_offset = meminfo.phys_offset() + addr + size - meminfo.address();
+            dstmem.map_partial_mapping(frames(), addr + size, phys_offset, (meminfo.address() + meminfo.length()) - (addr + size), meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
+        }
+
+        // Replace the middle mapping with the shared pages.
+        let offset_in_mapping = addr - meminfo.address();
+        let curlen = core::cmp::min(size, meminfo.length() - offset_in_mapping);
+        let shared = pages[pages_offset / PAGE_SIZE..][..curlen / PAGE_SIZE].to_vec();
+
+        dstmem.map_partial_mapping(MappingFrames::CopyOnWrite(shared), addr, 0, curlen, meminfo.state().ty(), meminfo.flags()).expect("Can't fail");
+
+        size -= curlen;
+        addr += curlen;
+        pages_offset += curlen;
+    }
+
+    Ok(())
+}
+
 /// Creates a new process. This will create an empty address space without any
 /// thread yet. The size of this address space is controlled through
 /// the [ProcInfoAddrSpace] found in `procinfo`.
@@ -1037,6 +1263,8 @@ pub fn unmap_process_memory(dst_addr: usize, proc_hnd: u32, src_addr: usize, siz
 ///    * ProcInfo's `code_addr` is not 21-bit aligned.
 /// * `InvalidMemRange`
 ///    * ProcInfo's `code_addr` is not within the allowed code region.
+/// * `MemoryFull`
+///    * Cannot allocate the page tables of the new process.
 /// * All the errors from [crate::process::capabilities::ProcessCapabilities#parse_kacs]
 pub fn create_process(procinfo: UserSpacePtr<ProcInfo>, caps: UserSpacePtr<[u8]>) -> Result<usize, UserspaceError> {
     // Ensure the procinfo structure is well-formed.
@@ -1135,6 +1363,8 @@ pub fn start_process(hnd: u32, main_thread_prio: u32, default_cpuid: u32, main_t
 /// -----------------|--------------------------
 /// ProcessState = 0 | The state the current process is in. Returns an instance
 ///                  | of [sunrise_libkern::process::ProcessState].
+/// ExitReason       | Sunrise extension. The reason the process exited with.
+/// = 0x10000000     | Returns an instance of [sunrise_libkern::process::ExitReason].
 ///
 /// # Errors
 ///
@@ -1148,10 +1378,261 @@ pub fn get_process_info(hnd: u32, info_type: u32) -> Result<usize, UserspaceErro
 
     match info_type {
         ProcessInfoType::ProcessState => Ok(target_proc.state().0 as usize),
+        ProcessInfoType::ExitReason => Ok(target_proc.exit_reason.load(Ordering::SeqCst).0 as usize),
         _ => Err(UserspaceError::InvalidEnum)
     }
 }
 
+/// Gets information about the system, a process or a thread.
+///
+/// Info Type          | Handle | Sub-type              | Description
+/// -------------------|--------|-----------------------|--------------------------
+/// IdleTickCount = 20 | 0      | Core number, or -1    | Time the CPU core spent idle, in system ticks.
+///                    |        | for the current core. |
+/// ThreadTickCount    | Thread | Core number, or -1    | CPU time used by the thread, in system ticks.
+/// = 25               |        | for all cores.        |
+/// ProcessUserTime    | Process| 0                     | Sunrise extension. CPU time the process spent in
+/// = 0x10000000       |        |                       | userspace, in nanoseconds.
+/// ProcessKernelTime  | Process| 0                     | Sunrise extension. CPU time the process spent in
+/// = 0x10000001       |        |                       | the kernel, in nanoseconds.
+/// PhysicalFreeBlocks | 0      | Order                 | Sunrise extension. Number of free blocks of
+/// = 0x10000002       |        |                       | 2^order physical frames.
+/// PhysicalUsedBlocks | 0      | Order                 | Sunrise extension. Number of allocated blocks of
+/// = 0x10000003       |        |                       | 2^order physical frames.
+/// PhysicalReserved-  | 0      | Order                 | Sunrise extension. Number of reserved blocks of
+/// Blocks = 0x10000004|        |                       | 2^order physical frames.
+/// BusyTickCount      | 0      | Core number, or -1    | Sunrise extension. Time the CPU core spent running
+/// = 0x10000005       |        | for the current core. | threads, in system ticks.
+///
+/// System ticks run at [SYSTEM_TICK_FREQUENCY](sunrise_libkern::SYSTEM_TICK_FREQUENCY). Compare the idle and busy tick
+/// counts to get the CPU load.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - IdleTickCount/BusyTickCount: the handle is not 0.
+///   - PhysicalFreeBlocks/PhysicalUsedBlocks/PhysicalReservedBlocks: the handle is not 0.
+///   - ThreadTickCount: the handle is invalid, not a thread, or the thread is dead.
+///   - ProcessUserTime/ProcessKernelTime: the handle is invalid or not a process.
+/// - `InvalidCombination`
+///   - IdleTickCount/BusyTickCount/ThreadTickCount: the core is not the current one.
+///   - ProcessUserTime/ProcessKernelTime: the sub-type is not 0.
+///   - PhysicalFreeBlocks/PhysicalUsedBlocks/PhysicalReservedBlocks: the order is
+///     bigger than [MAX_ORDER](crate::frame_allocator::MAX_ORDER).
+/// - `InvalidEnum`
+///   - The passed info_type is unknown.
+pub fn get_info(info_type: u32, hnd: u32, info_sub_type: u64) -> Result<u64, UserspaceError> {
+    match InfoType(info_type) {
+        InfoType::IdleTickCount | InfoType::BusyTickCount => {
+            if hnd != 0 {
+                return Err(UserspaceError::InvalidHandle);
+            }
+            // We only run on the core 0.
+            if info_sub_type != u64::max_value() && info_sub_type != 0 {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            if InfoType(info_type) == InfoType::IdleTickCount {
+                Ok(timer::ns_to_ticks(scheduler::idle_time_ns()))
+            } else {
+                Ok(timer::ns_to_ticks(scheduler::busy_time_ns()))
+            }
+        }
+        InfoType::ThreadTickCount => {
+            let thread = get_current_process().phandles.lock()
+                .get_handle(hnd)?.as_thread_handle()?;
+            let thread = thread.upgrade().ok_or(UserspaceError::InvalidHandle)?;
+            // We only run on the core 0.
+            if info_sub_type != u64::max_value() && info_sub_type != 0 {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            let cpu_time = thread.cpu_time();
+            Ok(timer::ns_to_ticks(cpu_time.user_ns + cpu_time.kernel_ns))
+        }
+        InfoType::ProcessUserTime | InfoType::ProcessKernelTime => {
+            let process = get_current_process().phandles.lock().get_handle(hnd)?.as_process()?;
+            if info_sub_type != 0 {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            let cpu_time = process.cpu_time();
+            if InfoType(info_type) == InfoType::ProcessUserTime {
+                Ok(cpu_time.user_ns)
+            } else {
+                Ok(cpu_time.kernel_ns)
+            }
+        }
+        InfoType::PhysicalFreeBlocks | InfoType::PhysicalUsedBlocks | InfoType::PhysicalReservedBlocks => {
+            if hnd != 0 {
+                return Err(UserspaceError::InvalidHandle);
+            }
+            if info_sub_type > MAX_ORDER as u64 {
+                return Err(UserspaceError::InvalidCombination);
+            }
+            let stats = FrameAllocator::stats();
+            let counts = match InfoType(info_type) {
+                InfoType::PhysicalFreeBlocks => stats.free,
+                InfoType::PhysicalUsedBlocks => stats.used,
+                _ => stats.reserved,
+            };
+            Ok(counts[info_sub_type as usize] as u64)
+        }
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Gets the PIDs of the processes currently alive, in ascending order.
+///
+/// Fills the given buffer with as many PIDs as it can hold, and returns the
+/// number of PIDs written.
+///
+/// Like [dump_info], it is only available to the processes having it in their
+/// capabilities.
+pub fn get_process_list(mut pids: UserSpacePtrMut<[u64]>) -> Result<usize, UserspaceError> {
+    let mut count = 0;
+    for (dst, pid) in pids.iter_mut().zip(ProcessStruct::pids()) {
+        *dst = pid as u64;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Sunrise extension: gets the CPU time the process with the given PID spent
+/// running userspace code, and in the kernel, in nanoseconds.
+///
+/// Unlike [get_info], this does not need a handle to the process. Like
+/// [dump_info], it is only available to the processes having it in their
+/// capabilities.
+///
+/// # Returns
+///
+/// The low and high 32 bits of the user time, then of the kernel time.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn get_process_cpu_time(pid: usize) -> Result<(usize, usize, usize, usize), UserspaceError> {
+    let process = ProcessStruct::by_pid(pid).ok_or(UserspaceError::NoSuchEntry)?;
+    let cpu_time = process.cpu_time();
+    Ok((cpu_time.user_ns as usize, (cpu_time.user_ns >> 32) as usize,
+        cpu_time.kernel_ns as usize, (cpu_time.kernel_ns >> 32) as usize))
+}
+
+/// Prints the state of the process with the given PID to the kernel log, as
+/// selected by `dump_type`: the mappings of its address space, its handles,
+/// and its threads with their saved registers. See [DumpInfoType].
+///
+/// Like on HOS, takes the dump type, then its argument. Sunrise extension: the
+/// argument is always the PID of the process to dump.
+///
+/// Same as [dump_info_new].
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - `dump_type` is not a valid [DumpInfoType].
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn dump_info(dump_type: u32, pid: usize) -> Result<(), UserspaceError> {
+    dump_info_new(dump_type, pid)
+}
+
+/// Prints part of the state of the process with the given PID to the kernel
+/// log, as selected by `dump_type`. See [DumpInfoType].
+///
+/// [DumpInfoType::KernelMemory] prints the state of the kernel heap and of the
+/// frame allocator instead, and ignores the PID.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - `dump_type` is not a valid [DumpInfoType].
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn dump_info_new(dump_type: u32, pid: usize) -> Result<(), UserspaceError> {
+    let dump_type = DumpInfoType(dump_type);
+    match dump_type {
+        DumpInfoType::All | DumpInfoType::Memory | DumpInfoType::Handles | DumpInfoType::Threads => (),
+        DumpInfoType::KernelMemory => {
+            // host tests use the system allocator.
+            #[cfg(any(not(test), target_os = "none"))]
+            crate::ALLOCATOR.dump_stats();
+            FrameAllocator::dump_stats();
+            return Ok(())
+        },
+        _ => return Err(UserspaceError::InvalidEnum)
+    }
+    let process = ProcessStruct::by_pid(pid).ok_or(UserspaceError::NoSuchEntry)?;
+
+    info!("Process {} ({}): {:?}", process.pid, process.name, process.state());
+    if dump_type == DumpInfoType::All || dump_type == DumpInfoType::Memory {
+        info!("Memory:");
+        process.pmemory.lock().dump_mappings();
+    }
+    if dump_type == DumpInfoType::All || dump_type == DumpInfoType::Handles {
+        info!("Handles:");
+        process.phandles.lock().dump();
+    }
+    if dump_type == DumpInfoType::All || dump_type == DumpInfoType::Threads {
+        info!("Threads:");
+        // don't hold the threads lock while printing.
+        let threads: Vec<Arc<ThreadStruct>> = process.threads.lock().iter().filter_map(Weak::upgrade).collect();
+        for thread in threads {
+            thread.dump();
+        }
+    }
+    Ok(())
+}
+
+/// Reads the kernel log ring buffer, starting at the record with sequence
+/// number `first_sequence`.
+///
+/// Fills the given buffer with as many consecutive records as it can hold, and
+/// returns the number of records written. If `first_sequence` was already
+/// overwritten, reading starts at the oldest record still in the ring buffer.
+/// Returns 0 once every record was read.
+pub fn read_kernel_log(mut buf: UserSpacePtrMut<[KernelLogRecord]>, first_sequence: u64) -> Result<usize, UserspaceError> {
+    let mut sequence = first_sequence;
+    let mut count = 0;
+    for dst in buf.iter_mut() {
+        // don't hold the ring buffer lock while writing to userspace.
+        match log_impl::ring_buffer::read(sequence) {
+            Some(record) => {
+                sequence = record.sequence + 1;
+                *dst = record;
+                count += 1;
+            }
+            None => break
+        }
+    }
+    Ok(count)
+}
+
+/// Replaces the kernel log filter with the given env_logger-style directives,
+/// e.g. `info,sunrise_kernel::ipc=trace`. This is the same syntax as the
+/// kernel command line.
+///
+/// Invalid directives are ignored, with a warning in the kernel log. Use
+/// [get_kernel_log_filter] to check which ones were applied.
+///
+/// Invalid UTF-8 sequences in the directives are replaced with U+FFFD.
+pub fn set_kernel_log_filter(directives: UserSpacePtr<[u8]>) -> Result<(), UserspaceError> {
+    let directives = String::from_utf8_lossy(&*directives);
+    log_impl::set_filter(&directives);
+    Ok(())
+}
+
+/// Gets the directives of the kernel log filter.
+///
+/// Copies as much of the directives as fits in the given buffer, and returns
+/// their full length, in bytes.
+pub fn get_kernel_log_filter(mut buf: UserSpacePtrMut<[u8]>) -> Result<usize, UserspaceError> {
+    let directives = log_impl::filter_directives();
+    let directives = directives.as_bytes();
+    let len = core::cmp::min(directives.len(), buf.len());
+    buf[..len].copy_from_slice(&directives[..len]);
+    Ok(directives.len())
+}
+
 /// Clear the "signaled" state of a readable event or process. After calling
 /// this on a signaled event, [wait_synchronization()] on this handle will wait
 /// until the handle is signaled again.
@@ -1178,19 +1659,145 @@ pub fn reset_signal(hnd: u32) -> Result<(), UserspaceError> {
     }
 }
 
-/// Gets the PID of the given Process handle. Alias handles (0xFFFF8000 and
-/// 0xFFFF8001) are not allowed here. PIDs are global, unique identifiers for a
-/// given process. PIDs are never reused, and can be passed over IPC safely (the
-/// kernel ensures the correct pid is passed when a process does a request),
-/// making them the best way for sysmodule to identify a calling process.
+/// Gets the ID of the given Thread handle. Thread IDs are global, unique
+/// identifiers for a given thread, and are never reused. The 0xFFFF8000
+/// pseudo-handle can be used to get the ID of the current thread.
 ///
 /// # Errors
 ///
 /// - `InvalidHandle`
-///   - The given handle is invalid or not a process.
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+pub fn get_thread_id(hnd: u32) -> Result<usize, UserspaceError> {
+    let thread = scheduler::get_current_process().phandles.lock()
+        .get_handle(hnd)?.as_thread_handle()?;
+    let thread = thread.upgrade().ok_or(UserspaceError::InvalidHandle)?;
+
+    Ok(thread.id)
+}
+
+/// Gets the PID of the given Process handle, or of the process owning the
+/// given Thread handle. Alias handles (0xFFFF8000 and 0xFFFF8001) are accepted.
+/// PIDs are global, unique identifiers for a given process. PIDs are never
+/// reused, and can be passed over IPC safely (the kernel ensures the correct pid
+/// is passed when a process does a request), making them the best way for
+/// sysmodule to identify a calling process.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a process or a thread.
+///   - The given thread is dead.
 pub fn get_process_id(hnd: u32) -> Result<usize, UserspaceError> {
-    let process = scheduler::get_current_process().phandles.lock()
-        .get_handle_no_alias(hnd)?.as_process()?;
+    let hnd = scheduler::get_current_process().phandles.lock().get_handle(hnd)?;
+
+    let process = match &*hnd {
+        Handle::Process(process) => process.clone(),
+        Handle::Thread(thread) => thread.upgrade().ok_or(UserspaceError::InvalidHandle)?.process.clone(),
+        _ => return Err(UserspaceError::InvalidHandle)
+    };
 
     Ok(process.pid)
-}
\ No newline at end of file
+}
+
+/// Converts a raw `ThreadActivity` passed by userspace to whether the thread
+/// should be paused.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - The activity is neither `Runnable` nor `Paused`.
+fn activity_is_paused(activity: u32) -> Result<bool, UserspaceError> {
+    match activity {
+        x if x == ThreadActivity::Runnable as u32 => Ok(false),
+        x if x == ThreadActivity::Paused as u32 => Ok(true),
+        _ => Err(UserspaceError::InvalidEnum)
+    }
+}
+
+/// Pauses or resumes a thread.
+///
+/// A paused thread is removed from the schedule queue, and will not run again
+/// until it is resumed. If it was waiting on an event, it stays paused after the
+/// wait completes, and will only return from its syscall once resumed.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+/// - `InvalidEnum`
+///   - The activity is neither `Runnable` nor `Paused`.
+/// - `InvalidState`
+///   - The given thread is the current thread.
+///   - The thread is already in the requested activity.
+pub fn set_thread_activity(hnd: u32, activity: u32) -> Result<(), UserspaceError> {
+    let paused = activity_is_paused(activity)?;
+    let thread = scheduler::get_current_process().phandles.lock()
+        .get_handle(hnd)?.as_thread_handle()?;
+    let thread = thread.upgrade().ok_or(UserspaceError::InvalidHandle)?;
+
+    scheduler::set_thread_paused(&thread, paused)
+}
+
+/// Pauses or resumes all the threads of a process.
+///
+/// Pausing a process behaves as if all its threads, including the ones created
+/// later, were paused with [set_thread_activity]. Resuming it does not resume
+/// threads that were paused individually.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a process.
+/// - `InvalidEnum`
+///   - The activity is neither `Runnable` nor `Paused`.
+/// - `InvalidState`
+///   - The given process is the current process.
+///   - The process is already in the requested activity.
+pub fn set_process_activity(hnd: u32, activity: u32) -> Result<(), UserspaceError> {
+    let paused = activity_is_paused(activity)?;
+    let hnd = scheduler::get_current_process().phandles.lock().get_handle(hnd)?;
+
+    match &*hnd {
+        Handle::Process(process) => scheduler::set_process_paused(process, paused),
+        _ => Err(UserspaceError::InvalidHandle)
+    }
+}
+
+/// Gets the userspace registers of a paused thread, as they were the last
+/// time it entered the kernel.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+/// - `InvalidState`
+///   - The thread is not paused, either by itself or through its process.
+pub fn get_thread_context3(mut context: UserSpacePtrMut<ThreadContext>, hnd: u32) -> Result<(), UserspaceError> {
+    let thread = scheduler::get_current_process().phandles.lock()
+        .get_handle(hnd)?.as_thread_handle()?;
+    let thread = thread.upgrade().ok_or(UserspaceError::InvalidHandle)?;
+
+    if !scheduler::is_suspended(&thread) {
+        return Err(UserspaceError::InvalidState);
+    }
+
+    let regs = thread.userspace_hwcontext.lock().clone();
+    *context = ThreadContext {
+        eax: regs.eax,
+        ebx: regs.ebx,
+        ecx: regs.ecx,
+        edx: regs.edx,
+        esi: regs.esi,
+        edi: regs.edi,
+        ebp: regs.ebp,
+        esp: regs.esp,
+        eip: regs.eip,
+        eflags: regs.eflags,
+        cs: regs.cs,
+        gs: regs.gs,
+    };
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! In-kernel test runner
+//!
+//! Host tests can only exercise the parts of the kernel that don't touch the hardware. When the
+//! kernel is built in test mode for its real target, `custom_test_frameworks` gathers every
+//! `#[test_case]` instead, and [common_start] calls [run_tests] where it would otherwise call
+//! `main`. Test cases thus run in kernel context, on a fully initialized kernel, as the first
+//! process.
+//!
+//! Results are reported on serial. When done, we exit QEMU through its `isa-debug-exit` device,
+//! with a status telling whether the tests passed. A test case fails by panicking: the panic
+//! handler prints its usual report, and then exits QEMU with [QemuExitCode::Failed].
+//!
+//! Run them with `cargo make test-kernel`.
+//!
+//! [common_start]: crate::common_start
+
+use core::fmt::Write;
+use crate::devices::rs232::SerialLogger;
+use crate::i386::pio::Pio;
+use crate::io::Io;
+
+/// IO port of the `isa-debug-exit` device, as set by the `iobase` argument passed to QEMU.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Status QEMU exits with, through the `isa-debug-exit` device.
+///
+/// QEMU turns it into `(status << 1) | 1`, so it can't be mistaken for QEMU's own exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    /// Every test passed. QEMU exits with 33.
+    Success = 0x10,
+    /// A test failed. QEMU exits with 35.
+    Failed = 0x11,
+}
+
+/// Exits QEMU with the given status.
+///
+/// Only returns if we're not running in QEMU, or it was started without an `isa-debug-exit`
+/// device.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    Pio::<u32>::new(ISA_DEBUG_EXIT_PORT).write(exit_code as u32);
+}
+
+/// A test case. Implemented by every function taking no argument.
+pub trait Testable {
+    /// Runs the test case, printing its name and outcome on serial.
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    #[allow(unused_must_use)]
+    fn run(&self) {
+        let name = unsafe {
+            // safe: just an intrinsic with no side effects.
+            core::intrinsics::type_name::<T>()
+        };
+        write!(SerialLogger, "test {} ... ", name);
+        self();
+        writeln!(SerialLogger, "ok");
+    }
+}
+
+/// Runs every `#[test_case]`, and exits QEMU with a status telling if they passed.
+///
+/// Called by the test harness, through the `test_main` function it generates.
+#[allow(unused_must_use)]
+pub fn run_tests(tests: &[&dyn Testable]) {
+    writeln!(SerialLogger, "\nrunning {} kernel tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    writeln!(SerialLogger, "\ntest result: ok. {} passed; 0 failed\n", tests.len());
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Checks the heap allocator works in kernel context.
+#[test_case]
+fn heap_allocation() {
+    let v: alloc::vec::Vec<usize> = (0..1000).collect();
+    assert_eq!(v.iter().sum::<usize>(), 999 * 1000 / 2);
+}
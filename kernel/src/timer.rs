@@ -1,103 +1,260 @@
 //! The core timing of Sunrise.
+//!
+//! All the timers of the kernel live in a single queue, sorted by deadline. When
+//! the hardware allows it (HPET), the timer IRQ is programmed in one-shot mode to
+//! trigger exactly when the earliest timer expires, so the CPU is not woken up
+//! when nobody is waiting. Otherwise (PIT), we fall back to a periodic IRQ, and
+//! check the queue on every tick.
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use super::event;
-use super::event::{IRQEvent, Waitable};
-use super::sync::Once;
-use super::utils::div_ceil;
+use crate::devices::hpet;
+use crate::event::Waitable;
+use crate::process::ThreadStruct;
+use crate::scheduler;
+use crate::sync::{Once, SpinLockIRQ};
+use sunrise_libkern::SYSTEM_TICK_FREQUENCY;
+
+/// The hardware timer that drives the timer queue.
+#[derive(Debug, Clone, Copy)]
+pub enum TimerSource {
+    /// A timer triggering an IRQ every `irq_period_ns` nanoseconds.
+    ///
+    /// The kernel time advances by one period on every IRQ, so this is also the
+    /// resolution of the timers.
+    Periodic {
+        /// The IRQ period in nanoseconds.
+        irq_period_ns: u64
+    },
+    /// The HPET, with its main timer in one-shot mode.
+    ///
+    /// The kernel time is read from the HPET main counter, and the IRQ is
+    /// programmed to trigger when the earliest timer expires.
+    Hpet,
+}
 
 /// This represent the information to derive all internal timing in Sunrise.
+#[derive(Debug)]
 struct KernelTimerInfo {
     /// The frequency of the oscillator used as primary source of this timer, when not divided, in Hertz.
     ///
-    /// The value here is only informative, you should use `.irq_periode_ns`.
+    /// The value here is only informative.
     oscillator_frequency: u64,
 
-    /// The IRQ period used on this timer in nanoseconds.
-    pub irq_period_ns: u64,
+    /// The hardware timer we use.
+    source: TimerSource,
 
     /// The IRQ number that the timer use.
-    pub irq_number: u8,
+    irq_number: u8,
 }
 
 /// Stores the information needed for Sunrise's internal timing.
 static KERNEL_TIMER_INFO: Once<KernelTimerInfo> = Once::new();
 
-/// Set the information required for Sunrise timer to work.
-/// 
+/// Set the information required for Sunrise timer to work, and unmask the timer IRQ.
+///
 /// # Panics
 ///
 /// Panics if the timer info has already been initialized.
-pub fn set_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, irq_period_ns: u64) {
+pub fn set_kernel_timer_info(irq_number: u8, oscillator_frequency: u64, source: TimerSource) {
     assert!(KERNEL_TIMER_INFO.r#try().is_none(), "Kernel Timer Info is already initialized!");
     KERNEL_TIMER_INFO.call_once(|| {
         KernelTimerInfo {
             irq_number,
             oscillator_frequency,
-            irq_period_ns
+            source
         }
     });
+    crate::i386::interrupt::unmask(irq_number);
 }
 
-/// Returns a stream of event that trigger every `ns` amount of nanoseconds.
-/// 
-/// # Note
-/// 
-/// - If the timer resolution cannot handle it, this is not going to be accurate.
-/// - Minimal resolution for HPET (10Mhz) / HPET QEMU (100Mhz): 100ns / 10ns
-/// - Minimal resolution for PIC (~1Mhz): 10ms
-pub fn wait_ns(ns: usize) -> impl Waitable {
-    let timer_info = KERNEL_TIMER_INFO.r#try().expect("Kernel Timer Info is not initialized!");
-    IRQTimer::new(ns, timer_info.irq_number, timer_info.irq_period_ns)
+/// The shared state of a [Timer], referenced by the timer queue until it expires.
+#[derive(Debug)]
+struct TimerState {
+    /// The time at which this timer expires, in nanoseconds since boot.
+    deadline_ns: u64,
+    /// Set once the deadline has been reached.
+    fired: AtomicBool,
+    /// List of threads waiting on this timer. They will be rescheduled when it expires.
+    waiting_threads: SpinLockIRQ<Vec<Arc<ThreadStruct>>>,
 }
 
+impl TimerState {
+    /// Marks the timer as expired, and wakes up its waiters.
+    fn fire(&self) {
+        self.fired.store(true, Ordering::SeqCst);
+        let mut threads = self.waiting_threads.lock();
+        while let Some(thread) = threads.pop() {
+            scheduler::add_to_schedule_queue(thread);
+        }
+    }
+}
+
+/// The global timer queue.
 #[derive(Debug)]
-/// A stream of event that trigger every `ns` amount of nanoseconds, by counting interruptions.
-pub struct IRQTimer {
-    /// Approximation of number of ns spent between triggers.
-    every_ns: usize,
-    /// IRQ event period in nanoseconds.
-    irq_period_ns: u64,
-    /// The IRQ that we wait on.
-    parent_event: IRQEvent,
-    /// The reset value of ``.countdown_value``.
-    reset_value: usize,
-    /// Number of IRQ triggers to wait for. Derived from `.every_ns`. This is the exact time amout that is used.
-    countdown_value: AtomicUsize
-}
-
-impl IRQTimer {
-    /// Create a new IRQ timer instance from the time to wait (in ns), the irq number and irq event period (in ns).
-    pub fn new(ns: usize, irq: u8, irq_period_ns: u64) -> Self {
-        let mut reset_value = div_ceil(ns as u64, irq_period_ns) as usize;
-        if reset_value == 0 {
-            reset_value = 1;
+struct TimerQueue {
+    /// Time elapsed since the timer was initialized, in nanoseconds.
+    ///
+    /// Only used with a [TimerSource::Periodic], advanced on every IRQ.
+    elapsed_ns: u64,
+    /// Timers that haven't expired yet, sorted by deadline (earliest first).
+    pending: Vec<Arc<TimerState>>,
+}
+
+/// The queue of all pending timers of the kernel.
+static TIMER_QUEUE: SpinLockIRQ<TimerQueue> = SpinLockIRQ::new(TimerQueue {
+    elapsed_ns: 0,
+    pending: Vec::new(),
+});
+
+/// Gets the timer info.
+///
+/// # Panics
+///
+/// Panics if the timer info is not initialized yet.
+fn timer_info() -> &'static KernelTimerInfo {
+    KERNEL_TIMER_INFO.r#try().expect("Kernel Timer Info is not initialized!")
+}
+
+/// Gets the current time, with the timer queue already locked.
+fn now_locked(info: &KernelTimerInfo, queue: &TimerQueue) -> u64 {
+    match info.source {
+        TimerSource::Periodic { .. } => queue.elapsed_ns,
+        TimerSource::Hpet => hpet::now_ns(),
+    }
+}
+
+/// Gets the time elapsed since the timer was initialized, in nanoseconds.
+///
+/// Its resolution depends on the timer source: a few nanoseconds on the HPET,
+/// one IRQ period on a periodic timer.
+pub fn now_ns() -> u64 {
+    let info = timer_info();
+    match info.source {
+        TimerSource::Periodic { .. } => TIMER_QUEUE.lock().elapsed_ns,
+        TimerSource::Hpet => hpet::now_ns(),
+    }
+}
+
+/// Converts a duration in nanoseconds to system ticks, which run at
+/// [SYSTEM_TICK_FREQUENCY].
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    // Split the seconds off so the multiplication can't overflow.
+    ns / 1_000_000_000 * SYSTEM_TICK_FREQUENCY + ns % 1_000_000_000 * SYSTEM_TICK_FREQUENCY / 1_000_000_000
+}
+
+/// Like [now_ns], but never blocks.
+///
+/// Returns None if the timer is not initialized yet, or if getting the time
+/// would require taking a lock that is currently held. Used by the logger,
+/// which may be called while the timer queue is locked.
+pub fn try_now_ns() -> Option<u64> {
+    let info = KERNEL_TIMER_INFO.r#try()?;
+    match info.source {
+        TimerSource::Periodic { .. } => TIMER_QUEUE.try_lock().map(|queue| queue.elapsed_ns),
+        TimerSource::Hpet => Some(hpet::now_ns()),
+    }
+}
+
+/// Fires all the expired timers, and programs the hardware for the next one.
+fn process_queue(info: &KernelTimerInfo, queue: &mut TimerQueue) {
+    loop {
+        let now = now_locked(info, queue);
+        while queue.pending.first().map(|timer| timer.deadline_ns <= now).unwrap_or(false) {
+            queue.pending.remove(0).fire();
         }
 
-        IRQTimer {
-            every_ns: ns,
-            irq_period_ns,
-            parent_event: event::wait_event(irq),
-            reset_value,
-            countdown_value: AtomicUsize::new(0)
+        match (info.source, queue.pending.first()) {
+            (TimerSource::Hpet, Some(next)) => {
+                hpet::set_deadline(next.deadline_ns);
+                // The comparator only triggers when the counter goes through it.
+                // If the deadline passed while we were programming it, handle it ourselves.
+                if hpet::now_ns() < next.deadline_ns {
+                    break;
+                }
+            }
+            _ => break
         }
     }
 }
 
-impl Waitable for IRQTimer {
-    fn register(&self) {
-        self.parent_event.register()
+/// Handles the timer IRQ, waking up the threads waiting on expired timers.
+///
+/// Called by the IRQ handlers with the number of the IRQ being handled, does
+/// nothing if it isn't the timer IRQ.
+pub fn handle_irq(irq: u8) {
+    let info = match KERNEL_TIMER_INFO.r#try() {
+        Some(info) if info.irq_number == irq => info,
+        _ => return
+    };
+
+    let mut queue = TIMER_QUEUE.lock();
+    if let TimerSource::Periodic { irq_period_ns } = info.source {
+        queue.elapsed_ns += irq_period_ns;
+    }
+    process_queue(info, &mut queue);
+}
+
+/// Returns a timer that gets signaled once `ns` nanoseconds have elapsed.
+///
+/// # Note
+///
+/// - The accuracy depends on the timer source.
+/// - Minimal resolution for HPET (10Mhz) / HPET QEMU (100Mhz): 100ns / 10ns
+/// - Minimal resolution for PIT: 10ms
+pub fn wait_ns(ns: usize) -> Timer {
+    let info = timer_info();
+    let mut queue = TIMER_QUEUE.lock();
+
+    let state = Arc::new(TimerState {
+        deadline_ns: now_locked(info, &queue).saturating_add(ns as u64),
+        fired: AtomicBool::new(false),
+        waiting_threads: SpinLockIRQ::new(Vec::new()),
+    });
+
+    let index = queue.pending.iter()
+        .position(|timer| timer.deadline_ns > state.deadline_ns)
+        .unwrap_or_else(|| queue.pending.len());
+    queue.pending.insert(index, state.clone());
+
+    if index == 0 {
+        // We're the new earliest timer, reprogram the hardware.
+        process_queue(info, &mut queue);
     }
 
+    Timer { state }
+}
+
+/// A one-shot timer, signaled once its deadline is reached. Created with [wait_ns].
+///
+/// Dropping it removes it from the timer queue.
+#[derive(Debug)]
+pub struct Timer {
+    /// The state shared with the timer queue.
+    state: Arc<TimerState>,
+}
+
+impl Waitable for Timer {
     fn is_signaled(&self) -> bool {
-        // First, reset the spins if necessary
-        self.countdown_value.compare_and_swap(0, self.reset_value, Ordering::SeqCst);
+        self.state.fired.load(Ordering::SeqCst)
+    }
 
-        // Then, check if it's us.
-        self.parent_event.is_signaled()
-            // Then, check if we need more spins.
-            && self.countdown_value.fetch_sub(1, Ordering::SeqCst) == 1
+    fn register(&self) {
+        let curthread = scheduler::get_current_thread();
+        let mut threads = self.state.waiting_threads.lock();
+        if threads.iter().find(|v| Arc::ptr_eq(&curthread, v)).is_none() {
+            threads.push(curthread);
+        }
     }
 }
 
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let mut queue = TIMER_QUEUE.lock();
+        queue.pending.retain(|timer| !Arc::ptr_eq(timer, &self.state));
+        // If we were the earliest timer, the hardware will trigger a spurious
+        // IRQ. No need to reprogram it, the IRQ handler will take care of it.
+    }
+}
@@ -183,7 +183,7 @@ async fn update_keyboard(work_queue: WorkQueue<'_>) {
                 KEYBOARD_INSTANCE.r#try().and_then(|x| Some(x.lock())).expect("Keyboard instance not initialized").handle_ps2_irq()
             }).await;
         let _ = writable_event.signal();
-        let _ = sunrise_libuser::syscalls::sleep_thread(0);
+        sunrise_libuser::threads::yield_now();
     }
 }
 
@@ -21,8 +21,12 @@ enum_with_val! {
         ///
         /// Generally means it is not page aligned.
         InvalidAddress = 102,
-        // SlabheapFull = 103,
-        /// The virtual address space was exhausted.
+        /// A kernel resource was exhausted.
+        ///
+        /// During IPC, this happens when an X descriptor does not fit in the
+        /// receive list (C descriptors) of the receiving end.
+        OutOfResource = 103,
+        /// The physical memory or the virtual address space was exhausted.
         MemoryFull = 104,
         /// The process' handle table is full.
         HandleTableFull = 105,
@@ -69,7 +73,8 @@ enum_with_val! {
         // InvalidHardwareBreakpoint = 127,
         // FatalException = 128,
         // LastThreadNotYours = 129,
-        // PortMaxSessions = 131,
+        /// The maximum number of sessions of the port was reached.
+        PortMaxSessions = 131,
         // ResourceLimitExceeded = 132,
         // CommandBufferTooSmall = 260,
         // ProcessNotBeingDebugged = 520
@@ -109,6 +114,7 @@ impl fmt::Display for KernelError {
             KernelError::MemoryFull => write!(f, "Memory full. Try to kill some processes and try again."),
             KernelError::HandleTableFull => write!(f, "Handle table full. You might want to bump your handle table size in the NPDM."),
             KernelError::InvalidMemPerms => write!(f, "Invalid memory permissions."),
+            KernelError::OutOfResource => write!(f, "Out of resource. For IPC, the receive list of the remote end is too small."),
             KernelError::InvalidHandle => write!(f, "Invalid handle. Either it does not exist, or the Handle is of the wrong type."),
             KernelError::CopyFromUserFailed => write!(f, "Copy from user failed. The pointer either does not point in userspace, or points to unmapped memory."),
             KernelError::InvalidCombination => write!(f, "Invalid combination."),
@@ -118,6 +124,7 @@ impl fmt::Display for KernelError {
             KernelError::NoSuchEntry => write!(f, "The entry does not exist."),
             KernelError::PortRemoteDead => write!(f, "Remote handle closed. Usually happens when an IPC got sent in the wrong format."),
             KernelError::InvalidState => write!(f, "Handle is in invalid state for this operation."),
+            KernelError::PortMaxSessions => write!(f, "The port reached its maximum number of sessions."),
             KernelError(err) => write!(f, "Unknown error: {}", err)
         }
     }
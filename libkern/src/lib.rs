@@ -52,10 +52,12 @@ bitflags! {
         const FORCE_READ_WRITABLE_BY_DEBUG_SYSCALLS = 1 << 9;
         /// Allows sending this region over IPC.
         const IPC_SEND_ALLOWED = 1 << 10;
-        /// Allows sending this region over IPC with buffer flag set to 1.
-        const NON_DEVICE_IPC_SEND_ALLOWED = 1 << 11;
-        /// Allows sending this region over IPC with buffer flag set to 3.
-        const NON_SECURE_IPC_SEND_ALLOWED = 1 << 12;
+        /// Allows sending this region over IPC with buffer flag set to 1, mapped as
+        /// [MemoryType::NonSecureIpc].
+        const NON_SECURE_IPC_SEND_ALLOWED = 1 << 11;
+        /// Allows sending this region over IPC with buffer flag set to 3, mapped as
+        /// [MemoryType::NonDeviceIpc].
+        const NON_DEVICE_IPC_SEND_ALLOWED = 1 << 12;
         // Empty
         /// Allows the use of `svcSetProcessMemoryPermission` on this memory region.
         const PROCESS_PERMISSION_CHANGE_ALLOWED = 1 << 14;
@@ -90,8 +92,8 @@ bitflags! {
 
         /// All IPC Buffer Send permissions are allowed by this type.
         const ALL_IPC_SEND_ALLOWED = Self::IPC_SEND_ALLOWED.bits |
-            Self::NON_DEVICE_IPC_SEND_ALLOWED.bits |
-            Self::NON_SECURE_IPC_SEND_ALLOWED.bits;
+            Self::NON_SECURE_IPC_SEND_ALLOWED.bits |
+            Self::NON_DEVICE_IPC_SEND_ALLOWED.bits;
         /// This type can use all IOMMU-related permissions (MapDevice allowed,
         /// MapDeviceAligned allowed, and QueryPhysicalAddress allowed).
         const CAN_IOMMU = Self::QUERY_PHYSICAL_ADDRESS_ALLOWED.bits |
@@ -318,6 +320,164 @@ pub struct MemoryInfo {
     pub ipc_ref_count: u32,
     /// Unknown.
     pub device_ref_count: u32,
+    /// Sunrise extension: how much of this memory region is backed by physical
+    /// memory. Comes after the HOS fields to keep their layout.
+    ///
+    /// Heap and Normal memory is committed lazily, on first access to each page. The rest of
+    /// `size` is only reserved.
+    pub committed_size: usize,
+}
+
+/// The activity of a thread or process, as set by `set_thread_activity` and
+/// `set_process_activity`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadActivity {
+    /// The thread is scheduled normally.
+    Runnable = 0,
+    /// The thread will not be scheduled until it is made runnable again.
+    Paused = 1,
+}
+
+/// The userspace registers of a paused thread, as returned by the
+/// `get_thread_context3` syscall.
+///
+/// Those are the registers the thread had the last time it entered the kernel.
+#[repr(C)]
+#[derive(Debug, Default, Clone)]
+#[allow(clippy::missing_docs_in_private_items)]
+#[allow(missing_docs)]
+pub struct ThreadContext {
+    pub eax: usize,
+    pub ebx: usize,
+    pub ecx: usize,
+    pub edx: usize,
+    pub esi: usize,
+    pub edi: usize,
+    pub ebp: usize,
+    pub esp: usize,
+    pub eip: usize,
+    pub eflags: usize,
+    pub cs: usize,
+    pub gs: usize,
+}
+
+/// Pseudo-handle always referring to the current thread.
+///
+/// It is never stored in a handle table, and cannot be closed.
+pub const CURRENT_THREAD_PSEUDO_HANDLE: u32 = 0xFFFF8000;
+
+/// Pseudo-handle always referring to the current process.
+///
+/// It is never stored in a handle table, and cannot be closed.
+pub const CURRENT_PROCESS_PSEUDO_HANDLE: u32 = 0xFFFF8001;
+
+/// Passed to `svcSleepThread` to yield to threads of the same priority, without
+/// migrating to another core.
+pub const YIELD_WITHOUT_CORE_MIGRATION: usize = 0;
+
+/// Passed to `svcSleepThread` to yield to threads of the same priority,
+/// allowing the thread to migrate to another core. This is `-1` as a signed value.
+pub const YIELD_WITH_CORE_MIGRATION: usize = -1isize as usize;
+
+/// Passed to `svcSleepThread` to yield to any other thread, including those of
+/// a lower priority. This is `-2` as a signed value.
+pub const YIELD_TO_ANY_THREAD: usize = -2isize as usize;
+
+/// Frequency of the system tick, in Hz. The tick counts returned by `svcGetInfo`
+/// are expressed in this unit, like on the Switch.
+pub const SYSTEM_TICK_FREQUENCY: u64 = 19_200_000;
+
+/// Maximum length of a thread name, in bytes. See `svcSetThreadName`.
+pub const MAX_THREAD_NAME_LEN: usize = 32;
+
+/// Maximum length of the target of a [KernelLogRecord], in bytes. Longer
+/// targets are truncated.
+pub const KERNEL_LOG_TARGET_LEN: usize = 44;
+
+/// Maximum length of the message of a [KernelLogRecord], in bytes. Longer
+/// messages are truncated.
+pub const KERNEL_LOG_MESSAGE_LEN: usize = 200;
+
+/// A kernel log record, as returned by the `read_kernel_log` syscall.
+///
+/// The kernel keeps the last few hundred records it logged in a ring buffer.
+/// Every record is given a sequence number, which increases by one for every
+/// record logged since boot. Userspace uses it to know where it left off, and
+/// to detect records that were overwritten before it could read them.
+#[repr(C)]
+#[derive(Copy)]
+pub struct KernelLogRecord {
+    /// Sequence number of this record.
+    pub sequence: u64,
+    /// Time at which this record was logged, in nanoseconds since boot.
+    pub timestamp: u64,
+    /// Level of this record: 1 is Error, 2 Warn, 3 Info, 4 Debug and 5 Trace.
+    pub level: u8,
+    /// Length of the valid part of `target`.
+    pub target_len: u8,
+    /// Length of the valid part of `message`.
+    pub message_len: u16,
+    /// Module path of the code that logged this record, in UTF-8.
+    pub target: [u8; KERNEL_LOG_TARGET_LEN],
+    /// The formatted message, in UTF-8.
+    pub message: [u8; KERNEL_LOG_MESSAGE_LEN],
+}
+
+assert_eq_size!(KernelLogRecord, [u8; 264]);
+
+// Safety: KernelLogRecord is repr(C), has no padding, and any bit pattern is valid.
+unsafe impl plain::Plain for KernelLogRecord {}
+
+impl KernelLogRecord {
+    /// Returns the target of this record.
+    ///
+    /// Invalid UTF-8 is replaced by an empty string.
+    pub fn target(&self) -> &str {
+        let len = core::cmp::min(self.target_len as usize, KERNEL_LOG_TARGET_LEN);
+        core::str::from_utf8(&self.target[..len]).unwrap_or("")
+    }
+
+    /// Returns the message of this record.
+    ///
+    /// Invalid UTF-8 is replaced by an empty string.
+    pub fn message(&self) -> &str {
+        let len = core::cmp::min(self.message_len as usize, KERNEL_LOG_MESSAGE_LEN);
+        core::str::from_utf8(&self.message[..len]).unwrap_or("")
+    }
+}
+
+impl Default for KernelLogRecord {
+    fn default() -> Self {
+        KernelLogRecord {
+            sequence: 0,
+            timestamp: 0,
+            level: 0,
+            target_len: 0,
+            message_len: 0,
+            target: [0; KERNEL_LOG_TARGET_LEN],
+            message: [0; KERNEL_LOG_MESSAGE_LEN],
+        }
+    }
+}
+
+// Arrays this big don't implement Clone, so the derive doesn't work.
+impl Clone for KernelLogRecord {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl fmt::Debug for KernelLogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        f.debug_struct("KernelLogRecord")
+            .field("sequence", &self.sequence)
+            .field("timestamp", &self.timestamp)
+            .field("level", &self.level)
+            .field("target", &self.target())
+            .field("message", &self.message())
+            .finish()
+    }
 }
 
 /// Buffer used for Inter Process Communication.
@@ -529,8 +689,16 @@ syscalls! {
     StartProcessEntrypoint = 0x81,
     MapMmioRegion = 0x82,
     SetThreadArea = 0x83,
+    SetThreadName = 0x84,
+    GetProcessName = 0x85,
+    ShareProcessMemory = 0x86,
+    ReadKernelLog = 0x87,
+    SetKernelLogFilter = 0x88,
+    GetKernelLogFilter = 0x89,
+    CreatePortDeathEvent = 0x8A,
+    GetProcessCpuTime = 0x8B,
 
     ---
     // Add SVCs before this line.
-    MaxSvc = 0x83
+    MaxSvc = 0x8B
 }
\ No newline at end of file
@@ -213,11 +213,80 @@ enum_with_val! {
     }
 }
 
+enum_with_val! {
+    /// Sunrise extension: the reason why a process exited, as passed to
+    /// `exit_process`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ExitReason(pub u32) {
+        /// Process exited normally, or is still alive.
+        Exited = 0,
+        /// Process ran out of memory and could not satisfy an allocation.
+        OutOfMemory = 1,
+    }
+}
+
+enum_with_val! {
+    /// Kind of information to extract with `get_info`.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct InfoType(pub u32) {
+        /// Time the given CPU core spent idle, in system ticks. The handle must
+        /// be 0, and the sub-type is the core number, or -1 for the current core.
+        IdleTickCount = 20,
+        /// CPU time used by the given thread, user and kernel time combined, in
+        /// system ticks. The handle is a thread handle, and the sub-type is the
+        /// core number, or -1 for all cores.
+        ThreadTickCount = 25,
+        /// Sunrise extension: CPU time the given process spent running userspace
+        /// code, in nanoseconds. The handle is a process handle and the sub-type
+        /// must be 0. Use `get_process_cpu_time` to get it from a PID.
+        ProcessUserTime = 0x1000_0000,
+        /// Sunrise extension: CPU time the given process spent in the kernel,
+        /// in nanoseconds. Takes the same arguments as `ProcessUserTime`.
+        ProcessKernelTime = 0x1000_0001,
+        /// Sunrise extension: number of free blocks of 2^order physical frames.
+        /// The handle must be 0, and the sub-type is the order.
+        PhysicalFreeBlocks = 0x1000_0002,
+        /// Sunrise extension: number of allocated blocks of 2^order physical
+        /// frames. Takes the same arguments as `PhysicalFreeBlocks`.
+        PhysicalUsedBlocks = 0x1000_0003,
+        /// Sunrise extension: number of reserved blocks of 2^order physical
+        /// frames. Takes the same arguments as `PhysicalFreeBlocks`.
+        PhysicalReservedBlocks = 0x1000_0004,
+        /// Sunrise extension: time the given CPU core spent running threads, in
+        /// system ticks. Takes the same arguments as `IdleTickCount`.
+        BusyTickCount = 0x1000_0005,
+    }
+}
+
+enum_with_val! {
+    /// What `dump_info` and `dump_info_new` print about a process to the kernel
+    /// log. Sunrise extension: HOS does not document its dump types.
+    #[derive(Default, Clone, Copy, PartialEq, Eq)]
+    pub struct DumpInfoType(pub u32) {
+        /// Everything below.
+        All = 0,
+        /// The mappings of the process's address space, with their memory type
+        /// and permissions.
+        Memory = 1,
+        /// Every handle of the process's handle table, with its object type and
+        /// peer.
+        Handles = 2,
+        /// Every thread of the process, with its state and saved userspace
+        /// registers.
+        Threads = 3,
+        /// The size of the kernel heap, the statistics of its slab caches, and
+        /// the physical frames count of every order. Not part of `All`: it is not about a process, and the PID is ignored.
+        KernelMemory = 4,
+    }
+}
+
 enum_with_val! {
     /// Kind of information to extract from a process wit `get_process_info`.
     #[derive(Default, Clone, Copy, PartialEq, Eq)]
     pub struct ProcessInfoType(pub u32) {
         /// Get the state the process is currently in.
         ProcessState = 0,
+        /// Sunrise extension: get the [ExitReason] the process exited with.
+        ExitReason = 0x1000_0000,
     }
 }
\ No newline at end of file
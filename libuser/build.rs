@@ -14,7 +14,7 @@ use std::path::{Path, PathBuf};
 use swipc_gen::generate_ipc;
 
 /// Array containing all module names and id path to use with swipc-gen.
-const MODULES_ARRAY: [(&str, &str); 8] =
+const MODULES_ARRAY: [(&str, &str); 9] =
     [
         ("sm", "../../ipcdefs/sm.id"),
         ("vi", "../../ipcdefs/vi.id"),
@@ -24,6 +24,7 @@ const MODULES_ARRAY: [(&str, &str); 8] =
         ("keyboard", "../../ipcdefs/keyboard.id"),
         ("ldr", "../../ipcdefs/loader.id"),
         ("example", "../../ipcdefs/example.id"),
+        ("lm", "../../ipcdefs/lm.id"),
     ];
 
 fn main() {
@@ -254,6 +254,19 @@ impl<'a> IPCBuffer<'a> {
         }
     }
 
+    /// Creates a Type-W IPCBuffer from the given reference.
+    fn inout_buffer<T: SizedIPCBuffer + ?Sized>(data: &mut T, flags: u8) -> IPCBuffer {
+        IPCBuffer {
+            addr: data as *mut T as *const u8 as usize as u64,
+            // The dereference is necessary because &T implements SizedIPCBuffer too...
+            size: (*data).size() as u64,
+            ty: IPCBufferType::W {
+                flags
+            },
+            phantom: PhantomData
+        }
+    }
+
     /// Creates a Type-C IPCBuffer from the given reference.
     ///
     /// If has_u16_size is true, the size of the pointer will be written after
@@ -422,10 +435,10 @@ where
     pub fn set_ty(&mut self, ty: MessageTy) -> &mut Self {
         match (ty, self.token) {
             (MessageTy::Close, _) => self.ty = 2,
-            (MessageTy::Request, Some(_)) => self.ty = 4,
-            (MessageTy::Request, None) => self.ty = 6,
-            (MessageTy::Control, Some(_)) => self.ty = 5,
-            (MessageTy::Control, None) => self.ty = 7,
+            (MessageTy::Request, None) => self.ty = 4,
+            (MessageTy::Request, Some(_)) => self.ty = 6,
+            (MessageTy::Control, None) => self.ty = 5,
+            (MessageTy::Control, Some(_)) => self.ty = 7,
         }
         self
     }
@@ -605,6 +618,34 @@ where
         }
     }
 
+    /// Retreive the next InOutBuffer (type-W buffer) in the message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an InvalidIpcBufferCount if not enough buffers are present
+    ///
+    /// Returns an InvalidIpcBuffer if the next buffer was not of the appropriate
+    /// size.
+    ///
+    /// # Safety
+    ///
+    /// This method is unsafe as it allows creating references to arbitrary
+    /// memory, and of arbitrary type.
+    pub unsafe fn pop_inout_buffer<'b, T: SizedIPCBuffer + ?Sized>(&mut self) -> Result<&'b mut T, Error> {
+        let buffer = self.buffers.iter().position(|buf| buf.buftype().is_type_w())
+            .and_then(|pos| self.buffers.pop_at(pos))
+            .ok_or_else(|| LibuserError::InvalidIpcBufferCount)?;
+
+        let addr = usize::try_from(buffer.addr).map_err(|_| LibuserError::InvalidIpcBuffer)?;
+        let size = usize::try_from(buffer.size).map_err(|_| LibuserError::InvalidIpcBuffer)?;
+
+        if T::is_cool(addr, size) {
+            Ok(T::from_raw_parts_mut(addr, size))
+        } else {
+            Err(LibuserError::InvalidIpcBuffer.into())
+        }
+    }
+
     /// Push an OutBuffer (type-A buffer) backed by the specified data.
     pub fn push_out_buffer<T: SizedIPCBuffer + ?Sized>(&mut self, data: &'a T) -> &mut Self {
         self.buffers.push(IPCBuffer::out_buffer(data, 0));
@@ -617,6 +658,12 @@ where
         self
     }
 
+    /// Push an InOutBuffer (type-W buffer) backed by the specified data.
+    pub fn push_inout_buffer<T: SizedIPCBuffer + ?Sized>(&mut self, data: &'a mut T) -> &mut Self {
+        self.buffers.push(IPCBuffer::inout_buffer(data, 0));
+        self
+    }
+
     /// Push an InPointer (type-C buffer) backed by the specified data.
     ///
     /// If `has_u16_count` is true, the size of the X buffer will be appended
@@ -799,6 +846,7 @@ where
                 let (addr, size, flags) = match buf.buftype() {
                     IPCBufferType::A {flags} => (buf.addr, buf.size, flags),
                     IPCBufferType::B {flags} => (buf.addr, buf.size, flags),
+                    IPCBufferType::W {flags} => (buf.addr, buf.size, flags),
                     _ => unreachable!()
                 };
 
@@ -1033,15 +1081,16 @@ where
     }
 }
 
-/// Quickly find the type and cmdid of an IPC message for the server dispatcher.
+/// Find the offset of the raw data section of an IPC message, that is, the
+/// 16-byte aligned position where the SFCI/SFCO magic (or the domain header)
+/// starts.
 ///
 /// Doesn't do any validation that the message is valid.
-fn find_ty_cmdid(buf: &[u8]) -> Option<(u16, u32)> {
+fn find_raw_offset(buf: &[u8]) -> Option<usize> {
     if buf.len() < 8 {
         return None
     }
     let hdr = u64::from_le_bytes(buf[0..8].try_into().expect("cmd header is invalid"));
-    let ty = hdr.get_bits(0..16) as u16;
     let x_descs = hdr.get_bits(16..20) as usize;
     let a_descs = hdr.get_bits(20..24) as usize;
     let b_descs = hdr.get_bits(24..28) as usize;
@@ -1056,10 +1105,19 @@ fn find_ty_cmdid(buf: &[u8]) -> Option<(u16, u32)> {
         (0, 0, 0)
     };
     let raw = 8 + (hdr.get_bit(63) as usize) * 4 + pid * 8 + (copyhandles + movehandles) * 4 + (x_descs * 8 + (a_descs + b_descs + w_descs) * 12);
-    let raw = align_up(raw, 16) + 8;
+    Some(align_up(raw, 16))
+}
+
+/// Quickly find the type and cmdid of an IPC message for the server dispatcher.
+///
+/// Doesn't do any validation that the message is valid. Messages sent to a
+/// domain should have their domain header removed first.
+fn find_ty_cmdid(buf: &[u8]) -> Option<(u16, u32)> {
+    let raw = find_raw_offset(buf)? + 8;
     if buf.len() < raw + 4 {
         return None
     }
+    let ty = u16::from_le_bytes(buf[0..2].try_into().expect("cmd header is invalid"));
     let cmdid = u32::from_le_bytes(buf[raw..raw + 4].try_into().expect("command id is invalid"));
     Some((ty, cmdid))
 }
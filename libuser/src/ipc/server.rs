@@ -251,11 +251,15 @@
 use crate::syscalls;
 use crate::types::{ServerPort, ServerSession};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::ops::{Deref, DerefMut, Index};
-use crate::error::{KernelError, Error};
-use crate::ipc::Message;
+use crate::error::{KernelError, LibuserError, Error};
+use crate::ipc::{Message, MsgPackedHdr};
 use futures::future::{FutureObj, FutureExt};
 use core::future::Future;
+use core::convert::TryInto;
+use core::pin::Pin;
+use core::task::Poll;
 use crate::futures::WorkQueue;
 
 /// Wrapper struct that forces the alignment to 0x10. Somewhat necessary for the
@@ -405,6 +409,13 @@ pub mod hrtb_hack {
 /// handle, call the dispatch function with the given object, and the request'
 /// cmdid and buffer, and finally reply to the request.
 ///
+/// Control messages are answered directly by the session wrapper, without
+/// going through the dispatch function. See [handle_control()] for the list of
+/// supported control commands. In particular, CloneCurrentObject creates a new
+/// session backed by the same object: all the sessions sharing an object are
+/// served by the same future, one request at a time, and the object is dropped
+/// once all of them are closed.
+///
 /// It may be used to open subsessions.
 pub fn new_session_wrapper<T, DISPATCH>(work_queue: WorkQueue<'static>, handle: ServerSession, mut object: T, mut dispatch: DISPATCH) -> impl Future<Output = ()> + Send
 where
@@ -414,11 +425,26 @@ where
 {
     let mut buf = Align16([0; 0x100]);
     let mut pointer_buf = [0; 0x400];
+    // Every session backed by this object, along with whether it was
+    // converted to a domain.
+    let mut sessions = vec![(handle, false)];
 
     async move {
         loop {
-            debug!("Waiting for a new session on handle {:?}", handle);
-            let res = handle.wait_async(work_queue.clone()).await;
+            debug!("Waiting for a new request on handles {:?}", sessions);
+            let (idx, res) = {
+                let mut waiters = sessions.iter()
+                    .map(|(handle, _)| handle.wait_async(work_queue.clone()))
+                    .collect::<Vec<_>>();
+                futures::future::poll_fn(|cx| {
+                    for (idx, waiter) in waiters.iter_mut().enumerate() {
+                        if let Poll::Ready(res) = Pin::new(waiter).poll(cx) {
+                            return Poll::Ready((idx, res));
+                        }
+                    }
+                    Poll::Pending
+                }).await
+            };
 
             if let Err(err) = res {
                 // This instance of WaitAsync can return one of two errors:
@@ -434,29 +460,173 @@ where
             req.push_in_pointer(&mut pointer_buf, false);
             req.pack(&mut buf[..]);
 
+            let (handle, is_domain) = &mut sessions[idx];
+
             // Use a timeout of 0 to avoid blocking.
             match handle.receive(&mut buf[..], Some(0)) {
                 Err(Error::Kernel(KernelError::Timeout, _)) => continue,
                 res => res.unwrap(),
             }
 
-            let tycmdid = super::find_ty_cmdid(&buf[..]);
+            let mut tycmdid = super::find_ty_cmdid(&buf[..]);
             debug!("Got request for: {:?}", tycmdid);
 
+            let mut reply_to_domain = false;
+            if let (true, Some((4, _))) | (true, Some((6, _))) = (*is_domain, tycmdid) {
+                reply_to_domain = true;
+                match remove_domain_header(&mut buf[..]) {
+                    // SendMessage to the root object.
+                    Some((1, 1)) => tycmdid = super::find_ty_cmdid(&buf[..]),
+                    // CloseVirtualHandle on the root object. The root object
+                    // lives as long as the session, so there's nothing to do.
+                    Some((2, 1)) => {
+                        tycmdid = None;
+                        Message::<(), [_; 0], [_; 0], [_; 0]>::new_response(None).pack(&mut buf[..]);
+                    },
+                    header => {
+                        error!("Unsupported domain message: {:?}", header);
+                        tycmdid = None;
+                        reply_error(&mut buf[..], LibuserError::InvalidIpcRequest.into());
+                    }
+                }
+            }
+
             let close = match tycmdid {
-                // TODO: Handle other types.
                 Some((4, cmdid)) | Some((6, cmdid)) => dispatch.call((&mut object, work_queue.clone(), cmdid, &mut buf[..])).await
                     .map(|_| false)
                     .unwrap_or_else(|err| { error!("Dispatch method errored out: {:?}", err); true }),
+                Some((5, cmdid)) | Some((7, cmdid)) => match handle_control(cmdid, &mut buf[..], pointer_buf.len()) {
+                    Ok(ControlAction::Reply) => false,
+                    Ok(ControlAction::AddSession(server)) => {
+                        sessions.push((server, false));
+                        false
+                    },
+                    Ok(ControlAction::ConvertToDomain) => {
+                        sessions[idx].1 = true;
+                        false
+                    },
+                    Err(err) => {
+                        error!("Control method errored out: {:?}", err);
+                        reply_error(&mut buf[..], err);
+                        false
+                    }
+                },
                 Some((2, _)) => true,
+                // Domain messages for which a reply was already written.
+                None if reply_to_domain => false,
                 _ => true
             };
 
             if close {
-                break;
+                sessions.swap_remove(idx);
+                if sessions.is_empty() {
+                    break;
+                }
+                continue;
             }
 
-            handle.reply(&mut buf[..]).unwrap();
+            if reply_to_domain {
+                insert_domain_header(&mut buf[..]);
+            }
+
+            sessions[idx].0.reply(&mut buf[..]).unwrap();
         }
     }
 }
+
+/// Action the session wrapper needs to take after a control message was
+/// handled by [handle_control()].
+#[derive(Debug)]
+enum ControlAction {
+    /// Simply send the reply written to the buffer.
+    Reply,
+    /// A new session sharing the current object was created. It should be
+    /// served alongside the current one.
+    AddSession(ServerSession),
+    /// The current session was converted to a domain. Further requests on it
+    /// will contain a domain header.
+    ConvertToDomain,
+}
+
+/// Handles a control message, writing the reply to `buf`.
+///
+/// The following control commands are supported:
+///
+/// - 0: ConvertCurrentObjectToDomain. Returns the object ID of the current
+///   object (always 1) inside the newly created domain. Only the root object
+///   may be used inside the domain, input and output objects are not
+///   supported.
+/// - 2: CloneCurrentObject. Returns a new session backed by the same object.
+/// - 3: QueryPointerBufferSize. Returns the size of the pointer buffer used to
+///   receive X descriptors, as an u16.
+/// - 4: CloneCurrentObjectEx. Same as CloneCurrentObject. The tag argument is
+///   ignored.
+///
+/// CopyFromCurrentDomain (1), and any unknown command, are rejected with
+/// [LibuserError::InvalidIpcRequest].
+fn handle_control(cmdid: u32, buf: &mut [u8], pointer_buf_size: usize) -> Result<ControlAction, Error> {
+    match cmdid {
+        0 => {
+            let mut msg = Message::<u32, [_; 0], [_; 0], [_; 0]>::new_response(None);
+            msg.push_raw(1);
+            msg.pack(buf);
+            Ok(ControlAction::ConvertToDomain)
+        },
+        2 | 4 => {
+            let (server, client) = syscalls::create_session(false, 0)?;
+            let mut msg = Message::<(), [_; 0], [_; 0], [_; 1]>::new_response(None);
+            msg.push_handle_move(client.into_handle());
+            msg.pack(buf);
+            Ok(ControlAction::AddSession(server))
+        },
+        3 => {
+            let mut msg = Message::<u16, [_; 0], [_; 0], [_; 0]>::new_response(None);
+            msg.push_raw(pointer_buf_size as u16);
+            msg.pack(buf);
+            Ok(ControlAction::Reply)
+        },
+        _ => Err(LibuserError::InvalidIpcRequest.into())
+    }
+}
+
+/// Writes an empty reply carrying the given error to `buf`.
+fn reply_error(buf: &mut [u8], err: Error) {
+    let mut msg = Message::<(), [_; 0], [_; 0], [_; 0]>::new_response(None);
+    msg.set_error(err.as_code());
+    msg.pack(buf);
+}
+
+/// Removes the domain header from a request sent to a domain, turning it into
+/// a normal request that can be passed to the dispatcher. Returns the domain
+/// command and the object ID the request is targeting.
+///
+/// Returns None if the message is too small to contain a domain header.
+fn remove_domain_header(buf: &mut [u8]) -> Option<(u8, u32)> {
+    let raw = super::find_raw_offset(buf)?;
+    if buf.len() < raw + 0x10 {
+        return None
+    }
+    let command = buf[raw];
+    let object_id = u32::from_le_bytes(buf[raw + 4..raw + 8].try_into().unwrap());
+    buf.copy_within(raw + 0x10.., raw);
+
+    let mut hdr = MsgPackedHdr(u64::from_le_bytes(buf[0..8].try_into().unwrap()));
+    hdr.set_raw_section_size(hdr.raw_section_size().saturating_sub(4));
+    buf[0..8].copy_from_slice(&hdr.0.to_le_bytes());
+    Some((command, object_id))
+}
+
+/// Inserts an empty domain header in a reply, so it can be sent back to a
+/// domain. The header contains no output objects.
+fn insert_domain_header(buf: &mut [u8]) {
+    let raw = super::find_raw_offset(buf).expect("Reply should have a valid header");
+    let len = buf.len();
+    buf.copy_within(raw..len - 0x10, raw + 0x10);
+    for byte in &mut buf[raw..raw + 0x10] {
+        *byte = 0;
+    }
+
+    let mut hdr = MsgPackedHdr(u64::from_le_bytes(buf[0..8].try_into().unwrap()));
+    hdr.set_raw_section_size(hdr.raw_section_size() + 4);
+    buf[0..8].copy_from_slice(&hdr.0.to_le_bytes());
+}
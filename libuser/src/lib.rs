@@ -60,6 +60,8 @@ pub mod futures;
 //pub mod ldr {}
 //#[gen_ipc(path = "../../ipcdefs/example.id", prefix = "sunrise_libuser")]
 //pub mod example {}
+//#[gen_ipc(path = "../../ipcdefs/lm.id", prefix = "sunrise_libuser")]
+//pub mod lm {}
 include!(concat!(env!("OUT_DIR"), "/ipc_code.rs"));
 
 
@@ -72,7 +74,7 @@ pub mod zero_box;
 
 #[cfg(all(target_os = "sunrise", not(feature = "build-for-std-app")))]
 mod crt0;
-mod log_impl;
+pub mod log_impl;
 pub use sunrise_libutils::loop_future;
 
 pub use sunrise_libutils::io;
@@ -108,15 +110,15 @@ pub extern fn panic_fmt(p: &core::panic::PanicInfo<'_>) -> ! {
     syscalls::exit_process();
 }
 
-// TODO: Don't panic in the oom handler, exit instead.
-// BODY: Panicking may allocate, so calling panic in the OOM handler is a
-// BODY: terrible idea.
-/// OOM handler. Causes a panic.
+/// OOM handler. Exits the process with `ExitReason::OutOfMemory`.
+///
+/// Panicking may allocate, so we exit straight away instead.
 #[cfg(all(target_os = "sunrise", not(test), feature = "lang-items", not(rustdoc)))]
 #[lang = "oom"]
 #[no_mangle]
 pub fn rust_oom(_: core::alloc::Layout) -> ! {
-    panic!("OOM")
+    let _ = syscalls::output_debug_string("Out of memory, exiting", 10, "sunrise_libuser::rust_oom");
+    syscalls::exit_process_with_reason(syscalls::ExitReason::OutOfMemory)
 }
 
 /// calls logger initialization, main, and finally exits the
@@ -1,15 +1,138 @@
 //! Implementation for the log crate
 //!
-//! Redirects all logs to the kernel logger (output_debug_string syscall). No
-//! filtering is done, so everything will be sent.
+//! Sends all logs to the log manager (`lm`) once it is registered in `sm`, and
+//! to the kernel logger (output_debug_string syscall) until then, or if the log
+//! manager dies. No filtering is done, so everything will be sent.
+//!
+//! Looking the log manager up costs a session to `sm`. While it isn't
+//! registered, we only try again after an exponentially growing number of
+//! records.
 
+use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering};
 use log::{self, Log, LevelFilter, Metadata, Record};
-use crate::syscalls::output_debug_string;
+use spin::Mutex;
+use crate::syscalls::{self, output_debug_string};
+use crate::sm::IUserInterfaceProxy;
+use crate::lm::{ILogServiceProxy, ILoggerProxy};
+use crate::error::Error;
+use crate::threads;
+
+/// Maximum length of the target sent to the log manager, in bytes. The target
+/// and the message must fit in its pointer buffer together.
+const MAX_TARGET_LEN: usize = 0x80;
+
+/// Maximum length of the message sent to the log manager, in bytes.
+const MAX_MESSAGE_LEN: usize = 0x300;
+
+/// Maximum number of records sent to the kernel between two attempts to
+/// connect to the log manager.
+const MAX_CONNECT_BACKOFF: usize = 256;
+
+/// Our connection to the log manager.
+struct LogManagerConnection {
+    /// Our logger session to the log manager, once it is registered.
+    logger: Option<ILoggerProxy>,
+    /// Number of records to send to the kernel before trying to connect again.
+    retry_in: usize,
+    /// Value of `retry_in` after the next failed attempt to connect. Doubles
+    /// on every failure, up to [MAX_CONNECT_BACKOFF].
+    backoff: usize,
+}
+
+impl LogManagerConnection {
+    /// Gets the logger session, connecting to the log manager if it is time to
+    /// try again.
+    fn session(&mut self) -> Option<&ILoggerProxy> {
+        if self.logger.is_none() {
+            if self.retry_in > 0 {
+                self.retry_in -= 1;
+                return None;
+            }
+            match connect_to_log_manager() {
+                Ok(logger) => {
+                    self.logger = Some(logger);
+                    self.backoff = 1;
+                },
+                Err(_) => {
+                    self.retry_in = self.backoff;
+                    self.backoff = cmp::min(self.backoff * 2, MAX_CONNECT_BACKOFF);
+                }
+            }
+        }
+        self.logger.as_ref()
+    }
+}
 
 /// Log implementation structure.
 ///
 /// See module documentation for more information.
-struct Logger;
+struct Logger {
+    /// Our connection to the log manager.
+    log_manager: Mutex<LogManagerConnection>,
+}
+
+/// Whether we should try to send our logs to the log manager.
+///
+/// Set once the logger is initialized, unless [disable_log_manager] was called.
+static USE_LOG_MANAGER: AtomicBool = AtomicBool::new(false);
+
+/// The logger.
+static LOGGER: Logger = Logger {
+    log_manager: Mutex::new(LogManagerConnection { logger: None, retry_in: 0, backoff: 1 })
+};
+
+/// Opens a logger session to the log manager, if it is registered.
+///
+/// Neither sm nor lm are waited for: this fails if either isn't up yet.
+fn connect_to_log_manager() -> Result<ILoggerProxy, Error> {
+    // Not IUserInterfaceProxy::raw_new(), it loops until sm is up.
+    let sm = IUserInterfaceProxy::from(syscalls::connect_to_named_port("sm:\0")?);
+    let session = sm.get_service_if_registered(u64::from_le_bytes(*b"lm\0\0\0\0\0\0"))?;
+    ILogServiceProxy::from(session).open_logger()
+}
+
+/// Truncates `s` to at most `len` bytes, without cutting an UTF-8 character in half.
+fn truncate(s: &str, len: usize) -> &str {
+    if s.len() <= len {
+        return s;
+    }
+    let mut len = len;
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+impl Logger {
+    /// Sends a record to the log manager, connecting to it if needed.
+    ///
+    /// Returns false if the record could not be sent, and should go to the
+    /// kernel instead.
+    fn log_to_log_manager(&self, level: u8, target: &str, message: &str) -> bool {
+        // The IPC code may log too. If it does, send those logs to the kernel
+        // instead of deadlocking.
+        let mut log_manager = match self.log_manager.try_lock() {
+            Some(log_manager) => log_manager,
+            None => return false
+        };
+
+        let res = match log_manager.session() {
+            Some(logger) => {
+                // The thread context is unique among our live threads.
+                let thread = threads::get_my_thread_context() as *const _ as usize as u64;
+                logger.log(level, thread, truncate(target, MAX_TARGET_LEN).as_bytes(), truncate(message, MAX_MESSAGE_LEN).as_bytes())
+            },
+            None => return false
+        };
+
+        if res.is_err() {
+            // The log manager probably died. Try to reconnect on the next record.
+            log_manager.logger = None;
+        }
+        res.is_ok()
+    }
+}
 
 impl Log for Logger {
     fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
@@ -17,6 +140,13 @@ impl Log for Logger {
     }
 
     fn log(&self, record: &Record<'_>) {
+        let message = format!("{}", record.args());
+
+        if USE_LOG_MANAGER.load(Ordering::SeqCst)
+            && self.log_to_log_manager(record.level() as usize as u8, record.target(), &message) {
+            return;
+        }
+
         let level = match record.level() {
             log::Level::Error => 10,
             log::Level::Warn => 30,
@@ -24,7 +154,7 @@ impl Log for Logger {
             log::Level::Debug => 70,
             log::Level::Trace => 90,
         };
-        let _ = output_debug_string(&*format!("{}", record.args()), level, record.target());
+        let _ = output_debug_string(&*message, level, record.target());
     }
 
     fn flush(&self) {}
@@ -40,8 +170,22 @@ impl Log for Logger {
 /// This function will panic if it is called more than once, or if another
 /// library has already initialized a global logger.
 pub fn init() {
-    log::set_logger(&Logger)
+    log::set_logger(&LOGGER)
         .expect("log_impl::init to be called only once");
     log::set_max_level(LevelFilter::Trace);
     info!("Logging enabled");
+    // Only now, so that processes get a chance to call disable_log_manager
+    // before they first try to reach it.
+    USE_LOG_MANAGER.store(true, Ordering::SeqCst);
+}
+
+/// Makes this process send its logs to the kernel only, never to the log
+/// manager.
+///
+/// Must be called first thing in main by `sm`, by the log manager, and by the
+/// services the log manager depends on: waiting on a service that is itself
+/// waiting on us would deadlock.
+pub fn disable_log_manager() {
+    USE_LOG_MANAGER.store(false, Ordering::SeqCst);
+    LOGGER.log_manager.lock().logger = None;
 }
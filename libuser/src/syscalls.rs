@@ -3,7 +3,9 @@
 use core::slice;
 use crate::types::*;
 pub use sunrise_libkern::nr;
-pub use sunrise_libkern::{MemoryInfo, MemoryPermissions};
+pub use sunrise_libkern::{MemoryInfo, MemoryPermissions, ThreadActivity, ThreadContext, KernelLogRecord};
+pub use sunrise_libkern::{YIELD_WITHOUT_CORE_MIGRATION, YIELD_WITH_CORE_MIGRATION, YIELD_TO_ANY_THREAD};
+pub use sunrise_libkern::SYSTEM_TICK_FREQUENCY;
 pub use sunrise_libkern::process::*;
 use crate::error::KernelError;
 
@@ -143,10 +145,19 @@ pub fn query_memory(addr: usize) -> Result<(MemoryInfo, usize), KernelError> {
 
 /// Exits the process, killing all threads.
 pub fn exit_process() -> ! {
+    exit_process_with_reason(ExitReason::Exited)
+}
+
+/// Exits the process with the given reason, killing all threads.
+///
+/// The reason is a Sunrise extension, other processes can query it with
+/// [Process::exit_reason]. This function never allocates, so it can be used
+/// from the OOM handler.
+pub fn exit_process_with_reason(reason: ExitReason) -> ! {
     unsafe {
-        match syscall(nr::ExitProcess, 0, 0, 0, 0, 0, 0) {
+        match syscall(nr::ExitProcess, reason.0 as _, 0, 0, 0, 0, 0) {
             Ok(_) => (),
-            Err(err) => { let _ = output_debug_string(&format!("Failed to exit: {}", err), 10, "sunrise_libuser::syscalls::exit_process"); },
+            Err(_) => { let _ = output_debug_string("Failed to exit", 10, "sunrise_libuser::syscalls::exit_process"); },
         }
         #[allow(clippy::empty_loop)]
         loop {} // unreachable, but we can't panic, as panic! calls exit_process
@@ -183,6 +194,9 @@ pub fn exit_thread() -> ! {
 }
 
 /// Sleeps for a specified amount of time, or yield thread.
+///
+/// Passing [YIELD_WITHOUT_CORE_MIGRATION], [YIELD_WITH_CORE_MIGRATION] or
+/// [YIELD_TO_ANY_THREAD] yields instead of sleeping. See [crate::threads::yield_now].
 pub fn sleep_thread(nanos: usize) -> Result<(), KernelError> {
     unsafe {
         syscall(nr::SleepThread, nanos, 0, 0, 0, 0, 0)?;
@@ -289,7 +303,7 @@ pub(crate) fn close_handle(handle: u32) -> Result<(), KernelError> {
 ///
 /// If a timeout of 0 is passed, this function is guaranteed not to reschedule.
 ///
-/// Does not accept 0xFFFF8001 or 0xFFFF8000 meta-handles.
+/// Accepts the 0xFFFF8000 and 0xFFFF8001 meta-handles.
 ///
 /// # Object types
 ///
@@ -535,6 +549,39 @@ pub unsafe fn set_thread_area(address: usize) -> Result<(), KernelError> {
     }
 }
 
+/// Sets the name of the given thread. The kernel uses it in its logs, in the
+/// form `process:thread`.
+///
+/// # Errors
+///
+/// - `InvalidSize`
+///   - The name is longer than [MAX_THREAD_NAME_LEN] bytes.
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+///
+/// [MAX_THREAD_NAME_LEN]: sunrise_libkern::MAX_THREAD_NAME_LEN
+pub fn set_thread_name(thread_handle: &Thread, name: &str) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetThreadName, (thread_handle.0).0.get() as usize, name.as_ptr() as _, name.len(), 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Gets the name of the process with the given PID. Copies as much of the name
+/// as fits in `buf`, and returns the full length of the name, in bytes.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn get_process_name(pid: u64, buf: &mut [u8]) -> Result<usize, KernelError> {
+    unsafe {
+        let (len, ..) = syscall(nr::GetProcessName, pid as usize, buf.as_mut_ptr() as _, buf.len(), 0, 0, 0)?;
+        Ok(len)
+    }
+}
+
 /// Change permission of a page-aligned memory region. Acceptable permissions
 /// are ---, r-- and rw-. In other words, it is not allowed to set the
 /// executable bit, nor is it acceptable to use write-only permissions.
@@ -632,6 +679,38 @@ pub unsafe fn unmap_process_memory(dstaddr: usize, proc_handle: &Process, srcadd
     Ok(())
 }
 
+/// Shares the given src memory range of the current process with a remote
+/// process, copy-on-write. This is used by the Loader to share the read-only
+/// segments of a binary between all the processes running it.
+///
+/// The src region should have been mapped with [map_process_memory()], and
+/// must not be written to anymore. The dst region should have the MAP_PROCESS
+/// state, and keeps its memory type and permissions.
+///
+/// # Errors
+///
+/// - `InvalidAddress`
+///    - src_addr or dst_addr is not aligned to 0x1000.
+/// - `InvalidSize`
+///    - size is 0
+///    - size is not aligned to 0x1000.
+///    - The src region spans several mappings.
+/// - `InvalidMemState`
+///    - `src_addr + size` overflows
+///    - `dst_addr + size` overflows
+///    - The src or dst region is outside of the UserLand address space.
+///    - The src memory pages are not of the ProcessMemory type.
+///    - The dst memory pages do not have the MAP_PROCESS state.
+/// - `InvalidHandle`
+///    - The handle passed as an argument does not exist, is not a Process
+///      handle, or refers to the current process.
+pub fn share_process_memory(proc_handle: &Process, dstaddr: usize, srcaddr: usize, size: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::ShareProcessMemory, (proc_handle.0).0.get() as _, dstaddr, srcaddr, size, 0, 0)?;
+        Ok(())
+    }
+}
+
 /// Creates a new process with the given parameters.
 ///
 /// Note that you probably don't want to use this! Look instead for
@@ -677,6 +756,8 @@ pub fn start_process(process_handle: &Process, main_thread_prio: u32, default_cp
 /// -----------------|--------------------------
 /// ProcessState = 0 | The state the current process is in. Returns an instance
 ///                  | of [sunrise_libkern::process::ProcessState].
+/// ExitReason       | Sunrise extension. The reason the process exited with.
+/// = 0x10000000     | Returns an instance of [sunrise_libkern::process::ExitReason].
 ///
 /// # Errors
 ///
@@ -691,6 +772,150 @@ pub fn get_process_info(process_handle: &Process, ty: ProcessInfoType) -> Result
     }
 }
 
+/// Gets information about the system, a process or a thread.
+///
+/// Info Type          | Handle | Sub-type              | Description
+/// -------------------|--------|-----------------------|--------------------------
+/// IdleTickCount = 20 | None   | Core number, or -1    | Time the CPU core spent idle, in system ticks.
+///                    |        | for the current core. |
+/// ThreadTickCount    | Thread | Core number, or -1    | CPU time used by the thread, in system ticks.
+/// = 25               |        | for all cores.        |
+/// ProcessUserTime    | Process| 0                     | CPU time the process spent in userspace, in
+/// = 0x10000000       |        |                       | nanoseconds.
+/// ProcessKernelTime  | Process| 0                     | CPU time the process spent in the kernel, in
+/// = 0x10000001       |        |                       | nanoseconds.
+/// PhysicalFreeBlocks | None   | Order                 | Number of free blocks of 2^order physical frames.
+/// = 0x10000002       |        |                       |
+/// PhysicalUsedBlocks | None   | Order                 | Number of allocated blocks of 2^order physical
+/// = 0x10000003       |        |                       | frames.
+/// PhysicalReserved-  | None   | Order                 | Number of reserved blocks of 2^order physical
+/// Blocks = 0x10000004|        |                       | frames.
+/// BusyTickCount      | None   | Core number, or -1    | Time the CPU core spent running threads, in
+/// = 0x10000005       |        | for the current core. | system ticks.
+///
+/// System ticks run at [SYSTEM_TICK_FREQUENCY].
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The passed handle is not valid for this info_type.
+/// - `InvalidCombination`
+///   - The passed sub-type is not valid for this info_type.
+/// - `InvalidEnum`
+///   - The passed info_type is unknown.
+pub fn get_info(ty: InfoType, handle: Option<&Handle>, sub_type: u64) -> Result<u64, KernelError> {
+    let handle = handle.map(|handle| handle.0.get()).unwrap_or(0);
+    unsafe {
+        let (lo, hi, ..) = syscall(nr::GetInfo, ty.0 as usize, handle as usize, sub_type as usize, (sub_type >> 32) as usize, 0, 0)?;
+        Ok((lo as u64) | ((hi as u64) << 32))
+    }
+}
+
+/// Gets the CPU time the process with the given PID spent running userspace
+/// code, and in the kernel, in nanoseconds.
+///
+/// # Errors
+///
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn get_process_cpu_time(pid: u64) -> Result<(u64, u64), KernelError> {
+    unsafe {
+        let (user_lo, user_hi, kernel_lo, kernel_hi) = syscall(nr::GetProcessCpuTime, pid as usize, 0, 0, 0, 0, 0)?;
+        Ok(((user_lo as u64) | ((user_hi as u64) << 32), (kernel_lo as u64) | ((kernel_hi as u64) << 32)))
+    }
+}
+
+/// Gets the PIDs of the processes currently alive, in ascending order. Fills
+/// `pids` with as many of them as it can hold, and returns the number of PIDs
+/// written.
+pub fn get_process_list(pids: &mut [u64]) -> Result<usize, KernelError> {
+    unsafe {
+        let (count, ..) = syscall(nr::GetProcessList, pids.as_mut_ptr() as _, pids.len(), 0, 0, 0, 0)?;
+        Ok(count)
+    }
+}
+
+/// Prints the state of the process with the given PID to the kernel log, as
+/// selected by `dump_type`: the mappings of its address space, its handles,
+/// and its threads with their saved registers.
+///
+/// Like on HOS, takes the dump type, then its argument. Sunrise extension: the
+/// argument is always the PID of the process to dump. It is ignored by
+/// [DumpInfoType::KernelMemory], which prints the state of the kernel heap and
+/// of the physical memory.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - `dump_type` is not a valid [DumpInfoType].
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn dump_info(dump_type: DumpInfoType, pid: u64) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::DumpInfo, dump_type.0 as usize, pid as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Prints part of the state of the process with the given PID to the kernel
+/// log, as selected by `dump_type`.
+///
+/// # Errors
+///
+/// - `InvalidEnum`
+///   - `dump_type` is not a valid [DumpInfoType].
+/// - `NoSuchEntry`
+///   - No process with the given PID is alive.
+pub fn dump_info_new(dump_type: DumpInfoType, pid: u64) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::DumpInfoNew, dump_type.0 as usize, pid as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Reads the kernel log ring buffer, starting at the record with sequence
+/// number `first_sequence`. Fills `buf` with as many consecutive records as it
+/// can hold, and returns the number of records written.
+///
+/// If `first_sequence` was already overwritten, reading starts at the oldest
+/// record the kernel still has. Returns 0 once every record was read.
+pub fn read_kernel_log(buf: &mut [KernelLogRecord], first_sequence: u64) -> Result<usize, KernelError> {
+    unsafe {
+        let (count, ..) = syscall(nr::ReadKernelLog, buf.as_mut_ptr() as _, buf.len(), first_sequence as usize, (first_sequence >> 32) as usize, 0, 0)?;
+        Ok(count)
+    }
+}
+
+/// Replaces the kernel log filter with the given env_logger-style directives,
+/// e.g. `info,sunrise_kernel::ipc=trace`. Invalid directives are ignored.
+pub fn set_kernel_log_filter(directives: &str) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetKernelLogFilter, directives.as_ptr() as _, directives.len(), 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Gets the directives of the kernel log filter. Copies as much of them as
+/// fits in `buf`, and returns their full length, in bytes.
+pub fn get_kernel_log_filter(buf: &mut [u8]) -> Result<usize, KernelError> {
+    unsafe {
+        let (len, ..) = syscall(nr::GetKernelLogFilter, buf.as_mut_ptr() as _, buf.len(), 0, 0, 0, 0)?;
+        Ok(len)
+    }
+}
+
+/// Creates an event that gets signaled once all the ServerPort handles
+/// associated with `port` are closed, e.g. because the process hosting it died.
+///
+/// Unlike waiting on the ClientPort, the event is not signaled when a session
+/// becomes available on the port.
+pub fn create_port_death_event(port: &ClientPort) -> Result<ReadableEvent, KernelError> {
+    unsafe {
+        let (out_handle, ..) = syscall(nr::CreatePortDeathEvent, (port.0).0.get() as _, 0, 0, 0, 0, 0)?;
+        Ok(ReadableEvent(Handle::new(out_handle as _)))
+    }
+}
+
 /// Clear the "signaled" state of a readable event or process. After calling
 /// this on a signaled event, [wait_synchronization()] on this handle will wait
 /// until the handle is signaled again.
@@ -713,8 +938,9 @@ pub(crate) fn reset_signal(event: HandleRef) -> Result<(), KernelError> {
 }
 
 /// Gets the PID of the given Process handle. Alias handles (0xFFFF8000 and
-/// 0xFFFF8001) are not allowed here. PIDs are global, unique identifiers for a
-/// given process. PIDs are never reused, and can be passed over IPC safely (the
+/// 0xFFFF8001) are allowed here, so [Process::current()] can be used to get the
+/// PID of the current process. PIDs are global, unique identifiers for a given
+/// process. PIDs are never reused, and can be passed over IPC safely (the
 /// kernel ensures the correct pid is passed when a process does a request),
 /// making them the best way for sysmodule to identify a calling process.
 ///
@@ -724,7 +950,76 @@ pub(crate) fn reset_signal(event: HandleRef) -> Result<(), KernelError> {
 ///   - The given handle is invalid or not a process.
 pub fn get_process_id(process_handle: &Process) -> Result<u64, KernelError> {
     unsafe {
-        let (pid, ..) = syscall(nr::GetProcessInfo, (process_handle.0).0.get() as usize, 0, 0, 0, 0, 0)?;
+        let (pid, ..) = syscall(nr::GetProcessId, (process_handle.0).0.get() as usize, 0, 0, 0, 0, 0)?;
         Ok(pid as _)
     }
-}
\ No newline at end of file
+}
+
+/// Gets the ID of the given Thread handle. The 0xFFFF8000 alias handle is
+/// allowed here, so [Thread::current()] can be used to get the ID of the
+/// current thread. Thread IDs are global, unique identifiers for a given
+/// thread, and are never reused.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+pub fn get_thread_id(thread_handle: &Thread) -> Result<u64, KernelError> {
+    unsafe {
+        let (tid, ..) = syscall(nr::GetThreadId, (thread_handle.0).0.get() as usize, 0, 0, 0, 0, 0)?;
+        Ok(tid as _)
+    }
+}
+/// Pauses or resumes a thread. A paused thread is not scheduled until it is
+/// resumed, even if the event it was waiting on gets signaled.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+/// - `InvalidState`
+///   - The given thread is the current thread.
+///   - The thread is already in the requested activity.
+pub fn set_thread_activity(thread_handle: &Thread, activity: ThreadActivity) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetThreadActivity, (thread_handle.0).0.get() as usize, activity as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Gets the userspace registers of a paused thread, as they were the last time
+/// it entered the kernel.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a thread.
+///   - The given thread is dead.
+/// - `InvalidState`
+///   - The thread is not paused, either by itself or through its process.
+pub fn get_thread_context3(thread_handle: &Thread) -> Result<ThreadContext, KernelError> {
+    let mut context = ThreadContext::default();
+    unsafe {
+        syscall(nr::GetThreadContext3, &mut context as *mut _ as usize, (thread_handle.0).0.get() as usize, 0, 0, 0, 0)?;
+    }
+    Ok(context)
+}
+
+/// Pauses or resumes all the threads of a process. Threads that were paused
+/// individually stay paused when their process is resumed.
+///
+/// # Errors
+///
+/// - `InvalidHandle`
+///   - The given handle is invalid or not a process.
+/// - `InvalidState`
+///   - The given process is the current process.
+///   - The process is already in the requested activity.
+pub fn set_process_activity(process_handle: &Process, activity: ThreadActivity) -> Result<(), KernelError> {
+    unsafe {
+        syscall(nr::SetProcessActivity, (process_handle.0).0.get() as usize, activity as usize, 0, 0, 0, 0)?;
+        Ok(())
+    }
+}
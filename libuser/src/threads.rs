@@ -61,7 +61,7 @@ use crate::syscalls;
 use crate::error::Error;
 use crate::error::KernelError;
 use crate::thread_local_storage::TlsElf;
-use sunrise_libkern::{TLS, IpcBuffer};
+use sunrise_libkern::{TLS, IpcBuffer, MAX_THREAD_NAME_LEN, YIELD_WITHOUT_CORE_MIGRATION};
 use alloc::boxed::Box;
 use alloc::alloc::{alloc, dealloc, Layout};
 use core::mem::ManuallyDrop;
@@ -238,6 +238,15 @@ pub fn get_my_ipc_buffer() -> *mut IpcBuffer {
     }
 }
 
+/// Gives up the rest of the current thread's time slice, letting other threads run.
+///
+/// Returns immediately if there is no other thread waiting to run. Useful to back
+/// off in a spin loop.
+pub fn yield_now() {
+    // Yielding cannot fail.
+    let _ = syscalls::sleep_thread(YIELD_WITHOUT_CORE_MIGRATION);
+}
+
 /// Libuser's representation of a thread.
 ///
 /// This is the low-level representation of a thread, kind to `pthread_t` on Unix.
@@ -266,9 +275,17 @@ impl Thread {
 
     /// Allocates resources for a thread. To start it, call [`start`].
     ///
-    /// Allocates the stack, sets up the context and TLS, and calls `svcCreateThread`.
+    /// Allocates the stack, sets up the context and TLS, calls `svcCreateThread`,
+    /// and names the thread with `svcSetThreadName`. The name is used to tell
+    /// threads apart in the logs.
+    ///
+    /// # Errors
+    ///
+    /// - `InvalidSize`
+    ///   - The name is longer than [MAX_THREAD_NAME_LEN] bytes.
     ///
     /// [`start`]: Thread::start
+    /// [MAX_THREAD_NAME_LEN]: sunrise_libkern::MAX_THREAD_NAME_LEN
     // todo: Libuser Thread stack guard
     // body: Currently the stack of every non-main thread is allocated in the heap, and no page
     // body: guard protects from stack-overflowing and rewriting all the heap.
@@ -277,7 +294,10 @@ impl Thread {
     // body:
     // body: The simpler way to fix this would be to continue allocating the stack on the heap,
     // body: but remap the last page with no permissions with the yet unimplemented svcMapMemory syscall.
-    pub fn create(entry: fn (usize) -> (), arg: usize, stack_size: usize) -> Result<Self, Error> {
+    pub fn create(entry: fn (usize) -> (), arg: usize, stack_size: usize, name: &str) -> Result<Self, Error> {
+        if name.len() > MAX_THREAD_NAME_LEN {
+            return Err(KernelError::InvalidSize.into());
+        }
 
         let tls_elf = Once::new();
         tls_elf.call_once(TlsElf::allocate);
@@ -306,6 +326,12 @@ impl Thread {
                 Err(err.into())
             }
             Ok(thread_handle) => {
+                // The name was checked to be valid, so this can only fail if we
+                // were given a weird handle.
+                if let Err(err) = syscalls::set_thread_name(&thread_handle, name) {
+                    warn!("Failed to name thread {:?}: {}", &*context, err);
+                }
+
                 // finally, push the handle to the context.
                 context.thread_handle.call_once(|| { thread_handle });
                 debug!("Allocated new thread: {:?}", context);
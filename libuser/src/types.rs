@@ -6,8 +6,8 @@
 use core::marker::PhantomData;
 use crate::syscalls;
 use core::num::NonZeroU32;
-use sunrise_libkern::MemoryPermissions;
-use sunrise_libkern::process::{ProcessState, ProcessInfoType};
+use sunrise_libkern::{MemoryPermissions, ThreadActivity, ThreadContext, CURRENT_THREAD_PSEUDO_HANDLE, CURRENT_PROCESS_PSEUDO_HANDLE};
+use sunrise_libkern::process::{ProcessState, ProcessInfoType, ExitReason};
 use crate::error::{Error, KernelError};
 use crate::ipc::{Message, MessageTy};
 use crate::futures::WorkQueue;
@@ -59,7 +59,7 @@ impl Handle {
 impl Drop for Handle {
     fn drop(&mut self) {
         match self.0.get() {
-            0xFFFF8000 | 0xFFFF8001 => (),
+            CURRENT_THREAD_PSEUDO_HANDLE | CURRENT_PROCESS_PSEUDO_HANDLE => (),
             handle => { let _ = syscalls::close_handle(handle); },
         }
     }
@@ -264,6 +264,38 @@ impl ClientSession {
             .map_err(|v| v.into())
     }
 
+    /// Creates a new session to the same object this session is connected to,
+    /// by sending a CloneCurrentObject control message.
+    ///
+    /// The returned session can be used concurrently with this one, e.g. to
+    /// share a connection to a service between multiple threads. Requests sent
+    /// on either session are handled by the same object on the server side.
+    pub fn try_clone(&self) -> Result<ClientSession, Error> {
+        let mut buf = [0; 0x100];
+        let mut msg = Message::<(), [_; 0], [_; 0], [_; 0]>::new_request(None, 2);
+        msg.set_ty(MessageTy::Control);
+        msg.pack(&mut buf[..]);
+        self.send_sync_request_with_user_buffer(&mut buf[..])?;
+        let mut res: Message<'_, (), [_; 0], [_; 0], [_; 1]> = Message::unpack(&buf[..]);
+        res.error()?;
+        Ok(ClientSession(res.pop_handle_move()?))
+    }
+
+    /// Queries the size of the pointer buffer the server uses to receive X
+    /// descriptors, by sending a QueryPointerBufferSize control message.
+    ///
+    /// X descriptors bigger than this size will be rejected by the kernel.
+    pub fn query_pointer_buffer_size(&self) -> Result<u16, Error> {
+        let mut buf = [0; 0x100];
+        let mut msg = Message::<(), [_; 0], [_; 0], [_; 0]>::new_request(None, 3);
+        msg.set_ty(MessageTy::Control);
+        msg.pack(&mut buf[..]);
+        self.send_sync_request_with_user_buffer(&mut buf[..])?;
+        let res: Message<'_, u16, [_; 0], [_; 0], [_; 0]> = Message::unpack(&buf[..]);
+        res.error()?;
+        Ok(res.raw())
+    }
+
     /// Consumes the session, returning the underlying handle. Note that closing
     /// a Handle without sending a close IPC message will leak the object in the
     /// sysmodule. You should always reconstruct the ClientSession from the
@@ -273,6 +305,21 @@ impl ClientSession {
         mem::forget(self);
         handle
     }
+
+    /// Waits for the server side of the session to be closed, e.g. because the
+    /// service died.
+    ///
+    /// Once this function returns, every request sent on this session will fail
+    /// with [KernelError::PortRemoteDead].
+    ///
+    /// # Panics
+    ///
+    /// Panics if used from outside the context of a Future spawned on a libuser
+    /// future executor. Please make sure you only call this function from a
+    /// future spawned on a WaitableManager.
+    pub fn wait_async<'a>(&self, queue: crate::futures::WorkQueue<'a>) -> impl core::future::Future<Output = Result<(), Error>> + Unpin + 'a {
+        self.0.as_ref().wait_async(queue)
+    }
 }
 
 impl Drop for ClientSession {
@@ -364,6 +411,31 @@ impl ClientPort {
         syscalls::connect_to_port(self)
             .map_err(|v| v.into())
     }
+
+    /// Waits for a session to become available on a port that reached its
+    /// maximum number of sessions, or for the server side of the port to be
+    /// closed.
+    ///
+    /// Ports created without a session limit are only signaled once the server
+    /// side of the port is closed. Once this function returns, [ClientPort::connect()]
+    /// will either fail with [KernelError::PortRemoteDead], or try to connect
+    /// without being limited by the session count.
+    ///
+    /// # Panics
+    ///
+    /// Panics if used from outside the context of a Future spawned on a libuser
+    /// future executor. Please make sure you only call this function from a
+    /// future spawned on a WaitableManager.
+    pub fn wait_async<'a>(&self, queue: crate::futures::WorkQueue<'a>) -> impl core::future::Future<Output = Result<(), Error>> + Unpin + 'a {
+        self.0.as_ref().wait_async(queue)
+    }
+
+    /// Creates an event that gets signaled once the server side of the port is
+    /// closed, whatever its session limit.
+    pub fn death_event(&self) -> Result<ReadableEvent, Error> {
+        syscalls::create_port_death_event(self)
+            .map_err(|v| v.into())
+    }
 }
 
 /// The server side of an IPC Port. Allows listening for connections, providing
@@ -422,10 +494,35 @@ impl ServerPort {
 pub struct Thread(pub Handle);
 
 impl Thread {
-    /// Gets the current process handle. Uses the 0xFFFF8000 meta-handle, which
-    /// may not be valid in all contexts!
-    fn current() -> Thread {
-        Thread(Handle::new(0xFFFF8000))
+    /// Gets the current thread handle. Uses the 0xFFFF8000 meta-handle, which
+    /// the kernel resolves to the calling thread. Dropping it does not close
+    /// anything.
+    pub fn current() -> Thread {
+        Thread(Handle::new(CURRENT_THREAD_PSEUDO_HANDLE))
+    }
+
+    /// Gets the ID of this thread.
+    pub fn id(&self) -> Result<u64, Error> {
+        syscalls::get_thread_id(self)
+            .map_err(|v| v.into())
+    }
+
+    /// Sets the name of this thread, as displayed in the kernel logs.
+    pub fn set_name(&self, name: &str) -> Result<(), Error> {
+        syscalls::set_thread_name(self, name)
+            .map_err(|v| v.into())
+    }
+
+    /// Pauses or resumes this thread. Cannot be used on the current thread.
+    pub fn set_activity(&self, activity: ThreadActivity) -> Result<(), Error> {
+        syscalls::set_thread_activity(self, activity)
+            .map_err(|v| v.into())
+    }
+
+    /// Gets the registers of this thread. The thread must be paused.
+    pub fn context(&self) -> Result<ThreadContext, Error> {
+        syscalls::get_thread_context3(self)
+            .map_err(|v| v.into())
     }
 }
 
@@ -437,9 +534,10 @@ pub struct Process(pub Handle);
 
 impl Process {
     /// Gets the current process handle. Uses the 0xFFFF8001 meta-handle, which
-    /// may not be valid in all contexts!
-    fn current() -> Process {
-        Process(Handle::new(0xFFFF8001))
+    /// the kernel resolves to the calling process. Dropping it does not close
+    /// anything.
+    pub fn current() -> Process {
+        Process(Handle::new(CURRENT_PROCESS_PSEUDO_HANDLE))
     }
 
     /// Start the given process on the provided CPU with the provided scheduler
@@ -478,6 +576,13 @@ impl Process {
         Ok(ProcessState(info as u8))
     }
 
+    /// Gets the reason the process exited with. Returns `ExitReason::Exited`
+    /// if the process is still alive or was killed.
+    pub fn exit_reason(&self) -> Result<ExitReason, Error> {
+        let info = syscalls::get_process_info(self, ProcessInfoType::ExitReason)?;
+        Ok(ExitReason(info))
+    }
+
     /// Waits for the process to change state. Use [Process::state] to get the
     /// new state and [Process::reset_signal] to reset the signaled state.
     ///
@@ -519,6 +624,13 @@ impl Process {
         let pid = syscalls::get_process_id(self)?;
         Ok(Pid(pid))
     }
+
+    /// Pauses or resumes all the threads of this process. Cannot be used on
+    /// the current process.
+    pub fn set_activity(&self, activity: ThreadActivity) -> Result<(), Error> {
+        syscalls::set_process_activity(self, activity)
+            .map_err(|v| v.into())
+    }
 }
 
 /// A handle to memory that may be mapped in multiple processes at the same time.
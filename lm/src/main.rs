@@ -0,0 +1,337 @@
+//! Log Manager
+//!
+//! Collects the logs of every process through the `lm` service, instead of
+//! having them all interleaved on the kernel's serial port.
+//!
+//! Each process gets a bounded ring buffer of its last records. A background
+//! thread appends the new records of every process to
+//! `/var/log/<process name>.log`, as long as a filesystem is available.
+//!
+//! With the `serial-echo` feature, the records are also echoed to the kernel
+//! log, tagged with the name of the process they come from. This duplicates
+//! every record on the serial port, so it is off by default.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate lazy_static;
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt::Write;
+use spin::Mutex;
+use sunrise_libuser::error::Error;
+use sunrise_libuser::fs::{FileSystemPath, IFileSystemProxy, IFileSystemServiceProxy};
+use sunrise_libuser::futures::{WaitableManager, WorkQueue};
+use sunrise_libuser::futures_rs::future::FutureObj;
+use sunrise_libuser::ipc::server::{new_session_wrapper, port_handler};
+use sunrise_libuser::lm::{ILoggerProxy, ILogService as _, ILogger as _};
+use sunrise_libuser::syscalls;
+use sunrise_libuser::threads::{self, Thread};
+use sunrise_libuser::types::Pid;
+
+/// Number of records kept for each process. Older records are dropped, even
+/// if they were not written to the log file yet.
+const MAX_RECORDS_PER_PROCESS: usize = 64;
+
+/// Number of processes we keep the records of. When a new process opens a
+/// logger, the records of the oldest one are dropped.
+const MAX_PROCESSES: usize = 32;
+
+/// Time between two writes to the log files, in nanoseconds.
+const FLUSH_PERIOD_NS: usize = 1_000_000_000;
+
+/// Directory holding the log files.
+const LOG_DIRECTORY: &str = "/var/log";
+
+/// A record, as received from a process.
+#[derive(Debug)]
+struct LogRecord {
+    /// Sequence number of this record among the records of its process.
+    sequence: u64,
+    /// Level of this record: 1 is Error, 2 Warn, 3 Info, 4 Debug and 5 Trace.
+    level: u8,
+    /// Identifier of the thread that logged this record, chosen by the process.
+    thread: u64,
+    /// Module path of the code that logged this record.
+    target: String,
+    /// The formatted message.
+    message: String,
+}
+
+/// The records of a process.
+#[derive(Debug)]
+struct ProcessLog {
+    /// The name of the process. Used as the name of its log file.
+    name: String,
+    /// The last records of the process.
+    records: VecDeque<LogRecord>,
+    /// Sequence number of the next record.
+    next_sequence: u64,
+    /// Sequence number of the first record that was not written to the log
+    /// file yet.
+    flushed_sequence: u64,
+}
+
+impl ProcessLog {
+    /// Creates an empty log for the process with the given name.
+    fn new(name: &str) -> ProcessLog {
+        ProcessLog {
+            name: String::from(name),
+            records: VecDeque::with_capacity(MAX_RECORDS_PER_PROCESS),
+            next_sequence: 0,
+            flushed_sequence: 0,
+        }
+    }
+
+    /// Appends a record, dropping the oldest one if the log is full.
+    fn push(&mut self, level: u8, thread: u64, target: String, message: String) {
+        if self.records.len() >= MAX_RECORDS_PER_PROCESS {
+            self.records.pop_front();
+        }
+        self.records.push_back(LogRecord {
+            sequence: self.next_sequence,
+            level, thread, target, message
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Formats the records that were not written to the log file yet, and
+    /// marks them as written.
+    fn take_unflushed(&mut self) -> String {
+        let mut data = String::new();
+        let oldest = self.records.front().map(|record| record.sequence).unwrap_or(self.next_sequence);
+        if self.flushed_sequence < oldest {
+            let _ = writeln!(data, "-- {} records lost --", oldest - self.flushed_sequence);
+        }
+        for record in self.records.iter().filter(|record| record.sequence >= self.flushed_sequence) {
+            let _ = writeln!(data, "[{}] - {:#x} - {} - {}", level_name(record.level),
+                             record.thread, record.target, record.message);
+        }
+        self.flushed_sequence = self.next_sequence;
+        data
+    }
+}
+
+lazy_static! {
+    /// The records of every process, by pid.
+    static ref LOGS: Mutex<BTreeMap<u64, ProcessLog>> = Mutex::new(BTreeMap::new());
+}
+
+/// Gets the name of a level, as used in the log files.
+fn level_name(level: u8) -> &'static str {
+    match level {
+        1 => "ERROR",
+        2 => "WARN",
+        3 => "INFO",
+        4 => "DEBUG",
+        5 => "TRACE",
+        _ => "?"
+    }
+}
+
+/// Gets the name of the process with the given pid, or its pid if it has none.
+fn process_name(pid: u64) -> String {
+    let mut name = [0; 12];
+    match syscalls::get_process_name(pid, &mut name) {
+        Ok(len) => String::from_utf8_lossy(&name[..cmp::min(len, name.len())])
+            .trim_end_matches('\0')
+            .into(),
+        Err(_) => format!("{}", pid)
+    }
+}
+
+/// Runs `f` on the log of the given process, creating it if needed.
+fn with_process_log<F: FnOnce(&mut ProcessLog)>(pid: u64, name: &str, f: F) {
+    let mut logs = LOGS.lock();
+    if !logs.contains_key(&pid) && logs.len() >= MAX_PROCESSES {
+        // Pids are never reused, the smallest is the oldest process.
+        let oldest = *logs.keys().next().unwrap();
+        logs.remove(&oldest);
+    }
+    f(logs.entry(pid).or_insert_with(|| ProcessLog::new(name)))
+}
+
+/// Entry point interface.
+#[derive(Default, Debug)]
+struct LogService;
+
+impl sunrise_libuser::lm::ILogService for LogService {
+    fn open_logger(&mut self, manager: WorkQueue<'static>, pid: Pid) -> Result<ILoggerProxy, Error> {
+        let Pid(pid) = pid;
+        let logger = Logger { pid, name: process_name(pid) };
+        with_process_log(pid, &logger.name, |_| ());
+
+        let (server, client) = syscalls::create_session(false, 0)?;
+        let wrapper = new_session_wrapper(manager.clone(), server, logger, Logger::dispatch);
+        manager.spawn(FutureObj::new(Box::new(wrapper)));
+        Ok(ILoggerProxy::from(client))
+    }
+}
+
+/// A logger, bound to the process that opened it.
+#[derive(Debug)]
+struct Logger {
+    /// The pid of the process.
+    pid: u64,
+    /// The name of the process.
+    name: String,
+}
+
+impl sunrise_libuser::lm::ILogger for Logger {
+    fn log(&mut self, _manager: WorkQueue<'static>, level: u8, thread: u64, target: &[u8], message: &[u8]) -> Result<(), Error> {
+        let target = String::from_utf8_lossy(target).into_owned();
+        let message = String::from_utf8_lossy(message).into_owned();
+
+        if cfg!(feature = "serial-echo") {
+            let svc_level = match level {
+                1 => 10,
+                2 => 30,
+                3 => 50,
+                4 => 70,
+                _ => 90,
+            };
+            let _ = syscalls::output_debug_string(&format!("{}: {}", self.name, message), svc_level, &target);
+        }
+
+        with_process_log(self.pid, &self.name, |log| log.push(level, thread, target, message));
+        Ok(())
+    }
+}
+
+/// Converts a path to the format expected by the filesystem service.
+fn ipc_path(path: &str) -> FileSystemPath {
+    let mut ipc_path = [0x0; 0x300];
+    let len = cmp::min(path.len(), ipc_path.len() - 1);
+    ipc_path[..len].copy_from_slice(&path.as_bytes()[..len]);
+    ipc_path
+}
+
+/// Opens the system partition, and creates the log directory if needed.
+fn open_log_filesystem() -> Result<IFileSystemProxy, Error> {
+    let filesystem = IFileSystemServiceProxy::raw_new()?.open_disk_partition(0, 0)?;
+    // Those fail if the directories already exist.
+    let _ = filesystem.create_directory(&ipc_path("/var"));
+    let _ = filesystem.create_directory(&ipc_path(LOG_DIRECTORY));
+    Ok(filesystem)
+}
+
+/// Appends `data` to the log file of the process with the given name.
+fn append_to_log_file(filesystem: &IFileSystemProxy, name: &str, data: &str) -> Result<(), Error> {
+    let path = ipc_path(&format!("{}/{}.log", LOG_DIRECTORY, name));
+    // This fails if the file already exists.
+    let _ = filesystem.create_file(0, 0, &path);
+    let file = filesystem.open_file(0b111, &path)?;
+    let size = file.get_size()?;
+    file.write(0, size, data.len() as u64, data.as_bytes())
+}
+
+/// Appends the new records of every process to its log file, forever.
+///
+/// Runs in its own thread, so that the processes logging never wait on the
+/// filesystem.
+fn flush_thread(_: usize) {
+    let filesystem = match open_log_filesystem() {
+        Ok(filesystem) => filesystem,
+        Err(err) => {
+            warn!("Cannot open the log directory, logs will only be kept in memory: {:?}", err);
+            return;
+        }
+    };
+
+    loop {
+        let _ = syscalls::sleep_thread(FLUSH_PERIOD_NS);
+
+        // Don't hold the lock while talking to the filesystem.
+        let pending: Vec<(String, String)> = LOGS.lock().values_mut()
+            .map(|log| (log.name.clone(), log.take_unflushed()))
+            .filter(|(_, data)| !data.is_empty())
+            .collect();
+
+        for (name, data) in pending {
+            if let Err(err) = append_to_log_file(&filesystem, &name, &data) {
+                error!("Failed to write to the log file of {}: {:?}", name, err);
+            }
+        }
+    }
+}
+
+fn main() {
+    // Looking up lm would have us wait on ourselves.
+    sunrise_libuser::log_impl::disable_log_manager();
+
+    let flusher = Thread::create(flush_thread, 0, threads::DEFAULT_STACK_SIZE, "flush")
+        .expect("Cannot create the flush thread");
+    flusher.start().expect("Cannot start the flush thread");
+
+    let mut man = WaitableManager::new();
+    let handler = port_handler(man.work_queue(), "lm", LogService::dispatch).unwrap();
+
+    man.work_queue().spawn(FutureObj::new(Box::new(handler)));
+    man.run();
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"lm\0\0\0\0\0\0\0\0\0\0",
+    title_id: 0x0200000000000015,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+        sunrise_libuser::syscalls::nr::ClearEvent,
+
+        sunrise_libuser::syscalls::nr::ReplyAndReceiveWithUserBuffer,
+        sunrise_libuser::syscalls::nr::AcceptSession,
+        sunrise_libuser::syscalls::nr::CreateSession,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+
+        sunrise_libuser::syscalls::nr::CreateThread,
+        sunrise_libuser::syscalls::nr::StartThread,
+        sunrise_libuser::syscalls::nr::ExitThread,
+        sunrise_libuser::syscalls::nr::SetThreadName,
+
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+
+        sunrise_libuser::syscalls::nr::GetProcessName,
+    ]
+});
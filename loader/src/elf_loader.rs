@@ -3,6 +3,12 @@
 //! Loads the elf binaries.
 
 use core::slice;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
 use xmas_elf::ElfFile;
 use xmas_elf::program::{ProgramHeader, Type::Load, SegmentData};
 use sunrise_libuser::syscalls::{self, map_process_memory};
@@ -12,6 +18,74 @@ use sunrise_libkern::MemoryPermissions;
 use sunrise_libutils::align_up;
 use sunrise_libuser::error::{Error, LoaderError};
 
+/// Identifies a read-only segment of a title's binary.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SegmentKey {
+    /// Name of the title the segment belongs to.
+    title: String,
+    /// Hash of the whole ELF, so that a binary updated on disk doesn't share
+    /// the segments of its previous version.
+    elf_hash: u64,
+    /// Address of the segment in the ELF.
+    vaddr: usize,
+    /// Size of the segment's mapping, page-aligned.
+    mem_size: usize,
+}
+
+/// A read-only segment loaded in a process. It stays mapped in the Loader, so
+/// that the next processes loading the same segment share its pages instead of
+/// getting a private copy.
+///
+/// The pages belong to the address space of the process the segment was first
+/// loaded in, which is kept alive until the last process sharing the segment
+/// is gone. Dropping the last reference unmaps the segment from the Loader and
+/// removes it from the cache.
+#[derive(Debug)]
+pub struct SharedSegment {
+    /// Key of the segment in [SHARED_SEGMENTS].
+    key: SegmentKey,
+    /// The process the segment was loaded in.
+    owner: Arc<Process>,
+    /// Address of the segment in the owner's address space.
+    owner_addr: usize,
+    /// Address of the segment's mapping in the Loader.
+    addr: usize,
+}
+
+impl Drop for SharedSegment {
+    fn drop(&mut self) {
+        {
+            let mut shared_segments = SHARED_SEGMENTS.lock();
+            // The key may already be reused by a newer segment, only remove our own entry.
+            if shared_segments.get(&self.key).map_or(false, |shared| shared.upgrade().is_none()) {
+                shared_segments.remove(&self.key);
+            }
+        }
+
+        let res = unsafe {
+            // Safety: the segment was never handed out as a reference, only
+            // shared with other processes through its address.
+            syscalls::unmap_process_memory(self.addr, &self.owner, self.owner_addr, self.key.mem_size)
+        };
+        if let Err(err) = res {
+            error!("Failed to unmap shared segment of {} at {:#010x}: {:?}", self.key.title, self.addr, err);
+        }
+    }
+}
+
+lazy_static! {
+    /// The read-only segments that can be shared with new processes. Only the
+    /// processes using a segment keep it alive.
+    static ref SHARED_SEGMENTS: Mutex<BTreeMap<SegmentKey, Weak<SharedSegment>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Hashes an ELF with 64-bit FNV-1a, to tell apart different binaries of a title.
+fn hash_elf(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
 /// Turn a byte array into an ELF file.
 ///
 /// # Errors
@@ -69,7 +143,10 @@ pub fn get_kacs<'a>(elf: &'a ElfFile<'_>) -> Option<&'a [u8]> {
         .map(|section| section.raw_data(&elf))
 }
 
-/// Loads the given executable into the given process/address space.
+/// Loads the given executable of the given title into the given process/address space.
+///
+/// Returns the read-only segments shared with other processes of the same
+/// title. They must be kept around as long as the process lives.
 ///
 /// # Errors
 ///
@@ -79,12 +156,17 @@ pub fn get_kacs<'a>(elf: &'a ElfFile<'_>) -> Option<&'a [u8]> {
 /// - `KernelError`
 ///   - A syscall failed while trying to map the remote process memory or set
 ///     the mappings' permissions.
-pub fn load_file(process: &Process, elf: &ElfFile<'_>, base: usize) -> Result<(), Error> {
+pub fn load_file(process: &Arc<Process>, title: &str, elf: &ElfFile<'_>, base: usize) -> Result<Vec<Arc<SharedSegment>>, Error> {
+    let elf_hash = hash_elf(elf.input);
+    let mut shared_segments = Vec::new();
+
     // load all segments into the page_table we had above
     for ph in elf.program_iter().filter(|ph|
         if let Ok(Load) = ph.get_type() { true } else { false })
     {
-        load_segment(process, ph, &elf, base)?;
+        if let Some(shared) = load_segment(process, title, elf_hash, ph, &elf, base)? {
+            shared_segments.push(shared);
+        }
     }
 
     // return the entry point
@@ -94,14 +176,18 @@ pub fn load_file(process: &Process, elf: &ElfFile<'_>, base: usize) -> Result<()
         return Err(LoaderError::InvalidElf.into())
     }
 
-    Ok(())
+    Ok(shared_segments)
 }
 
 /// Loads an elf segment by coping file_size bytes to the right address,
 /// and filling remaining with 0s.
 /// This is used by NOBITS sections (.bss), this way we initialize them to 0.
+///
+/// Read-only segments already loaded for another process of the same title are
+/// shared with it copy-on-write instead. Returns the shared segment the
+/// process now uses, if any.
 #[allow(clippy::match_bool)] // more readable
-fn load_segment(process: &Process, segment: ProgramHeader<'_>, elf_file: &ElfFile, base: usize) -> Result<(), Error> {
+fn load_segment(process: &Arc<Process>, title: &str, elf_hash: u64, segment: ProgramHeader<'_>, elf_file: &ElfFile, base: usize) -> Result<Option<Arc<SharedSegment>>, Error> {
     // Map the segment memory in the current process space
     let mem_size_total = align_up(segment.mem_size() as usize, PAGE_SIZE);
 
@@ -132,6 +218,29 @@ fn load_segment(process: &Process, segment: ProgramHeader<'_>, elf_file: &ElfFil
 
     let virtual_addr = base + segment.virtual_addr() as usize;
 
+    // Read-only segments of a binary that was already loaded are shared
+    // copy-on-write with the previous process.
+    let shared_key = if flags.contains(MemoryPermissions::WRITABLE) {
+        None
+    } else {
+        Some(SegmentKey {
+            title: String::from(title),
+            elf_hash,
+            vaddr: segment.virtual_addr() as usize,
+            mem_size: mem_size_total,
+        })
+    };
+
+    if let Some(key) = &shared_key {
+        let shared = SHARED_SEGMENTS.lock().get(key).and_then(Weak::upgrade);
+        if let Some(shared) = shared {
+            syscalls::share_process_memory(process, virtual_addr, shared.addr, mem_size_total)?;
+            syscalls::set_process_memory_permission(process, virtual_addr, mem_size_total, flags)?;
+            debug!("Shared segment - VirtAddr {:#010x}, MemSize {:#010x}", virtual_addr, segment.mem_size());
+            return Ok(Some(shared))
+        }
+    }
+
     // Access the mapping in the remote process
     let addr = find_free_address(mem_size_total, 0x1000)?;
     map_process_memory(addr, process, virtual_addr, mem_size_total)?;
@@ -154,12 +263,29 @@ fn load_segment(process: &Process, segment: ProgramHeader<'_>, elf_file: &ElfFil
         }
     }
 
-    // Maybe I should panic if this fails, cuz that'd be really bad.
-    unsafe {
-        // Safety: this memory was previously mapped and all pointers to it
-        // should have been dropped already.
-        syscalls::unmap_process_memory(addr, process, virtual_addr, mem_size_total)?;
-    }
+    let shared = match shared_key {
+        Some(key) => {
+            // Keep the mapping around, so that the next processes can share it.
+            // From now on, dropping it unmaps it.
+            let shared = Arc::new(SharedSegment {
+                key: key.clone(),
+                owner: process.clone(),
+                owner_addr: virtual_addr,
+                addr,
+            });
+            SHARED_SEGMENTS.lock().insert(key, Arc::downgrade(&shared));
+            Some(shared)
+        },
+        None => {
+            // Maybe I should panic if this fails, cuz that'd be really bad.
+            unsafe {
+                // Safety: this memory was previously mapped and all pointers to it
+                // should have been dropped already.
+                syscalls::unmap_process_memory(addr, process, virtual_addr, mem_size_total)?;
+            }
+            None
+        }
+    };
 
     syscalls::set_process_memory_permission(process, virtual_addr, mem_size_total, flags)?;
 
@@ -170,5 +296,5 @@ fn load_segment(process: &Process, segment: ProgramHeader<'_>, elf_file: &ElfFil
         match segment.flags().is_execute() { true => 'X', false => ' '},
     );
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(shared)
+}
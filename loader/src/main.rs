@@ -34,6 +34,8 @@ use core::slice;
 use core::mem::size_of;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 
 use sunrise_libuser::fs::{DirectoryEntry, DirectoryEntryType, FileSystemPath, IFileSystemProxy, IFileSystemServiceProxy};
 use sunrise_libuser::{kip_header, capabilities};
@@ -60,8 +62,18 @@ mod elf_loader;
 /// file bigger than 128MiB.
 const MAX_ELF_SIZE: u64 = 128 * 1024 * 1024;
 
+/// A process started by the Loader.
+#[derive(Debug)]
+struct LoadedProcess {
+    /// Handle to the process.
+    process: Arc<Process>,
+    /// The read-only segments the process shares with other processes of the
+    /// same title. Dropping the last reference to a segment frees it.
+    shared_segments: Vec<Arc<elf_loader::SharedSegment>>,
+}
+
 lazy_static! {
-    static ref PROCESSES: Mutex<BTreeMap<u64, Process>> = Mutex::new(BTreeMap::new());
+    static ref PROCESSES: Mutex<BTreeMap<u64, LoadedProcess>> = Mutex::new(BTreeMap::new());
 }
 
 /// Start the given titleid by loading its content from the provided filesystem.
@@ -139,7 +151,7 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8]) -> Result<Pid, Erro
 
     let total_size = elf_size + align_up(args_size, PAGE_SIZE);
 
-    let process = sunrise_libuser::syscalls::create_process(&ProcInfo {
+    let process = Arc::new(sunrise_libuser::syscalls::create_process(&ProcInfo {
         name: titlename_bytes,
         process_category: ProcessCategory::RegularTitle,
         title_id: 0,
@@ -148,10 +160,10 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8]) -> Result<Pid, Erro
         flags,
         resource_limit_handle: None,
         system_resource_num_pages: 0,
-    }, &kacs)?;
+    }, &kacs)?);
 
     debug!("Loading ELF");
-    elf_loader::load_file(&process, &elf, aslr_base)?;
+    let shared_segments = elf_loader::load_file(&process, titlename, &elf, aslr_base)?;
 
     debug!("Handling args");
     let addr = find_free_address(args_size, 0x1000)?;
@@ -187,7 +199,7 @@ fn boot(fs: &IFileSystemProxy, titlename: &str, args: &[u8]) -> Result<Pid, Erro
     }
 
     let pid = process.pid()?;
-    PROCESSES.lock().insert(pid.0, process);
+    PROCESSES.lock().insert(pid.0, LoadedProcess { process, shared_segments });
 
     Ok(pid)
 }
@@ -234,21 +246,22 @@ impl ILoaderInterfaceAsync for LoaderIface {
             // BODY: and we'd just expose "Process" and "ProcessBorrowed" types
             // BODY: through typedef/newtypes. Needs a lot of thought.
             let process_wait = (PROCESSES.lock().get(&pid)
-                .ok_or(PmError::PidNotFound)?.0).as_ref_static();
+                .ok_or(PmError::PidNotFound)?.process.0).as_ref_static();
             loop {
                 process_wait.wait_async(workqueue.clone()).await?;
                 let mut lock = PROCESSES.lock();
-                let process = lock.get(&pid)
-                    .ok_or(PmError::PidNotFound)?;
+                let process = &lock.get(&pid)
+                    .ok_or(PmError::PidNotFound)?.process;
                 match process.reset_signal() {
                     Ok(()) | Err(Error::Kernel(KernelError::InvalidState, _)) => (),
                     Err(err) => return Err(err)
                 };
 
                 if process.state()? == ProcessState::Exited {
+                    let exit_reason = process.exit_reason()?;
+                    // Frees the segments no other process uses anymore.
                     lock.remove(&pid);
-                    // TODO: Return exit state.
-                    return Ok(0);
+                    return Ok(exit_reason.0);
                 }
             }
         }))
@@ -344,6 +357,7 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::CreateProcess,
         sunrise_libuser::syscalls::nr::MapProcessMemory,
         sunrise_libuser::syscalls::nr::UnmapProcessMemory,
+        sunrise_libuser::syscalls::nr::ShareProcessMemory,
         sunrise_libuser::syscalls::nr::SetProcessMemoryPermission,
         sunrise_libuser::syscalls::nr::StartProcess,
 
@@ -232,6 +232,39 @@ fn main() {
                 let handle = sm::IUserInterfaceProxy::raw_new().unwrap().get_service(u64::from_le_bytes(*b"vi:\0\0\0\0\0"));
                 let _ = writeln!(&mut terminal, "Got handle {:?}", handle);
             },
+            "dumpinfo" => {
+                let res = match arguments.nth(0) {
+                    Some("kernel") => Some(syscalls::dump_info(syscalls::DumpInfoType::KernelMemory, 0)),
+                    Some(pid) => pid.parse::<u64>().ok().map(|pid| syscalls::dump_info(syscalls::DumpInfoType::All, pid)),
+                    None => None
+                };
+                match res {
+                    Some(Err(err)) => {
+                        let _ = writeln!(&mut terminal, "dumpinfo: {:?}", err);
+                    },
+                    Some(Ok(())) => (),
+                    None => {
+                        let _ = writeln!(&mut terminal, "usage: dumpinfo <pid|kernel>");
+                    }
+                }
+            },
+            "loglevel" => {
+                if let Some(directives) = arguments.nth(0) {
+                    if let Err(err) = syscalls::set_kernel_log_filter(directives) {
+                        let _ = writeln!(&mut terminal, "loglevel: {:?}", err);
+                    }
+                }
+                let mut buf = [0; 256];
+                match syscalls::get_kernel_log_filter(&mut buf) {
+                    Ok(len) => {
+                        let directives = core::str::from_utf8(&buf[..core::cmp::min(len, buf.len())]).unwrap_or("?");
+                        let _ = writeln!(&mut terminal, "{}", directives);
+                    },
+                    Err(err) => {
+                        let _ = writeln!(&mut terminal, "loglevel: {:?}", err);
+                    }
+                }
+            },
             "exit" => return,
             //"stackdump" => unsafe { stack::KernelStack::dump_current_stack() },
             "help" => {
@@ -242,6 +275,9 @@ fn main() {
                 let _ = writeln!(&mut terminal, "cd <directory>: change the working directory");
                 let _ = writeln!(&mut terminal, "ls [directory]: List directory contents. Defaults to the current directory.");
                 let _ = writeln!(&mut terminal, "pwd: Print name of the current/working directory");
+                let _ = writeln!(&mut terminal, "dumpinfo <pid>: Print the memory map, handles and threads of a process to the kernel log");
+                let _ = writeln!(&mut terminal, "dumpinfo kernel: Print the kernel memory usage to the kernel log");
+                let _ = writeln!(&mut terminal, "loglevel [directives]: Print or set the kernel log filter, e.g. `loglevel info,sunrise_kernel::ipc=trace`");
                 let _ = writeln!(&mut terminal, "meme1: Display the KFS-1 meme");
                 let _ = writeln!(&mut terminal, "meme2: Display the KFS-2 meme");
                 let _ = writeln!(&mut terminal, "meme3: Display the KFS-3 meme");
@@ -265,6 +301,9 @@ fn main() {
                     Err(err) => {
                         let _ = writeln!(&mut terminal, "Error: {:?}", err);
                     },
+                    Ok(exitstatus) if syscalls::ExitReason(exitstatus) == syscalls::ExitReason::OutOfMemory => {
+                        let _ = writeln!(&mut terminal, "{}: out of memory", name);
+                    },
                     Ok(_exitstatus) => ()
                 }
             }
@@ -506,7 +545,7 @@ fn test_threads(terminal: Terminal) -> Terminal {
                 let _ = writeln!(lock, "A");
                 i += 1;
             }
-            let _ = libuser::syscalls::sleep_thread(0);
+            libuser::threads::yield_now();
         }
     }
 
@@ -523,14 +562,14 @@ fn test_threads(terminal: Terminal) -> Terminal {
                     let _ = writeln!(lock, "B");
                     i += 1;
                 }
-                let _ = libuser::syscalls::sleep_thread(0);
+                libuser::threads::yield_now();
             }
         }
     }
 
     let terminal = Arc::new(Mutex::new(terminal));
 
-    let t = Thread::create(thread_b, Arc::into_raw(terminal.clone()) as usize, threads::DEFAULT_STACK_SIZE)
+    let t = Thread::create(thread_b, Arc::into_raw(terminal.clone()) as usize, threads::DEFAULT_STACK_SIZE, "thread_b")
         .expect("Failed to create thread B");
     t.start()
         .expect("Failed to start thread B");
@@ -613,11 +652,15 @@ capabilities!(CAPABILITIES = Capabilities {
         libuser::syscalls::nr::CreateThread,
         libuser::syscalls::nr::StartThread,
         libuser::syscalls::nr::ExitThread,
+        libuser::syscalls::nr::SetThreadName,
         libuser::syscalls::nr::MapSharedMemory,
         libuser::syscalls::nr::UnmapSharedMemory,
         libuser::syscalls::nr::ConnectToNamedPort,
         libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
         libuser::syscalls::nr::CreateSharedMemory,
         libuser::syscalls::nr::CreateInterruptEvent,
+        libuser::syscalls::nr::DumpInfo,
+        libuser::syscalls::nr::SetKernelLogFilter,
+        libuser::syscalls::nr::GetKernelLogFilter,
     ]
 });
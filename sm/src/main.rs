@@ -19,6 +19,11 @@
 //! it, and it returns a ClientSession. The difference is that a Service handled
 //! by "sm:" has an additional permission check done to ensure it isn't accessed
 //! by an unprivileged process.
+//!
+//! When the process hosting a service dies, its port dies with it. The Service
+//! Manager notices it, either by waiting on the death event of its port or
+//! when connecting to it fails, and unregisters the service so it can be
+//! registered again, e.g. by a restarted instance of the service.
 //! Service Manager
 
 #![feature(async_await)]
@@ -50,7 +55,7 @@ use crate::libuser::syscalls;
 use crate::libuser::futures::{WaitableManager, WorkQueue};
 use crate::libuser::ipc::server::managed_port_handler;
 use crate::libuser::types::*;
-use crate::libuser::error::Error;
+use crate::libuser::error::{Error, KernelError};
 use crate::libuser::error::SmError;
 use crate::libuser::futures_rs::future::{FutureExt, FutureObj};
 use crate::libuser::sm::IUserInterfaceAsync;
@@ -68,9 +73,22 @@ use crate::libuser::futures_rs as futures;
 #[derive(Debug, Default)]
 struct UserInterface;
 
+/// A registered service.
+#[derive(Debug)]
+struct Service {
+    /// Port the clients of the service connect to.
+    port: ClientPort,
+    /// Identifies this registration of the service. Unlike the handle of the
+    /// port, it is never reused, even once the service is unregistered.
+    id: u64,
+}
+
+/// Id of the next registered service. See [Service::id].
+static NEXT_SERVICE_ID: Mutex<u64> = Mutex::new(0);
+
 lazy_static! {
-    /// Global mapping of Service Name -> ClientPort.
-    static ref SERVICES: Mutex<HashMap<ServiceName, ClientPort>> = Mutex::new(HashMap::new());
+    /// Global mapping of Service Name -> Service.
+    static ref SERVICES: Mutex<HashMap<ServiceName, Service>> = Mutex::new(HashMap::new());
     // TODO: Implement a futures-based condvar instead of using event for in-process eventing.
     // BODY: A futures-based condvar can easily be implemented entirely in userspace, without
     // BODY: the need for any kernel help. It would have a lot less overhead than using a kernel Event.
@@ -138,6 +156,23 @@ impl core::fmt::Display for ServiceName {
     }
 }
 
+/// Unregisters the service `servicename`, registered with the given `id`,
+/// whose port died.
+///
+/// Nothing is done if the service was already unregistered, or registered
+/// again in the meantime.
+fn unregister_dead_service(servicename: ServiceName, id: u64) {
+    let mut services = SERVICES.lock();
+    let is_same_service = services.get(&servicename)
+        .map(|service| service.id == id)
+        .unwrap_or(false);
+
+    if is_same_service {
+        info!("Service {} died, unregistering it.", servicename);
+        services.remove(&servicename);
+    }
+}
+
 impl IUserInterfaceAsync for UserInterface {
     /// Initialize the UserInterface, acquiring the Pid of the remote
     /// process, which will then be used to validate the permissions of each
@@ -159,14 +194,31 @@ impl IUserInterfaceAsync for UserInterface {
     // trying to connect to the port!
     //
     // For this reason, it is recommended for processes to use a global `sm:` handle.
+    //
+    // If the service reached its maximum number of sessions, we wait for one of
+    // them to be closed. If the service died, it gets unregistered, and we wait
+    // for it to come back.
     fn get_service<'a>(&mut self, work_queue: WorkQueue<'a>, servicename: u64) -> FutureObj<'a, Result<ClientSession, Error>> {
         let servicename = ServiceName(servicename);
         FutureObj::new(Box::new(loop_fn(work_queue, move |work_queue| {
-            if let Some(port) = SERVICES.lock().get(&servicename) {
+            let mut services = SERVICES.lock();
+            if let Some(Service { port, .. }) = services.get(&servicename) {
                 debug!("Acquired service {}!", servicename);
                 // Synchronous connect. This can block.
-                let client = port.connect();
-                futures::future::ready(Loop::Break(client)).left_future()
+                match port.connect() {
+                    Err(Error::Kernel(KernelError::PortMaxSessions, _)) => {
+                        debug!("Service {} has no session available. Sleeping.", servicename);
+                        port.wait_async(work_queue.clone())
+                            .map(|_| Loop::Continue(work_queue))
+                            .left_future().right_future()
+                    },
+                    Err(Error::Kernel(KernelError::PortRemoteDead, _)) => {
+                        info!("Service {} died, unregistering it.", servicename);
+                        services.remove(&servicename);
+                        futures::future::ready(Loop::Continue(work_queue)).left_future()
+                    },
+                    client => futures::future::ready(Loop::Break(client)).left_future()
+                }
             } else {
                 debug!("Service {} not currently registered. Sleeping.", servicename);
                 SERVICES_EVENT.1.wait_async_cb(work_queue.clone(), move || {
@@ -174,13 +226,16 @@ impl IUserInterfaceAsync for UserInterface {
                 })
                     .map(|_| {
                         Loop::Continue(work_queue)
-                    }).right_future()
+                    }).right_future().right_future()
             }
         })))
     }
     /// Register a new service, returning a ServerPort to the newly
     /// registered service.
-    fn register_service(&mut self, _work_queue: WorkQueue<'static>, servicename: u64, is_light: bool, max_handles: u32) -> FutureObj<'_, Result<ServerPort, Error>> {
+    ///
+    /// The service is unregistered when its ServerPort gets closed, e.g. when
+    /// the process hosting it dies.
+    fn register_service(&mut self, work_queue: WorkQueue<'static>, servicename: u64, is_light: bool, max_handles: u32) -> FutureObj<'_, Result<ServerPort, Error>> {
         let servicename = ServiceName(servicename);
 
         let serverport = {
@@ -195,7 +250,28 @@ impl IUserInterfaceAsync for UserInterface {
                 Err(err) => return FutureObj::new(Box::new(futures::future::err(err.into())))
             };
 
-            entry.insert(clientport);
+            let id = {
+                let mut next_id = NEXT_SERVICE_ID.lock();
+                *next_id += 1;
+                *next_id
+            };
+
+            // The ClientPort is also signaled when a session becomes available,
+            // wait on an event that only gets signaled when the port dies.
+            match clientport.death_event() {
+                Ok(death_event) => {
+                    let watcher = death_event.wait_async(work_queue.clone())
+                        .map(move |_| {
+                            unregister_dead_service(servicename, id);
+                            drop(death_event);
+                        });
+                    work_queue.spawn(FutureObj::new(Box::new(watcher)));
+                },
+                // it will be cleaned up when connecting to it fails instead.
+                Err(err) => warn!("Cannot watch service {} for its death: {:?}", servicename, err)
+            }
+
+            entry.insert(Service { port: clientport, id });
 
             serverport
         };
@@ -214,9 +290,35 @@ impl IUserInterfaceAsync for UserInterface {
             None => FutureObj::new(Box::new(futures::future::err(SmError::ServiceNotRegistered.into())))
         }
     }
+
+    /// Get a ClientSession to this service if it is currently registered,
+    /// without waiting for it.
+    ///
+    /// Fails with `MaxSessions` if the service has no session available, and
+    /// with `ServiceNotRegistered` if the service isn't registered or died.
+    //
+    // Implementation note: see get_service, the synchronous connect can block.
+    fn get_service_if_registered(&mut self, _work_queue: WorkQueue<'static>, servicename: u64) -> FutureObj<'_, Result<ClientSession, Error>> {
+        let servicename = ServiceName(servicename);
+        let mut services = SERVICES.lock();
+        let res = match services.get(&servicename).map(|service| service.port.connect()) {
+            None => Err(SmError::ServiceNotRegistered.into()),
+            Some(Err(Error::Kernel(KernelError::PortMaxSessions, _))) => Err(SmError::MaxSessions.into()),
+            Some(Err(Error::Kernel(KernelError::PortRemoteDead, _))) => {
+                info!("Service {} died, unregistering it.", servicename);
+                services.remove(&servicename);
+                Err(SmError::ServiceNotRegistered.into())
+            },
+            Some(client) => client
+        };
+        FutureObj::new(Box::new(futures::future::ready(res)))
+    }
 }
 
 fn main() {
+    // Looking up lm would have us wait on ourselves.
+    libuser::log_impl::disable_log_manager();
+
     let mut man = WaitableManager::new();
     let handler = managed_port_handler(man.work_queue(), "sm:\0", UserInterface::dispatch).unwrap();
 
@@ -257,5 +359,6 @@ capabilities!(CAPABILITIES = Capabilities {
         sunrise_libuser::syscalls::nr::SignalEvent,
         sunrise_libuser::syscalls::nr::ClearEvent,
         sunrise_libuser::syscalls::nr::ResetSignal,
+        sunrise_libuser::syscalls::nr::CreatePortDeathEvent,
     ]
 });
@@ -893,6 +893,10 @@ pub fn generate_proxy(ifacename: &str, interface: &Interface) -> String {
     }
 
     writeln!(s, "impl {} {{", struct_name).unwrap();
+    writeln!(s, "    /// Creates a new [{}] to the same remote object. See [ClientSession::try_clone].", struct_name).unwrap();
+    writeln!(s, "    pub fn try_clone(&self) -> Result<{}, Error> {{", struct_name).unwrap();
+    writeln!(s, "        Ok({}(self.0.try_clone()?))", struct_name).unwrap();
+    writeln!(s, "    }}").unwrap();
     for cmd in &interface.funcs {
         match format_cmd(&cmd) {
             Ok(out) => write!(s, "{}", out).unwrap(),
@@ -0,0 +1,163 @@
+//! Process monitor
+//!
+//! Shows the CPU usage of every process in a terminal window, refreshed every
+//! second. The usage of a process is the CPU time it used since the last
+//! refresh, split between userspace and kernel time, as reported by the kernel
+//! through `svcGetInfo`.
+
+#![no_std]
+
+// rustc warnings
+#![warn(unused)]
+#![warn(missing_debug_implementations)]
+#![allow(unused_unsafe)]
+#![allow(unreachable_code)]
+#![allow(dead_code)]
+#![cfg_attr(test, allow(unused_imports))]
+
+// rustdoc warnings
+#![warn(missing_docs)] // hopefully this will soon become deny(missing_docs)
+#![deny(intra_doc_link_resolution_failure)]
+
+#[macro_use]
+extern crate sunrise_libuser;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::cmp::{self, Reverse};
+use core::fmt::Write;
+use sunrise_libuser::terminal::{Terminal, WindowSize};
+use sunrise_libuser::syscalls::{self, InfoType};
+
+/// Maximum number of processes we can display.
+const MAX_PROCESSES: usize = 64;
+
+/// Time between two refreshes, in nanoseconds.
+const REFRESH_PERIOD_NS: usize = 1_000_000_000;
+
+/// CPU time used by a process, in nanoseconds.
+///
+/// Either the total since the process started, or the time used between two refreshes.
+#[derive(Debug, Clone, Copy)]
+struct ProcessSample {
+    /// The PID of the process.
+    pid: u64,
+    /// Time spent running userspace code.
+    user_ns: u64,
+    /// Time spent in the kernel.
+    kernel_ns: u64,
+}
+
+/// Samples the CPU time of all the processes currently alive.
+///
+/// Processes that die while we're sampling them are skipped.
+fn sample_processes() -> Vec<ProcessSample> {
+    let mut pids = [0; MAX_PROCESSES];
+    let count = syscalls::get_process_list(&mut pids).unwrap();
+    pids[..count].iter().filter_map(|&pid| {
+        let (user_ns, kernel_ns) = syscalls::get_process_cpu_time(pid).ok()?;
+        Some(ProcessSample { pid, user_ns, kernel_ns })
+    }).collect()
+}
+
+/// Gets the time the CPU spent idle and running threads, in nanoseconds.
+fn sample_cpu() -> (u64, u64) {
+    let to_ns = |ticks: u64| ticks / syscalls::SYSTEM_TICK_FREQUENCY * 1_000_000_000
+        + ticks % syscalls::SYSTEM_TICK_FREQUENCY * 1_000_000_000 / syscalls::SYSTEM_TICK_FREQUENCY;
+    let idle = syscalls::get_info(InfoType::IdleTickCount, None, u64::max_value()).unwrap();
+    let busy = syscalls::get_info(InfoType::BusyTickCount, None, u64::max_value()).unwrap();
+    (to_ns(idle), to_ns(busy))
+}
+
+/// Writes `part` as a percentage of `total`, with one decimal.
+fn write_percent(terminal: &mut Terminal, part: u64, total: u64) {
+    let permille = if total == 0 { 0 } else { part.saturating_mul(1000) / total };
+    let _ = write!(terminal, " {:>3}.{}%", permille / 10, permille % 10);
+}
+
+fn main() {
+    let mut terminal = Terminal::new(WindowSize::FontLines(16, false)).unwrap();
+
+    let mut previous = sample_processes();
+    let mut previous_cpu = sample_cpu();
+
+    loop {
+        syscalls::sleep_thread(REFRESH_PERIOD_NS).unwrap();
+
+        let current = sample_processes();
+        let cpu = sample_cpu();
+
+        // Processes born since the last refresh are compared against 0.
+        let mut deltas: Vec<ProcessSample> = current.iter().map(|sample| {
+            let old = previous.iter().find(|old| old.pid == sample.pid);
+            ProcessSample {
+                pid: sample.pid,
+                user_ns: sample.user_ns.saturating_sub(old.map(|old| old.user_ns).unwrap_or(0)),
+                kernel_ns: sample.kernel_ns.saturating_sub(old.map(|old| old.kernel_ns).unwrap_or(0)),
+            }
+        }).collect();
+        deltas.sort_by_key(|delta| Reverse(delta.user_ns + delta.kernel_ns));
+        let idle_delta = cpu.0.saturating_sub(previous_cpu.0);
+
+        // The CPU is either idle, or running a thread, so this is the time
+        // elapsed since the last refresh.
+        let total = idle_delta + cpu.1.saturating_sub(previous_cpu.1);
+
+        terminal.clear();
+        let _ = writeln!(terminal, "  PID NAME           CPU   USER KERNEL");
+        for delta in &deltas {
+            let mut name = [0; 12];
+            let len = syscalls::get_process_name(delta.pid, &mut name).unwrap_or(0);
+            let name = core::str::from_utf8(&name[..cmp::min(len, name.len())])
+                .unwrap_or("?")
+                .trim_end_matches('\0');
+            let _ = write!(terminal, "{:>5} {:<12}", delta.pid, name);
+            write_percent(&mut terminal, delta.user_ns + delta.kernel_ns, total);
+            write_percent(&mut terminal, delta.user_ns, total);
+            write_percent(&mut terminal, delta.kernel_ns, total);
+            let _ = writeln!(terminal);
+        }
+        let _ = write!(terminal, "{:>5} {:<12}", "", "idle");
+        write_percent(&mut terminal, idle_delta, total);
+        let _ = terminal.draw();
+
+        previous = current;
+        previous_cpu = cpu;
+    }
+}
+
+kip_header!(HEADER = sunrise_libuser::caps::KipHeader {
+    magic: *b"KIP1",
+    name: *b"top\0\0\0\0\0\0\0\0\0",
+    title_id: 0x0200000000001080,
+    process_category: sunrise_libuser::caps::ProcessCategory::KernelBuiltin,
+    main_thread_priority: 0,
+    default_cpu_core: 0,
+    flags: 0,
+    reserved: 0,
+    stack_page_count: 16,
+});
+
+capabilities!(CAPABILITIES = Capabilities {
+    svcs: [
+        sunrise_libuser::syscalls::nr::SleepThread,
+        sunrise_libuser::syscalls::nr::ExitProcess,
+        sunrise_libuser::syscalls::nr::CloseHandle,
+        sunrise_libuser::syscalls::nr::WaitSynchronization,
+        sunrise_libuser::syscalls::nr::OutputDebugString,
+        sunrise_libuser::syscalls::nr::SetThreadArea,
+
+        sunrise_libuser::syscalls::nr::ConnectToNamedPort,
+        sunrise_libuser::syscalls::nr::SetHeapSize,
+        sunrise_libuser::syscalls::nr::SendSyncRequestWithUserBuffer,
+        sunrise_libuser::syscalls::nr::QueryMemory,
+        sunrise_libuser::syscalls::nr::CreateSharedMemory,
+        sunrise_libuser::syscalls::nr::MapSharedMemory,
+        sunrise_libuser::syscalls::nr::UnmapSharedMemory,
+
+        sunrise_libuser::syscalls::nr::GetInfo,
+        sunrise_libuser::syscalls::nr::GetProcessList,
+        sunrise_libuser::syscalls::nr::GetProcessName,
+        sunrise_libuser::syscalls::nr::GetProcessCpuTime,
+    ]
+});